@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ntfs_reader::parse::{parse_attribute, parse_data_runs};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_attribute(data);
+    let _ = parse_data_runs(data);
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ntfs_reader::parse::parse_record;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_record(data);
+});
@@ -0,0 +1,114 @@
+/* Generated with cbindgen from src/ffi.rs, do not edit. */
+
+#ifndef NTFS_READER_H
+#define NTFS_READER_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define NTFS_OK 0
+
+#define NTFS_STOPPED 1
+
+#define NTFS_ERROR -1
+
+#define NTFS_INVALID_ARGUMENT -2
+
+#define NTFS_BUFFER_TOO_SMALL -3
+
+#define NTFS_ELEVATION_REQUIRED -4
+
+#define NTFS_PANIC -5
+
+#define NTFS_ITERATE_PATHS 1
+
+#if defined(NTFS_READER_WINDOWS)
+typedef struct NtfsJournal NtfsJournal;
+#endif
+
+typedef struct NtfsMft NtfsMft;
+
+typedef struct NtfsVolume NtfsVolume;
+
+typedef struct NtfsFileEntry {
+  uint64_t number;
+  uint64_t reference;
+  uint64_t parent_reference;
+  uint32_t is_directory;
+  uint64_t size;
+  uint64_t created;
+  uint64_t modified;
+  uint64_t accessed;
+  const uint16_t *name;
+  size_t name_len;
+  const uint16_t *path;
+  size_t path_len;
+} NtfsFileEntry;
+
+typedef int32_t (*NtfsFileCallback)(const struct NtfsFileEntry *entry, void *context);
+
+typedef struct NtfsUsnEntry {
+  int64_t usn;
+  int64_t timestamp;
+  uint64_t file_reference;
+  uint64_t parent_reference;
+  uint32_t reason;
+  uint32_t file_attributes;
+  const uint16_t *path;
+  size_t path_len;
+} NtfsUsnEntry;
+
+typedef int32_t (*NtfsUsnCallback)(const struct NtfsUsnEntry *entry, void *context);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+const char *ntfs_version(void);
+
+int32_t ntfs_last_error(uint16_t *buffer, size_t *len);
+
+#if defined(NTFS_READER_WINDOWS)
+int32_t ntfs_volume_open(const uint16_t *path, struct NtfsVolume **out);
+#endif
+
+int32_t ntfs_volume_open_image(const uint16_t *path, uint64_t offset, struct NtfsVolume **out);
+
+void ntfs_volume_free(struct NtfsVolume *volume);
+
+int32_t ntfs_mft_open(const struct NtfsVolume *volume, struct NtfsMft **out);
+
+void ntfs_mft_free(struct NtfsMft *mft);
+
+uint64_t ntfs_mft_max_record(const struct NtfsMft *mft);
+
+int32_t ntfs_mft_iterate(const struct NtfsMft *mft,
+                         uint32_t flags,
+                         NtfsFileCallback callback,
+                         void *context);
+
+int32_t ntfs_mft_get_path(const struct NtfsMft *mft, uint64_t number, uint16_t *buffer, size_t *len);
+
+#if defined(NTFS_READER_WINDOWS)
+int32_t ntfs_journal_open(const struct NtfsVolume *volume, struct NtfsJournal **out);
+#endif
+
+#if defined(NTFS_READER_WINDOWS)
+void ntfs_journal_free(struct NtfsJournal *journal);
+#endif
+
+#if defined(NTFS_READER_WINDOWS)
+int32_t ntfs_journal_read(struct NtfsJournal *journal,
+                          uint32_t timeout_ms,
+                          NtfsUsnCallback callback,
+                          void *context);
+#endif
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* NTFS_READER_H */
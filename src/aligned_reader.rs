@@ -2,11 +2,339 @@
 // This project is dual licensed under the Apache License 2.0 and the MIT license.
 // See the LICENSE files in the project root for details.
 
-use std::fs::File;
-use std::io::{self, BufReader};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader};
 use std::io::{Read, Seek, SeekFrom};
+#[cfg(windows)]
+use std::os::windows::fs::OpenOptionsExt;
 use std::path::Path;
+use std::time::{Duration, Instant};
 
+use tracing::warn;
+
+use crate::errors::Win32Error;
+
+// From WinBase.h, to not need the windows crate here.
+#[cfg(windows)]
+const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x02000000;
+#[cfg(windows)]
+const FILE_FLAG_NO_BUFFERING: u32 = 0x20000000;
+#[cfg(windows)]
+const FILE_FLAG_SEQUENTIAL_SCAN: u32 = 0x08000000;
+
+// From WinError.h, the reads of raw volumes sometimes fail with these under contention.
+pub const ERROR_INVALID_FUNCTION: u32 = 1;
+pub const ERROR_IO_DEVICE: u32 = 1117;
+
+// How the volume is opened, see `Volume::read_options`. Only used on Windows.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct VolumeReadOptions {
+    // Bypass the system cache (FILE_FLAG_NO_BUFFERING), so that reading the whole MFT doesn't
+    // evict everything else from it. The reads are always aligned to 4096 bytes.
+    pub unbuffered: bool,
+    // Hint that the volume is read sequentially (FILE_FLAG_SEQUENTIAL_SCAN).
+    pub sequential_scan: bool,
+    pub retry: RetryPolicy,
+    // Not limited by default.
+    pub throttle: Throttle,
+}
+
+// How many times `RetryReader` tries again a failed read, and after how long.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    // After the first failure, zero never retries.
+    pub max_retries: u32,
+    // Before the first retry, then doubled each time up to `max_delay`.
+    pub delay: Duration,
+    pub max_delay: Duration,
+    // The Win32 error codes worth retrying (`io::Error::raw_os_error` or `Win32Error::code`).
+    pub errors: Vec<u32>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            delay: Duration::from_millis(50),
+            max_delay: Duration::from_secs(1),
+            errors: vec![ERROR_INVALID_FUNCTION, ERROR_IO_DEVICE],
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Default::default()
+        }
+    }
+
+    pub fn should_retry(&self, err: &io::Error) -> bool {
+        let code = match Win32Error::from_io(err) {
+            Some(err) => Some(err.code),
+            None => err.raw_os_error().map(|code| code as u32),
+        };
+        code.is_some_and(|code| self.errors.contains(&code))
+    }
+
+    // Before the retry `attempt` (starting from zero).
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.delay
+            .saturating_mul(1u32.checked_shl(attempt).unwrap_or(u32::MAX))
+            .min(self.max_delay)
+    }
+}
+
+// Retries the failed reads of the inner reader according to the policy, seeking back to where
+// the read started first (a failed read may have moved the position).
+pub struct RetryReader<R>
+where
+    R: Read + Seek,
+{
+    inner: R,
+    policy: RetryPolicy,
+    position: u64,
+    // Since the reader was created, including the ones that still failed in the end.
+    pub retries: u64,
+}
+
+impl<R> RetryReader<R>
+where
+    R: Read + Seek,
+{
+    pub fn new(mut inner: R, policy: RetryPolicy) -> io::Result<Self> {
+        let position = inner.stream_position()?;
+        Ok(Self {
+            inner,
+            policy,
+            position,
+            retries: 0,
+        })
+    }
+
+    pub fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R> Read for RetryReader<R>
+where
+    R: Read + Seek,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut attempt = 0;
+        loop {
+            match self.inner.read(buf) {
+                Ok(n) => {
+                    self.position += n as u64;
+                    return Ok(n);
+                }
+                Err(err) if attempt < self.policy.max_retries && self.policy.should_retry(&err) => {
+                    let delay = self.policy.delay(attempt);
+                    warn!(
+                        "Read at {} failed ({}), retrying in {:?}",
+                        self.position, err, delay
+                    );
+                    std::thread::sleep(delay);
+                    attempt += 1;
+                    self.retries += 1;
+                    self.inner.seek(SeekFrom::Start(self.position))?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl<R> Seek for RetryReader<R>
+where
+    R: Read + Seek,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = self.inner.seek(pos)?;
+        Ok(self.position)
+    }
+}
+
+// Limits how fast `ThrottledReader` reads, so that a background scan doesn't slow down the
+// other applications using the disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Throttle {
+    pub bytes_per_second: Option<u64>,
+    pub reads_per_second: Option<u32>,
+    // Lowers the I/O priority of the volume handle (FileIoPriorityHintInfo), so that the other
+    // accesses to the disk go first. Needs the `windows` feature, see also `BackgroundMode`.
+    pub low_priority: bool,
+}
+
+impl Throttle {
+    pub fn is_limited(&self) -> bool {
+        self.bytes_per_second.is_some() || self.reads_per_second.is_some()
+    }
+
+    // How long a read of `bytes` takes at the maximum rate.
+    pub fn cost(&self, bytes: usize) -> Duration {
+        let throughput = match self.bytes_per_second {
+            Some(rate) if rate > 0 => Duration::from_secs_f64(bytes as f64 / rate as f64),
+            _ => Duration::ZERO,
+        };
+        let iops = match self.reads_per_second {
+            Some(rate) if rate > 0 => Duration::from_secs(1) / rate,
+            _ => Duration::ZERO,
+        };
+        throughput.max(iops)
+    }
+}
+
+// Waits before each read so that the inner reader is not read faster than the throttle allows.
+// The reads are spread evenly, the time spent idle is not saved for a later burst.
+pub struct ThrottledReader<R>
+where
+    R: Read + Seek,
+{
+    inner: R,
+    throttle: Throttle,
+    // When the next read can start.
+    next: Option<Instant>,
+    // Waiting, since the reader was created.
+    pub throttled: Duration,
+}
+
+impl<R> ThrottledReader<R>
+where
+    R: Read + Seek,
+{
+    pub fn new(inner: R, throttle: Throttle) -> Self {
+        Self {
+            inner,
+            throttle,
+            next: None,
+            throttled: Duration::ZERO,
+        }
+    }
+
+    pub fn throttle(&self) -> &Throttle {
+        &self.throttle
+    }
+
+    pub fn set_throttle(&mut self, throttle: Throttle) {
+        self.throttle = throttle;
+        self.next = None;
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R> Read for ThrottledReader<R>
+where
+    R: Read + Seek,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.throttle.is_limited() {
+            return self.inner.read(buf);
+        }
+
+        let mut start = Instant::now();
+        if let Some(next) = self.next.filter(|next| *next > start) {
+            std::thread::sleep(next - start);
+            self.throttled += next - start;
+            start = next;
+        }
+
+        let n = self.inner.read(buf)?;
+        self.next = Some(start + self.throttle.cost(n));
+        Ok(n)
+    }
+}
+
+impl<R> Seek for ThrottledReader<R>
+where
+    R: Read + Seek,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+// Lowers the priority of the current thread, its I/O included (THREAD_MODE_BACKGROUND_BEGIN),
+// until dropped. Affects everything the thread does, not only the reads of the volume.
+#[cfg(feature = "windows")]
+pub struct BackgroundMode {
+    // Only the thread that started it can end it.
+    _thread: std::marker::PhantomData<*const ()>,
+}
+
+#[cfg(feature = "windows")]
+impl BackgroundMode {
+    pub fn begin() -> io::Result<Self> {
+        use windows::Win32::System::Threading::{
+            GetCurrentThread, SetThreadPriority, THREAD_MODE_BACKGROUND_BEGIN,
+        };
+
+        unsafe { SetThreadPriority(GetCurrentThread(), THREAD_MODE_BACKGROUND_BEGIN) }
+            .map_err(crate::errors::win32("SetThreadPriority"))?;
+        Ok(Self {
+            _thread: std::marker::PhantomData,
+        })
+    }
+}
+
+#[cfg(feature = "windows")]
+impl Drop for BackgroundMode {
+    fn drop(&mut self) {
+        use windows::Win32::System::Threading::{
+            GetCurrentThread, SetThreadPriority, THREAD_MODE_BACKGROUND_END,
+        };
+
+        let _ = unsafe { SetThreadPriority(GetCurrentThread(), THREAD_MODE_BACKGROUND_END) };
+    }
+}
+
+#[cfg(feature = "windows")]
+pub(crate) fn set_low_io_priority(file: &File) -> io::Result<()> {
+    use std::os::windows::io::AsRawHandle;
+    use windows::Win32::Foundation::HANDLE;
+    use windows::Win32::Storage::FileSystem::{
+        FileIoPriorityHintInfo, IoPriorityHintLow, SetFileInformationByHandle,
+        FILE_IO_PRIORITY_HINT_INFO,
+    };
+
+    let info = FILE_IO_PRIORITY_HINT_INFO {
+        PriorityHint: IoPriorityHintLow,
+    };
+    unsafe {
+        SetFileInformationByHandle(
+            HANDLE(file.as_raw_handle()),
+            FileIoPriorityHintInfo,
+            &info as *const _ as *const std::ffi::c_void,
+            std::mem::size_of::<FILE_IO_PRIORITY_HINT_INFO>() as u32,
+        )
+    }
+    .map_err(crate::errors::win32("SetFileInformationByHandle"))
+}
+
+// What `AlignedReader` asked to the inner reader, see `AlignedReader::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReadStats {
+    // From the inner reader, rounded to the alignment (and more, to fill the buffer).
+    pub bytes_read: u64,
+    // Each time the buffer was filled.
+    pub reads: u64,
+    // Of the inner reader, the reads that continue the previous one don't need to seek.
+    pub seeks: u64,
+    // The reads (and `fill_buf`) served from the buffer.
+    pub cache_hits: u64,
+}
+
+// Reads the inner reader only at aligned positions and in multiples of the alignment, as needed
+// by the raw volumes. The data is buffered, `fill_buf` gives access to it without copying.
 pub struct AlignedReader<R>
 where
     R: Read + Seek,
@@ -14,35 +342,179 @@ where
     inner: R,
     alignment: u64,
     position: u64,
+    // Of the whole stream, for `SeekFrom::End`. Asked to the inner reader when not set.
+    len: Option<u64>,
+    // How much is read at least when the buffer is filled, so that the small sequential reads
+    // don't each need a system call.
+    capacity: usize,
 
     buffer_pos: u64,
     buffer_size: usize,
+    // Bigger than needed, the data starts at `buffer_start` which is aligned in memory too
+    // (needed for unbuffered reads).
     buffer: Vec<u8>,
+    buffer_start: usize,
+
+    // Where the inner reader is, None when unknown.
+    inner_position: Option<u64>,
+    stats: ReadStats,
 }
 
 impl<R> AlignedReader<R>
 where
     R: Read + Seek,
 {
+    pub const DEFAULT_CAPACITY: usize = 64 * 1024;
+
     pub fn new(inner: R, alignment: u64) -> io::Result<Self> {
+        Self::with_capacity(inner, alignment, Self::DEFAULT_CAPACITY)
+    }
+
+    // `capacity` is rounded up to the alignment.
+    pub fn with_capacity(inner: R, alignment: u64, capacity: usize) -> io::Result<Self> {
         assert!(alignment.is_power_of_two());
 
         Ok(Self {
             inner,
             alignment,
             position: 0,
+            len: None,
+            capacity: (capacity as u64).div_ceil(alignment).max(1) as usize * alignment as usize,
             buffer_pos: 0,
             buffer_size: 0,
-            buffer: Vec::with_capacity(alignment as usize),
+            buffer: Vec::new(),
+            buffer_start: 0,
+            inner_position: None,
+            stats: ReadStats::default(),
         })
     }
 
+    // For volumes, where the size of the device can't be found by seeking.
+    pub fn with_len(inner: R, alignment: u64, len: u64) -> io::Result<Self> {
+        let mut reader = Self::new(inner, alignment)?;
+        reader.len = Some(len);
+        Ok(reader)
+    }
+
+    pub fn set_len(&mut self, len: u64) {
+        self.len = Some(len);
+    }
+
+    pub fn len(&mut self) -> io::Result<u64> {
+        if let Some(len) = self.len {
+            return Ok(len);
+        }
+
+        // The inner reader is positioned again before filling the buffer.
+        self.inner_position = None;
+        let len = self.inner.seek(SeekFrom::End(0))?;
+        self.len = Some(len);
+        Ok(len)
+    }
+
+    pub fn is_empty(&mut self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        self.inner_position = None;
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    // The buffered data from the current position.
+    pub fn buffer(&self) -> &[u8] {
+        match self.buffered(self.position) {
+            Some(offset) => {
+                &self.buffer[self.buffer_start + offset..self.buffer_start + self.buffer_size]
+            }
+            None => &[],
+        }
+    }
+
+    pub fn stats(&self) -> ReadStats {
+        self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = ReadStats::default();
+    }
+
     fn round_down(&self, n: u64) -> u64 {
         n / self.alignment * self.alignment
     }
 
     fn round_up(&self, n: u64) -> u64 {
-        self.round_down(n) + self.alignment
+        n.div_ceil(self.alignment) * self.alignment
+    }
+
+    // Where `position` is in the buffer, if it is.
+    fn buffered(&self, position: u64) -> Option<usize> {
+        let offset = position.checked_sub(self.buffer_pos)?;
+        (offset < self.buffer_size as u64).then_some(offset as usize)
+    }
+
+    // Fills the buffer from `aligned_position` with `size` bytes (a multiple of the alignment),
+    // or up to the capacity, but never past the end of the stream: the buffer is shorter (or
+    // empty) there.
+    fn fill(&mut self, aligned_position: u64, size: usize) -> io::Result<()> {
+        let mut size = size.max(self.capacity);
+        // Only when known, the volumes can't be sized by seeking (see `with_len`).
+        if let Some(len) = self.len {
+            let available = self.round_up(len.saturating_sub(aligned_position));
+            size = size.min(usize::try_from(available).unwrap_or(usize::MAX));
+        }
+        if size == 0 {
+            self.buffer_size = 0;
+            return Ok(());
+        }
+
+        let alignment = self.alignment as usize;
+        if self.buffer.len() < size + alignment {
+            self.buffer = vec![0u8; size + alignment];
+            self.buffer_start = self.buffer.as_ptr().align_offset(alignment);
+        }
+
+        self.buffer_size = 0;
+        if self.inner_position != Some(aligned_position) {
+            self.inner_position = None;
+            self.inner.seek(SeekFrom::Start(aligned_position))?;
+            self.stats.seeks += 1;
+        }
+
+        self.stats.reads += 1;
+        self.inner_position = None;
+        let buffer = &mut self.buffer[self.buffer_start..self.buffer_start + size];
+        let mut filled = 0;
+        while filled < size {
+            match self.inner.read(&mut buffer[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        self.stats.bytes_read += filled as u64;
+        self.inner_position = Some(aligned_position + filled as u64);
+        self.buffer_pos = aligned_position;
+        // Past the length of a volume, the inner reader may have more.
+        self.buffer_size = match self.len {
+            Some(len) => filled.min(len.saturating_sub(aligned_position) as usize),
+            None => filled,
+        };
+        Ok(())
     }
 }
 
@@ -50,24 +522,50 @@ impl<R> Read for AlignedReader<R>
 where
     R: Read + Seek,
 {
+    // Shorter than `buf` only at the end of the stream, zero past it.
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let aligned_position = self.round_down(self.position);
-
-        let start = self.position as usize - aligned_position as usize;
-        let end = start + buf.len();
-        let size = self.round_up(end as u64) as usize;
+        if buf.is_empty() {
+            return Ok(0);
+        }
 
-        if aligned_position != self.buffer_pos || size > self.buffer_size {
-            self.buffer.resize(size, 0u8);
-            self.inner.read_exact(&mut self.buffer)?;
-            self.buffer_pos = aligned_position;
-            self.buffer_size = size;
+        let end = self.position + buf.len() as u64;
+        let hit = self.buffered(self.position).is_some()
+            && end <= self.buffer_pos + self.buffer_size as u64;
+        if hit {
+            self.stats.cache_hits += 1;
+        } else {
+            let aligned_position = self.round_down(self.position);
+            let size = self.round_up(end) - aligned_position;
+            self.fill(aligned_position, size as usize)?;
         }
 
-        buf.copy_from_slice(&self.buffer[start..end]);
+        let data = self.buffer();
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
 
-        self.position += buf.len() as u64;
-        Ok(buf.len())
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R> BufRead for AlignedReader<R>
+where
+    R: Read + Seek,
+{
+    // Empty at the end of the stream.
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.buffered(self.position).is_none() {
+            let aligned_position = self.round_down(self.position);
+            self.fill(aligned_position, self.alignment as usize)?;
+        } else {
+            self.stats.cache_hits += 1;
+        }
+        Ok(self.buffer())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        let available = self.buffer().len();
+        self.position += amt.min(available) as u64;
     }
 }
 
@@ -75,11 +573,17 @@ impl<R> Seek for AlignedReader<R>
 where
     R: Read + Seek,
 {
+    // Doesn't move the inner reader, nor drop the buffer.
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
         let raw_position = match pos {
             SeekFrom::Start(n) => Some(n),
-            SeekFrom::End(_) => {
-                return Err(io::Error::new(io::ErrorKind::Other, "unsupported"));
+            SeekFrom::End(n) => {
+                let len = self.len()?;
+                if n >= 0 {
+                    len.checked_add(n as u64)
+                } else {
+                    len.checked_sub(n.wrapping_neg() as u64)
+                }
             }
             SeekFrom::Current(n) => {
                 if n >= 0 {
@@ -92,8 +596,6 @@ where
 
         match raw_position {
             Some(n) => {
-                let aligned_position = self.round_down(n);
-                self.inner.seek(SeekFrom::Start(aligned_position))?;
                 self.position = n;
                 Ok(n)
             }
@@ -103,13 +605,299 @@ where
             )),
         }
     }
+
+    fn stream_position(&mut self) -> io::Result<u64> {
+        Ok(self.position)
+    }
+}
+
+pub trait ReadSeek: Read + Seek {}
+impl<T: Read + Seek> ReadSeek for T {}
+
+// Shifts the positions so that the volume inside an image file starts at zero.
+pub struct OffsetReader<R>
+where
+    R: Read + Seek,
+{
+    inner: R,
+    offset: u64,
+}
+
+impl<R> OffsetReader<R>
+where
+    R: Read + Seek,
+{
+    pub fn new(mut inner: R, offset: u64) -> io::Result<Self> {
+        inner.seek(SeekFrom::Start(offset))?;
+        Ok(Self { inner, offset })
+    }
+}
+
+impl<R> Read for OffsetReader<R>
+where
+    R: Read + Seek,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<R> Seek for OffsetReader<R>
+where
+    R: Read + Seek,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(n) => SeekFrom::Start(self.offset + n),
+            pos => pos,
+        };
+
+        let position = self.inner.seek(pos)?;
+        position
+            .checked_sub(self.offset)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid position"))
+    }
+}
+
+pub fn open_image(path: &Path, offset: u64) -> std::io::Result<BufReader<OffsetReader<File>>> {
+    let file = File::open(path)?;
+    Ok(BufReader::new(OffsetReader::new(file, offset)?))
+}
+
+pub type VolumeReader = AlignedReader<ThrottledReader<RetryReader<File>>>;
+
+pub fn open_volume(path: &Path) -> std::io::Result<VolumeReader> {
+    open_volume_with_options(path, VolumeReadOptions::default())
+}
+
+pub fn open_volume_with_options(
+    path: &Path,
+    read_options: VolumeReadOptions,
+) -> std::io::Result<VolumeReader> {
+    // Lets the backup privilege grant the access, when it is enabled.
+    let mut options = OpenOptions::new();
+    options.read(true);
+    #[cfg(windows)]
+    {
+        let mut flags = FILE_FLAG_BACKUP_SEMANTICS;
+        if read_options.unbuffered {
+            flags |= FILE_FLAG_NO_BUFFERING;
+        }
+        if read_options.sequential_scan {
+            flags |= FILE_FLAG_SEQUENTIAL_SCAN;
+        }
+        options.custom_flags(flags);
+    }
+    let file = options.open(path)?;
+    #[cfg(feature = "windows")]
+    if read_options.throttle.low_priority {
+        set_low_io_priority(&file)?;
+    }
+    let file = RetryReader::new(file, read_options.retry)?;
+    let file = ThrottledReader::new(file, read_options.throttle);
+    // Buffered by itself, another `BufReader` on top would break the alignment of the large reads.
+    AlignedReader::new(file, 4096u64)
 }
 
-pub fn open_volume(path: &Path) -> std::io::Result<BufReader<AlignedReader<File>>> {
-    let file = File::open(&path)?;
-    let sr = AlignedReader::new(file, 4096u64)?;
-    let mut reader = BufReader::new(sr);
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, Cursor, Read, Seek, SeekFrom};
+    use std::time::{Duration, Instant};
+
+    use super::{
+        AlignedReader, RetryPolicy, RetryReader, Throttle, ThrottledReader, ERROR_IO_DEVICE,
+    };
+
+    #[test]
+    fn aligned_reads() {
+        let data: Vec<u8> = (0..20000u32).map(|i| i as u8).collect();
+        let mut reader = AlignedReader::new(Cursor::new(data.clone()), 4096).unwrap();
+
+        // Sequential reads across the end of the buffer.
+        let mut buf = vec![0u8; 100];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data[0..100]);
+        let mut buf = vec![0u8; 5000];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data[100..5100]);
+
+        reader.seek(SeekFrom::Start(10000)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data[10000..15000]);
+
+        // The first read filled the buffer up to the capacity (here the end of the data).
+        let stats = reader.stats();
+        assert_eq!(stats.reads, 1);
+        assert_eq!(stats.seeks, 1);
+        assert_eq!(stats.bytes_read, 20000);
+        assert_eq!(stats.cache_hits, 2);
+
+        // The unbuffered reads need the memory to be aligned too.
+        let start = reader.buffer[reader.buffer_start..].as_ptr() as usize;
+        assert!(start.is_multiple_of(4096));
+    }
+
+    #[test]
+    fn buffered_reads() {
+        let data: Vec<u8> = (0..3 * 4096u32).map(|i| (i / 7 % 200) as u8).collect();
+        let mut reader = AlignedReader::new(Cursor::new(data.clone()), 4096).unwrap();
+
+        // Up to the capacity, or the end of the stream.
+        reader.seek(SeekFrom::Start(5000)).unwrap();
+        assert_eq!(reader.fill_buf().unwrap(), &data[5000..]);
+        reader.consume(100);
+        assert_eq!(reader.stream_position().unwrap(), 5100);
+        assert_eq!(reader.buffer(), &data[5100..]);
 
-    reader.seek(SeekFrom::Start(0))?;
-    Ok(reader)
+        // Seeking back in the buffer doesn't read again.
+        reader.seek(SeekFrom::Start(4100)).unwrap();
+        *reader.get_mut() = Cursor::new(Vec::new());
+        let mut buf = [0u8; 10];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data[4100..4110]);
+
+        let mut reader = AlignedReader::with_capacity(Cursor::new(data.clone()), 4096, 1).unwrap();
+        assert_eq!(reader.capacity(), 4096);
+        // Through `fill_buf` and `consume`, there is no 0xFF.
+        let mut rest = Vec::new();
+        reader.read_until(0xFF, &mut rest).unwrap();
+        assert_eq!(rest, data);
+        assert!(reader.fill_buf().unwrap().is_empty());
+
+        // The sequential reads don't seek, and without a length the end is found by reading
+        // (twice here, once for each empty `fill_buf`), not by seeking to it.
+        let stats = reader.stats();
+        assert_eq!((stats.reads, stats.seeks), (5, 1));
+        assert!(reader.len.is_none());
+        assert_eq!(stats.bytes_read, data.len() as u64);
+    }
+
+    #[test]
+    fn seek_from_end() {
+        let data = vec![7u8; 10000];
+        let mut reader = AlignedReader::new(Cursor::new(data.clone()), 4096).unwrap();
+        assert_eq!(reader.len().unwrap(), 10000);
+        assert_eq!(reader.seek(SeekFrom::End(-5000)).unwrap(), 5000);
+        assert_eq!(reader.stream_position().unwrap(), 5000);
+        let mut buf = vec![0u8; 100];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(reader.stream_position().unwrap(), 5100);
+        assert!(reader.seek(SeekFrom::End(-20000)).is_err());
+
+        // A volume, smaller than what the inner reader has.
+        let mut reader = AlignedReader::with_len(Cursor::new(data), 4096, 8192).unwrap();
+        assert_eq!(reader.seek(SeekFrom::End(0)).unwrap(), 8192);
+        reader.seek(SeekFrom::Start(8000)).unwrap();
+        assert_eq!(reader.read(&mut buf).unwrap(), 100);
+        assert_eq!(reader.read(&mut buf).unwrap(), 92);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn partial_reads() {
+        // Not a multiple of the alignment.
+        let data: Vec<u8> = (0..10000u32).map(|i| i as u8).collect();
+        let mut reader = AlignedReader::new(Cursor::new(data.clone()), 4096).unwrap();
+
+        reader.seek(SeekFrom::Start(9950)).unwrap();
+        let mut buf = [0u8; 100];
+        assert_eq!(reader.read(&mut buf).unwrap(), 50);
+        assert_eq!(buf[..50], data[9950..]);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert!(reader.read_exact(&mut buf).is_err());
+
+        // Past the end.
+        reader.seek(SeekFrom::Start(20000)).unwrap();
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+
+        reader.seek(SeekFrom::Start(1000)).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, data[1000..]);
+    }
+
+    // Fails the first `failures` reads, after moving the position.
+    struct FlakyReader {
+        inner: Cursor<Vec<u8>>,
+        failures: u32,
+        code: i32,
+    }
+
+    impl Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                self.inner.seek(SeekFrom::Current(1))?;
+                return Err(std::io::Error::from_raw_os_error(self.code));
+            }
+            self.inner.read(buf)
+        }
+    }
+
+    impl Seek for FlakyReader {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn retry_reads() {
+        let data: Vec<u8> = (0..100u8).collect();
+        let policy = RetryPolicy {
+            delay: Duration::ZERO,
+            ..Default::default()
+        };
+        let flaky = |failures, code| FlakyReader {
+            inner: Cursor::new(data.clone()),
+            failures,
+            code,
+        };
+
+        let mut reader =
+            RetryReader::new(flaky(2, ERROR_IO_DEVICE as i32), policy.clone()).unwrap();
+        reader.seek(SeekFrom::Start(10)).unwrap();
+        let mut buf = [0u8; 10];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data[10..20]);
+        assert_eq!(reader.retries, 2);
+
+        // Too many failures, or not one of the errors to retry.
+        let mut reader =
+            RetryReader::new(flaky(4, ERROR_IO_DEVICE as i32), policy.clone()).unwrap();
+        assert!(reader.read_exact(&mut buf).is_err());
+        assert_eq!(reader.retries, 3);
+        let mut reader = RetryReader::new(flaky(1, 5), policy.clone()).unwrap();
+        assert!(reader.read_exact(&mut buf).is_err());
+        assert_eq!(reader.retries, 0);
+
+        let policy = RetryPolicy {
+            delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(300),
+            ..Default::default()
+        };
+        assert_eq!(policy.delay(1), Duration::from_millis(200));
+        assert_eq!(policy.delay(5), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn throttled_reads() {
+        let throttle = Throttle {
+            bytes_per_second: Some(10_000),
+            reads_per_second: Some(1000),
+            low_priority: false,
+        };
+        assert_eq!(throttle.cost(100), Duration::from_millis(10));
+        assert_eq!(throttle.cost(1), Duration::from_millis(1));
+        assert_eq!(Throttle::default().cost(100), Duration::ZERO);
+
+        let mut reader = ThrottledReader::new(Cursor::new(vec![0u8; 1000]), throttle);
+        let start = Instant::now();
+        let mut buf = [0u8; 100];
+        for _ in 0..3 {
+            reader.read_exact(&mut buf).unwrap();
+        }
+        // The first read doesn't wait.
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert!(reader.throttled > Duration::ZERO);
+    }
 }
@@ -2,15 +2,61 @@
 // This project is dual licensed under the Apache License 2.0 and the MIT license.
 // See the LICENSE files in the project root for details.
 
+use std::ffi::OsString;
+use std::mem::size_of;
+#[cfg(windows)]
+use std::os::windows::ffi::OsStringExt;
+
 use binread::BinRead;
 use time::OffsetDateTime;
 
 pub const SECTOR_SIZE: usize = 512;
 pub const MFT_RECORD: u64 = 0;
 pub const ROOT_RECORD: u64 = 5;
+// $Secure, the security descriptors of the volume.
+pub const SECURE_RECORD: u64 = 9;
 pub const FIRST_NORMAL_RECORD: u64 = 24;
 pub const FILE_RECORD_SIGNATURE: &[u8; 4] = b"FILE";
+// How NTFS marks the records that failed the fixup.
+pub const BAAD_RECORD_SIGNATURE: &[u8; 4] = b"BAAD";
 pub const EPOCH_DIFFERENCE: u64 = 116_444_736_000_000_000;
+pub const REFERENCE_NUMBER_MASK: u64 = 0x0000_FFFF_FFFF_FFFF;
+
+// A file reference is made of the record number (low 48 bits) and the sequence number (high 16 bits).
+pub fn file_reference(number: u64, sequence: u16) -> u64 {
+    (number & REFERENCE_NUMBER_MASK) | ((sequence as u64) << 48)
+}
+
+pub fn reference_number(reference: u64) -> u64 {
+    reference & REFERENCE_NUMBER_MASK
+}
+
+pub fn reference_sequence(reference: u64) -> u16 {
+    (reference >> 48) as u16
+}
+
+// For the on-disk structs, to view them in place.
+/// # Safety
+/// Only for packed (no alignment) structs made only of integers, so any bytes are a valid value.
+pub unsafe trait FromBytes: Sized {
+    // None if `data` is too short.
+    fn ref_from_prefix(data: &[u8]) -> Option<&Self> {
+        if data.len() < size_of::<Self>() {
+            return None;
+        }
+        Some(unsafe { &*(data.as_ptr() as *const Self) })
+    }
+}
+
+unsafe impl FromBytes for NtfsFileRecordHeader {}
+unsafe impl FromBytes for NtfsAttributeHeader {}
+unsafe impl FromBytes for NtfsResidentAttributeHeader {}
+unsafe impl FromBytes for NtfsNonResidentAttributeHeader {}
+unsafe impl FromBytes for NtfsStandardInformation {}
+unsafe impl FromBytes for NtfsStandardInformationExtended {}
+unsafe impl FromBytes for NtfsFileNameHeader {}
+unsafe impl FromBytes for NtfsFileName {}
+unsafe impl FromBytes for NtfsAttributeListEntry {}
 
 #[allow(unused)]
 #[repr(C, packed)]
@@ -24,7 +70,11 @@ pub struct BootSector {
     pub mft_lcn: u64,
     pub mft_lcn_mirror: u64,
     pub file_record_size_info: i8,
-    pub crap_2: [u8; 447],
+    pub crap_2: [u8; 3],
+    pub index_record_size_info: i8,
+    pub crap_3: [u8; 3],
+    pub serial_number: u64,
+    pub crap_4: [u8; 432],
 }
 
 #[repr(C, packed)]
@@ -93,6 +143,20 @@ pub struct NtfsStandardInformation {
     pub file_attributes: u32,
 }
 
+// The layout since NTFS 3.0, the volumes formatted before only have the first part.
+#[repr(C, packed)]
+pub struct NtfsStandardInformationExtended {
+    pub standard: NtfsStandardInformation,
+    pub max_versions: u32,
+    pub version_number: u32,
+    pub class_id: u32,
+    pub owner_id: u32,
+    // The key of the security descriptor in $Secure.
+    pub security_id: u32,
+    pub quota_charged: u64,
+    pub usn: u64,
+}
+
 #[repr(u8)]
 pub enum NtfsFileNamespace {
     Posix = 0,
@@ -151,36 +215,195 @@ pub struct NtfsAttributeListEntry {
     //pub name: Option<String>,
 }
 
+// The fields of packed structs can be unaligned, so they are read by value instead of borrowed.
+macro_rules! unaligned_accessors {
+    ($name:ident { $($field:ident: $type:ty),* $(,)? }) => {
+        impl $name {
+            $(
+                pub fn $field(&self) -> $type {
+                    unsafe { std::ptr::addr_of!(self.$field).read_unaligned() }
+                }
+            )*
+        }
+    };
+}
+
+unaligned_accessors!(NtfsFileRecordHeader {
+    update_sequence_offset: u16,
+    update_sequence_length: u16,
+    logfile_sequence_number: u64,
+    sequence_value: u16,
+    link_count: u16,
+    attributes_offset: u16,
+    flags: u16,
+    used_size: u32,
+    allocated_size: u32,
+    base_reference: u64,
+    next_attribute_id: u16,
+});
+
+unaligned_accessors!(NtfsAttributeHeader {
+    type_id: u32,
+    length: u32,
+    is_non_resident: u8,
+    name_length: u8,
+    name_offset: u16,
+    flags: u16,
+    id: u16,
+});
+
+unaligned_accessors!(NtfsResidentAttributeHeader {
+    value_length: u32,
+    value_offset: u16,
+    indexed_flag: u8,
+});
+
+unaligned_accessors!(NtfsNonResidentAttributeHeader {
+    lowest_vcn: i64,
+    highest_vcn: i64,
+    data_runs_offset: u16,
+    compression_unit_exponent: u8,
+    allocated_size: u64,
+    data_size: u64,
+    initialized_size: u64,
+});
+
+unaligned_accessors!(NtfsStandardInformation {
+    creation_time: u64,
+    modification_time: u64,
+    mft_record_modification_time: u64,
+    access_time: u64,
+    file_attributes: u32,
+});
+
+unaligned_accessors!(NtfsStandardInformationExtended {
+    max_versions: u32,
+    version_number: u32,
+    class_id: u32,
+    owner_id: u32,
+    security_id: u32,
+    quota_charged: u64,
+    usn: u64,
+});
+
+unaligned_accessors!(NtfsFileNameHeader {
+    parent_directory_reference: u64,
+    allocated_size: u64,
+    real_size: u64,
+    file_attributes: u32,
+    reparse_point_tag: u32,
+    name_length: u8,
+    namespace: u8,
+});
+
+unaligned_accessors!(NtfsAttributeListEntry {
+    type_id: u32,
+    length: u16,
+    name_length: u8,
+    name_offset: u8,
+    starting_vcn: u64,
+    base_file_reference: u64,
+    id: u16,
+});
+
 impl NtfsAttributeListEntry {
     pub fn reference(&self) -> u64 {
-        self.base_file_reference & 0x0000_FFFF_FFFF_FFFF
+        reference_number(self.base_file_reference())
     }
 }
 
 impl NtfsFileName {
+    // Lossy, names with unpaired surrogates are not valid UTF-16.
     pub fn to_string(&self) -> String {
-        let data = self.data;
-        String::from_utf16_lossy(&data[..self.header.name_length as usize])
+        char::decode_utf16(self.name_units())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect()
+    }
+
+    pub fn to_os_string(&self) -> OsString {
+        match self.as_wide() {
+            Some(wide) => wide_to_os_string(wide),
+            None => wide_to_os_string(&self.to_wide()),
+        }
+    }
+
+    // The raw UTF-16 name.
+    pub fn to_wide(&self) -> Vec<u16> {
+        self.name_units().collect()
+    }
+
+    pub fn name_length(&self) -> usize {
+        self.header.name_length() as usize
+    }
+
+    // The name as UTF-16 (little endian) bytes, borrowed from the record.
+    pub fn name_bytes(&self) -> &[u8] {
+        let data = std::ptr::addr_of!(self.data) as *const u8;
+        unsafe { std::slice::from_raw_parts(data, self.name_length() * 2) }
+    }
+
+    // The UTF-16 code units of the name, read in place.
+    pub fn name_units(&self) -> impl Iterator<Item = u16> + '_ {
+        self.name_bytes()
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+    }
+
+    // The name borrowed from the record, None if it is not aligned to 2 bytes in memory (the
+    // attributes are aligned to 8 bytes in the records, so only for the records copied to an odd
+    // address), see `name_units`.
+    pub fn as_wide(&self) -> Option<&[u16]> {
+        let data = std::ptr::addr_of!(self.data) as *const u16;
+        data.is_aligned()
+            .then(|| unsafe { std::slice::from_raw_parts(data, self.name_length()) })
+    }
+
+    // Compares the name with `name` without allocating, case sensitive.
+    pub fn name_eq(&self, name: &str) -> bool {
+        self.name_units().eq(name.encode_utf16())
     }
 
     pub fn parent(&self) -> u64 {
-        self.header.parent_directory_reference & 0x0000_FFFF_FFFF_FFFF
+        reference_number(self.header.parent_directory_reference())
+    }
+
+    pub fn parent_reference(&self) -> u64 {
+        self.header.parent_directory_reference()
+    }
+
+    // To check that the parent was not reused since, see `Mft::is_reference_current`.
+    pub fn parent_sequence(&self) -> u16 {
+        reference_sequence(self.header.parent_directory_reference())
+    }
+
+    // The sizes and the attributes are duplicated from the file when the name is written, so
+    // they can be stale (NTFS updates them lazily, mostly for the names in the directory index).
+    pub fn allocated_size(&self) -> u64 {
+        self.header.allocated_size()
+    }
+
+    pub fn real_size(&self) -> u64 {
+        self.header.real_size()
+    }
+
+    pub fn file_attributes(&self) -> u32 {
+        self.header.file_attributes()
     }
 
     pub fn is_readonly(&self) -> bool {
-        self.header.file_attributes & NtfsFileNameFlags::ReadOnly as u32 != 0
+        self.header.file_attributes() & NtfsFileNameFlags::ReadOnly as u32 != 0
     }
 
     pub fn is_hidden(&self) -> bool {
-        self.header.file_attributes & NtfsFileNameFlags::Hidden as u32 != 0
+        self.header.file_attributes() & NtfsFileNameFlags::Hidden as u32 != 0
     }
 
     pub fn is_system(&self) -> bool {
-        self.header.file_attributes & NtfsFileNameFlags::System as u32 != 0
+        self.header.file_attributes() & NtfsFileNameFlags::System as u32 != 0
     }
 
     pub fn is_reparse_point(&self) -> bool {
-        self.header.file_attributes & NtfsFileNameFlags::ReparsePoint as u32 != 0
+        self.header.file_attributes() & NtfsFileNameFlags::ReparsePoint as u32 != 0
     }
 }
 
@@ -195,7 +418,110 @@ pub enum NtfsAttributeType {
     End = 0xFFFF_FFFF,
 }
 
+#[cfg(windows)]
+pub fn wide_to_os_string(wide: &[u16]) -> OsString {
+    OsString::from_wide(wide)
+}
+
+// Lossy like `NtfsFileName::to_string`, an OsString can't hold UTF-16 on the other platforms.
+#[cfg(not(windows))]
+pub fn wide_to_os_string(wide: &[u16]) -> OsString {
+    String::from_utf16_lossy(wide).into()
+}
+
+// None for zero (not set) and for the values past what OffsetDateTime can represent (year 9999),
+// keep the raw FILETIME around for those.
+pub fn try_ntfs_to_unix_time(src: u64) -> Option<OffsetDateTime> {
+    if src == 0 {
+        return None;
+    }
+    let unix = src as i128 - EPOCH_DIFFERENCE as i128;
+    OffsetDateTime::from_unix_timestamp_nanos(unix * 100).ok()
+}
+
+// Same as `try_ntfs_to_unix_time`, but falls back to the unix epoch.
 pub fn ntfs_to_unix_time(src: u64) -> OffsetDateTime {
-    let unix = src.saturating_sub(EPOCH_DIFFERENCE) as i128;
-    OffsetDateTime::from_unix_timestamp_nanos(unix * 100).unwrap_or(OffsetDateTime::UNIX_EPOCH)
+    try_ntfs_to_unix_time(src).unwrap_or(OffsetDateTime::UNIX_EPOCH)
+}
+
+#[cfg(test)]
+mod tests {
+    use time::OffsetDateTime;
+
+    use super::{
+        file_reference, ntfs_to_unix_time, try_ntfs_to_unix_time, FromBytes, NtfsFileName,
+        NtfsNonResidentAttributeHeader, EPOCH_DIFFERENCE,
+    };
+
+    #[test]
+    fn unaligned_accessors() {
+        let mut data = [0u8; 80];
+        // Odd offset, so the u64 fields are not aligned.
+        data[1 + 48..1 + 56].copy_from_slice(&0x1122_3344_5566_7788u64.to_le_bytes());
+        data[1 + 32..1 + 34].copy_from_slice(&64u16.to_le_bytes());
+
+        let header = NtfsNonResidentAttributeHeader::ref_from_prefix(&data[1..]).unwrap();
+        assert_eq!(header.data_size(), 0x1122_3344_5566_7788);
+        assert_eq!(header.data_runs_offset(), 64);
+        assert!(NtfsNonResidentAttributeHeader::ref_from_prefix(&data[20..]).is_none());
+    }
+
+    #[test]
+    fn ntfs_time() {
+        assert_eq!(try_ntfs_to_unix_time(0), None);
+        assert_eq!(try_ntfs_to_unix_time(u64::MAX), None);
+        assert_eq!(ntfs_to_unix_time(u64::MAX), OffsetDateTime::UNIX_EPOCH);
+
+        assert_eq!(
+            try_ntfs_to_unix_time(EPOCH_DIFFERENCE),
+            Some(OffsetDateTime::UNIX_EPOCH)
+        );
+        // Before 1970.
+        let time = try_ntfs_to_unix_time(1).unwrap();
+        assert_eq!(time.year(), 1601);
+        assert_eq!(time.nanosecond(), 100);
+    }
+
+    #[test]
+    fn borrowed_file_name() {
+        let mut data = vec![0u8; 600];
+        // Even and odd addresses.
+        let even = data.as_ptr() as usize % 2;
+        for start in [even, even + 1] {
+            data.fill(0);
+            data[start + 64] = 3;
+            for (i, unit) in "n\u{e9}e".encode_utf16().enumerate() {
+                data[start + 66 + i * 2..start + 68 + i * 2].copy_from_slice(&unit.to_le_bytes());
+            }
+
+            let name = NtfsFileName::ref_from_prefix(&data[start..]).unwrap();
+            assert_eq!(name.name_length(), 3);
+            assert_eq!(name.name_bytes(), &data[start + 66..start + 72]);
+            assert!(name.name_eq("n\u{e9}e") && !name.name_eq("ne") && !name.name_eq("N\u{e9}e"));
+            assert_eq!(name.to_string(), "n\u{e9}e");
+            assert_eq!(name.to_wide(), vec![0x6E, 0xE9, 0x65]);
+            assert_eq!(name.parent_sequence(), 0);
+            if start == even {
+                assert_eq!(name.as_wide(), Some(&[0x6E, 0xE9, 0x65][..]));
+            } else {
+                assert_eq!(name.as_wide(), None);
+            }
+        }
+    }
+
+    #[test]
+    fn file_name_duplicated_info() {
+        let mut data = vec![0u8; 600];
+        data[0..8].copy_from_slice(&file_reference(30, 7).to_le_bytes());
+        data[40..48].copy_from_slice(&4096u64.to_le_bytes());
+        data[48..56].copy_from_slice(&1234u64.to_le_bytes());
+        data[56..60].copy_from_slice(&0x20u32.to_le_bytes());
+
+        let name = NtfsFileName::ref_from_prefix(&data).unwrap();
+        assert_eq!(name.parent(), 30);
+        assert_eq!(name.parent_sequence(), 7);
+        assert_eq!(name.allocated_size(), 4096);
+        assert_eq!(name.real_size(), 1234);
+        assert_eq!(name.file_attributes(), 0x20);
+    }
 }
@@ -0,0 +1,191 @@
+// Copyright (c) 2022, Matteo Bernacchia <dev@kikijiki.com>. All rights reserved.
+// This project is dual licensed under the Apache License 2.0 and the MIT license.
+// See the LICENSE files in the project root for details.
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, JoinHandle};
+
+use futures_core::Stream;
+use windows::Win32::Foundation::HANDLE;
+use windows::Win32::System::IO::PostQueuedCompletionStatus;
+
+use crate::journal::{dequeue_completion, Journal, JournalOptions, ReadEnd, UsnRecord};
+use crate::volume::Volume;
+
+// The reads are queued with key 0, see `Journal::new`.
+const STOP_KEY: usize = 1;
+
+#[derive(Default)]
+struct Completion {
+    // A read was started and its completion was not dequeued yet.
+    in_flight: bool,
+    result: Option<Result<u32, std::io::Error>>,
+    waker: Option<Waker>,
+}
+
+#[derive(Default)]
+struct Shared {
+    completion: Mutex<Completion>,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Completion> {
+        self.completion.lock().unwrap()
+    }
+}
+
+// Joined before the journal (and the port) is dropped.
+struct Port(HANDLE);
+
+unsafe impl Send for Port {}
+
+// Reads the journal with overlapped reads that wait for new records, and delivers them as a
+// `Stream`. The reads are started when the stream is polled, and a thread dequeues their
+// completions from the port of the journal to wake the task, so no thread is blocked on a read.
+pub struct AsyncJournal {
+    journal: Journal,
+    records: VecDeque<UsnRecord>,
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+    done: bool,
+}
+
+impl AsyncJournal {
+    pub fn new(volume: Volume, options: JournalOptions) -> Result<Self, std::io::Error> {
+        let journal = Journal::new(volume, options)?;
+        Ok(Self::from_journal(journal))
+    }
+
+    pub fn from_journal(journal: Journal) -> Self {
+        let shared = Arc::new(Shared::default());
+
+        let (port, operation) = journal.completion_port();
+        let port = Port(port);
+        let thread_shared = shared.clone();
+        let thread = thread::spawn(move || wait_completions(port, operation, &thread_shared));
+
+        AsyncJournal {
+            journal,
+            records: VecDeque::new(),
+            shared,
+            thread: Some(thread),
+            done: false,
+        }
+    }
+
+    pub fn journal(&self) -> &Journal {
+        &self.journal
+    }
+
+    pub async fn next(&mut self) -> Option<Result<UsnRecord, std::io::Error>> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+
+    fn start_read(&mut self) {
+        // Marked first, the completion can be dequeued before `begin_read` returns.
+        self.shared.lock().in_flight = true;
+
+        // Waits (without a timeout) until there are new records.
+        let buffer_size = self.journal.buffer_size();
+        if let Err(err) = self.journal.begin_read(buffer_size, 1, 0) {
+            let mut completion = self.shared.lock();
+            completion.in_flight = false;
+            completion.result = Some(Err(err));
+        }
+    }
+
+    fn end_read(&mut self, result: Result<u32, std::io::Error>) -> Result<(), std::io::Error> {
+        match self.journal.end_read(result)? {
+            ReadEnd::Bytes(len) => self.records.extend(self.journal.take_records(len)?),
+            // With the larger buffer, from `Journal::buffer_size`.
+            ReadEnd::Retry(_) => {}
+        }
+        Ok(())
+    }
+}
+
+impl Stream for AsyncJournal {
+    type Item = Result<UsnRecord, std::io::Error>;
+
+    // Ends after the first error.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(record) = this.records.pop_front() {
+                return Poll::Ready(Some(Ok(record)));
+            }
+            if this.done {
+                return Poll::Ready(None);
+            }
+
+            let result = {
+                let mut completion = this.shared.lock();
+                if completion.in_flight {
+                    // Under the lock, so that the completion can't be missed.
+                    completion.waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+                completion.result.take()
+            };
+
+            match result {
+                None => this.start_read(),
+                Some(result) => {
+                    if let Err(err) = this.end_read(result) {
+                        this.done = true;
+                        return Poll::Ready(Some(Err(err)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for AsyncJournal {
+    fn drop(&mut self) {
+        if self.shared.lock().in_flight {
+            self.journal.cancel_read();
+        }
+
+        // The thread exits once the cancelled read completed too, the buffer can be freed then.
+        let (port, _) = self.journal.completion_port();
+        let stopped = unsafe { PostQueuedCompletionStatus(port, 0, STOP_KEY, None) };
+        if let (Ok(()), Some(thread)) = (stopped, self.thread.take()) {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn wait_completions(port: Port, operation: &'static str, shared: &Shared) {
+    let mut stopping = false;
+
+    loop {
+        match dequeue_completion(port.0, operation) {
+            Ok((STOP_KEY, _)) => stopping = true,
+            Ok((_, result)) => complete(shared, result),
+            // Nothing more can be dequeued, the current (or next) read fails with this.
+            Err(err) => return complete(shared, Err(err)),
+        }
+
+        if stopping && !shared.lock().in_flight {
+            return;
+        }
+    }
+}
+
+fn complete(shared: &Shared, result: Result<u32, std::io::Error>) {
+    let waker = {
+        let mut completion = shared.lock();
+        completion.in_flight = false;
+        completion.result = Some(result);
+        completion.waker.take()
+    };
+
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
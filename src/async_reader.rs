@@ -0,0 +1,320 @@
+// Copyright (c) 2022, Matteo Bernacchia <dev@kikijiki.com>. All rights reserved.
+// This project is dual licensed under the Apache License 2.0 and the MIT license.
+// See the LICENSE files in the project root for details.
+
+use std::fs::{File, OpenOptions};
+use std::os::windows::fs::OpenOptionsExt;
+use std::os::windows::io::AsRawHandle;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use tokio::sync::oneshot;
+use windows::Win32::Foundation::{CloseHandle, ERROR_HANDLE_EOF, ERROR_IO_PENDING, HANDLE};
+use windows::Win32::Storage::FileSystem::{
+    ReadFile, FILE_FLAG_BACKUP_SEMANTICS, FILE_FLAG_NO_BUFFERING, FILE_FLAG_OVERLAPPED,
+    FILE_FLAG_SEQUENTIAL_SCAN,
+};
+use windows::Win32::System::Threading::INFINITE;
+use windows::Win32::System::IO::{
+    CancelIoEx, CreateIoCompletionPort, GetQueuedCompletionStatus, PostQueuedCompletionStatus,
+    OVERLAPPED, OVERLAPPED_0, OVERLAPPED_0_0,
+};
+
+use crate::aligned_reader::{set_low_io_priority, RetryPolicy};
+use crate::errors::{win32, Win32Error};
+use crate::volume::Volume;
+
+// The reads are queued with key 0.
+const STOP_KEY: usize = 1;
+
+// Given to the port while the read is pending, `overlapped` first so that the pointer dequeued
+// from the port is the read.
+#[repr(C)]
+struct PendingRead {
+    overlapped: OVERLAPPED,
+    // Aligned on the device.
+    position: u64,
+    size: usize,
+    // Bigger than needed, the data starts at `start` which is aligned in memory too (needed for
+    // unbuffered reads).
+    buffer: Vec<u8>,
+    start: usize,
+    // What was asked, from `position`.
+    skip: usize,
+    len: usize,
+    attempt: u32,
+    reply: oneshot::Sender<Result<Vec<u8>, std::io::Error>>,
+}
+
+struct Shared {
+    file: HANDLE,
+    port: HANDLE,
+    retry: RetryPolicy,
+    // Started and not dequeued yet, the thread waits for them before exiting.
+    in_flight: AtomicUsize,
+}
+
+// Both closed by `Inner` after the thread is joined.
+unsafe impl Send for Shared {}
+unsafe impl Sync for Shared {}
+
+struct Inner {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+    alignment: u64,
+    // Where the volume starts in the image.
+    offset: u64,
+    _file: File,
+}
+
+// Reads the volume (or the raw image it was opened from) with overlapped reads, completed through
+// a port: a thread dequeues the completions and hands the data to the task that asked, so no
+// thread is blocked on a read. Several reads can be pending at once, from any number of tasks.
+// For live volumes the reads are aligned to the sector size, and the retry policy of
+// `Volume::read_options` is applied by the thread (the reads that fail to start are not retried).
+// The throttle is not, the reads are started as soon as they are asked.
+// It can be cloned, the thread exits when all the clones are dropped.
+#[derive(Clone)]
+pub struct AsyncVolumeReader {
+    inner: Arc<Inner>,
+}
+
+impl AsyncVolumeReader {
+    pub fn new(volume: &Volume) -> Result<Self, std::io::Error> {
+        let options = &volume.read_options;
+
+        let mut flags = FILE_FLAG_OVERLAPPED.0;
+        let (alignment, offset, retry) = match volume.image_offset {
+            Some(offset) => (1, offset, RetryPolicy::none()),
+            None => {
+                flags |= FILE_FLAG_BACKUP_SEMANTICS.0;
+                if options.unbuffered {
+                    flags |= FILE_FLAG_NO_BUFFERING.0;
+                }
+                if options.sequential_scan {
+                    flags |= FILE_FLAG_SEQUENTIAL_SCAN.0;
+                }
+                let alignment = volume.sector_size.max(512).next_power_of_two();
+                (alignment, 0, options.retry.clone())
+            }
+        };
+
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(flags)
+            .open(&volume.path)?;
+        if volume.image_offset.is_none() && options.throttle.low_priority {
+            set_low_io_priority(&file)?;
+        }
+
+        let handle = HANDLE(file.as_raw_handle());
+        let port = unsafe { CreateIoCompletionPort(handle, None, 0, 1) }
+            .map_err(win32("CreateIoCompletionPort"))?;
+
+        let shared = Arc::new(Shared {
+            file: handle,
+            port,
+            retry,
+            in_flight: AtomicUsize::new(0),
+        });
+        let thread_shared = shared.clone();
+        let thread = thread::spawn(move || wait_completions(&thread_shared));
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                shared,
+                thread: Some(thread),
+                alignment,
+                offset,
+                _file: file,
+            }),
+        })
+    }
+
+    // Fails with `UnexpectedEof` if the data ends before `len` bytes.
+    pub async fn read_at(&self, position: u64, len: usize) -> Result<Vec<u8>, std::io::Error> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+
+        let (reply, result) = oneshot::channel();
+        let read = self.inner.prepare(position, len, reply)?;
+        submit(&self.inner.shared, read);
+        result.await.map_err(|_| closed())?
+    }
+}
+
+impl Inner {
+    fn prepare(
+        &self,
+        position: u64,
+        len: usize,
+        reply: oneshot::Sender<Result<Vec<u8>, std::io::Error>>,
+    ) -> Result<Box<PendingRead>, std::io::Error> {
+        let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid read");
+
+        let start = position.checked_add(self.offset).ok_or_else(invalid)?;
+        let end = start.checked_add(len as u64).ok_or_else(invalid)?;
+        let aligned_start = start / self.alignment * self.alignment;
+        let aligned_end = end
+            .div_ceil(self.alignment)
+            .checked_mul(self.alignment)
+            .ok_or_else(invalid)?;
+
+        // A single `ReadFile`.
+        let size = aligned_end - aligned_start;
+        if size > u32::MAX as u64 {
+            return Err(invalid());
+        }
+        let size = size as usize;
+
+        let buffer = vec![0u8; size + self.alignment as usize - 1];
+        let buffer_start = buffer.as_ptr().align_offset(self.alignment as usize);
+
+        Ok(Box::new(PendingRead {
+            overlapped: OVERLAPPED::default(),
+            position: aligned_start,
+            size,
+            buffer,
+            start: buffer_start,
+            skip: (start - aligned_start) as usize,
+            len,
+            attempt: 0,
+            reply,
+        }))
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        unsafe {
+            // Fails if none is pending.
+            let _ = CancelIoEx(self.shared.file, None);
+        }
+
+        // The thread exits once the cancelled reads completed too, their buffers can be freed then.
+        let stopped = unsafe { PostQueuedCompletionStatus(self.shared.port, 0, STOP_KEY, None) };
+        if let (Ok(()), Some(thread)) = (stopped, self.thread.take()) {
+            let _ = thread.join();
+        }
+
+        unsafe {
+            let _ = CloseHandle(self.shared.port);
+        }
+    }
+}
+
+fn submit(shared: &Shared, read: Box<PendingRead>) {
+    // Counted first, the completion can be dequeued before `ReadFile` returns.
+    shared.in_flight.fetch_add(1, Ordering::SeqCst);
+
+    // Owned by the port until the completion is dequeued.
+    let read = Box::into_raw(read);
+    let result = unsafe {
+        let read = &mut *read;
+        read.overlapped = OVERLAPPED {
+            Anonymous: OVERLAPPED_0 {
+                Anonymous: OVERLAPPED_0_0 {
+                    Offset: read.position as u32,
+                    OffsetHigh: (read.position >> 32) as u32,
+                },
+            },
+            ..Default::default()
+        };
+        let buffer = &mut read.buffer[read.start..read.start + read.size];
+        ReadFile(shared.file, Some(buffer), None, Some(&mut read.overlapped))
+    };
+
+    match result {
+        // Queued to the port either way.
+        Ok(()) => {}
+        Err(err) if err.code() == ERROR_IO_PENDING.to_hresult() => {}
+        // Nothing is queued.
+        Err(err) => {
+            shared.in_flight.fetch_sub(1, Ordering::SeqCst);
+            let read = unsafe { Box::from_raw(read) };
+            finish(*read, Err(win32("ReadFile")(err)));
+        }
+    }
+}
+
+fn wait_completions(shared: &Shared) {
+    let mut stopping = false;
+
+    loop {
+        let mut bytes_read = 0;
+        let mut key = 0usize;
+        let mut overlapped = std::ptr::null_mut();
+
+        let result = unsafe {
+            GetQueuedCompletionStatus(
+                shared.port,
+                &mut bytes_read,
+                &mut key,
+                &mut overlapped,
+                INFINITE,
+            )
+        };
+
+        if key == STOP_KEY {
+            stopping = true;
+        } else if overlapped.is_null() {
+            // Nothing more can be dequeued, the pending reads are leaked with their buffers.
+            return;
+        } else {
+            shared.in_flight.fetch_sub(1, Ordering::SeqCst);
+            let read = unsafe { Box::from_raw(overlapped as *mut PendingRead) };
+            complete(
+                shared,
+                read,
+                result.map(|_| bytes_read).map_err(win32("ReadFile")),
+            );
+        }
+
+        if stopping && shared.in_flight.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+    }
+}
+
+fn complete(shared: &Shared, mut read: Box<PendingRead>, result: Result<u32, std::io::Error>) {
+    match result {
+        Err(err) if read.attempt < shared.retry.max_retries && shared.retry.should_retry(&err) => {
+            // Only this thread waits, the other completions are dequeued after.
+            thread::sleep(shared.retry.delay(read.attempt));
+            read.attempt += 1;
+            submit(shared, read);
+        }
+        result => finish(*read, result),
+    }
+}
+
+fn finish(read: PendingRead, result: Result<u32, std::io::Error>) {
+    let result = match result {
+        // Past the end of the image.
+        Err(err) if Win32Error::from_io(&err).is_some_and(|err| err.code == ERROR_HANDLE_EOF.0) => {
+            Ok(0)
+        }
+        result => result,
+    };
+
+    let data = result.and_then(|bytes_read| {
+        if (bytes_read as usize) < read.skip + read.len {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+        }
+        let start = read.start + read.skip;
+        Ok(read.buffer[start..start + read.len].to_vec())
+    });
+
+    // The task that asked may have been cancelled.
+    let _ = read.reply.send(data);
+}
+
+// The completion thread has stopped.
+fn closed() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::BrokenPipe,
+        "the completion thread has stopped",
+    )
+}
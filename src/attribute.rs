@@ -2,9 +2,41 @@
 // This project is dual licensed under the Apache License 2.0 and the MIT license.
 // See the LICENSE files in the project root for details.
 
-use std::ops::Range;
-
-use crate::{api::*, volume::Volume};
+use std::mem::size_of;
+
+use crate::{
+    api::*,
+    errors::ParseError,
+    parse::{parse_data_runs, DataRun},
+};
+
+// Points the header of the other kind when it doesn't fit in the data.
+const EMPTY_HEADER: NtfsAttributeHeader = NtfsAttributeHeader {
+    type_id: 0,
+    length: 0,
+    is_non_resident: 0,
+    name_length: 0,
+    name_offset: 0,
+    flags: 0,
+    id: 0,
+};
+static EMPTY_RESIDENT_HEADER: NtfsResidentAttributeHeader = NtfsResidentAttributeHeader {
+    attribute_header: EMPTY_HEADER,
+    value_length: 0,
+    value_offset: 0,
+    indexed_flag: 0,
+};
+static EMPTY_NONRESIDENT_HEADER: NtfsNonResidentAttributeHeader = NtfsNonResidentAttributeHeader {
+    attribute_header: EMPTY_HEADER,
+    lowest_vcn: 0,
+    highest_vcn: 0,
+    data_runs_offset: 0,
+    compression_unit_exponent: 0,
+    reserved: [0; 5],
+    allocated_size: 0,
+    data_size: 0,
+    initialized_size: 0,
+};
 
 pub struct NtfsAttribute<'a> {
     pub data: &'a [u8],
@@ -14,99 +46,101 @@ pub struct NtfsAttribute<'a> {
 }
 
 impl<'a> NtfsAttribute<'a> {
-    pub fn new(data: &'a [u8]) -> Self {
-        unsafe {
-            NtfsAttribute {
-                data,
-                header: &*(data.as_ptr() as *const NtfsAttributeHeader),
-                header_res: &*(data.as_ptr() as *const NtfsResidentAttributeHeader),
-                header_nonres: &*(data.as_ptr() as *const NtfsNonResidentAttributeHeader),
-            }
+    // None if the header doesn't fit in `data`.
+    pub fn new(data: &'a [u8]) -> Option<Self> {
+        let header = NtfsAttributeHeader::ref_from_prefix(data)?;
+        let header_res = NtfsResidentAttributeHeader::ref_from_prefix(data);
+        let header_nonres = NtfsNonResidentAttributeHeader::ref_from_prefix(data);
+
+        let fits = if header.is_non_resident() == 0 {
+            header_res.is_some()
+        } else {
+            header_nonres.is_some()
+        };
+        if !fits {
+            return None;
         }
-    }
 
-    pub fn get_resident(&self) -> &'a [u8] {
-        assert!(self.header.is_non_resident == 0);
-        let start = self.header_res.value_offset as usize;
-        let end = start + self.header_res.value_length as usize;
-        &self.data[start..end]
+        Some(NtfsAttribute {
+            data,
+            header,
+            header_res: header_res.unwrap_or(&EMPTY_RESIDENT_HEADER),
+            header_nonres: header_nonres.unwrap_or(&EMPTY_NONRESIDENT_HEADER),
+        })
     }
 
-    pub fn as_standard_info(&self) -> &'a NtfsStandardInformation {
-        assert!(self.header.type_id == NtfsAttributeType::StandardInformation as u32);
-        let slice = self.get_resident();
-        unsafe { &*(slice.as_ptr() as *const NtfsStandardInformation) }
+    pub fn name(&self) -> String {
+        let mut name = String::new();
+        self.name_into(&mut name);
+        name
     }
 
-    pub fn as_name(&self) -> &'a NtfsFileName {
-        assert!(self.header.type_id == NtfsAttributeType::FileName as u32);
-        let slice = self.get_resident();
-        unsafe { &*(slice.as_ptr() as *const NtfsFileName) }
+    // Same as `name`, appended to `name` (e.g. a buffer reused between the records).
+    pub fn name_into(&self, name: &mut String) {
+        let start = self.header.name_offset() as usize;
+        let end = start + self.header.name_length() as usize * 2;
+        let wide = self
+            .data
+            .get(start..end)
+            .unwrap_or_default()
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]));
+        name.extend(char::decode_utf16(wide).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)));
     }
 
-    pub fn as_resident_data(&self) -> &'a [u8] {
-        assert!(self.header.type_id == NtfsAttributeType::Data as u32);
-        self.get_resident()
+    // Empty for nonresident attributes, or if the value doesn't fit in the data.
+    pub fn get_resident(&self) -> &'a [u8] {
+        if self.header.is_non_resident() != 0 {
+            return &[];
+        }
+        let start = self.header_res.value_offset() as usize;
+        let end = start + self.header_res.value_length() as usize;
+        self.data.get(start..end).unwrap_or_default()
     }
 
-    pub fn get_nonresident_data_runs(&self, volume: &Volume) -> (usize, Vec<Range<usize>>) {
-        let mut out = Vec::new();
-
-        let total_size = self.header_nonres.data_size as usize;
-        if total_size == 0 {
-            return (total_size, out);
+    pub fn as_standard_info(&self) -> Option<&'a NtfsStandardInformation> {
+        if self.header.type_id() != NtfsAttributeType::StandardInformation as u32 {
+            return None;
         }
+        NtfsStandardInformation::ref_from_prefix(self.get_resident())
+    }
 
-        let runs_data = {
-            let start = self.header_nonres.data_runs_offset as usize;
-            let end = start + self.header.length as usize;
-            &self.data[start..end]
-        };
-
-        let cluster_size = volume.cluster_size as usize;
-        const BUF_SIZE: usize = 8;
-
-        let mut cursor = 0usize;
-        let mut prev_run = 0usize;
-        loop {
-            if runs_data[cursor] == 0 {
-                break;
-            }
-
-            // How many bytes to read for each value.
-            let cluster_count_b = (runs_data[cursor] & 0x0f) as usize;
-            let cluster_offset_b = ((runs_data[cursor] & 0xf0) >> 4) as usize;
-            assert!(cluster_count_b > 0 && cluster_count_b <= BUF_SIZE);
-            assert!(cluster_offset_b > 0 && cluster_offset_b <= BUF_SIZE);
-
-            cursor += 1;
-
-            // Read cluster_count
-            let mut buf = [0u8; BUF_SIZE];
-            buf[..cluster_count_b].copy_from_slice(&runs_data[cursor..cursor + cluster_count_b]);
-            let cluster_count = u64::from_le_bytes(buf) as usize;
-            cursor += cluster_count_b;
-
-            // Read cluster_offset (and fix sign bits)
-            let mut buf = [0u8; BUF_SIZE];
-            buf[..cluster_offset_b].copy_from_slice(&runs_data[cursor..cursor + cluster_offset_b]);
-            let cluster_offset = i64::from_le_bytes(buf);
-            let empty_bits = (BUF_SIZE - cluster_offset_b) * 8;
-            let cluster_offset = (cluster_offset << empty_bits) >> empty_bits;
-            cursor += cluster_offset_b;
-
-            let start = if cluster_offset >= 0 {
-                prev_run + (cluster_offset as usize) * cluster_size
-            } else {
-                prev_run - (cluster_offset.wrapping_neg() as usize) * cluster_size
-            };
+    // None for the short layout of the volumes formatted before NTFS 3.0.
+    pub fn as_standard_info_extended(&self) -> Option<&'a NtfsStandardInformationExtended> {
+        if self.header.type_id() != NtfsAttributeType::StandardInformation as u32 {
+            return None;
+        }
+        NtfsStandardInformationExtended::ref_from_prefix(self.get_resident())
+    }
 
-            let end = start + cluster_count * cluster_size;
-            prev_run = start;
+    // The value is shorter than the struct (which has room for 255 characters), so the view
+    // is of the rest of the data, and None if the struct doesn't fit or the name is not in the value.
+    pub fn as_name(&self) -> Option<&'a NtfsFileName> {
+        if self.header.type_id() != NtfsAttributeType::FileName as u32 {
+            return None;
+        }
+        let value = self.get_resident();
+        let header = NtfsFileNameHeader::ref_from_prefix(value)?;
+        if size_of::<NtfsFileNameHeader>() + header.name_length() as usize * 2 > value.len() {
+            return None;
+        }
+        NtfsFileName::ref_from_prefix(self.data.get(self.header_res.value_offset() as usize..)?)
+    }
 
-            out.push(start..end);
+    pub fn as_resident_data(&self) -> Option<&'a [u8]> {
+        if self.header.type_id() != NtfsAttributeType::Data as u32 {
+            return None;
         }
+        Some(self.get_resident())
+    }
 
-        (total_size, out)
+    // In the order of the data, empty for resident attributes.
+    pub fn data_runs(&self) -> Result<Vec<DataRun>, ParseError> {
+        if self.header.is_non_resident() == 0 {
+            return Ok(Vec::new());
+        }
+        let start = self.header_nonres.data_runs_offset() as usize;
+        let end = usize::min(self.header.length() as usize, self.data.len());
+        parse_data_runs(self.data.get(start..end).unwrap_or_default())
     }
 }
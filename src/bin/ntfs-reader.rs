@@ -0,0 +1,380 @@
+// Copyright (c) 2022, Matteo Bernacchia <dev@kikijiki.com>. All rights reserved.
+// This project is dual licensed under the Apache License 2.0 and the MIT license.
+// See the LICENSE files in the project root for details.
+
+// The `ntfs-reader` command line tool, built with the `cli` feature.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::process::ExitCode;
+
+use ntfs_reader::{
+    api::{ntfs_to_unix_time, NtfsAttributeType},
+    export::{
+        csv::{Column, CsvOptions, CsvWriter},
+        format_time,
+        jsonl::JsonlWriter,
+    },
+    file_info::{FileInfo, VecCache},
+    mft::Mft,
+    parse::AttributeContent,
+    volume::Volume,
+};
+
+const USAGE: &str = "\
+Usage: ntfs-reader <command> <source> [options]
+
+The source is a volume (e.g. C:, Windows only) or an image of a volume.
+
+Commands:
+  dump <source>            List all the files
+      --format csv|jsonl   (default: csv)
+      --output <file>      (default: the standard output)
+      --streams            Include the named data streams
+  find <source> <pattern>  List the files with a matching name (case insensitive, * and ?)
+      --directories        Only the directories
+  stat <source> <record>   Show a record of the MFT
+  watch <volume>           Print the changes from the USN journal (Windows only)
+      --format text|jsonl  (default: text)
+
+Options:
+  --offset <bytes>         Where the volume starts in the image (default: 0)";
+
+type CliResult<T = ()> = Result<T, Box<dyn Error>>;
+
+struct Args {
+    command: String,
+    positional: Vec<String>,
+    options: Vec<(String, Option<String>)>,
+}
+
+impl Args {
+    // The options that take a value, all the others are flags.
+    const VALUES: &'static [&'static str] = &["format", "output", "offset"];
+
+    fn parse(mut args: impl Iterator<Item = String>) -> CliResult<Self> {
+        let command = args.next().ok_or("missing command")?;
+        let mut positional = Vec::new();
+        let mut options = Vec::new();
+
+        while let Some(arg) = args.next() {
+            match arg.strip_prefix("--") {
+                Some(name) if Self::VALUES.contains(&name) => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| format!("missing value for --{}", name))?;
+                    options.push((name.to_string(), Some(value)));
+                }
+                Some(name) => options.push((name.to_string(), None)),
+                None => positional.push(arg),
+            }
+        }
+
+        Ok(Self {
+            command,
+            positional,
+            options,
+        })
+    }
+
+    fn positional(&self, index: usize, name: &str) -> CliResult<&str> {
+        self.positional
+            .get(index)
+            .map(|arg| arg.as_str())
+            .ok_or_else(|| format!("missing {}", name).into())
+    }
+
+    fn value(&self, name: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .find(|(option, _)| option == name)
+            .and_then(|(_, value)| value.as_deref())
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.options.iter().any(|(option, _)| option == name)
+    }
+
+    fn open_volume(&self) -> CliResult<Volume> {
+        let source = self.positional(0, "source")?;
+        if Path::new(source).is_file() {
+            let offset = match self.value("offset") {
+                Some(offset) => offset.parse()?,
+                None => 0,
+            };
+            return Ok(Volume::from_image(source, offset)?);
+        }
+
+        #[cfg(feature = "windows")]
+        return Ok(Volume::new(source)?);
+        #[cfg(not(feature = "windows"))]
+        return Err(format!("{} is not an image file", source).into());
+    }
+
+    fn output(&self) -> CliResult<Box<dyn Write>> {
+        Ok(match self.value("output") {
+            Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+            None => Box::new(BufWriter::new(io::stdout().lock())),
+        })
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(_) => {
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let result = match args.command.as_str() {
+        "dump" => dump(&args),
+        "find" => find(&args),
+        "stat" => stat(&args),
+        "watch" => watch(&args),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        command => Err(format!("unknown command {}, see `ntfs-reader help`", command).into()),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn dump(args: &Args) -> CliResult {
+    let mft = Mft::new(args.open_volume()?)?;
+    let builder = FileInfo::builder().with_streams(args.flag("streams"));
+    let mut output = args.output()?;
+
+    let count = match args.value("format").unwrap_or("csv") {
+        "csv" => {
+            let mut columns = Column::DEFAULT.to_vec();
+            if args.flag("streams") {
+                columns.push(Column::Streams);
+            }
+            let options = CsvOptions {
+                columns,
+                ..Default::default()
+            };
+            CsvWriter::with_options(&mut output, options).write_mft(&mft, &builder)?
+        }
+        "jsonl" => JsonlWriter::new(&mut output).write_mft(&mft, &builder)?,
+        format => return Err(format!("unknown format {}", format).into()),
+    };
+
+    output.flush()?;
+    eprintln!("{} files", count);
+    Ok(())
+}
+
+fn find(args: &Args) -> CliResult {
+    let pattern: Vec<char> = args
+        .positional(1, "pattern")?
+        .to_lowercase()
+        .chars()
+        .collect();
+    let directories = args.flag("directories");
+
+    let mft = Mft::new(args.open_volume()?)?;
+    let builder = FileInfo::builder().with_times(false).with_size(false);
+    let mut cache = VecCache::with_capacity(mft.max_record as usize);
+    let mut output = args.output()?;
+    let mut result = Ok(());
+    let mut name_chars = Vec::new();
+
+    mft.iterate_files(|file| {
+        if result.is_err() || (directories && !file.is_directory()) {
+            return;
+        }
+        let Some(name) = file.get_best_file_name_ref(&mft) else {
+            return;
+        };
+        name_chars.clear();
+        name_chars.extend(
+            char::decode_utf16(name.name_units())
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .flat_map(char::to_lowercase),
+        );
+        if wildcard_match(&pattern, &name_chars) {
+            let info = builder.build_with_cache(&mft, file, &mut cache);
+            result = writeln!(output, "{}", info.path.display());
+        }
+    });
+
+    result?;
+    output.flush()?;
+    Ok(())
+}
+
+fn stat(args: &Args) -> CliResult {
+    let number: u64 = args.positional(1, "record")?.parse()?;
+    let mft = Mft::new(args.open_volume()?)?;
+    let record = mft.parse_record(number)?;
+
+    println!("Record:      {}", number);
+    println!("In use:      {}", record.is_used());
+    println!("Directory:   {}", record.is_directory());
+    println!("Sequence:    {}", record.sequence_value);
+    println!("Links:       {}", record.link_count);
+    if record.base_record() != 0 {
+        println!("Base record: {}", record.base_record());
+    }
+
+    if let Some(file) = mft.get_record(number).filter(|file| file.is_used()) {
+        let info = FileInfo::builder().with_streams(true).build(&mft, &file);
+        println!("Path:        {}", info.path.display());
+        println!("Size:        {}", info.size);
+        for (label, time) in [
+            ("Created", info.created_filetime),
+            ("Modified", info.modified_filetime),
+            ("Accessed", info.accessed_filetime),
+        ] {
+            println!(
+                "{:<12} {}",
+                format!("{}:", label),
+                format_time(&ntfs_to_unix_time(time))
+            );
+        }
+    }
+
+    println!("Attributes:");
+    for att in &record.attributes {
+        let name = if att.name.is_empty() {
+            String::new()
+        } else {
+            format!(" \"{}\"", att.name)
+        };
+        match &att.content {
+            AttributeContent::Resident(data) => println!(
+                "  {}{}: resident, {} bytes",
+                attribute_name(att.type_id),
+                name,
+                data.len()
+            ),
+            AttributeContent::NonResident {
+                data_size, runs, ..
+            } => println!(
+                "  {}{}: nonresident, {} bytes in {} runs",
+                attribute_name(att.type_id),
+                name,
+                data_size,
+                runs.len()
+            ),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "windows")]
+fn watch(args: &Args) -> CliResult {
+    use ntfs_reader::journal::{Journal, JournalOptions};
+
+    let mut journal = Journal::new(args.open_volume()?, JournalOptions::default())?;
+    let jsonl = match args.value("format").unwrap_or("text") {
+        "text" => false,
+        "jsonl" => true,
+        format => return Err(format!("unknown format {}", format).into()),
+    };
+
+    let mut output = args.output()?;
+    loop {
+        for record in journal.read_wait(None)? {
+            if jsonl {
+                JsonlWriter::new(&mut output).write(&record)?;
+            } else {
+                writeln!(
+                    output,
+                    "{} {} {}",
+                    format_time(&record.timestamp),
+                    Journal::get_reason_str(record.reason).trim_end(),
+                    record.path.display()
+                )?;
+            }
+        }
+        output.flush()?;
+    }
+}
+
+#[cfg(not(feature = "windows"))]
+fn watch(_args: &Args) -> CliResult {
+    Err("the journal can only be read on Windows".into())
+}
+
+fn attribute_name(type_id: u32) -> String {
+    let name = match type_id {
+        t if t == NtfsAttributeType::StandardInformation as u32 => "$STANDARD_INFORMATION",
+        t if t == NtfsAttributeType::AttributeList as u32 => "$ATTRIBUTE_LIST",
+        t if t == NtfsAttributeType::FileName as u32 => "$FILE_NAME",
+        0x40 => "$OBJECT_ID",
+        0x50 => "$SECURITY_DESCRIPTOR",
+        0x60 => "$VOLUME_NAME",
+        0x70 => "$VOLUME_INFORMATION",
+        t if t == NtfsAttributeType::Data as u32 => "$DATA",
+        0x90 => "$INDEX_ROOT",
+        0xA0 => "$INDEX_ALLOCATION",
+        t if t == NtfsAttributeType::Bitmap as u32 => "$BITMAP",
+        0xC0 => "$REPARSE_POINT",
+        0xD0 => "$EA_INFORMATION",
+        0xE0 => "$EA",
+        0x100 => "$LOGGED_UTILITY_STREAM",
+        _ => return format!("0x{:X}", type_id),
+    };
+    name.to_string()
+}
+
+// `*` matches any number of characters and `?` exactly one.
+fn wildcard_match(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Where to go back to after a mismatch: the last star and the name position it matched to.
+    let mut star = None;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::wildcard_match;
+
+    #[test]
+    fn wildcards() {
+        let matches = |pattern: &str, name: &str| {
+            let pattern: Vec<char> = pattern.chars().collect();
+            let name: Vec<char> = name.chars().collect();
+            wildcard_match(&pattern, &name)
+        };
+
+        assert!(matches("*.txt", "notes.txt"));
+        assert!(matches("n?tes*", "notes.txt"));
+        assert!(matches("*a*b*", "xxaxxbxx"));
+        assert!(matches("notes.txt", "notes.txt"));
+        assert!(!matches("*.txt", "notes.txt.bak"));
+        assert!(!matches("notes", "notes.txt"));
+        assert!(!matches("?", ""));
+    }
+}
@@ -0,0 +1,249 @@
+// Copyright (c) 2022, Matteo Bernacchia <dev@kikijiki.com>. All rights reserved.
+// This project is dual licensed under the Apache License 2.0 and the MIT license.
+// See the LICENSE files in the project root for details.
+
+// Finds the FILE records by their signature instead of the bitmap: the deleted records still in
+// the MFT, the records of an MFT whose bitmap is damaged, or records in any raw data
+// (e.g. the unallocated clusters of an image).
+
+use crate::{api::*, mft::Mft};
+
+const SIGNATURE: u32 = u32::from_le_bytes(*FILE_RECORD_SIGNATURE);
+const LOW_BITS: u64 = 0x0101_0101_0101_0101;
+const HIGH_BITS: u64 = 0x8080_8080_8080_8080;
+
+// The offsets in `data` of the FILE signatures at multiples of `alignment`, in order.
+pub fn find_signatures(data: &[u8], alignment: usize) -> FileSignatures<'_> {
+    FileSignatures {
+        data,
+        alignment: alignment.max(1),
+        next: 0,
+        base: 0,
+        candidates: 0,
+    }
+}
+
+// With an alignment of at least 8 only the aligned offsets are compared, otherwise the data is
+// scanned 8 bytes at a time for the first byte of the signature (like memchr).
+pub struct FileSignatures<'a> {
+    data: &'a [u8],
+    alignment: usize,
+    // The next offset to compare, or the next word to scan.
+    next: usize,
+    // The start of the word of `candidates`.
+    base: usize,
+    // The high bit of the bytes of the word that may be an 'F'.
+    candidates: u64,
+}
+
+impl FileSignatures<'_> {
+    fn is_signature(&self, offset: usize) -> bool {
+        self.data
+            .get(offset..offset + 4)
+            .is_some_and(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()) == SIGNATURE)
+    }
+
+    fn next_aligned(&mut self) -> Option<usize> {
+        while self.next + 4 <= self.data.len() {
+            let offset = self.next;
+            self.next += self.alignment;
+            if self.is_signature(offset) {
+                return Some(offset);
+            }
+        }
+        None
+    }
+
+    fn next_scanned(&mut self) -> Option<usize> {
+        loop {
+            while self.candidates == 0 {
+                if self.next + 4 > self.data.len() {
+                    return None;
+                }
+                self.base = self.next;
+                self.next += 8;
+                self.candidates = self.word_candidates(self.base);
+            }
+
+            let offset = self.base + self.candidates.trailing_zeros() as usize / 8;
+            self.candidates &= self.candidates - 1;
+            if offset.is_multiple_of(self.alignment) && self.is_signature(offset) {
+                return Some(offset);
+            }
+        }
+    }
+
+    // The bytes equal to 'F' have their high bit set, with some false positives after them.
+    fn word_candidates(&self, base: usize) -> u64 {
+        let mut bytes = [0u8; 8];
+        let chunk = &self.data[base..];
+        let len = chunk.len().min(8);
+        bytes[..len].copy_from_slice(&chunk[..len]);
+
+        let word = u64::from_le_bytes(bytes) ^ (LOW_BITS * FILE_RECORD_SIGNATURE[0] as u64);
+        word.wrapping_sub(LOW_BITS) & !word & HIGH_BITS
+    }
+}
+
+impl Iterator for FileSignatures<'_> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if self.alignment >= 8 {
+            self.next_aligned()
+        } else {
+            self.next_scanned()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CarvedRecord {
+    // Where the record starts in the scanned data.
+    pub offset: usize,
+    // From the header, None for the records written before NTFS 3.1.
+    pub number: Option<u64>,
+    // Fixed up.
+    pub data: Vec<u8>,
+}
+
+// The FILE records in `data` (at sector boundaries) that pass the fixup, not overlapping.
+pub fn carve_records(data: &[u8], record_size: usize, sector_size: usize) -> Vec<CarvedRecord> {
+    let mut records = Vec::new();
+    let mut next = 0;
+
+    for offset in find_signatures(data, sector_size) {
+        if offset < next {
+            continue;
+        }
+        let Some(record) = data.get(offset..offset + record_size) else {
+            break;
+        };
+
+        let mut record = record.to_vec();
+        if Mft::check_and_fixup(&mut record, sector_size).is_err() {
+            continue;
+        }
+
+        // The number follows the header since NTFS 3.1, where the update sequence moved to 48.
+        let header = NtfsFileRecordHeader::ref_from_prefix(&record).unwrap();
+        let number = (header.update_sequence_offset() >= 48)
+            .then(|| u32::from_le_bytes(record[44..48].try_into().unwrap()) as u64);
+
+        records.push(CarvedRecord {
+            offset,
+            number,
+            data: record,
+        });
+        next = offset + record_size;
+    }
+
+    records
+}
+
+impl Mft {
+    // The records not in use that still have a name, e.g. the deleted files. Their data runs may
+    // point to clusters that were reused since.
+    pub fn deleted_records(&self) -> Vec<u64> {
+        let record_size = self.volume.file_record_size as usize;
+
+        find_signatures(self.data(), record_size)
+            .map(|offset| (offset / record_size) as u64)
+            .filter(|number| {
+                self.get_record(*number).is_some_and(|file| {
+                    !file.is_used() && file.get_attribute(NtfsAttributeType::FileName).is_some()
+                })
+            })
+            .collect()
+    }
+
+    // Replaces the bitmap with the in-use flags of the valid records, for when it is damaged or
+    // was not loaded (see `BitmapLoading`).
+    pub fn rebuild_bitmap(&mut self) {
+        let record_size = self.volume.file_record_size as usize;
+        let mut bitmap = vec![0u8; (self.max_record as usize).div_ceil(8)];
+
+        for offset in find_signatures(self.data(), record_size) {
+            let number = (offset / record_size) as u64;
+            if self.get_record(number).is_some_and(|file| file.is_used()) {
+                bitmap[(number / 8) as usize] |= 1 << (number % 8);
+            }
+        }
+
+        self.set_bitmap(bitmap);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{carve_records, find_signatures};
+    use crate::mft::memory_tests::{make_mft, make_named_record, make_record, RECORD_SIZE};
+
+    #[test]
+    fn signatures() {
+        let mut data = vec![0u8; 100];
+        for offset in [0, 13, 40, 64, 96] {
+            data[offset..offset + 4].copy_from_slice(b"FILE");
+        }
+        // Almost signatures, and one cut at the end.
+        data[20..24].copy_from_slice(b"FILF");
+        data[30..33].copy_from_slice(b"FFI");
+        data[80..83].copy_from_slice(b"FIL");
+
+        let naive = |alignment: usize| -> Vec<usize> {
+            (0..data.len() - 3)
+                .filter(|offset| {
+                    offset.is_multiple_of(alignment) && &data[*offset..*offset + 4] == b"FILE"
+                })
+                .collect()
+        };
+        for alignment in [1, 2, 4, 8, 16, 32] {
+            let found: Vec<usize> = find_signatures(&data, alignment).collect();
+            assert_eq!(found, naive(alignment), "{}", alignment);
+        }
+        assert_eq!(find_signatures(&data, 1).count(), 5);
+        assert_eq!(find_signatures(&data[..99], 1).last(), Some(64));
+        assert_eq!(find_signatures(b"FIL", 1).count(), 0);
+    }
+
+    #[test]
+    fn carve() {
+        let mut data = vec![0u8; 4 * RECORD_SIZE + 512];
+        let mut record = make_record();
+        record[44..48].copy_from_slice(&42u32.to_le_bytes());
+        data[512..512 + RECORD_SIZE].copy_from_slice(&record);
+        // A torn record, and one cut at the end.
+        let mut torn = make_record();
+        torn[1022] = 7;
+        data[2048..2048 + RECORD_SIZE].copy_from_slice(&torn);
+        data[4 * RECORD_SIZE..4 * RECORD_SIZE + 4].copy_from_slice(b"FILE");
+
+        let records = carve_records(&data, RECORD_SIZE, 512);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].offset, 512);
+        assert_eq!(records[0].number, Some(42));
+        assert_eq!(records[0].data, record);
+    }
+
+    #[test]
+    fn deleted_records() {
+        let mut deleted = make_named_record(1, 5, "deleted");
+        deleted[22] = 0;
+        let mut free = make_record();
+        free[22] = 0;
+        let mut mft = make_mft(vec![
+            make_named_record(1, 5, "used"),
+            deleted,
+            free,
+            vec![0; RECORD_SIZE],
+            make_record(),
+        ]);
+        assert_eq!(mft.deleted_records(), vec![1]);
+
+        // All in use according to the damaged bitmap.
+        assert!(mft.record_exists(3));
+        mft.rebuild_bitmap();
+        assert_eq!(mft.get_bitmap(), Some(&[0b10001][..]));
+        assert!(!mft.record_exists(1) && mft.record_exists(4));
+    }
+}
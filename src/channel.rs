@@ -0,0 +1,636 @@
+// Copyright (c) 2022, Matteo Bernacchia <dev@kikijiki.com>. All rights reserved.
+// This project is dual licensed under the Apache License 2.0 and the MIT license.
+// See the LICENSE files in the project root for details.
+
+// A bounded queue between a producer (e.g. a thread reading the journal) and a slower consumer,
+// with an explicit policy for when it is full instead of buffering without limit.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+#[cfg(feature = "windows")]
+use crate::journal::{Journal, UsnRecord};
+
+// How often the journal thread checks if the receiver was dropped when the journal is idle.
+#[cfg(feature = "windows")]
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    // The producer waits for the consumer.
+    Block,
+    // The oldest items are dropped, the consumer receives a `Delivery::Gap` in their place.
+    DropOldest,
+    // The items are written to this file (truncated first, deleted with the channel)
+    // and read back in order.
+    SpillToDisk(PathBuf),
+}
+
+// How the items are written for `OverflowPolicy::SpillToDisk`.
+pub trait Spill: Sized {
+    fn spill<W: Write>(&self, writer: &mut W) -> io::Result<()>;
+    fn unspill<R: Read>(reader: &mut R) -> io::Result<Self>;
+}
+
+#[derive(Debug)]
+pub enum Delivery<T> {
+    Item(T),
+    // How many items were dropped here.
+    Gap(u64),
+    // The producer stopped because of this, nothing follows.
+    Error(io::Error),
+}
+
+// `capacity` is the number of items kept in memory (at least one).
+pub fn bounded<T: Spill>(
+    capacity: usize,
+    policy: OverflowPolicy,
+) -> io::Result<(EventSender<T>, EventReceiver<T>)> {
+    let spill = match &policy {
+        OverflowPolicy::SpillToDisk(path) => Some(SpillFile::create(path.clone())?),
+        _ => None,
+    };
+
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queue: VecDeque::new(),
+            items: 0,
+            spill,
+            error: None,
+            sender_alive: true,
+            receiver_alive: true,
+        }),
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+        capacity: capacity.max(1),
+        policy,
+    });
+
+    Ok((
+        EventSender {
+            shared: shared.clone(),
+        },
+        EventReceiver { shared },
+    ))
+}
+
+struct SpillFile {
+    path: PathBuf,
+    file: File,
+    // Items written and not read back yet.
+    count: usize,
+    read_pos: u64,
+    write_pos: u64,
+    buffer: Vec<u8>,
+}
+
+impl SpillFile {
+    fn create(path: PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+
+        Ok(SpillFile {
+            path,
+            file,
+            count: 0,
+            read_pos: 0,
+            write_pos: 0,
+            buffer: Vec::new(),
+        })
+    }
+
+    fn push<T: Spill>(&mut self, item: &T) -> io::Result<()> {
+        self.buffer.clear();
+        item.spill(&mut self.buffer)?;
+        self.file.seek(SeekFrom::Start(self.write_pos))?;
+        self.file.write_all(&self.buffer)?;
+        self.write_pos += self.buffer.len() as u64;
+        self.count += 1;
+        Ok(())
+    }
+
+    fn pop<T: Spill>(&mut self, max: usize) -> io::Result<Vec<T>> {
+        self.file.seek(SeekFrom::Start(self.read_pos))?;
+        let mut reader = BufReader::new(&self.file);
+
+        let count = max.min(self.count);
+        let items = (0..count)
+            .map(|_| T::unspill(&mut reader))
+            .collect::<io::Result<Vec<T>>>()?;
+        self.read_pos = reader.stream_position()?;
+
+        self.count -= count;
+        if self.count == 0 {
+            self.reset()?;
+        }
+        Ok(items)
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.count = 0;
+        self.read_pos = 0;
+        self.write_pos = 0;
+        self.file.set_len(0)
+    }
+}
+
+struct State<T> {
+    // There can be a gap only at the front, before the items it replaced.
+    queue: VecDeque<Delivery<T>>,
+    // Not counting the gap.
+    items: usize,
+    spill: Option<SpillFile>,
+    // Delivered after all the items.
+    error: Option<io::Error>,
+    sender_alive: bool,
+    receiver_alive: bool,
+}
+
+impl<T: Spill> State<T> {
+    fn push(&mut self, item: T) {
+        self.queue.push_back(Delivery::Item(item));
+        self.items += 1;
+    }
+
+    fn drop_oldest(&mut self) {
+        match self.queue.front_mut() {
+            Some(Delivery::Gap(count)) => {
+                *count += 1;
+                self.queue.remove(1);
+            }
+            _ => {
+                self.queue.pop_front();
+                self.queue.push_front(Delivery::Gap(1));
+            }
+        }
+        self.items -= 1;
+    }
+
+    fn spilled(&self) -> usize {
+        self.spill.as_ref().map_or(0, |spill| spill.count)
+    }
+
+    fn pop(&mut self, capacity: usize) -> Option<Delivery<T>> {
+        if self.queue.is_empty() && self.spilled() > 0 {
+            self.unspill(capacity);
+        }
+
+        match self.queue.pop_front() {
+            Some(delivery) => {
+                if let Delivery::Item(_) = delivery {
+                    self.items -= 1;
+                }
+                Some(delivery)
+            }
+            None => self.error.take().map(Delivery::Error),
+        }
+    }
+
+    // The items that can't be read back are reported as a gap.
+    fn unspill(&mut self, capacity: usize) {
+        let spill = self.spill.as_mut().unwrap();
+        match spill.pop::<T>(capacity) {
+            Ok(items) => {
+                for item in items {
+                    self.push(item);
+                }
+            }
+            Err(err) => {
+                warn!("Failed to read back {} spilled items: {}", spill.count, err);
+                self.queue.push_back(Delivery::Gap(spill.count as u64));
+                let _ = spill.reset();
+            }
+        }
+    }
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: usize,
+    policy: OverflowPolicy,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl<T> Drop for Shared<T> {
+    fn drop(&mut self) {
+        let spill = self
+            .state
+            .get_mut()
+            .ok()
+            .and_then(|state| state.spill.take());
+        if let Some(spill) = spill {
+            // Closed first, the file can't be deleted while open on Windows.
+            let path = spill.path.clone();
+            drop(spill);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+pub struct EventSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Spill> EventSender<T> {
+    // Fails when the receiver was dropped, or the item could not be spilled.
+    pub fn send(&self, item: T) -> io::Result<()> {
+        let mut state = self.shared.lock();
+
+        loop {
+            if !state.receiver_alive {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "The receiver was dropped",
+                ));
+            }
+
+            // Once spilling, until the file is read back, to keep the order.
+            if state.items < self.shared.capacity && state.spilled() == 0 {
+                state.push(item);
+                break;
+            }
+
+            match self.shared.policy {
+                OverflowPolicy::Block => state = self.shared.not_full.wait(state).unwrap(),
+                OverflowPolicy::DropOldest => {
+                    state.drop_oldest();
+                    state.push(item);
+                    break;
+                }
+                OverflowPolicy::SpillToDisk(_) => {
+                    state.spill.as_mut().unwrap().push(&item)?;
+                    break;
+                }
+            }
+        }
+
+        drop(state);
+        self.shared.not_empty.notify_one();
+        Ok(())
+    }
+
+    // Closes the channel, the receiver gets the error after the items already sent.
+    pub fn fail(self, err: io::Error) {
+        self.shared.lock().error = Some(err);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        !self.shared.lock().receiver_alive
+    }
+}
+
+impl<T> Drop for EventSender<T> {
+    fn drop(&mut self) {
+        self.shared.lock().sender_alive = false;
+        self.shared.not_empty.notify_all();
+    }
+}
+
+pub struct EventReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Spill> EventReceiver<T> {
+    // None once the sender was dropped and everything was received.
+    pub fn recv(&self) -> Option<Delivery<T>> {
+        self.recv_deadline(None).ok()
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Delivery<T>, RecvTimeoutError> {
+        self.recv_deadline(Some(Instant::now() + timeout))
+    }
+
+    pub fn try_recv(&self) -> Result<Delivery<T>, TryRecvError> {
+        let mut state = self.shared.lock();
+        let delivery = state.pop(self.shared.capacity);
+        let disconnected = !state.sender_alive;
+        drop(state);
+
+        match delivery {
+            Some(delivery) => {
+                self.shared.not_full.notify_one();
+                Ok(delivery)
+            }
+            None if disconnected => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    // The items waiting, in memory and spilled.
+    pub fn pending(&self) -> usize {
+        let state = self.shared.lock();
+        state.items + state.spilled()
+    }
+
+    fn recv_deadline(&self, deadline: Option<Instant>) -> Result<Delivery<T>, RecvTimeoutError> {
+        let mut state = self.shared.lock();
+
+        loop {
+            if let Some(delivery) = state.pop(self.shared.capacity) {
+                drop(state);
+                self.shared.not_full.notify_one();
+                return Ok(delivery);
+            }
+            if !state.sender_alive {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+
+            state = match deadline {
+                None => self.shared.not_empty.wait(state).unwrap(),
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    if timeout.is_zero() {
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+                    self.shared
+                        .not_empty
+                        .wait_timeout(state, timeout)
+                        .unwrap()
+                        .0
+                }
+            };
+        }
+    }
+}
+
+impl<T: Spill> Iterator for EventReceiver<T> {
+    type Item = Delivery<T>;
+
+    fn next(&mut self) -> Option<Delivery<T>> {
+        self.recv()
+    }
+}
+
+impl<T> Drop for EventReceiver<T> {
+    fn drop(&mut self) {
+        self.shared.lock().receiver_alive = false;
+        self.shared.not_full.notify_all();
+    }
+}
+
+// Sends a copy of each item to all the subscribers, each with its own queue and policy,
+// so that one reader can serve several consumers. Note that a subscriber with
+// `OverflowPolicy::Block` holds back the others (and `subscribe`) while it is full.
+// The clones share the subscribers.
+pub struct Broadcast<T> {
+    subscribers: Arc<Mutex<Vec<EventSender<T>>>>,
+}
+
+impl<T> Clone for Broadcast<T> {
+    fn clone(&self) -> Self {
+        Broadcast {
+            subscribers: self.subscribers.clone(),
+        }
+    }
+}
+
+impl<T: Spill + Clone> Default for Broadcast<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Spill + Clone> Broadcast<T> {
+    pub fn new() -> Self {
+        Broadcast {
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    // Receives the items sent from now on.
+    pub fn subscribe(
+        &self,
+        capacity: usize,
+        policy: OverflowPolicy,
+    ) -> io::Result<EventReceiver<T>> {
+        let (sender, receiver) = bounded(capacity, policy)?;
+        self.subscribers.lock().unwrap().push(sender);
+        Ok(receiver)
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers.lock().unwrap().len()
+    }
+
+    // Returns how many subscribers received it. The dropped subscribers are removed, as are
+    // the ones that failed to spill it (after receiving the error).
+    pub fn send(&self, item: T) -> usize {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        for sender in std::mem::take(&mut *subscribers) {
+            match sender.send(item.clone()) {
+                Ok(()) => subscribers.push(sender),
+                Err(err) => sender.fail(err),
+            }
+        }
+        subscribers.len()
+    }
+
+    // All the subscribers get the error, and are removed.
+    pub fn fail(&self, err: &io::Error) {
+        for sender in self.subscribers.lock().unwrap().drain(..) {
+            sender.fail(io::Error::new(err.kind(), err.to_string()));
+        }
+    }
+}
+
+#[cfg(feature = "windows")]
+impl Broadcast<UsnRecord> {
+    // Reads the journal on a dedicated thread for the subscribers, until all the clones of the
+    // broadcast are dropped. The records read while there are no subscribers are discarded.
+    pub fn from_journal(mut journal: Journal) -> Self {
+        let broadcast = Broadcast::new();
+        let subscribers = Arc::downgrade(&broadcast.subscribers);
+
+        std::thread::spawn(move || loop {
+            let result = journal.read_wait(Some(POLL_INTERVAL));
+            let Some(subscribers) = subscribers.upgrade() else {
+                return;
+            };
+            let broadcast = Broadcast { subscribers };
+
+            match result {
+                Ok(records) => {
+                    for record in records {
+                        broadcast.send(record);
+                    }
+                }
+                Err(err) => {
+                    broadcast.fail(&err);
+                    return;
+                }
+            }
+        });
+
+        broadcast
+    }
+}
+
+#[cfg(feature = "windows")]
+impl EventReceiver<UsnRecord> {
+    // Reads the journal on a dedicated thread until the receiver is dropped.
+    pub fn from_journal(
+        mut journal: Journal,
+        capacity: usize,
+        policy: OverflowPolicy,
+    ) -> io::Result<Self> {
+        let (sender, receiver) = bounded(capacity, policy)?;
+
+        std::thread::spawn(move || {
+            while !sender.is_closed() {
+                match journal.read_wait(Some(POLL_INTERVAL)) {
+                    Ok(records) => {
+                        for record in records {
+                            if let Err(err) = sender.send(record) {
+                                sender.fail(err);
+                                return;
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        sender.fail(err);
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read, Write};
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::{bounded, Broadcast, Delivery, OverflowPolicy, Spill};
+
+    impl Spill for u64 {
+        fn spill<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+            writer.write_all(&self.to_le_bytes())
+        }
+
+        fn unspill<R: Read>(reader: &mut R) -> io::Result<Self> {
+            let mut buf = [0u8; 8];
+            reader.read_exact(&mut buf)?;
+            Ok(u64::from_le_bytes(buf))
+        }
+    }
+
+    fn deliveries(receiver: impl Iterator<Item = Delivery<u64>>) -> Vec<String> {
+        receiver
+            .map(|delivery| match delivery {
+                Delivery::Item(item) => item.to_string(),
+                Delivery::Gap(count) => format!("gap {}", count),
+                Delivery::Error(err) => format!("error {}", err),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn block() {
+        let (sender, receiver) = bounded::<u64>(2, OverflowPolicy::Block).unwrap();
+        let (full_sender, full) = mpsc::channel();
+        let producer = thread::spawn(move || {
+            for item in 0..10 {
+                sender.send(item).unwrap();
+                if item == 1 {
+                    full_sender.send(()).unwrap();
+                }
+            }
+        });
+
+        // The producer can't get ahead by more than the capacity, the next send blocks.
+        full.recv().unwrap();
+        assert_eq!(receiver.pending(), 2);
+
+        assert_eq!(
+            deliveries(receiver),
+            (0..10).map(|i| i.to_string()).collect::<Vec<_>>()
+        );
+        producer.join().unwrap();
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let (sender, receiver) = bounded::<u64>(3, OverflowPolicy::DropOldest).unwrap();
+        for item in 0..7 {
+            sender.send(item).unwrap();
+        }
+        assert!(matches!(receiver.try_recv(), Ok(Delivery::Gap(4))));
+        assert!(matches!(receiver.try_recv(), Ok(Delivery::Item(4))));
+
+        for item in 7..9 {
+            sender.send(item).unwrap();
+        }
+        sender.fail(io::Error::other("stopped"));
+        assert_eq!(
+            deliveries(receiver),
+            ["gap 1", "6", "7", "8", "error stopped"]
+        );
+    }
+
+    #[test]
+    fn broadcast() {
+        let broadcast = Broadcast::<u64>::new();
+        let all = broadcast.subscribe(10, OverflowPolicy::Block).unwrap();
+        let latest = broadcast.subscribe(1, OverflowPolicy::DropOldest).unwrap();
+        assert_eq!(broadcast.send(0), 2);
+        assert!(matches!(latest.try_recv(), Ok(Delivery::Item(0))));
+
+        for item in 1..4 {
+            broadcast.send(item);
+        }
+        let dropped = broadcast.subscribe(1, OverflowPolicy::Block).unwrap();
+        drop(dropped);
+        assert_eq!(broadcast.send(4), 2);
+        assert_eq!(broadcast.subscriber_count(), 2);
+
+        broadcast.fail(&io::Error::other("stopped"));
+        assert_eq!(broadcast.subscriber_count(), 0);
+        assert_eq!(deliveries(latest), ["gap 3", "4", "error stopped"]);
+        assert_eq!(deliveries(all), ["0", "1", "2", "3", "4", "error stopped"]);
+    }
+
+    #[test]
+    fn spill_to_disk() {
+        let path = std::env::temp_dir().join(format!("ntfs-reader-spill-{}", std::process::id()));
+        let (sender, receiver) =
+            bounded::<u64>(2, OverflowPolicy::SpillToDisk(path.clone())).unwrap();
+        for item in 0..5 {
+            sender.send(item).unwrap();
+        }
+        assert_eq!(receiver.pending(), 5);
+        assert!(matches!(receiver.try_recv(), Ok(Delivery::Item(0))));
+        assert!(matches!(receiver.try_recv(), Ok(Delivery::Item(1))));
+
+        // Still spilled until the file is read back, to keep the order.
+        sender.send(5).unwrap();
+        drop(sender);
+        assert_eq!(deliveries(receiver), ["2", "3", "4", "5"]);
+        assert!(!path.exists());
+
+        let (sender, receiver) = bounded::<u64>(1, OverflowPolicy::Block).unwrap();
+        drop(receiver);
+        assert!(sender.send(0).is_err());
+    }
+}
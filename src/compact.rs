@@ -0,0 +1,187 @@
+// Copyright (c) 2022, Matteo Bernacchia <dev@kikijiki.com>. All rights reserved.
+// This project is dual licensed under the Apache License 2.0 and the MIT license.
+// See the LICENSE files in the project root for details.
+
+// The name, parent, flags and size of every record, in a few tens of bytes each instead of the
+// whole record, for the indexers that keep them in memory.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::PathBuf;
+
+use crate::{
+    api::FIRST_NORMAL_RECORD,
+    file_info::FileInfo,
+    mft::{is_reference_current, relative_path, Mft, ParentLinks},
+    volume::Volume,
+};
+
+const IN_USE: u16 = 0x0001;
+const DIRECTORY: u16 = 0x0002;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactEntry {
+    // With the sequence number.
+    pub parent: u64,
+    pub size: u64,
+    // In `CompactIndex::names`.
+    pub name_offset: u32,
+    pub name_len: u16,
+    pub sequence: u16,
+    pub flags: u16,
+}
+
+impl CompactEntry {
+    pub fn is_used(&self) -> bool {
+        self.flags & IN_USE != 0
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.flags & DIRECTORY != 0
+    }
+}
+
+pub struct CompactIndex {
+    pub volume: Volume,
+    // One per record, the free ones are left empty.
+    pub entries: Vec<CompactEntry>,
+    // All the names one after the other, each one only once.
+    pub names: String,
+}
+
+impl CompactIndex {
+    // In a single pass over the records, the MFT is freed right after.
+    pub fn new(mft: Mft) -> Self {
+        Self::build(&mft)
+    }
+
+    // Keeps the MFT, e.g. to build the index again after `Mft::reload_record`.
+    pub fn build(mft: &Mft) -> Self {
+        let builder = FileInfo::builder().with_path(false).with_times(false);
+        let mut entries = vec![CompactEntry::default(); mft.max_record as usize];
+        let mut names = String::new();
+        let mut interned: HashMap<String, (u32, u16)> = HashMap::new();
+
+        for number in mft.used_records(0..mft.max_record) {
+            let Some(file) = mft.get_record(number).filter(|file| file.is_used()) else {
+                continue;
+            };
+            let Some(name) = file.get_best_file_name_ref(mft) else {
+                continue;
+            };
+
+            let name_string = name.to_string();
+            let (name_offset, name_len) = *interned.entry(name_string).or_insert_with_key(|name| {
+                let offset = names.len() as u32;
+                names.push_str(name);
+                (offset, name.len() as u16)
+            });
+
+            entries[number as usize] = CompactEntry {
+                parent: name.parent_reference(),
+                size: builder.build(mft, &file).size,
+                name_offset,
+                name_len,
+                sequence: file.header.sequence_value(),
+                flags: IN_USE | if file.is_directory() { DIRECTORY } else { 0 },
+            };
+        }
+
+        names.shrink_to_fit();
+        Self {
+            volume: mft.volume.clone(),
+            entries,
+            names,
+        }
+    }
+
+    pub fn max_record(&self) -> u64 {
+        self.entries.len() as u64
+    }
+
+    pub fn get(&self, number: u64) -> Option<&CompactEntry> {
+        self.entries
+            .get(number as usize)
+            .filter(|entry| entry.is_used())
+    }
+
+    pub fn name(&self, number: u64) -> Option<&str> {
+        let entry = self.get(number)?;
+        let start = entry.name_offset as usize;
+        self.names.get(start..start + entry.name_len as usize)
+    }
+
+    // Same as `Mft::is_reference_current`.
+    pub fn is_reference_current(&self, reference: u64) -> bool {
+        is_reference_current(self, reference)
+    }
+
+    // Same as `Mft::get_relative_path`.
+    pub fn get_relative_path(&self, number: u64) -> Option<PathBuf> {
+        relative_path(self, number)
+    }
+
+    // The records from `FIRST_NORMAL_RECORD` in use, as in `Mft::iterate_files`.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &CompactEntry)> + '_ {
+        self.entries
+            .iter()
+            .enumerate()
+            .skip(FIRST_NORMAL_RECORD as usize)
+            .filter(|(_, entry)| entry.is_used())
+            .map(|(number, entry)| (number as u64, entry))
+    }
+}
+
+impl ParentLinks for CompactIndex {
+    fn name_and_parent(&self, number: u64) -> Option<(Cow<'_, OsStr>, u64)> {
+        let name = self.name(number)?;
+        Some((Cow::Borrowed(OsStr::new(name)), self.get(number)?.parent))
+    }
+
+    fn sequence(&self, number: u64) -> Option<u16> {
+        self.get(number).map(|entry| entry.sequence)
+    }
+
+    fn record_count(&self) -> u64 {
+        self.entries.len() as u64
+    }
+}
+
+impl Mft {
+    pub fn into_compact(self) -> CompactIndex {
+        CompactIndex::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::api::file_reference;
+    use crate::mft::memory_tests::{make_mft, make_named_record, make_tree};
+
+    #[test]
+    fn compact_index() {
+        let mut records = make_tree(34);
+        records[31] = make_named_record(1, file_reference(30, 2), "same");
+        records[32] = make_named_record(1, file_reference(5, 5), "same");
+        records[33] = make_named_record(1, file_reference(30, 1), "stale");
+        let index = make_mft(records).into_compact();
+
+        assert_eq!(index.max_record(), 34);
+        assert_eq!(index.name(31), Some("same"));
+        assert_eq!(
+            index.get(31).unwrap().name_offset,
+            index.get(32).unwrap().name_offset
+        );
+        assert!(index.get(30).unwrap().is_directory() && !index.get(31).unwrap().is_directory());
+        assert_eq!(
+            index.get_relative_path(31),
+            Some(PathBuf::from("dir").join("same"))
+        );
+        assert_eq!(index.get_relative_path(33), None);
+        // The unnamed records are left out.
+        assert_eq!(index.iter().count(), 4);
+    }
+}
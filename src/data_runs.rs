@@ -0,0 +1,313 @@
+// Copyright (c) 2022, Matteo Bernacchia <dev@kikijiki.com>. All rights reserved.
+// This project is dual licensed under the Apache License 2.0 and the MIT license.
+// See the LICENSE files in the project root for details.
+
+// Reads the data of nonresident attributes with as few seeks as possible:
+// the runs are read in the order they are on the disk, and the adjacent ones together.
+
+use std::io::{self, IoSliceMut, Read, Seek, SeekFrom};
+use std::ops::Range;
+
+use crate::parse::DataRun;
+
+// Where a part of the data is in the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadPiece {
+    pub offset: usize,
+    pub len: usize,
+}
+
+// A contiguous range on the disk, read with a single seek.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadExtent {
+    // In bytes.
+    pub position: u64,
+    // In the order they are on the disk.
+    pub pieces: Vec<ReadPiece>,
+}
+
+impl ReadExtent {
+    pub fn len(&self) -> usize {
+        self.pieces.iter().map(|piece| piece.len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pieces.is_empty()
+    }
+}
+
+// The length of the data covered by the runs (at most `size`), and the extents to read it,
+// sorted by position. The sparse runs are not read, they are zeros.
+pub fn plan_reads(runs: &[DataRun], cluster_size: u64, size: u64) -> (usize, Vec<ReadExtent>) {
+    let size = usize::try_from(size).unwrap_or(usize::MAX);
+
+    let mut pieces = Vec::new();
+    let mut offset = 0usize;
+    for run in runs {
+        if offset >= size {
+            break;
+        }
+        let Some(len) = run
+            .cluster_count
+            .checked_mul(cluster_size)
+            .and_then(|len| usize::try_from(len).ok())
+        else {
+            break;
+        };
+        let len = len.min(size - offset);
+
+        if let Some(lcn) = run.lcn.filter(|_| len > 0) {
+            // The run must end before the end of the disk too.
+            let Some(position) = lcn
+                .checked_mul(cluster_size)
+                .filter(|position| position.checked_add(len as u64).is_some())
+            else {
+                break;
+            };
+            pieces.push((position, ReadPiece { offset, len }));
+        }
+        offset += len;
+    }
+
+    (offset, group_extents(pieces))
+}
+
+// Same as `plan_reads`, only for the parts of the output in `ranges` (sorted and disjoint).
+pub fn plan_reads_in(
+    runs: &[DataRun],
+    cluster_size: u64,
+    size: u64,
+    ranges: &[Range<usize>],
+) -> (usize, Vec<ReadExtent>) {
+    let (len, extents) = plan_reads(runs, cluster_size, size);
+
+    let mut pieces = Vec::new();
+    for extent in extents {
+        let mut position = extent.position;
+        for piece in extent.pieces {
+            let end = piece.offset + piece.len;
+            let first = ranges.partition_point(|range| range.end <= piece.offset);
+            for range in ranges[first..].iter().take_while(|range| range.start < end) {
+                let start = range.start.max(piece.offset);
+                let clipped = ReadPiece {
+                    offset: start,
+                    len: range.end.min(end) - start,
+                };
+                if let Some(position) = position.checked_add((start - piece.offset) as u64) {
+                    pieces.push((position, clipped));
+                }
+            }
+            position = position.saturating_add(piece.len as u64);
+        }
+    }
+
+    (len, group_extents(pieces))
+}
+
+// Merges the pieces that are next to each other on the disk.
+fn group_extents(mut pieces: Vec<(u64, ReadPiece)>) -> Vec<ReadExtent> {
+    pieces.sort_by_key(|(position, _)| *position);
+
+    let mut extents: Vec<ReadExtent> = Vec::new();
+    for (position, piece) in pieces {
+        match extents.last_mut() {
+            Some(extent) if extent.position.checked_add(extent.len() as u64) == Some(position) => {
+                extent.pieces.push(piece)
+            }
+            _ => extents.push(ReadExtent {
+                position,
+                pieces: vec![piece],
+            }),
+        }
+    }
+
+    extents
+}
+
+// With `vectored`, each extent is a single `read_vectored` into the pieces of the output,
+// otherwise the pieces are read one after the other (still without seeking in between).
+pub fn read_data_runs<R>(
+    reader: &mut R,
+    runs: &[DataRun],
+    cluster_size: u64,
+    size: u64,
+    vectored: bool,
+) -> io::Result<Vec<u8>>
+where
+    R: Read + Seek,
+{
+    let (len, extents) = plan_reads(runs, cluster_size, size);
+    read_extents(reader, len, &extents, vectored)
+}
+
+// Only the parts of the data in `ranges` (sorted and disjoint), the rest is left zeroed.
+pub fn read_data_runs_in<R>(
+    reader: &mut R,
+    runs: &[DataRun],
+    cluster_size: u64,
+    size: u64,
+    ranges: &[Range<usize>],
+) -> io::Result<Vec<u8>>
+where
+    R: Read + Seek,
+{
+    let (len, extents) = plan_reads_in(runs, cluster_size, size, ranges);
+    read_extents(reader, len, &extents, false)
+}
+
+fn read_extents<R>(
+    reader: &mut R,
+    len: usize,
+    extents: &[ReadExtent],
+    vectored: bool,
+) -> io::Result<Vec<u8>>
+where
+    R: Read + Seek,
+{
+    let mut data = vec![0u8; len];
+
+    for extent in extents {
+        reader.seek(SeekFrom::Start(extent.position))?;
+
+        if vectored {
+            let mut slices = split_pieces(&mut data, &extent.pieces);
+            read_exact_vectored(reader, &mut slices)?;
+        } else {
+            for piece in &extent.pieces {
+                reader.read_exact(&mut data[piece.offset..piece.offset + piece.len])?;
+            }
+        }
+    }
+
+    Ok(data)
+}
+
+// The pieces are disjoint, but not in order in the output.
+fn split_pieces<'a>(data: &'a mut [u8], pieces: &[ReadPiece]) -> Vec<IoSliceMut<'a>> {
+    let mut order: Vec<usize> = (0..pieces.len()).collect();
+    order.sort_by_key(|i| pieces[*i].offset);
+
+    let mut slices: Vec<(usize, &mut [u8])> = Vec::with_capacity(pieces.len());
+    let mut rest = data;
+    let mut rest_offset = 0;
+    for i in order {
+        let piece = pieces[i];
+        let (_, tail) = rest.split_at_mut(piece.offset - rest_offset);
+        let (slice, tail) = tail.split_at_mut(piece.len);
+        slices.push((i, slice));
+        rest = tail;
+        rest_offset = piece.offset + piece.len;
+    }
+
+    slices.sort_by_key(|(i, _)| *i);
+    slices
+        .into_iter()
+        .map(|(_, slice)| IoSliceMut::new(slice))
+        .collect()
+}
+
+fn read_exact_vectored<R: Read>(reader: &mut R, mut slices: &mut [IoSliceMut]) -> io::Result<()> {
+    while !slices.is_empty() {
+        match reader.read_vectored(slices) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => IoSliceMut::advance_slices(&mut slices, n),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{plan_reads, plan_reads_in, read_data_runs, read_data_runs_in, ReadPiece};
+    use crate::parse::DataRun;
+
+    #[test]
+    fn fragmented_reads() {
+        let disk: Vec<u8> = (0..64u8).collect();
+        // Clusters of 4 bytes: 8-9, a sparse one, 2, then 10 (next to the first run).
+        let runs = [
+            DataRun {
+                lcn: Some(8),
+                cluster_count: 2,
+            },
+            DataRun {
+                lcn: None,
+                cluster_count: 1,
+            },
+            DataRun {
+                lcn: Some(2),
+                cluster_count: 1,
+            },
+            DataRun {
+                lcn: Some(10),
+                cluster_count: 1,
+            },
+        ];
+
+        let (len, extents) = plan_reads(&runs, 4, 18);
+        assert_eq!(len, 18);
+        assert_eq!(extents.len(), 2);
+        assert_eq!(extents[0].position, 8);
+        assert_eq!(
+            extents[1].pieces,
+            vec![
+                ReadPiece { offset: 0, len: 8 },
+                ReadPiece { offset: 16, len: 2 }
+            ]
+        );
+
+        let mut expected: Vec<u8> = (32..40).collect();
+        expected.extend([0; 4]);
+        expected.extend(8..12);
+        expected.extend(40..42);
+        for vectored in [false, true] {
+            let data = read_data_runs(&mut Cursor::new(&disk), &runs, 4, 18, vectored).unwrap();
+            assert_eq!(data, expected);
+        }
+
+        // The runs are shorter than the size.
+        let data = read_data_runs(&mut Cursor::new(&disk), &runs[..1], 4, 100, true).unwrap();
+        assert_eq!(data.len(), 8);
+
+        // Only some ranges, across the runs.
+        let ranges = [2..6, 7..9, 14..17];
+        let data = read_data_runs_in(&mut Cursor::new(&disk), &runs, 4, 18, &ranges).unwrap();
+        let mut expected_in = vec![0u8; 18];
+        for range in &ranges {
+            expected_in[range.clone()].copy_from_slice(&expected[range.clone()]);
+        }
+        assert_eq!(data, expected_in);
+        // 2..6 and 7..8 are in the same extent, 8..9 is sparse.
+        let (_, extents) = plan_reads_in(&runs, 4, 18, &ranges);
+        assert_eq!(extents.len(), 3);
+    }
+
+    #[test]
+    fn runs_past_the_end_of_the_disk() {
+        // The second run starts on the disk but ends past u64::MAX.
+        let runs = [
+            DataRun {
+                lcn: Some(1),
+                cluster_count: 1,
+            },
+            DataRun {
+                lcn: Some(u64::MAX / 4),
+                cluster_count: 2,
+            },
+        ];
+
+        let (len, extents) = plan_reads(&runs, 4, 12);
+        assert_eq!(len, 4);
+        assert_eq!(extents.len(), 1);
+        assert_eq!(extents[0].position, 4);
+
+        let (len, extents) = plan_reads_in(&runs, 4, 12, &[0..2, 3..12]);
+        assert_eq!(len, 4);
+        assert_eq!(extents.len(), 2);
+        assert_eq!(extents[1].position, 7);
+    }
+}
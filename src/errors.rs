@@ -1,32 +1,235 @@
 use thiserror::Error;
 
+use crate::volume::FileSystemType;
+
 #[derive(Error, Debug)]
 pub enum NtfsReaderError {
-    #[error("elevation error")]
+    #[error("access to the volume was denied, run elevated or with SeBackupPrivilege")]
     ElevationError,
+    #[error("not an NTFS volume, detected {detected:?}")]
+    NotNtfs { detected: FileSystemType },
+    #[error("invalid boot sector: {0}")]
+    InvalidBootSector(#[from] BootSectorError),
+    // The offset of the attribute in the record (0 for the header), the offsets in `source` are
+    // relative to it.
+    #[error(
+        "corrupt record {record} at offset {offset} (attribute type {attribute_type:?}): {source}"
+    )]
+    CorruptRecord {
+        record: u64,
+        attribute_type: Option<u32>,
+        offset: usize,
+        source: ParseError,
+    },
+    #[error("record {record} is past the end of the MFT")]
+    RecordOutOfRange { record: u64 },
     #[error("io error")]
     IOError(#[from] std::io::Error),
     #[error("binread error")]
     BinReadError(#[from] binread::error::Error),
+    #[error("{0}")]
+    Win32(#[from] Win32Error),
+    #[cfg(feature = "windows")]
     #[error("windows error")]
     WindowsError(#[from] WindowsErrorWrapper),
     #[error("unknown")]
     Unknown,
 }
 
+// Which field of the boot sector is out of range.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootSectorError {
+    #[error("invalid sector size {0}")]
+    SectorSize(u16),
+    #[error("invalid sectors per cluster code {0:#x}")]
+    SectorsPerCluster(u8),
+    #[error("invalid file record size code {0}")]
+    FileRecordSize(i8),
+    #[error("the MFT (lcn {mft_lcn}) is past the end of the volume ({total_clusters} clusters)")]
+    MftOutOfBounds { mft_lcn: u64, total_clusters: u64 },
+}
+
+// Returned inside a `std::io::Error` by the journal reads when records were lost,
+// use `Journal::resync` to continue from the oldest records still available.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalError {
+    #[error("journal records were deleted, the lowest valid usn is {lowest_valid_usn}")]
+    EntryDeleted { lowest_valid_usn: i64 },
+    #[error("the journal was deleted or recreated")]
+    Recreated {
+        // Zero if there is no active journal.
+        journal_id: u64,
+        lowest_valid_usn: i64,
+    },
+    // Only in strict mode, see `JournalOptions::strict`.
+    #[error("invalid journal record: {0}")]
+    InvalidRecord(ParseIssue),
+}
+
+// Why a record was skipped while parsing the journal data.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseIssue {
+    #[error("unknown major version {0}")]
+    UnknownVersion(u16),
+    #[error("record is truncated or its length is inconsistent")]
+    Truncated,
+}
+
+// Returned by the functions of `parse`.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    #[error("not a file record")]
+    BadSignature,
+    #[error("{len} bytes at offset {offset} are out of bounds")]
+    OutOfBounds { offset: usize, len: usize },
+    #[error("invalid update sequence array")]
+    InvalidFixup,
+    #[error("update sequence mismatch in sector {sector}")]
+    FixupMismatch { sector: usize },
+    #[error("invalid attribute at offset {offset}")]
+    InvalidAttribute { offset: usize },
+    #[error("invalid data run at offset {offset}")]
+    InvalidDataRun { offset: usize },
+    #[error("missing attribute")]
+    MissingAttribute,
+}
+
+// A failed system call, with the name of the operation (e.g. FSCTL_READ_USN_JOURNAL).
+// Returned inside a `std::io::Error` by the journal and volume functions, which keeps
+// the `ErrorKind` of the code.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("{operation} failed with error {code:#x}")]
+pub struct Win32Error {
+    pub operation: &'static str,
+    // The win32 error code, or the HRESULT when it's not one.
+    pub code: u32,
+}
+
+impl NtfsReaderError {
+    // Also looks inside `IOError`.
+    pub fn win32_error(&self) -> Option<&Win32Error> {
+        match self {
+            NtfsReaderError::Win32(err) => Some(err),
+            NtfsReaderError::IOError(err) => Win32Error::from_io(err),
+            _ => None,
+        }
+    }
+}
+
+impl Win32Error {
+    pub fn from_io(err: &std::io::Error) -> Option<&Win32Error> {
+        err.get_ref()?.downcast_ref::<Win32Error>()
+    }
+
+    #[cfg(feature = "windows")]
+    pub fn from_windows(operation: &'static str, err: &windows::core::Error) -> Win32Error {
+        let hresult = err.code().0 as u32;
+        // HRESULT_FROM_WIN32
+        let code = if hresult & 0xFFFF_0000 == 0x8007_0000 {
+            hresult & 0xFFFF
+        } else {
+            hresult
+        };
+        Win32Error { operation, code }
+    }
+
+    // The errors that can go away when the operation is retried later.
+    pub fn is_transient(&self) -> bool {
+        const ERROR_SHARING_VIOLATION: u32 = 32;
+        const ERROR_LOCK_VIOLATION: u32 = 33;
+        const ERROR_NOT_READY: u32 = 21;
+        const ERROR_SEM_TIMEOUT: u32 = 121;
+        const ERROR_BUSY: u32 = 170;
+        const ERROR_OPERATION_ABORTED: u32 = 995;
+        const ERROR_JOURNAL_DELETE_IN_PROGRESS: u32 = 1178;
+        const ERROR_TIMEOUT: u32 = 1460;
+
+        matches!(
+            self.code,
+            ERROR_SHARING_VIOLATION
+                | ERROR_LOCK_VIOLATION
+                | ERROR_NOT_READY
+                | ERROR_SEM_TIMEOUT
+                | ERROR_BUSY
+                | ERROR_OPERATION_ABORTED
+                | ERROR_JOURNAL_DELETE_IN_PROGRESS
+                | ERROR_TIMEOUT
+        )
+    }
+}
+
+impl From<Win32Error> for std::io::Error {
+    fn from(err: Win32Error) -> Self {
+        let kind = if err.code <= 0xFFFF {
+            std::io::Error::from_raw_os_error(err.code as i32).kind()
+        } else {
+            std::io::ErrorKind::Other
+        };
+        std::io::Error::new(kind, err)
+    }
+}
+
+// To convert the errors of the windows crate, e.g. `.map_err(win32("CloseHandle"))?`.
+#[cfg(feature = "windows")]
+pub(crate) fn win32(operation: &'static str) -> impl Fn(windows::core::Error) -> std::io::Error {
+    move |err| Win32Error::from_windows(operation, &err).into()
+}
+
+impl JournalError {
+    pub fn from_io(err: &std::io::Error) -> Option<&JournalError> {
+        err.get_ref()?.downcast_ref::<JournalError>()
+    }
+}
+
+impl From<JournalError> for std::io::Error {
+    fn from(err: JournalError) -> Self {
+        std::io::Error::other(err)
+    }
+}
+
+#[cfg(feature = "windows")]
 #[derive(Debug)]
 pub struct WindowsErrorWrapper(windows::core::Error);
+#[cfg(feature = "windows")]
 impl WindowsErrorWrapper {
     pub fn from_win32() -> WindowsErrorWrapper {
         WindowsErrorWrapper(windows::core::Error::from_win32())
     }
 }
 
+#[cfg(feature = "windows")]
 impl std::fmt::Display for WindowsErrorWrapper {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "Windows error: {}", self.0)
     }
 }
+#[cfg(feature = "windows")]
 impl std::error::Error for WindowsErrorWrapper {}
 
 pub type NtfsReaderResult<T> = core::result::Result<T, NtfsReaderError>;
+
+#[cfg(test)]
+mod tests {
+    use super::{NtfsReaderError, Win32Error};
+
+    #[test]
+    fn win32_errors() {
+        let err = Win32Error {
+            operation: "FSCTL_READ_USN_JOURNAL",
+            code: 5,
+        };
+        assert!(!err.is_transient());
+
+        let io: std::io::Error = err.into();
+        // PermissionDenied on windows.
+        assert_eq!(io.kind(), std::io::Error::from_raw_os_error(5).kind());
+        assert_eq!(Win32Error::from_io(&io), Some(&err));
+        assert_eq!(NtfsReaderError::from(io).win32_error(), Some(&err));
+
+        let err = Win32Error {
+            operation: "FSCTL_READ_USN_JOURNAL",
+            code: 0xC000_0185,
+        };
+        assert_eq!(std::io::Error::from(err).kind(), std::io::ErrorKind::Other);
+    }
+}
@@ -0,0 +1,427 @@
+// Copyright (c) 2022, Matteo Bernacchia <dev@kikijiki.com>. All rights reserved.
+// This project is dual licensed under the Apache License 2.0 and the MIT license.
+// See the LICENSE files in the project root for details.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+const EWF_SIGNATURE: &[u8; 8] = b"EVF\x09\x0d\x0a\xff\x00";
+const FILE_HEADER_SIZE: u64 = 13;
+const SECTION_DESCRIPTOR_SIZE: u64 = 76;
+const TABLE_HEADER_SIZE: u64 = 24;
+const TABLE_COMPRESSED: u32 = 1 << 31;
+// The chunks are 32 KB by default, larger ones are corrupted.
+const MAX_CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+
+// Inflates the zlib compressed chunks, so that no compression library is needed here.
+// Implemented for closures, e.g. with flate2:
+// `|input: &[u8], output: &mut Vec<u8>| ZlibDecoder::new(input).read_to_end(output).map(|_| ())`
+pub trait Decompressor {
+    fn decompress(&self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()>;
+}
+
+impl<F> Decompressor for F
+where
+    F: Fn(&[u8], &mut Vec<u8>) -> io::Result<()>,
+{
+    fn decompress(&self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        self(input, output)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Chunk {
+    segment: usize,
+    offset: u64,
+    size: u64,
+    compressed: bool,
+}
+
+// Reads the media stored in Expert Witness Format (E01) files as a flat image,
+// e.g. to find the partitions with `image::read_partitions` and read them with `Mft::from_reader`.
+pub struct EwfReader<R = BufReader<File>>
+where
+    R: Read + Seek,
+{
+    segments: Vec<R>,
+    chunks: Vec<Chunk>,
+    chunk_size: u64,
+    media_size: u64,
+    position: u64,
+    decompressor: Box<dyn Decompressor>,
+    // The last chunk read, by index.
+    cache: Option<(usize, Vec<u8>)>,
+}
+
+impl EwfReader {
+    // Opens the first segment (.E01) and the ones following it (.E02, ... .E99, .EAA, ...).
+    pub fn open<P, D>(path: P, decompressor: D) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+        D: Decompressor + 'static,
+    {
+        let mut segments = Vec::new();
+        let mut path = Some(path.as_ref().to_path_buf());
+
+        while let Some(current) = path.filter(|p| segments.is_empty() || p.exists()) {
+            segments.push(BufReader::new(File::open(&current)?));
+            path = next_segment_path(&current);
+        }
+
+        Self::from_segments(segments, decompressor)
+    }
+}
+
+impl<R> EwfReader<R>
+where
+    R: Read + Seek,
+{
+    pub fn from_segments<D>(mut segments: Vec<R>, decompressor: D) -> io::Result<Self>
+    where
+        D: Decompressor + 'static,
+    {
+        let mut chunks = Vec::new();
+        let mut chunk_size = 0;
+        let mut media_size = 0;
+
+        for (index, segment) in segments.iter_mut().enumerate() {
+            // Nothing in the segment can be larger than it.
+            let segment_size = segment.seek(SeekFrom::End(0))?;
+            let header = read_at(segment, 0, FILE_HEADER_SIZE as usize)?;
+            if header[0..8] != *EWF_SIGNATURE {
+                return Err(invalid_data("not an EWF segment"));
+            }
+
+            // Where the data of the last sectors section ends, the last chunk of a table ends there.
+            let mut sectors_end = None;
+            let mut offset = FILE_HEADER_SIZE;
+            loop {
+                let descriptor = read_at(segment, offset, SECTION_DESCRIPTOR_SIZE as usize)?;
+                let kind = descriptor[0..16].split(|c| *c == 0).next().unwrap_or(&[]);
+                let next = read_u64(&descriptor, 16);
+                let size = read_u64(&descriptor, 24);
+                let data = offset + SECTION_DESCRIPTOR_SIZE;
+                let end = offset
+                    .checked_add(size)
+                    .ok_or_else(|| invalid_data("invalid EWF section size"))?;
+
+                match kind {
+                    b"volume" | b"disk" => {
+                        let volume = read_at(segment, data, 24)?;
+                        let sectors_per_chunk = read_u32(&volume, 8) as u64;
+                        let bytes_per_sector = read_u32(&volume, 12) as u64;
+                        chunk_size = sectors_per_chunk * bytes_per_sector;
+                        if chunk_size > MAX_CHUNK_SIZE {
+                            return Err(invalid_data("invalid EWF chunk size"));
+                        }
+                        media_size = read_u64(&volume, 16)
+                            .checked_mul(bytes_per_sector)
+                            .ok_or_else(|| invalid_data("invalid EWF media size"))?;
+                    }
+                    b"sectors" => sectors_end = Some(end),
+                    b"table" => {
+                        let table = read_at(segment, data, TABLE_HEADER_SIZE as usize)?;
+                        let count = read_u32(&table, 0) as u64;
+                        let base = read_u64(&table, 8);
+                        if data + TABLE_HEADER_SIZE + count * 4 > segment_size {
+                            return Err(invalid_data("invalid EWF table size"));
+                        }
+                        let entries =
+                            read_at(segment, data + TABLE_HEADER_SIZE, count as usize * 4)?;
+                        let entries: Vec<u32> = (0..count as usize)
+                            .map(|i| read_u32(&entries, i * 4))
+                            .collect();
+
+                        let chunk_offset = |entry: &u32| {
+                            base.checked_add((entry & !TABLE_COMPRESSED) as u64)
+                                .filter(|offset| *offset <= segment_size)
+                                .ok_or_else(|| invalid_data("invalid EWF chunk offset"))
+                        };
+                        let last_end = sectors_end.unwrap_or(offset).min(segment_size);
+                        for (i, entry) in entries.iter().enumerate() {
+                            let start = chunk_offset(entry)?;
+                            let end = match entries.get(i + 1) {
+                                Some(next) => chunk_offset(next)?,
+                                None => last_end,
+                            };
+                            chunks.push(Chunk {
+                                segment: index,
+                                offset: start,
+                                size: end.saturating_sub(start),
+                                compressed: entry & TABLE_COMPRESSED != 0,
+                            });
+                        }
+                    }
+                    b"next" | b"done" => break,
+                    _ => {}
+                }
+
+                if next <= offset {
+                    break;
+                }
+                offset = next;
+            }
+        }
+
+        if chunk_size == 0 {
+            return Err(invalid_data("missing EWF volume section"));
+        }
+
+        Ok(EwfReader {
+            segments,
+            chunks,
+            chunk_size,
+            media_size,
+            position: 0,
+            decompressor: Box::new(decompressor),
+            cache: None,
+        })
+    }
+
+    // The size of the acquired media, in bytes.
+    pub fn media_size(&self) -> u64 {
+        self.media_size
+    }
+
+    fn load_chunk(&mut self, index: usize) -> io::Result<&[u8]> {
+        if self
+            .cache
+            .as_ref()
+            .is_none_or(|(cached, _)| *cached != index)
+        {
+            let chunk = *self
+                .chunks
+                .get(index)
+                .ok_or_else(|| invalid_data("missing EWF chunk"))?;
+            let raw = read_at(
+                &mut self.segments[chunk.segment],
+                chunk.offset,
+                chunk.size as usize,
+            )?;
+
+            let mut data = Vec::with_capacity(self.chunk_size as usize);
+            if chunk.compressed {
+                self.decompressor.decompress(&raw, &mut data)?;
+            } else {
+                // Without the checksum that follows the data.
+                data.extend_from_slice(&raw[..raw.len().min(self.chunk_size as usize)]);
+            }
+            self.cache = Some((index, data));
+        }
+
+        Ok(&self.cache.as_ref().unwrap().1)
+    }
+}
+
+impl<R> Read for EwfReader<R>
+where
+    R: Read + Seek,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.media_size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let index = (self.position / self.chunk_size) as usize;
+        let start = (self.position % self.chunk_size) as usize;
+        let remaining = (self.media_size - self.position) as usize;
+
+        let chunk = self.load_chunk(index)?;
+        let len = buf
+            .len()
+            .min(chunk.len().saturating_sub(start))
+            .min(remaining);
+        if len == 0 {
+            return Err(invalid_data("truncated EWF chunk"));
+        }
+
+        buf[..len].copy_from_slice(&chunk[start..start + len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R> Seek for EwfReader<R>
+where
+    R: Read + Seek,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.media_size.checked_add_signed(n),
+            SeekFrom::Current(n) => self.position.checked_add_signed(n),
+        };
+
+        self.position = position
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid position"))?;
+        Ok(self.position)
+    }
+}
+
+// E01 -> E02, E99 -> EAA, EAZ -> EBA, EZZ -> FAA, keeping the case.
+fn next_segment_path(path: &Path) -> Option<PathBuf> {
+    let extension = path.extension()?.to_str()?;
+    let bytes = extension.as_bytes();
+    if bytes.len() != 3 {
+        return None;
+    }
+
+    let a = if bytes[0].is_ascii_lowercase() {
+        b'a'
+    } else {
+        b'A'
+    };
+    let next = match (bytes[1], bytes[2]) {
+        (b'9', b'9') => [bytes[0], a, a],
+        (tens @ b'0'..=b'9', units @ b'0'..=b'9') => {
+            let n = (tens - b'0') * 10 + units - b'0' + 1;
+            [bytes[0], b'0' + n / 10, b'0' + n % 10]
+        }
+        (first, second) if second.eq_ignore_ascii_case(&b'z') => {
+            if !first.eq_ignore_ascii_case(&b'z') {
+                [bytes[0], first + 1, a]
+            } else if !bytes[0].eq_ignore_ascii_case(&b'z') {
+                [bytes[0] + 1, a, a]
+            } else {
+                return None;
+            }
+        }
+        (first, second) => [bytes[0], first, second + 1],
+    };
+
+    Some(path.with_extension(std::str::from_utf8(&next).ok()?))
+}
+
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut data = vec![0; len];
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut data)?;
+    Ok(data)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+    use std::path::{Path, PathBuf};
+
+    use super::{next_segment_path, EwfReader, EWF_SIGNATURE};
+
+    fn section(disk: &mut Vec<u8>, kind: &[u8], data: &[u8], last: bool) {
+        let offset = disk.len() as u64;
+        let size = 76 + data.len() as u64;
+        let next = if last { offset } else { offset + size };
+
+        let mut descriptor = [0u8; 76];
+        descriptor[..kind.len()].copy_from_slice(kind);
+        descriptor[16..24].copy_from_slice(&next.to_le_bytes());
+        descriptor[24..32].copy_from_slice(&size.to_le_bytes());
+        disk.extend_from_slice(&descriptor);
+        disk.extend_from_slice(data);
+    }
+
+    #[test]
+    fn read_chunks() {
+        let media: Vec<u8> = (0..2048u32).map(|i| (i * 7) as u8).collect();
+
+        let mut disk = EWF_SIGNATURE.to_vec();
+        disk.extend_from_slice(&[1, 1, 0, 0, 0]);
+
+        let mut volume = [0u8; 24];
+        volume[8..12].copy_from_slice(&2u32.to_le_bytes());
+        volume[12..16].copy_from_slice(&512u32.to_le_bytes());
+        volume[16..24].copy_from_slice(&4u64.to_le_bytes());
+        section(&mut disk, b"volume", &volume, false);
+
+        // An uncompressed chunk with its checksum, then a "compressed" one.
+        let first = disk.len() as u32 + 76;
+        let mut sectors = media[..1024].to_vec();
+        sectors.extend_from_slice(&[0; 4]);
+        sectors.extend(media[1024..].iter().rev());
+        section(&mut disk, b"sectors", &sectors, false);
+
+        let mut table = vec![0u8; 24];
+        table[0..4].copy_from_slice(&2u32.to_le_bytes());
+        table.extend_from_slice(&first.to_le_bytes());
+        table.extend_from_slice(&((first + 1028) | 1 << 31).to_le_bytes());
+        section(&mut disk, b"table", &table, false);
+        section(&mut disk, b"done", &[], true);
+
+        let reverse = |input: &[u8], output: &mut Vec<u8>| {
+            output.extend(input.iter().rev());
+            Ok(())
+        };
+        let mut reader = EwfReader::from_segments(vec![Cursor::new(disk)], reverse).unwrap();
+        assert_eq!(reader.media_size(), 2048);
+
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, media);
+
+        let mut data = [0u8; 100];
+        reader.seek(SeekFrom::Start(1000)).unwrap();
+        reader.read_exact(&mut data).unwrap();
+        assert_eq!(data[..], media[1000..1100]);
+    }
+
+    #[test]
+    fn corrupt_headers() {
+        let open = |volume: &[u8], table: &[u8]| {
+            let mut disk = EWF_SIGNATURE.to_vec();
+            disk.extend_from_slice(&[1, 1, 0, 0, 0]);
+            section(&mut disk, b"volume", volume, false);
+            section(&mut disk, b"table", table, false);
+            section(&mut disk, b"done", &[], true);
+            let decompress = |_: &[u8], _: &mut Vec<u8>| Ok(());
+            EwfReader::from_segments(vec![Cursor::new(disk)], decompress)
+        };
+
+        let mut volume = [0u8; 24];
+        volume[8..12].copy_from_slice(&2u32.to_le_bytes());
+        volume[12..16].copy_from_slice(&512u32.to_le_bytes());
+        volume[16..24].copy_from_slice(&4u64.to_le_bytes());
+        let table = [0u8; 24];
+        assert!(open(&volume, &table).is_ok());
+
+        // Not allocated.
+        let mut huge_table = table;
+        huge_table[0..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(open(&volume, &huge_table).is_err());
+
+        let mut past_end = table.to_vec();
+        past_end[0..4].copy_from_slice(&1u32.to_le_bytes());
+        past_end[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+        past_end.extend_from_slice(&1u32.to_le_bytes());
+        assert!(open(&volume, &past_end).is_err());
+
+        let mut huge_chunks = volume;
+        huge_chunks[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(open(&huge_chunks, &table).is_err());
+
+        let mut huge_media = volume;
+        huge_media[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(open(&huge_media, &table).is_err());
+    }
+
+    #[test]
+    fn segment_names() {
+        let next = |name: &str| next_segment_path(Path::new(name));
+        assert_eq!(next("disk.E01"), Some(PathBuf::from("disk.E02")));
+        assert_eq!(next("disk.e09"), Some(PathBuf::from("disk.e10")));
+        assert_eq!(next("disk.E99"), Some(PathBuf::from("disk.EAA")));
+        assert_eq!(next("disk.EAZ"), Some(PathBuf::from("disk.EBA")));
+        assert_eq!(next("disk.EZZ"), Some(PathBuf::from("disk.FAA")));
+    }
+}
@@ -0,0 +1,334 @@
+// Copyright (c) 2022, Matteo Bernacchia <dev@kikijiki.com>. All rights reserved.
+// This project is dual licensed under the Apache License 2.0 and the MIT license.
+// See the LICENSE files in the project root for details.
+
+// The file listings by column, in batches of rows, to build Arrow record batches (and from them
+// Parquet files) without going through CSV. Each column maps directly to an Arrow array, see
+// `FileInfoColumns::SCHEMA`. With the `arrow` feature the batches are converted to
+// `RecordBatch`es, and with the `parquet` feature written to a Parquet file.
+
+#[cfg(feature = "parquet")]
+use std::io::Write;
+#[cfg(feature = "arrow")]
+use std::sync::Arc;
+
+#[cfg(feature = "arrow")]
+use arrow_array::{
+    builder::OffsetBufferBuilder, ArrayRef, BooleanArray, ListArray, RecordBatch, StringArray,
+    StructArray, TimestampMicrosecondArray, UInt64Array,
+};
+#[cfg(feature = "arrow")]
+use arrow_schema::{ArrowError, DataType, Field, Fields, Schema, SchemaRef, TimeUnit};
+#[cfg(feature = "parquet")]
+use parquet::{arrow::ArrowWriter, errors::ParquetError, file::properties::WriterProperties};
+use time::OffsetDateTime;
+
+use crate::{
+    file_info::{FileInfo, FileInfoBuilder},
+    mft::Mft,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    Utf8,
+    Boolean,
+    UInt64,
+    // Microseconds since the Unix epoch in UTC, None when the time is not valid.
+    TimestampMicrosecond,
+    // List<Struct<name: Utf8, size: UInt64>>.
+    Streams,
+}
+
+#[cfg(feature = "arrow")]
+impl ColumnType {
+    pub fn data_type(self) -> DataType {
+        match self {
+            ColumnType::Utf8 => DataType::Utf8,
+            ColumnType::Boolean => DataType::Boolean,
+            ColumnType::UInt64 => DataType::UInt64,
+            ColumnType::TimestampMicrosecond => {
+                DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into()))
+            }
+            ColumnType::Streams => DataType::List(Arc::new(Field::new_list_field(
+                DataType::Struct(stream_fields()),
+                false,
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileInfoColumns {
+    pub name: Vec<String>,
+    pub path: Vec<String>,
+    pub is_directory: Vec<bool>,
+    pub size: Vec<u64>,
+    pub created: Vec<Option<i64>>,
+    pub accessed: Vec<Option<i64>>,
+    pub modified: Vec<Option<i64>>,
+    pub created_filetime: Vec<u64>,
+    pub accessed_filetime: Vec<u64>,
+    pub modified_filetime: Vec<u64>,
+    pub streams: Vec<Vec<(String, u64)>>,
+    pub stale_parent: Vec<bool>,
+}
+
+impl FileInfoColumns {
+    // The name, type and nullability of each column, in order. Only the times are nullable.
+    pub const SCHEMA: &'static [(&'static str, ColumnType, bool)] = &[
+        ("name", ColumnType::Utf8, false),
+        ("path", ColumnType::Utf8, false),
+        ("is_directory", ColumnType::Boolean, false),
+        ("size", ColumnType::UInt64, false),
+        ("created", ColumnType::TimestampMicrosecond, true),
+        ("accessed", ColumnType::TimestampMicrosecond, true),
+        ("modified", ColumnType::TimestampMicrosecond, true),
+        ("created_filetime", ColumnType::UInt64, false),
+        ("accessed_filetime", ColumnType::UInt64, false),
+        ("modified_filetime", ColumnType::UInt64, false),
+        ("streams", ColumnType::Streams, false),
+        ("stale_parent", ColumnType::Boolean, false),
+    ];
+
+    pub fn with_capacity(rows: usize) -> Self {
+        Self {
+            name: Vec::with_capacity(rows),
+            path: Vec::with_capacity(rows),
+            is_directory: Vec::with_capacity(rows),
+            size: Vec::with_capacity(rows),
+            created: Vec::with_capacity(rows),
+            accessed: Vec::with_capacity(rows),
+            modified: Vec::with_capacity(rows),
+            created_filetime: Vec::with_capacity(rows),
+            accessed_filetime: Vec::with_capacity(rows),
+            modified_filetime: Vec::with_capacity(rows),
+            streams: Vec::with_capacity(rows),
+            stale_parent: Vec::with_capacity(rows),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.name.is_empty()
+    }
+
+    pub fn push(&mut self, info: FileInfo) {
+        self.name.push(info.name);
+        self.path.push(info.path.to_string_lossy().into_owned());
+        self.is_directory.push(info.is_directory);
+        self.size.push(info.size);
+        self.created.push(info.created.map(unix_micros));
+        self.accessed.push(info.accessed.map(unix_micros));
+        self.modified.push(info.modified.map(unix_micros));
+        self.created_filetime.push(info.created_filetime);
+        self.accessed_filetime.push(info.accessed_filetime);
+        self.modified_filetime.push(info.modified_filetime);
+        self.streams.push(info.streams);
+        self.stale_parent.push(info.stale_parent);
+    }
+
+    // Keeps the capacity, for the next batch.
+    pub fn clear(&mut self) {
+        self.name.clear();
+        self.path.clear();
+        self.is_directory.clear();
+        self.size.clear();
+        self.created.clear();
+        self.accessed.clear();
+        self.modified.clear();
+        self.created_filetime.clear();
+        self.accessed_filetime.clear();
+        self.modified_filetime.clear();
+        self.streams.clear();
+        self.stale_parent.clear();
+    }
+
+    // The Arrow schema of `SCHEMA`.
+    #[cfg(feature = "arrow")]
+    pub fn schema() -> SchemaRef {
+        let fields = Self::SCHEMA
+            .iter()
+            .map(|&(name, column, nullable)| Field::new(name, column.data_type(), nullable))
+            .collect::<Vec<_>>();
+        Arc::new(Schema::new(fields))
+    }
+
+    #[cfg(feature = "arrow")]
+    pub fn to_record_batch(&self) -> Result<RecordBatch, ArrowError> {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from_iter_values(&self.name)),
+            Arc::new(StringArray::from_iter_values(&self.path)),
+            Arc::new(BooleanArray::from(self.is_directory.clone())),
+            Arc::new(UInt64Array::from(self.size.clone())),
+            timestamps(&self.created),
+            timestamps(&self.accessed),
+            timestamps(&self.modified),
+            Arc::new(UInt64Array::from(self.created_filetime.clone())),
+            Arc::new(UInt64Array::from(self.accessed_filetime.clone())),
+            Arc::new(UInt64Array::from(self.modified_filetime.clone())),
+            self.streams_array()?,
+            Arc::new(BooleanArray::from(self.stale_parent.clone())),
+        ];
+        RecordBatch::try_new(Self::schema(), columns)
+    }
+
+    #[cfg(feature = "arrow")]
+    fn streams_array(&self) -> Result<ArrayRef, ArrowError> {
+        let streams = self.streams.iter().flatten();
+        let names = StringArray::from_iter_values(streams.clone().map(|(name, _)| name));
+        let sizes = UInt64Array::from_iter_values(streams.map(|&(_, size)| size));
+        let values = StructArray::try_new(
+            stream_fields(),
+            vec![Arc::new(names), Arc::new(sizes)],
+            None,
+        )?;
+
+        let mut offsets = OffsetBufferBuilder::new(self.streams.len());
+        for file in &self.streams {
+            offsets.push_length(file.len());
+        }
+
+        let DataType::List(field) = ColumnType::Streams.data_type() else {
+            unreachable!()
+        };
+        Ok(Arc::new(ListArray::try_new(
+            field,
+            offsets.finish(),
+            Arc::new(values),
+            None,
+        )?))
+    }
+
+    // Writes all the files of the MFT to a Parquet file, with a row group for each batch of
+    // `rows` files. Returns the number of rows written.
+    #[cfg(feature = "parquet")]
+    pub fn write_parquet<W: Write + Send>(
+        mft: &Mft,
+        builder: &FileInfoBuilder,
+        rows: usize,
+        writer: W,
+        properties: Option<WriterProperties>,
+    ) -> Result<u64, ParquetError> {
+        let mut writer = ArrowWriter::try_new(writer, Self::schema(), properties)?;
+        let mut count = 0;
+
+        Self::for_each_batch(mft, builder, rows, |batch| {
+            writer.write(&batch.to_record_batch()?)?;
+            writer.flush()?;
+            count += batch.len() as u64;
+            Ok::<_, ParquetError>(())
+        })?;
+
+        writer.close()?;
+        Ok(count)
+    }
+
+    // Calls `f` with batches of up to `rows` files (the last one can be shorter), so that the
+    // whole volume is never in memory at once. Stops at the first error.
+    pub fn for_each_batch<F, E>(
+        mft: &Mft,
+        builder: &FileInfoBuilder,
+        rows: usize,
+        mut f: F,
+    ) -> Result<(), E>
+    where
+        F: FnMut(&FileInfoColumns) -> Result<(), E>,
+    {
+        assert!(rows > 0);
+
+        let mut batch = Self::with_capacity(rows);
+
+        builder.for_each_file_info(mft, |_, info| {
+            batch.push(info);
+            if batch.len() == rows {
+                f(&batch)?;
+                batch.clear();
+            }
+            Ok(())
+        })?;
+
+        if !batch.is_empty() {
+            f(&batch)?;
+        }
+        Ok(())
+    }
+}
+
+fn unix_micros(time: OffsetDateTime) -> i64 {
+    (time.unix_timestamp_nanos() / 1000) as i64
+}
+
+#[cfg(feature = "arrow")]
+fn stream_fields() -> Fields {
+    Fields::from(vec![
+        Field::new("name", DataType::Utf8, false),
+        Field::new("size", DataType::UInt64, false),
+    ])
+}
+
+#[cfg(feature = "arrow")]
+fn timestamps(times: &[Option<i64>]) -> ArrayRef {
+    Arc::new(TimestampMicrosecondArray::from(times.to_vec()).with_timezone("UTC"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use time::OffsetDateTime;
+
+    use super::FileInfoColumns;
+    use crate::file_info::FileInfo;
+
+    #[test]
+    fn columns() {
+        let mut columns = FileInfoColumns::with_capacity(2);
+        for i in 0..2 {
+            columns.push(FileInfo {
+                name: format!("{}.txt", i),
+                path: PathBuf::from(format!("dir/{}.txt", i)),
+                is_directory: false,
+                size: i,
+                created: OffsetDateTime::from_unix_timestamp(1_600_000_000).ok(),
+                accessed: None,
+                modified: None,
+                created_filetime: 0,
+                accessed_filetime: 0,
+                modified_filetime: 0,
+                streams: Vec::new(),
+                stale_parent: false,
+            });
+        }
+
+        assert_eq!(columns.len(), 2);
+        assert_eq!(columns.path[1], "dir/1.txt");
+        assert_eq!(columns.size, [0, 1]);
+        assert_eq!(columns.created[0], Some(1_600_000_000_000_000));
+        assert_eq!(columns.accessed[0], None);
+        assert_eq!(FileInfoColumns::SCHEMA.len(), 12);
+
+        #[cfg(feature = "arrow")]
+        {
+            use arrow_array::{Array, ListArray, StringArray};
+
+            columns.streams[1].push(("ads".to_string(), 5));
+            let batch = columns.to_record_batch().unwrap();
+            assert_eq!(batch.num_rows(), 2);
+            assert_eq!(batch.schema(), FileInfoColumns::schema());
+
+            let names = batch.column(1).as_any().downcast_ref::<StringArray>();
+            assert_eq!(names.unwrap().value(1), "dir/1.txt");
+            assert!(batch.column(5).is_null(0));
+            let streams = batch.column(10).as_any().downcast_ref::<ListArray>();
+            assert_eq!(streams.unwrap().value_length(0), 0);
+            assert_eq!(streams.unwrap().value_length(1), 1);
+        }
+
+        columns.clear();
+        assert!(columns.is_empty());
+        assert!(columns.name.capacity() >= 2);
+    }
+}
@@ -0,0 +1,324 @@
+// Copyright (c) 2022, Matteo Bernacchia <dev@kikijiki.com>. All rights reserved.
+// This project is dual licensed under the Apache License 2.0 and the MIT license.
+// See the LICENSE files in the project root for details.
+
+use std::io::{self, Write};
+
+use crate::{
+    export::format_time,
+    file_info::{FileInfo, FileInfoBuilder},
+    mft::Mft,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    Name,
+    Path,
+    IsDirectory,
+    Size,
+    // RFC 3339, empty when the time is not valid.
+    Created,
+    Accessed,
+    Modified,
+    // The raw FILETIME values.
+    CreatedFiletime,
+    AccessedFiletime,
+    ModifiedFiletime,
+    // The named streams as `name:size`, separated by `;`.
+    Streams,
+    StaleParent,
+}
+
+impl Column {
+    pub const ALL: &'static [Column] = &[
+        Column::Name,
+        Column::Path,
+        Column::IsDirectory,
+        Column::Size,
+        Column::Created,
+        Column::Accessed,
+        Column::Modified,
+        Column::CreatedFiletime,
+        Column::AccessedFiletime,
+        Column::ModifiedFiletime,
+        Column::Streams,
+        Column::StaleParent,
+    ];
+
+    pub const DEFAULT: &'static [Column] = &[
+        Column::Path,
+        Column::IsDirectory,
+        Column::Size,
+        Column::Created,
+        Column::Modified,
+    ];
+
+    // In the header.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Column::Name => "name",
+            Column::Path => "path",
+            Column::IsDirectory => "is_directory",
+            Column::Size => "size",
+            Column::Created => "created",
+            Column::Accessed => "accessed",
+            Column::Modified => "modified",
+            Column::CreatedFiletime => "created_filetime",
+            Column::AccessedFiletime => "accessed_filetime",
+            Column::ModifiedFiletime => "modified_filetime",
+            Column::Streams => "streams",
+            Column::StaleParent => "stale_parent",
+        }
+    }
+
+    fn value(&self, info: &FileInfo) -> String {
+        let time = |time: &Option<_>| time.as_ref().map(format_time).unwrap_or_default();
+
+        match self {
+            Column::Name => info.name.clone(),
+            Column::Path => info.path.to_string_lossy().into_owned(),
+            Column::IsDirectory => info.is_directory.to_string(),
+            Column::Size => info.size.to_string(),
+            Column::Created => time(&info.created),
+            Column::Accessed => time(&info.accessed),
+            Column::Modified => time(&info.modified),
+            Column::CreatedFiletime => info.created_filetime.to_string(),
+            Column::AccessedFiletime => info.accessed_filetime.to_string(),
+            Column::ModifiedFiletime => info.modified_filetime.to_string(),
+            Column::Streams => info
+                .streams
+                .iter()
+                .map(|(name, size)| format!("{}:{}", name, size))
+                .collect::<Vec<_>>()
+                .join(";"),
+            Column::StaleParent => info.stale_parent.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    // With a byte order mark, as expected by Excel.
+    Utf8Bom,
+    // Little endian with a byte order mark.
+    Utf16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsvOptions {
+    pub columns: Vec<Column>,
+    pub header: bool,
+    pub delimiter: char,
+    pub encoding: Encoding,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            columns: Column::DEFAULT.to_vec(),
+            header: true,
+            delimiter: ',',
+            encoding: Encoding::Utf8,
+        }
+    }
+}
+
+// Writes one row per file as they come, following RFC 4180 (the lines end with CRLF, the fields
+// with a delimiter, a quote or a line break are quoted).
+// The writer is not buffered, wrap it in a `BufWriter` when writing to a file.
+pub struct CsvWriter<W: Write> {
+    writer: W,
+    options: CsvOptions,
+    started: bool,
+    line: String,
+}
+
+impl<W: Write> CsvWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self::with_options(writer, CsvOptions::default())
+    }
+
+    pub fn with_options(writer: W, options: CsvOptions) -> Self {
+        Self {
+            writer,
+            options,
+            started: false,
+            line: String::new(),
+        }
+    }
+
+    // The byte order mark and the header (when enabled) are written before the first row.
+    pub fn write(&mut self, info: &FileInfo) -> io::Result<()> {
+        self.start()?;
+
+        self.line.clear();
+        for (i, column) in self.options.columns.iter().enumerate() {
+            if i > 0 {
+                self.line.push(self.options.delimiter);
+            }
+            push_field(&mut self.line, &column.value(info), self.options.delimiter);
+        }
+        self.line.push_str("\r\n");
+        self.write_line()
+    }
+
+    pub fn write_all<'a, I>(&mut self, infos: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = &'a FileInfo>,
+    {
+        for info in infos {
+            self.write(info)?;
+        }
+        Ok(())
+    }
+
+    // Streams all the files of the MFT without collecting them first, returns how many were
+    // written. Stops at the first error.
+    pub fn write_mft(&mut self, mft: &Mft, builder: &FileInfoBuilder) -> io::Result<u64> {
+        let mut count = 0;
+        self.start()?;
+
+        builder.for_each_file_info(mft, |_, info| {
+            count += 1;
+            self.write(&info)
+        })?;
+        Ok(count)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn start(&mut self) -> io::Result<()> {
+        if self.started {
+            return Ok(());
+        }
+        self.started = true;
+
+        match self.options.encoding {
+            Encoding::Utf8 => {}
+            Encoding::Utf8Bom => self.writer.write_all(&[0xEF, 0xBB, 0xBF])?,
+            Encoding::Utf16 => self.writer.write_all(&[0xFF, 0xFE])?,
+        }
+
+        if self.options.header {
+            self.line.clear();
+            for (i, column) in self.options.columns.iter().enumerate() {
+                if i > 0 {
+                    self.line.push(self.options.delimiter);
+                }
+                self.line.push_str(column.name());
+            }
+            self.line.push_str("\r\n");
+            self.write_line()?;
+        }
+        Ok(())
+    }
+
+    fn write_line(&mut self) -> io::Result<()> {
+        match self.options.encoding {
+            Encoding::Utf8 | Encoding::Utf8Bom => self.writer.write_all(self.line.as_bytes()),
+            Encoding::Utf16 => {
+                let bytes: Vec<u8> = self
+                    .line
+                    .encode_utf16()
+                    .flat_map(|unit| unit.to_le_bytes())
+                    .collect();
+                self.writer.write_all(&bytes)
+            }
+        }
+    }
+}
+
+fn push_field(line: &mut String, value: &str, delimiter: char) {
+    let quote = value
+        .chars()
+        .any(|c| c == delimiter || c == '"' || c == '\r' || c == '\n');
+    if !quote {
+        line.push_str(value);
+        return;
+    }
+
+    line.push('"');
+    for c in value.chars() {
+        if c == '"' {
+            line.push('"');
+        }
+        line.push(c);
+    }
+    line.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use time::OffsetDateTime;
+
+    use super::{Column, CsvOptions, CsvWriter, Encoding};
+    use crate::file_info::FileInfo;
+
+    fn file_info(name: &str, path: &str) -> FileInfo {
+        FileInfo {
+            name: name.to_string(),
+            path: PathBuf::from(path),
+            is_directory: false,
+            size: 42,
+            created: OffsetDateTime::from_unix_timestamp(1_600_000_000).ok(),
+            accessed: None,
+            modified: None,
+            created_filetime: 0,
+            accessed_filetime: 0,
+            modified_filetime: 0,
+            streams: vec![("Zone.Identifier".to_string(), 26)],
+            stale_parent: false,
+        }
+    }
+
+    #[test]
+    fn csv_rows() {
+        let options = CsvOptions {
+            columns: vec![
+                Column::Name,
+                Column::Size,
+                Column::Created,
+                Column::Modified,
+                Column::Streams,
+            ],
+            ..Default::default()
+        };
+        let mut writer = CsvWriter::with_options(Vec::new(), options);
+        writer.write(&file_info("plain.txt", "")).unwrap();
+        writer.write(&file_info("a, \"b\".txt", "")).unwrap();
+
+        let text = String::from_utf8(writer.into_inner()).unwrap();
+        assert_eq!(
+            text,
+            "name,size,created,modified,streams\r\n\
+             plain.txt,42,2020-09-13T12:26:40Z,,Zone.Identifier:26\r\n\
+             \"a, \"\"b\"\".txt\",42,2020-09-13T12:26:40Z,,Zone.Identifier:26\r\n"
+        );
+
+        let options = CsvOptions {
+            columns: vec![Column::Path],
+            header: false,
+            delimiter: ';',
+            encoding: Encoding::Utf16,
+        };
+        let mut writer = CsvWriter::with_options(Vec::new(), options);
+        writer.write(&file_info("", "dir;é")).unwrap();
+        let bytes = writer.into_inner();
+        assert_eq!(bytes[..2], [0xFF, 0xFE]);
+        let units: Vec<u16> = bytes[2..]
+            .chunks(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .collect();
+        assert_eq!(String::from_utf16(&units).unwrap(), "\"dir;é\"\r\n");
+    }
+}
@@ -0,0 +1,251 @@
+// Copyright (c) 2022, Matteo Bernacchia <dev@kikijiki.com>. All rights reserved.
+// This project is dual licensed under the Apache License 2.0 and the MIT license.
+// See the LICENSE files in the project root for details.
+
+// One JSON object per line, written with serde_json, for the file listings and the journal
+// records (or any other `Serialize` type).
+
+use std::borrow::Cow;
+use std::io::{self, Write};
+
+use serde_core::ser::{Serialize, SerializeMap, SerializeSeq, SerializeStruct, Serializer};
+use time::OffsetDateTime;
+
+use crate::{
+    export::format_time,
+    file_info::{FileInfo, FileInfoBuilder},
+    mft::Mft,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JsonValue<'a> {
+    Null,
+    Bool(bool),
+    U64(u64),
+    I64(i64),
+    Str(Cow<'a, str>),
+    // As an array of `{"name": ..., "size": ...}`.
+    Streams(&'a [(String, u64)]),
+}
+
+impl<'a> JsonValue<'a> {
+    fn str<S: Into<Cow<'a, str>>>(value: S) -> Self {
+        JsonValue::Str(value.into())
+    }
+
+    fn time(time: &Option<OffsetDateTime>) -> Self {
+        time.as_ref()
+            .map_or(JsonValue::Null, |time| JsonValue::str(format_time(time)))
+    }
+}
+
+// The fields of the JSON object of a record, in order, as serialized by `Serialize`. The names
+// don't change between versions, new fields are only added.
+pub trait JsonRecord {
+    fn json_fields(&self) -> Vec<(&'static str, JsonValue<'_>)>;
+}
+
+impl JsonRecord for FileInfo {
+    fn json_fields(&self) -> Vec<(&'static str, JsonValue<'_>)> {
+        vec![
+            ("name", JsonValue::str(self.name.as_str())),
+            ("path", JsonValue::str(self.path.to_string_lossy())),
+            ("is_directory", JsonValue::Bool(self.is_directory)),
+            ("size", JsonValue::U64(self.size)),
+            ("created", JsonValue::time(&self.created)),
+            ("accessed", JsonValue::time(&self.accessed)),
+            ("modified", JsonValue::time(&self.modified)),
+            ("created_filetime", JsonValue::U64(self.created_filetime)),
+            ("accessed_filetime", JsonValue::U64(self.accessed_filetime)),
+            ("modified_filetime", JsonValue::U64(self.modified_filetime)),
+            ("streams", JsonValue::Streams(&self.streams)),
+            ("stale_parent", JsonValue::Bool(self.stale_parent)),
+        ]
+    }
+}
+
+#[cfg(feature = "windows")]
+impl JsonRecord for crate::journal::UsnRecord {
+    fn json_fields(&self) -> Vec<(&'static str, JsonValue<'_>)> {
+        use crate::journal::Journal;
+
+        vec![
+            ("usn", JsonValue::I64(self.usn)),
+            ("timestamp", JsonValue::time(&Some(self.timestamp))),
+            ("filetime", JsonValue::I64(self.filetime)),
+            ("file_id", JsonValue::U64(self.file_id.reference())),
+            ("parent_id", JsonValue::U64(self.parent_id.reference())),
+            ("reason", JsonValue::U64(self.reason as u64)),
+            (
+                "reasons",
+                JsonValue::str(Journal::get_reason_str(self.reason).trim_end().to_string()),
+            ),
+            (
+                "file_attributes",
+                JsonValue::U64(self.file_attributes as u64),
+            ),
+            ("is_directory", JsonValue::Bool(self.is_directory())),
+            ("source_info", JsonValue::U64(self.source_info as u64)),
+            ("security_id", JsonValue::U64(self.security_id as u64)),
+            ("name", JsonValue::str(self.name.to_string_lossy())),
+            ("path", JsonValue::str(self.path.to_string_lossy())),
+            (
+                "rename_from",
+                self.rename_from.as_ref().map_or(JsonValue::Null, |path| {
+                    JsonValue::str(path.to_string_lossy())
+                }),
+            ),
+        ]
+    }
+}
+
+// Writes one JSON object per line (LF), as they come.
+// The writer is not buffered, wrap it in a `BufWriter` when writing to a file.
+pub struct JsonlWriter<W: Write> {
+    writer: W,
+    line: Vec<u8>,
+}
+
+impl<W: Write> JsonlWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            line: Vec::new(),
+        }
+    }
+
+    // Any `Serialize` type, written as a single line.
+    pub fn write<T: Serialize + ?Sized>(&mut self, record: &T) -> io::Result<()> {
+        self.line.clear();
+        serde_json::to_writer(&mut self.line, record)?;
+        self.line.push(b'\n');
+        self.writer.write_all(&self.line)
+    }
+
+    pub fn write_all<'a, T, I>(&mut self, records: I) -> io::Result<()>
+    where
+        T: Serialize + 'a,
+        I: IntoIterator<Item = &'a T>,
+    {
+        for record in records {
+            self.write(record)?;
+        }
+        Ok(())
+    }
+
+    // Streams all the files of the MFT without collecting them first, returns how many were
+    // written. Stops at the first error.
+    pub fn write_mft(&mut self, mft: &Mft, builder: &FileInfoBuilder) -> io::Result<u64> {
+        let mut count = 0;
+
+        builder.for_each_file_info(mft, |_, info| {
+            count += 1;
+            self.write(&info)
+        })?;
+        Ok(count)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl Serialize for JsonValue<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            JsonValue::Null => serializer.serialize_none(),
+            JsonValue::Bool(value) => serializer.serialize_bool(*value),
+            JsonValue::U64(value) => serializer.serialize_u64(*value),
+            JsonValue::I64(value) => serializer.serialize_i64(*value),
+            JsonValue::Str(value) => serializer.serialize_str(value),
+            JsonValue::Streams(streams) => {
+                let mut seq = serializer.serialize_seq(Some(streams.len()))?;
+                for (name, size) in streams.iter() {
+                    seq.serialize_element(&Stream { name, size: *size })?;
+                }
+                seq.end()
+            }
+        }
+    }
+}
+
+struct Stream<'a> {
+    name: &'a str,
+    size: u64,
+}
+
+impl Serialize for Stream<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(2))?;
+        map.serialize_entry("name", self.name)?;
+        map.serialize_entry("size", &self.size)?;
+        map.end()
+    }
+}
+
+fn serialize_record<R, S>(name: &'static str, record: &R, serializer: S) -> Result<S::Ok, S::Error>
+where
+    R: JsonRecord,
+    S: Serializer,
+{
+    let fields = record.json_fields();
+    let mut object = serializer.serialize_struct(name, fields.len())?;
+    for (name, value) in &fields {
+        object.serialize_field(name, value)?;
+    }
+    object.end()
+}
+
+impl Serialize for FileInfo {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_record("FileInfo", self, serializer)
+    }
+}
+
+#[cfg(feature = "windows")]
+impl Serialize for crate::journal::UsnRecord {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_record("UsnRecord", self, serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::JsonlWriter;
+    use crate::file_info::FileInfo;
+
+    #[test]
+    fn json_lines() {
+        let info = FileInfo {
+            name: "a \"b\"\n.txt".to_string(),
+            path: PathBuf::from("C:\\a"),
+            is_directory: false,
+            size: 42,
+            created: None,
+            accessed: None,
+            modified: None,
+            created_filetime: 0,
+            accessed_filetime: 0,
+            modified_filetime: 0,
+            streams: vec![("ads".to_string(), 3)],
+            stale_parent: false,
+        };
+
+        let mut writer = JsonlWriter::new(Vec::new());
+        writer.write_all([&info, &info]).unwrap();
+        let text = String::from_utf8(writer.into_inner()).unwrap();
+
+        let line = "{\"name\":\"a \\\"b\\\"\\n.txt\",\"path\":\"C:\\\\a\",\"is_directory\":false,\
+                    \"size\":42,\"created\":null,\"accessed\":null,\"modified\":null,\
+                    \"created_filetime\":0,\"accessed_filetime\":0,\"modified_filetime\":0,\
+                    \"streams\":[{\"name\":\"ads\",\"size\":3}],\"stale_parent\":false}";
+        assert_eq!(serde_json::to_string(&info).unwrap(), line);
+        assert_eq!(text, format!("{}\n{}\n", line, line));
+    }
+}
@@ -0,0 +1,32 @@
+// Copyright (c) 2022, Matteo Bernacchia <dev@kikijiki.com>. All rights reserved.
+// This project is dual licensed under the Apache License 2.0 and the MIT license.
+// See the LICENSE files in the project root for details.
+
+// Writers for the listings of the files, to be read by other tools.
+
+use time::{OffsetDateTime, UtcOffset};
+
+pub mod columns;
+pub mod csv;
+#[cfg(feature = "serde")]
+pub mod jsonl;
+
+// RFC 3339 in UTC, with the 100ns precision of the FILETIME when there is a fraction,
+// e.g. 2022-03-04T05:06:07Z or 2022-03-04T05:06:07.1234567Z.
+pub fn format_time(time: &OffsetDateTime) -> String {
+    let time = time.to_offset(UtcOffset::UTC);
+    let mut text = format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        time.year(),
+        time.month() as u8,
+        time.day(),
+        time.hour(),
+        time.minute(),
+        time.second()
+    );
+    if time.nanosecond() != 0 {
+        text.push_str(&format!(".{:07}", time.nanosecond() / 100));
+    }
+    text.push('Z');
+    text
+}
@@ -0,0 +1,460 @@
+// Copyright (c) 2022, Matteo Bernacchia <dev@kikijiki.com>. All rights reserved.
+// This project is dual licensed under the Apache License 2.0 and the MIT license.
+// See the LICENSE files in the project root for details.
+
+// A C ABI for the other languages, see include/ntfs_reader.h (generated with cbindgen).
+// Build the library with `cargo rustc --release --features ffi --crate-type cdylib`.
+//
+// The handles are opaque and freed with their `_free` function. The failing functions return
+// a negative status, `ntfs_last_error` gives the message. The strings are UTF-16, the ones
+// passed to the callbacks are only valid during the call.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_void, OsStr};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::ptr;
+
+use crate::{
+    api::wide_to_os_string, errors::NtfsReaderError, file_info::FileInfo, mft::Mft, volume::Volume,
+};
+
+pub const NTFS_OK: i32 = 0;
+// The callback asked to stop.
+pub const NTFS_STOPPED: i32 = 1;
+pub const NTFS_ERROR: i32 = -1;
+pub const NTFS_INVALID_ARGUMENT: i32 = -2;
+// `len` was set to the needed length (in UTF-16 units, with the terminator).
+pub const NTFS_BUFFER_TOO_SMALL: i32 = -3;
+pub const NTFS_ELEVATION_REQUIRED: i32 = -4;
+pub const NTFS_PANIC: i32 = -5;
+
+// Flags of `ntfs_mft_iterate`.
+// Build the full path of each file (slower), otherwise only the name is set.
+pub const NTFS_ITERATE_PATHS: u32 = 1;
+
+pub struct NtfsVolume(Volume);
+pub struct NtfsMft(Mft);
+#[cfg(feature = "windows")]
+pub struct NtfsJournal(crate::journal::Journal);
+
+#[repr(C)]
+pub struct NtfsFileEntry {
+    pub number: u64,
+    // With the sequence number, as in the USN records.
+    pub reference: u64,
+    pub parent_reference: u64,
+    pub is_directory: u32,
+    pub size: u64,
+    // FILETIME, zero when not set.
+    pub created: u64,
+    pub modified: u64,
+    pub accessed: u64,
+    // NUL terminated, the lengths don't count the terminator.
+    pub name: *const u16,
+    pub name_len: usize,
+    // Empty without NTFS_ITERATE_PATHS.
+    pub path: *const u16,
+    pub path_len: usize,
+}
+
+#[repr(C)]
+pub struct NtfsUsnEntry {
+    pub usn: i64,
+    // FILETIME.
+    pub timestamp: i64,
+    pub file_reference: u64,
+    pub parent_reference: u64,
+    pub reason: u32,
+    pub file_attributes: u32,
+    pub path: *const u16,
+    pub path_len: usize,
+}
+
+// Return non zero to stop.
+pub type NtfsFileCallback = extern "C" fn(entry: *const NtfsFileEntry, context: *mut c_void) -> i32;
+pub type NtfsUsnCallback = extern "C" fn(entry: *const NtfsUsnEntry, context: *mut c_void) -> i32;
+
+thread_local! {
+    static LAST_ERROR: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+fn set_last_error(message: String) {
+    LAST_ERROR.with(|error| *error.borrow_mut() = message);
+}
+
+fn status(err: NtfsReaderError) -> i32 {
+    let status = match err {
+        NtfsReaderError::ElevationError => NTFS_ELEVATION_REQUIRED,
+        _ => NTFS_ERROR,
+    };
+    // With the causes, "io error" alone doesn't help much.
+    let mut message = err.to_string();
+    let mut source = std::error::Error::source(&err);
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    set_last_error(message);
+    status
+}
+
+// Runs `f`, turning the panics into NTFS_PANIC.
+fn guard<F: FnOnce() -> i32>(f: F) -> i32 {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(status) => status,
+        Err(_) => {
+            set_last_error("panicked".to_string());
+            NTFS_PANIC
+        }
+    }
+}
+
+// NUL terminated.
+fn to_wide(text: &OsStr) -> Vec<u16> {
+    #[cfg(windows)]
+    let mut wide: Vec<u16> = std::os::windows::ffi::OsStrExt::encode_wide(text).collect();
+    #[cfg(not(windows))]
+    let mut wide: Vec<u16> = text.to_string_lossy().encode_utf16().collect();
+    wide.push(0);
+    wide
+}
+
+unsafe fn from_wide(text: *const u16) -> Option<PathBuf> {
+    if text.is_null() {
+        return None;
+    }
+    let mut len = 0;
+    while *text.add(len) != 0 {
+        len += 1;
+    }
+    let wide = std::slice::from_raw_parts(text, len);
+    Some(PathBuf::from(wide_to_os_string(wide)))
+}
+
+// Copies `text` with the terminator, or tells the length needed.
+unsafe fn copy_wide(text: &OsStr, buffer: *mut u16, len: *mut usize) -> i32 {
+    if len.is_null() {
+        return NTFS_INVALID_ARGUMENT;
+    }
+    let wide = to_wide(text);
+    if buffer.is_null() || *len < wide.len() {
+        *len = wide.len();
+        return NTFS_BUFFER_TOO_SMALL;
+    }
+    ptr::copy_nonoverlapping(wide.as_ptr(), buffer, wide.len());
+    *len = wide.len();
+    NTFS_OK
+}
+
+unsafe fn put<T>(out: *mut *mut T, value: T) {
+    *out = Box::into_raw(Box::new(value));
+}
+
+#[no_mangle]
+pub extern "C" fn ntfs_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
+
+/// # Safety
+/// `buffer` is valid for `*len` writes (or null to get the length), `len` is valid.
+#[no_mangle]
+pub unsafe extern "C" fn ntfs_last_error(buffer: *mut u16, len: *mut usize) -> i32 {
+    let message = LAST_ERROR.with(|error| error.borrow().clone());
+    copy_wide(OsStr::new(&message), buffer, len)
+}
+
+/// # Safety
+/// `path` is a NUL terminated UTF-16 string, `out` is valid for writes.
+#[cfg(feature = "windows")]
+#[no_mangle]
+pub unsafe extern "C" fn ntfs_volume_open(path: *const u16, out: *mut *mut NtfsVolume) -> i32 {
+    let Some(path) = from_wide(path).filter(|_| !out.is_null()) else {
+        return NTFS_INVALID_ARGUMENT;
+    };
+    guard(|| match Volume::new(path) {
+        Ok(volume) => {
+            put(out, NtfsVolume(volume));
+            NTFS_OK
+        }
+        Err(err) => status(err),
+    })
+}
+
+/// # Safety
+/// `path` is a NUL terminated UTF-16 string, `out` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn ntfs_volume_open_image(
+    path: *const u16,
+    offset: u64,
+    out: *mut *mut NtfsVolume,
+) -> i32 {
+    let Some(path) = from_wide(path).filter(|_| !out.is_null()) else {
+        return NTFS_INVALID_ARGUMENT;
+    };
+    guard(|| match Volume::from_image(path, offset) {
+        Ok(volume) => {
+            put(out, NtfsVolume(volume));
+            NTFS_OK
+        }
+        Err(err) => status(err),
+    })
+}
+
+/// # Safety
+/// `volume` comes from `ntfs_volume_open` or `ntfs_volume_open_image` (or is null), and is not
+/// used after.
+#[no_mangle]
+pub unsafe extern "C" fn ntfs_volume_free(volume: *mut NtfsVolume) {
+    if !volume.is_null() {
+        drop(Box::from_raw(volume));
+    }
+}
+
+/// # Safety
+/// `volume` is a valid handle (still owned by the caller), `out` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn ntfs_mft_open(volume: *const NtfsVolume, out: *mut *mut NtfsMft) -> i32 {
+    if volume.is_null() || out.is_null() {
+        return NTFS_INVALID_ARGUMENT;
+    }
+    let volume = (*volume).0.clone();
+    guard(|| match Mft::new(volume) {
+        Ok(mft) => {
+            put(out, NtfsMft(mft));
+            NTFS_OK
+        }
+        Err(err) => status(err),
+    })
+}
+
+/// # Safety
+/// `mft` comes from `ntfs_mft_open` (or is null), and is not used after.
+#[no_mangle]
+pub unsafe extern "C" fn ntfs_mft_free(mft: *mut NtfsMft) {
+    if !mft.is_null() {
+        drop(Box::from_raw(mft));
+    }
+}
+
+/// # Safety
+/// `mft` is a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn ntfs_mft_max_record(mft: *const NtfsMft) -> u64 {
+    mft.as_ref().map_or(0, |mft| mft.0.max_record)
+}
+
+/// # Safety
+/// `mft` is a valid handle, `context` is passed as is to `callback`.
+#[no_mangle]
+pub unsafe extern "C" fn ntfs_mft_iterate(
+    mft: *const NtfsMft,
+    flags: u32,
+    callback: Option<NtfsFileCallback>,
+    context: *mut c_void,
+) -> i32 {
+    let (Some(mft), Some(callback)) = (mft.as_ref(), callback) else {
+        return NTFS_INVALID_ARGUMENT;
+    };
+    let mft = &mft.0;
+    let paths = flags & NTFS_ITERATE_PATHS != 0;
+
+    guard(|| {
+        let builder = FileInfo::builder().with_path(paths);
+
+        let iterated = builder.for_each_file_info(mft, |file, info| {
+            let name = to_wide(OsStr::new(&info.name));
+            let path = to_wide(info.path.as_os_str());
+            let entry = NtfsFileEntry {
+                number: file.number,
+                reference: file.reference(),
+                parent_reference: file
+                    .get_best_file_name_ref(mft)
+                    .map_or(0, |name| name.header.parent_directory_reference),
+                is_directory: info.is_directory as u32,
+                size: info.size,
+                created: info.created_filetime,
+                modified: info.modified_filetime,
+                accessed: info.accessed_filetime,
+                name: name.as_ptr(),
+                name_len: name.len() - 1,
+                path: path.as_ptr(),
+                path_len: path.len() - 1,
+            };
+            match callback(&entry, context) {
+                0 => Ok(()),
+                _ => Err(NTFS_STOPPED),
+            }
+        });
+        iterated.err().unwrap_or(NTFS_OK)
+    })
+}
+
+/// # Safety
+/// `mft` is a valid handle, `buffer` is valid for `*len` writes (or null to get the length).
+#[no_mangle]
+pub unsafe extern "C" fn ntfs_mft_get_path(
+    mft: *const NtfsMft,
+    number: u64,
+    buffer: *mut u16,
+    len: *mut usize,
+) -> i32 {
+    let Some(mft) = mft.as_ref() else {
+        return NTFS_INVALID_ARGUMENT;
+    };
+    let mft = &mft.0;
+
+    if number >= mft.max_record {
+        status(NtfsReaderError::RecordOutOfRange { record: number });
+        return NTFS_INVALID_ARGUMENT;
+    }
+
+    guard(|| {
+        let Some(file) = mft.get_record(number).filter(|file| file.is_used()) else {
+            set_last_error(format!("record {} is not in use", number));
+            return NTFS_ERROR;
+        };
+        let info = FileInfo::new(mft, &file);
+        copy_wide(info.path.as_os_str(), buffer, len)
+    })
+}
+
+/// # Safety
+/// `volume` is a valid handle (still owned by the caller), `out` is valid for writes.
+#[cfg(feature = "windows")]
+#[no_mangle]
+pub unsafe extern "C" fn ntfs_journal_open(
+    volume: *const NtfsVolume,
+    out: *mut *mut NtfsJournal,
+) -> i32 {
+    use crate::journal::{Journal, JournalOptions};
+
+    if volume.is_null() || out.is_null() {
+        return NTFS_INVALID_ARGUMENT;
+    }
+    let volume = (*volume).0.clone();
+    guard(|| match Journal::new(volume, JournalOptions::default()) {
+        Ok(journal) => {
+            put(out, NtfsJournal(journal));
+            NTFS_OK
+        }
+        Err(err) => status(err.into()),
+    })
+}
+
+/// # Safety
+/// `journal` comes from `ntfs_journal_open` (or is null), and is not used after.
+#[cfg(feature = "windows")]
+#[no_mangle]
+pub unsafe extern "C" fn ntfs_journal_free(journal: *mut NtfsJournal) {
+    if !journal.is_null() {
+        drop(Box::from_raw(journal));
+    }
+}
+
+/// # Safety
+/// `journal` is a valid handle, `context` is passed as is to `callback`.
+// Waits up to `timeout_ms` for new records (0xFFFFFFFF waits forever, zero doesn't wait).
+#[cfg(feature = "windows")]
+#[no_mangle]
+pub unsafe extern "C" fn ntfs_journal_read(
+    journal: *mut NtfsJournal,
+    timeout_ms: u32,
+    callback: Option<NtfsUsnCallback>,
+    context: *mut c_void,
+) -> i32 {
+    let (Some(journal), Some(callback)) = (journal.as_mut(), callback) else {
+        return NTFS_INVALID_ARGUMENT;
+    };
+    let journal = &mut journal.0;
+
+    guard(|| {
+        let records = match timeout_ms {
+            0 => journal.read(),
+            u32::MAX => journal.read_wait(None),
+            ms => journal.read_wait(Some(std::time::Duration::from_millis(ms as u64))),
+        };
+        let records = match records {
+            Ok(records) => records,
+            Err(err) => return status(err.into()),
+        };
+
+        for record in records {
+            let path = to_wide(record.path.as_os_str());
+            let entry = NtfsUsnEntry {
+                usn: record.usn,
+                timestamp: record.filetime,
+                file_reference: record.file_id.reference(),
+                parent_reference: record.parent_id.reference(),
+                reason: record.reason,
+                file_attributes: record.file_attributes,
+                path: path.as_ptr(),
+                path_len: path.len() - 1,
+            };
+            if callback(&entry, context) != 0 {
+                return NTFS_STOPPED;
+            }
+        }
+        NTFS_OK
+    })
+}
+
+// Only for the tests, to not need a volume.
+#[cfg(test)]
+fn into_handle(mft: Mft) -> *mut NtfsMft {
+    Box::into_raw(Box::new(NtfsMft(mft)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::c_void;
+
+    use super::*;
+    use crate::mft::memory_tests::{make_mft, make_tree};
+
+    extern "C" fn collect(entry: *const NtfsFileEntry, context: *mut c_void) -> i32 {
+        let entry = unsafe { &*entry };
+        let paths = unsafe { &mut *(context as *mut Vec<(u64, String)>) };
+        let path = unsafe { std::slice::from_raw_parts(entry.path, entry.path_len) };
+        paths.push((entry.number, String::from_utf16(path).unwrap()));
+        0
+    }
+
+    #[test]
+    fn iterate_files() {
+        let mft = into_handle(make_mft(make_tree(33)));
+
+        unsafe {
+            assert_eq!(ntfs_mft_max_record(mft), 33);
+
+            let mut paths: Vec<(u64, String)> = Vec::new();
+            let context = &mut paths as *mut _ as *mut c_void;
+            let status = ntfs_mft_iterate(mft, NTFS_ITERATE_PATHS, Some(collect), context);
+            assert_eq!(status, NTFS_OK);
+            let file = paths.iter().find(|(number, _)| *number == 31).unwrap();
+            assert!(file.1.ends_with("file") && file.1.contains("dir"));
+
+            // Asking for the length first.
+            let mut len = 0;
+            let status = ntfs_mft_get_path(mft, 31, ptr::null_mut(), &mut len);
+            assert_eq!(status, NTFS_BUFFER_TOO_SMALL);
+            let mut buffer = vec![0u16; len];
+            assert_eq!(
+                ntfs_mft_get_path(mft, 31, buffer.as_mut_ptr(), &mut len),
+                NTFS_OK
+            );
+            assert_eq!(String::from_utf16(&buffer[..len - 1]).unwrap(), file.1);
+
+            assert_eq!(
+                ntfs_mft_get_path(mft, 40, buffer.as_mut_ptr(), &mut len),
+                NTFS_INVALID_ARGUMENT
+            );
+            let mut len = 0;
+            ntfs_last_error(ptr::null_mut(), &mut len);
+            assert!(len > 1);
+
+            ntfs_mft_free(mft);
+        }
+    }
+}
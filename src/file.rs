@@ -2,7 +2,14 @@
 // This project is dual licensed under the Apache License 2.0 and the MIT license.
 // See the LICENSE files in the project root for details.
 
-use crate::{api::*, attribute::NtfsAttribute, mft::Mft};
+use std::mem::size_of;
+
+use crate::{
+    api::*,
+    attribute::NtfsAttribute,
+    errors::{NtfsReaderError, NtfsReaderResult, ParseError},
+    mft::Mft,
+};
 
 pub struct NtfsFile<'a> {
     pub number: u64,
@@ -11,14 +18,54 @@ pub struct NtfsFile<'a> {
 }
 
 impl<'a> NtfsFile<'a> {
-    pub fn new(number: u64, data: &'a [u8]) -> Self {
-        unsafe {
-            let header = &*(data.as_ptr() as *const NtfsFileRecordHeader);
-            NtfsFile {
-                number,
-                header,
-                data,
-            }
+    // Checks that the header and the attribute offsets fit in `data`.
+    pub fn new(number: u64, data: &'a [u8]) -> NtfsReaderResult<Self> {
+        let corrupt = |source| NtfsReaderError::CorruptRecord {
+            record: number,
+            attribute_type: None,
+            offset: 0,
+            source,
+        };
+
+        if data.len() < size_of::<NtfsFileRecordHeader>() {
+            return Err(corrupt(ParseError::OutOfBounds {
+                offset: 0,
+                len: size_of::<NtfsFileRecordHeader>(),
+            }));
+        }
+        if &data[0..4] != FILE_RECORD_SIGNATURE {
+            return Err(corrupt(ParseError::BadSignature));
+        }
+
+        let file = Self::from_header(number, data);
+        let used_size = file.header.used_size() as usize;
+        if used_size > data.len() || file.header.attributes_offset() as usize > used_size {
+            return Err(corrupt(ParseError::OutOfBounds {
+                offset: 0,
+                len: used_size,
+            }));
+        }
+
+        Ok(file)
+    }
+
+    /// # Safety
+    /// `data` must be at least as big as the record header.
+    pub unsafe fn new_unchecked(number: u64, data: &'a [u8]) -> Self {
+        let header = &*(data.as_ptr() as *const NtfsFileRecordHeader);
+        NtfsFile {
+            number,
+            header,
+            data,
+        }
+    }
+
+    // Panics if the header doesn't fit.
+    pub(crate) fn from_header(number: u64, data: &'a [u8]) -> Self {
+        NtfsFile {
+            number,
+            header: NtfsFileRecordHeader::ref_from_prefix(data).unwrap(),
+            data,
         }
     }
 
@@ -26,21 +73,28 @@ impl<'a> NtfsFile<'a> {
         self.number
     }
 
-    pub fn is_valid(data: &[u8]) -> bool {
-        let header = unsafe { &*(data.as_ptr() as *const NtfsFileRecordHeader) };
+    pub fn reference(&self) -> u64 {
+        file_reference(self.number, self.header.sequence_value())
+    }
+
+    // The update sequence array has an entry for each sector of the record.
+    pub fn is_valid(data: &[u8], sector_size: usize) -> bool {
+        let Some(header) = NtfsFileRecordHeader::ref_from_prefix(data) else {
+            return false;
+        };
         if &header.signature != FILE_RECORD_SIGNATURE {
             return false;
         }
 
-        if header.update_sequence_length == 0 {
+        if header.update_sequence_length() == 0 {
             return false;
         }
 
         let usa_end =
-            header.update_sequence_offset as usize + header.update_sequence_length as usize * 2;
+            header.update_sequence_offset() as usize + header.update_sequence_length() as usize * 2;
 
-        let usa_num = header.update_sequence_length as usize - 1;
-        let sector_num = data.len() / SECTOR_SIZE;
+        let usa_num = header.update_sequence_length() as usize - 1;
+        let sector_num = data.len() / sector_size;
 
         if usa_end > data.len() || usa_num > sector_num {
             return false;
@@ -53,109 +107,108 @@ impl<'a> NtfsFile<'a> {
     where
         F: FnMut(&NtfsAttribute),
     {
-        let mut offset = self.header.attributes_offset as usize;
-        loop {
-            if offset >= self.header.used_size as usize {
+        let mut offset = self.header.attributes_offset() as usize;
+        while offset < self.header.used_size() as usize {
+            let Some(att) = self.data.get(offset..).and_then(NtfsAttribute::new) else {
                 break;
-            }
-
-            let att_type = u32::from_le_bytes(self.data[offset..offset + 4].try_into().unwrap());
-            if att_type == NtfsAttributeType::End as u32 {
+            };
+            if att.header.type_id() == NtfsAttributeType::End as u32 || att.header.length() == 0 {
                 break;
             }
 
-            let att = NtfsAttribute::new(&self.data[offset..]);
             f(&att);
 
-            offset += att.header.length as usize;
+            offset += att.header.length() as usize;
         }
     }
 
-    pub fn get_attribute(&self, attribute_type: NtfsAttributeType) -> Option<NtfsAttribute> {
-        let mut offset = self.header.attributes_offset as usize;
+    pub fn get_attribute(&self, attribute_type: NtfsAttributeType) -> Option<NtfsAttribute<'a>> {
+        let data = self.data;
+        let mut offset = self.header.attributes_offset() as usize;
 
-        loop {
-            if offset >= self.header.used_size as usize {
-                break;
-            }
-            let att = NtfsAttribute::new(&self.data[offset..]);
-            if att.header.type_id == NtfsAttributeType::End as u32 {
+        while offset < self.header.used_size() as usize {
+            let att = NtfsAttribute::new(data.get(offset..)?)?;
+            if att.header.type_id() == NtfsAttributeType::End as u32 || att.header.length() == 0 {
                 break;
             }
-            if att.header.type_id == attribute_type as u32 {
-                return Some(NtfsAttribute::new(&self.data[offset..]));
+            if att.header.type_id() == attribute_type as u32 {
+                return Some(att);
             }
 
-            offset += att.header.length as usize;
+            offset += att.header.length() as usize;
         }
         None
     }
 
     pub fn get_best_file_name(&self, mft: &Mft) -> Option<NtfsFileName> {
-        let mut offset = self.header.attributes_offset as usize;
+        self.get_best_file_name_ref(mft).copied()
+    }
+
+    // Same as `get_best_file_name`, but borrows the name from the record data.
+    pub fn get_best_file_name_ref<'m>(&self, mft: &'m Mft) -> Option<&'m NtfsFileName>
+    where
+        'a: 'm,
+    {
+        let data = self.data;
+        let mut offset = self.header.attributes_offset() as usize;
         let mut best = None;
 
-        loop {
-            if offset >= self.header.used_size as usize {
+        while offset < self.header.used_size() as usize {
+            let Some(att) = data.get(offset..).and_then(NtfsAttribute::new) else {
                 break;
-            }
-            let att = NtfsAttribute::new(&self.data[offset..]);
-            if att.header.type_id == NtfsAttributeType::End as u32 {
+            };
+            if att.header.type_id() == NtfsAttributeType::End as u32 || att.header.length() == 0 {
                 break;
             }
 
-            if att.header.type_id == NtfsAttributeType::FileName as u32 {
-                let name = att.as_name();
-
+            if let Some(name) = att.as_name() {
                 // Ignore junctions
                 if !name.is_reparse_point() {
-                    if name.header.namespace == NtfsFileNamespace::Win32 as u8
-                        || name.header.namespace == NtfsFileNamespace::Win32AndDos as u8
+                    if name.header.namespace() == NtfsFileNamespace::Win32 as u8
+                        || name.header.namespace() == NtfsFileNamespace::Win32AndDos as u8
                     {
-                        return Some(name.clone());
+                        return Some(name);
                     } else {
-                        best = Some(name.clone());
+                        best = Some(name);
                     }
                 }
             }
 
-            if att.header.type_id == NtfsAttributeType::AttributeList as u32 {
-                let header = unsafe {
-                    &*(self.data[offset..].as_ptr() as *const NtfsResidentAttributeHeader)
-                };
-
-                let att_data = &self.data[offset + header.value_offset as usize..];
+            if att.header.type_id() == NtfsAttributeType::AttributeList as u32 {
+                let att_data = att.get_resident();
 
                 let mut att_offset = 0;
-                while att_offset < header.value_length as usize {
-                    let entry = unsafe {
-                        &*(att_data[att_offset..].as_ptr() as *const NtfsAttributeListEntry)
-                    };
-                    if entry.type_id == NtfsAttributeType::FileName as u32 {
+                while let Some(entry) = att_data
+                    .get(att_offset..)
+                    .and_then(NtfsAttributeListEntry::ref_from_prefix)
+                {
+                    if entry.length() == 0 {
+                        break;
+                    }
+                    if entry.type_id() == NtfsAttributeType::FileName as u32 {
                         let rec = mft.get_record(entry.reference())?;
                         let att = rec.get_attribute(NtfsAttributeType::FileName)?;
-                        let name = att.as_name();
 
                         // Ignore junctions
-                        if !name.is_reparse_point() {
-                            if name.header.namespace == NtfsFileNamespace::Win32 as u8
-                                || name.header.namespace == NtfsFileNamespace::Win32AndDos as u8
+                        if let Some(name) = att.as_name().filter(|name| !name.is_reparse_point()) {
+                            if name.header.namespace() == NtfsFileNamespace::Win32 as u8
+                                || name.header.namespace() == NtfsFileNamespace::Win32AndDos as u8
                             {
-                                return Some(name.clone());
+                                return Some(name);
                             } else {
-                                best = Some(name.clone());
+                                best = Some(name);
                                 break;
                             }
                         }
                     }
 
-                    att_offset += entry.length as usize;
+                    att_offset += entry.length() as usize;
                     // Make sure the offset is aligned to 8 bytes
                     att_offset += (8 - (att_offset % 8)) % 8;
                 }
             }
 
-            offset += att.header.length as usize;
+            offset += att.header.length() as usize;
         }
 
         best
@@ -163,18 +216,81 @@ impl<'a> NtfsFile<'a> {
 
     // This cannot read nonresident data!
     pub fn read_data(&self) -> Option<&[u8]> {
-        if let Some(att) = self.get_attribute(NtfsAttributeType::Data) {
-            assert!(att.header.is_non_resident != 0);
-            return Some(att.as_resident_data());
+        let att = self.get_attribute(NtfsAttributeType::Data)?;
+        if att.header.is_non_resident() != 0 {
+            return None;
         }
-        None
+        att.as_resident_data()
+    }
+
+    // The key of the security descriptor, see `SecurityDescriptors`.
+    // None for the volumes formatted before NTFS 3.0, which store it in the record.
+    pub fn security_id(&self) -> Option<u32> {
+        self.get_attribute(NtfsAttributeType::StandardInformation)?
+            .as_standard_info_extended()
+            .map(|stdinfo| stdinfo.security_id())
     }
 
     pub fn is_used(&self) -> bool {
-        return self.header.flags & NtfsFileFlags::InUse as u16 != 0;
+        self.header.flags() & NtfsFileFlags::InUse as u16 != 0
     }
 
     pub fn is_directory(&self) -> bool {
-        return self.header.flags & NtfsFileFlags::IsDirectory as u16 != 0;
+        self.header.flags() & NtfsFileFlags::IsDirectory as u16 != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NtfsFile;
+    use crate::api::NtfsAttributeType;
+
+    #[test]
+    fn new_checks_the_header() {
+        let mut data = vec![0u8; 1024];
+        assert!(NtfsFile::new(1, &data[..16]).is_err());
+        assert!(NtfsFile::new(1, &data).is_err());
+
+        data[0..4].copy_from_slice(b"FILE");
+        data[20..22].copy_from_slice(&56u16.to_le_bytes());
+        data[24..28].copy_from_slice(&2048u32.to_le_bytes());
+        assert!(NtfsFile::new(1, &data).is_err());
+
+        data[24..28].copy_from_slice(&64u32.to_le_bytes());
+        assert_eq!(NtfsFile::new(1, &data).unwrap().number(), 1);
+    }
+
+    #[test]
+    fn attributes_stay_in_the_record() {
+        let mut data = vec![0u8; 1024];
+        data[0..4].copy_from_slice(b"FILE");
+        data[20..22].copy_from_slice(&56u16.to_le_bytes());
+        data[24..28].copy_from_slice(&1024u32.to_le_bytes());
+
+        // A $FILE_NAME with a zero length, and a value past the end of the record.
+        data[56..60].copy_from_slice(&(NtfsAttributeType::FileName as u32).to_le_bytes());
+        data[72..76].copy_from_slice(&4096u32.to_le_bytes());
+        data[76..78].copy_from_slice(&24u16.to_le_bytes());
+
+        let file = NtfsFile::new(1, &data).unwrap();
+        let mut count = 0;
+        file.attributes(|_| count += 1);
+        assert_eq!(count, 0);
+
+        data[60..64].copy_from_slice(&968u32.to_le_bytes());
+        let file = NtfsFile::new(1, &data).unwrap();
+        file.attributes(|_| count += 1);
+        assert_eq!(count, 1);
+
+        let att = file.get_attribute(NtfsAttributeType::FileName).unwrap();
+        assert!(att.get_resident().is_empty());
+        assert!(att.as_name().is_none());
+        assert!(att.as_standard_info().is_none());
+
+        // The attribute header doesn't fit.
+        assert!(NtfsFile::new(1, &data[..60]).is_err());
+        data[24..28].copy_from_slice(&64u32.to_le_bytes());
+        let file = NtfsFile::new(1, &data[..64]).unwrap();
+        assert!(file.get_attribute(NtfsAttributeType::FileName).is_none());
     }
 }
@@ -3,56 +3,730 @@
 // See the LICENSE files in the project root for details.
 
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    cell::RefCell,
+    char::DecodeUtf16Error,
+    collections::{BTreeMap, HashMap},
+    ffi::{OsStr, OsString},
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    num::NonZeroUsize,
+    ops::Range,
     path::{Path, PathBuf},
 };
 
 use time::OffsetDateTime;
 
 use crate::{
-    api::{ntfs_to_unix_time, NtfsAttributeType, ROOT_RECORD},
+    api::{
+        reference_number, reference_sequence, try_ntfs_to_unix_time, wide_to_os_string,
+        NtfsAttributeType, ROOT_RECORD,
+    },
+    errors::NtfsReaderResult,
     file::NtfsFile,
     mft::Mft,
+    volume::PathFormat,
 };
 
-pub trait FileInfoCache<'a> {
-    fn get(&self, number: u64) -> Option<&Path>;
-    fn insert(&mut self, number: u64, path: PathBuf);
+// The caches are keyed by file reference (record number and sequence number),
+// so entries of records that were deleted and reused are not returned.
+pub trait FileInfoCache {
+    fn get(&self, reference: u64) -> Option<Cow<'_, Path>>;
+    fn insert(&mut self, reference: u64, path: PathBuf);
+    fn len(&self) -> usize;
+    fn clear(&mut self);
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn get_or_insert_with<F>(&mut self, reference: u64, f: F) -> PathBuf
+    where
+        F: FnOnce() -> PathBuf,
+    {
+        if let Some(path) = self.get(reference) {
+            return path.into_owned();
+        }
+
+        let path = f();
+        self.insert(reference, path.clone());
+        path
+    }
+
+    // Called while building paths, for caches that can store the single components.
+    fn insert_component(&mut self, reference: u64, _parent: u64, _name: &OsStr, path: &Path) {
+        self.insert(reference, path.to_path_buf());
+    }
 }
 
 #[derive(Default)]
-pub struct HashMapCache(pub HashMap<u64, PathBuf>);
-impl<'a> FileInfoCache<'a> for HashMapCache {
-    fn get(&self, number: u64) -> Option<&Path> {
-        if let Some(p) = self.0.get(&number) {
-            Some(&p)
-        } else {
-            None
+pub struct HashMapCache(pub HashMap<u64, (u16, PathBuf)>);
+impl FileInfoCache for HashMapCache {
+    fn get(&self, reference: u64) -> Option<Cow<'_, Path>> {
+        let (sequence, path) = self.0.get(&reference_number(reference))?;
+        (*sequence == reference_sequence(reference)).then_some(Cow::Borrowed(path.as_path()))
+    }
+
+    fn insert(&mut self, reference: u64, path: PathBuf) {
+        self.0.insert(
+            reference_number(reference),
+            (reference_sequence(reference), path),
+        );
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+// Each record takes only 4 bytes until it is cached, and cached paths are stored
+// without spare capacity, so sparse volumes don't pay for a huge vector of paths.
+#[derive(Default)]
+pub struct VecCache {
+    // 0 if the record is not cached, otherwise the index in `entries` + 1.
+    slots: Vec<u32>,
+    entries: Vec<(u16, Box<Path>)>,
+}
+
+impl VecCache {
+    // Use `Mft::max_record` to avoid growing the slots while iterating.
+    pub fn with_capacity(records: usize) -> Self {
+        VecCache {
+            slots: vec![0; records],
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl FileInfoCache for VecCache {
+    fn get(&self, reference: u64) -> Option<Cow<'_, Path>> {
+        let slot = *self.slots.get(reference_number(reference) as usize)?;
+        let (sequence, path) = self.entries.get(slot.checked_sub(1)? as usize)?;
+        (*sequence == reference_sequence(reference)).then_some(Cow::Borrowed(path))
+    }
+
+    fn insert(&mut self, reference: u64, path: PathBuf) {
+        let number = reference_number(reference) as usize;
+        let entry = (reference_sequence(reference), path.into_boxed_path());
+
+        if self.slots.len() <= number {
+            self.slots.resize(number + 1, 0);
+        }
+
+        match self.slots[number] {
+            0 => {
+                self.entries.push(entry);
+                self.slots[number] = self.entries.len() as u32;
+            }
+            slot => self.entries[slot as usize - 1] = entry,
         }
     }
 
-    fn insert(&mut self, number: u64, path: PathBuf) {
-        self.0.insert(number, path);
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn clear(&mut self) {
+        self.slots.clear();
+        self.entries.clear();
     }
 }
 
+struct LruCacheEntry {
+    sequence: u16,
+    path: PathBuf,
+    last_used: u64,
+}
+
 #[derive(Default)]
-pub struct VecCache(pub Vec<PathBuf>);
-impl<'a> FileInfoCache<'a> for VecCache {
-    fn get(&self, number: u64) -> Option<&Path> {
-        if self.0.len() > number as usize {
-            Some(&self.0[number as usize])
+struct LruCacheInner {
+    entries: HashMap<u64, LruCacheEntry>,
+    order: BTreeMap<u64, u64>,
+    tick: u64,
+}
+
+impl LruCacheInner {
+    fn touch(&mut self, number: u64) -> Option<&mut LruCacheEntry> {
+        self.tick += 1;
+        let entry = self.entries.get_mut(&number)?;
+        self.order.remove(&entry.last_used);
+        self.order.insert(self.tick, number);
+        entry.last_used = self.tick;
+        Some(entry)
+    }
+}
+
+// Keeps at most `capacity` paths, evicting the least recently used ones.
+pub struct LruCache {
+    capacity: NonZeroUsize,
+    inner: RefCell<LruCacheInner>,
+}
+
+impl LruCache {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        LruCache {
+            capacity,
+            inner: RefCell::new(LruCacheInner::default()),
+        }
+    }
+
+    pub fn capacity(&self) -> NonZeroUsize {
+        self.capacity
+    }
+}
+
+impl FileInfoCache for LruCache {
+    fn get(&self, reference: u64) -> Option<Cow<'_, Path>> {
+        let mut inner = self.inner.borrow_mut();
+        let entry = inner.touch(reference_number(reference))?;
+        (entry.sequence == reference_sequence(reference)).then(|| Cow::Owned(entry.path.clone()))
+    }
+
+    fn insert(&mut self, reference: u64, path: PathBuf) {
+        let number = reference_number(reference);
+        let sequence = reference_sequence(reference);
+        let inner = self.inner.get_mut();
+
+        if let Some(entry) = inner.touch(number) {
+            entry.sequence = sequence;
+            entry.path = path;
+            return;
+        }
+
+        if inner.entries.len() >= self.capacity.get() {
+            if let Some((_, oldest)) = inner.order.pop_first() {
+                inner.entries.remove(&oldest);
+            }
+        }
+
+        let last_used = inner.tick;
+        inner.order.insert(last_used, number);
+        inner.entries.insert(
+            number,
+            LruCacheEntry {
+                sequence,
+                path,
+                last_used,
+            },
+        );
+    }
+
+    fn len(&self) -> usize {
+        self.inner.borrow().entries.len()
+    }
+
+    fn clear(&mut self) {
+        *self.inner.get_mut() = LruCacheInner::default();
+    }
+}
+
+enum InternedEntry {
+    Path(PathBuf),
+    Component { parent: u64, name: Range<usize> },
+}
+
+// Stores only the parent and the name of each record (all the names share the same buffer),
+// the full paths are built when requested.
+#[derive(Default)]
+pub struct InternedCache {
+    entries: Vec<Option<(u16, InternedEntry)>>,
+    // The encoded bytes of all the names, only ever sliced at the boundaries of a name.
+    names: Vec<u8>,
+}
+
+impl InternedCache {
+    fn set(&mut self, reference: u64, entry: InternedEntry) {
+        let number = reference_number(reference) as usize;
+        if self.entries.len() <= number {
+            self.entries.resize_with(number + 1, || None);
+        }
+        self.entries[number] = Some((reference_sequence(reference), entry));
+    }
+
+    fn entry(&self, reference: u64) -> Option<&InternedEntry> {
+        let (sequence, entry) = self
+            .entries
+            .get(reference_number(reference) as usize)?
+            .as_ref()?;
+        (*sequence == reference_sequence(reference)).then_some(entry)
+    }
+}
+
+impl FileInfoCache for InternedCache {
+    fn get(&self, reference: u64) -> Option<Cow<'_, Path>> {
+        let mut names = Vec::new();
+        let mut current = reference;
+
+        loop {
+            match self.entry(current)? {
+                InternedEntry::Path(path) => {
+                    if names.is_empty() {
+                        return Some(Cow::Borrowed(path));
+                    }
+
+                    let mut path = path.clone();
+                    for name in names.iter().rev() {
+                        path.push(name);
+                    }
+                    return Some(Cow::Owned(path));
+                }
+                InternedEntry::Component { parent, name } => {
+                    // Guard against loops.
+                    if names.len() > self.entries.len() {
+                        return None;
+                    }
+
+                    names.push(unsafe {
+                        OsStr::from_encoded_bytes_unchecked(&self.names[name.clone()])
+                    });
+                    current = *parent;
+                }
+            }
+        }
+    }
+
+    fn insert(&mut self, reference: u64, path: PathBuf) {
+        self.set(reference, InternedEntry::Path(path));
+    }
+
+    fn insert_component(&mut self, reference: u64, parent: u64, name: &OsStr, path: &Path) {
+        // Without the parent we cannot rebuild the path, so store it whole.
+        if self.entry(parent).is_none() {
+            self.insert(reference, path.to_path_buf());
+            return;
+        }
+
+        let start = self.names.len();
+        self.names.extend_from_slice(name.as_encoded_bytes());
+        let name = start..self.names.len();
+        self.set(reference, InternedEntry::Component { parent, name });
+    }
+
+    fn len(&self) -> usize {
+        self.entries.iter().filter(|e| e.is_some()).count()
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.names.clear();
+    }
+}
+
+const PERSISTENT_CACHE_MAGIC: &[u8; 4] = b"NRPC";
+const PERSISTENT_CACHE_VERSION: u32 = 2;
+// How the paths are written, as they are on the platform that saved the cache (so they are not
+// lost when they are not valid Unicode).
+#[cfg(windows)]
+const PERSISTENT_CACHE_ENCODING: u8 = 0; // UTF-16
+#[cfg(not(windows))]
+const PERSISTENT_CACHE_ENCODING: u8 = 1; // Bytes
+
+// A cache that can be saved to disk and loaded back on the next scan.
+// Records that were deleted or reused since the cache was saved are discarded when loading,
+// `usn` can be used to remember up to where the journal was read and apply the changes since then.
+#[derive(Default)]
+pub struct PersistentCache {
+    pub usn: i64,
+    entries: HashMap<u64, (u16, PathBuf)>,
+}
+
+impl PersistentCache {
+    pub fn remove(&mut self, number: u64) -> Option<PathBuf> {
+        self.entries.remove(&number).map(|(_, path)| path)
+    }
+
+    pub fn save<W: Write>(&self, writer: &mut W) -> NtfsReaderResult<()> {
+        let entries: Vec<_> = self
+            .entries
+            .iter()
+            .map(|(number, (sequence, path))| (*number, *sequence, path_to_bytes(path)))
+            .filter(|(_, _, path)| u32::try_from(path.len()).is_ok())
+            .collect();
+
+        writer.write_all(PERSISTENT_CACHE_MAGIC)?;
+        writer.write_all(&PERSISTENT_CACHE_VERSION.to_le_bytes())?;
+        writer.write_all(&[PERSISTENT_CACHE_ENCODING])?;
+        writer.write_all(&self.usn.to_le_bytes())?;
+        writer.write_all(&(entries.len() as u64).to_le_bytes())?;
+
+        for (number, sequence, path) in entries {
+            writer.write_all(&number.to_le_bytes())?;
+            writer.write_all(&sequence.to_le_bytes())?;
+            writer.write_all(&(path.len() as u32).to_le_bytes())?;
+            writer.write_all(&path)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn load<R: Read>(mft: &Mft, reader: &mut R) -> NtfsReaderResult<Self> {
+        fn read_array<R: Read, const N: usize>(reader: &mut R) -> io::Result<[u8; N]> {
+            let mut buf = [0u8; N];
+            reader.read_exact(&mut buf)?;
+            Ok(buf)
+        }
+
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        if &read_array::<_, 4>(reader)? != PERSISTENT_CACHE_MAGIC {
+            return Err(invalid("not a path cache").into());
+        }
+        if u32::from_le_bytes(read_array(reader)?) != PERSISTENT_CACHE_VERSION {
+            return Err(invalid("unsupported path cache version").into());
+        }
+        if read_array::<_, 1>(reader)? != [PERSISTENT_CACHE_ENCODING] {
+            return Err(invalid("path cache saved on another platform").into());
+        }
+
+        let usn = i64::from_le_bytes(read_array(reader)?);
+        let count = u64::from_le_bytes(read_array(reader)?);
+        let mut entries = HashMap::new();
+
+        for _ in 0..count {
+            let number = u64::from_le_bytes(read_array(reader)?);
+            let sequence = u16::from_le_bytes(read_array(reader)?);
+            let len = u32::from_le_bytes(read_array(reader)?) as u64;
+
+            // Grown with what is actually read, the length can't be trusted.
+            let mut path = Vec::new();
+            reader.by_ref().take(len).read_to_end(&mut path)?;
+            if path.len() as u64 != len {
+                return Err(invalid("truncated path cache").into());
+            }
+            let path = path_from_bytes(path).ok_or_else(|| invalid("invalid path"))?;
+
+            // Skip records that were deleted or reused since the cache was saved.
+            let valid = number < mft.max_record
+                && mft
+                    .get_record(number)
+                    .is_some_and(|file| file.is_used() && file.header.sequence_value() == sequence);
+            if valid {
+                entries.insert(number, (sequence, path));
+            }
+        }
+
+        Ok(PersistentCache { usn, entries })
+    }
+
+    pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> NtfsReaderResult<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.save(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn load_from_file<P: AsRef<Path>>(mft: &Mft, path: P) -> NtfsReaderResult<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        Self::load(mft, &mut reader)
+    }
+}
+
+#[cfg(windows)]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    use std::os::windows::ffi::OsStrExt;
+    path.as_os_str()
+        .encode_wide()
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+#[cfg(windows)]
+fn path_from_bytes(bytes: Vec<u8>) -> Option<PathBuf> {
+    if !bytes.len().is_multiple_of(2) {
+        return None;
+    }
+    let wide: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .collect();
+    Some(wide_to_os_string(&wide).into())
+}
+
+#[cfg(not(windows))]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(windows))]
+fn path_from_bytes(bytes: Vec<u8>) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+    Some(OsString::from_vec(bytes).into())
+}
+
+impl FileInfoCache for PersistentCache {
+    fn get(&self, reference: u64) -> Option<Cow<'_, Path>> {
+        let (sequence, path) = self.entries.get(&reference_number(reference))?;
+        (*sequence == reference_sequence(reference)).then_some(Cow::Borrowed(path.as_path()))
+    }
+
+    fn insert(&mut self, reference: u64, path: PathBuf) {
+        self.entries.insert(
+            reference_number(reference),
+            (reference_sequence(reference), path),
+        );
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileInfoOptions {
+    pub path: bool,
+    pub times: bool,
+    pub size: bool,
+    pub streams: bool,
+    pub path_format: PathFormat,
+}
+
+impl Default for FileInfoOptions {
+    fn default() -> Self {
+        FileInfoOptions {
+            path: true,
+            times: true,
+            size: true,
+            streams: false,
+            path_format: PathFormat::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FileInfoBuilder {
+    pub options: FileInfoOptions,
+}
+
+impl FileInfoBuilder {
+    // Computing the path walks the whole parent chain, disable it if only the name is needed.
+    pub fn with_path(mut self, enabled: bool) -> Self {
+        self.options.path = enabled;
+        self
+    }
+
+    // Don't share a cache between builders with different formats.
+    pub fn with_path_format(mut self, format: PathFormat) -> Self {
+        self.options.path_format = format;
+        self
+    }
+
+    pub fn with_times(mut self, enabled: bool) -> Self {
+        self.options.times = enabled;
+        self
+    }
+
+    pub fn with_size(mut self, enabled: bool) -> Self {
+        self.options.size = enabled;
+        self
+    }
+
+    // List the named data streams (ADS) with their sizes.
+    pub fn with_streams(mut self, enabled: bool) -> Self {
+        self.options.streams = enabled;
+        self
+    }
+
+    pub fn build(&self, mft: &Mft, file: &NtfsFile) -> FileInfo {
+        let mut info = FileInfo::_new(file, &self.options);
+        if self.options.path {
+            info._compute_path(mft, file, self.options.path_format);
         } else {
-            None
+            info._compute_name(mft, file);
         }
+        info
     }
 
-    fn insert(&mut self, number: u64, path: PathBuf) {
-        if self.0.len() <= number as usize {
-            self.0.resize(number as usize + 1, PathBuf::new());
+    pub fn build_with_cache<C: FileInfoCache>(
+        &self,
+        mft: &Mft,
+        file: &NtfsFile,
+        cache: &mut C,
+    ) -> FileInfo {
+        let mut info = FileInfo::_new(file, &self.options);
+        if self.options.path {
+            info._compute_path_with_cache(mft, file, self.options.path_format, cache);
+        } else {
+            info._compute_name(mft, file);
         }
-        self.0[number as usize] = path;
+        info
     }
+
+    // Builds the info of all the files of the MFT with a shared cache, without collecting them.
+    // Stops at the first error of `f`, the records that can't be read are skipped.
+    pub fn for_each_file_info<F, E>(&self, mft: &Mft, mut f: F) -> Result<(), E>
+    where
+        F: FnMut(&NtfsFile, FileInfo) -> Result<(), E>,
+    {
+        let mut cache = VecCache::with_capacity(mft.max_record as usize);
+        mft.try_iterate_files(|file| match file {
+            Ok(file) => f(file, self.build_with_cache(mft, file, &mut cache)),
+            Err(_) => Ok(()),
+        })
+    }
+
+    // Fills `info` again, reusing the memory of its name, path and streams: scanning with the same
+    // `info` and `buffers` doesn't allocate per record once they have grown.
+    pub fn build_into(
+        &self,
+        mft: &Mft,
+        file: &NtfsFile,
+        info: &mut FileInfo,
+        buffers: &mut FileInfoBuffers,
+    ) {
+        info._fill(file, &self.options);
+        if self.options.path {
+            info._compute_path_into(mft, file, self.options.path_format, buffers);
+        } else if let Some(name) = file.get_best_file_name_ref(mft) {
+            info.name
+                .extend(char::decode_utf16(name.name_units()).map(lossy));
+        }
+    }
+
+    // Like `Mft::iterate_files`, with the info of each file filled by `build_into` (always the
+    // same `FileInfo`, copy out what has to be kept).
+    pub fn iterate<F>(&self, mft: &Mft, mut f: F)
+    where
+        F: FnMut(&NtfsFile, &FileInfo),
+    {
+        let mut info = FileInfo::empty();
+        let mut buffers = FileInfoBuffers::default();
+
+        mft.iterate_files(|file| {
+            self.build_into(mft, file, &mut info, &mut buffers);
+            f(file, &info);
+        });
+    }
+
+    // Builds the info of all the files on the volume.
+    // Names and parents are collected first, then each directory path is resolved only once.
+    pub fn build_all(&self, mft: &Mft) -> Vec<FileInfo> {
+        let mut infos = Vec::new();
+        let mut numbers = Vec::new();
+        let mut nodes: Vec<Option<(u64, OsString)>> = vec![None; mft.max_record as usize];
+
+        mft.iterate_files(|file| {
+            let mut info = FileInfo::_new(file, &self.options);
+            if let Some(name) = file.get_best_file_name(mft) {
+                info.name = name.to_string();
+                nodes[file.number as usize] = Some((name.parent_reference(), name.to_os_string()));
+            }
+            numbers.push(file.number);
+            infos.push(info);
+        });
+
+        if !self.options.path {
+            return infos;
+        }
+
+        let mut paths: Vec<Option<PathBuf>> = vec![None; nodes.len()];
+        if let Some(root) = paths.get_mut(ROOT_RECORD as usize) {
+            *root = Some(mft.volume.root(self.options.path_format));
+        }
+
+        for (info, number) in infos.iter_mut().zip(numbers) {
+            let (parent, name) = match &nodes[number as usize] {
+                Some((parent, name)) => (*parent, name.clone()),
+                None => continue,
+            };
+
+            match Self::resolve_directory(mft, parent, &mut nodes, &mut paths) {
+                Ok(parent_path) => info.path = parent_path.join(name),
+                Err(PathError::Stale) => info.stale_parent = true,
+                Err(PathError::Missing) => {}
+            }
+        }
+
+        infos
+    }
+
+    fn resolve_directory<'p>(
+        mft: &Mft,
+        reference: u64,
+        nodes: &mut [Option<(u64, OsString)>],
+        paths: &'p mut [Option<PathBuf>],
+    ) -> Result<&'p Path, PathError> {
+        let number = reference_number(reference);
+        let mut chain = Vec::new();
+        let mut current = reference;
+
+        let mut path = loop {
+            if !mft.is_reference_current(current) {
+                return Err(PathError::Stale);
+            }
+
+            let index = reference_number(current) as usize;
+            if let Some(path) = paths.get(index).ok_or(PathError::Missing)? {
+                break path.clone();
+            }
+
+            // Corrupted parent references could loop forever.
+            if chain.len() > nodes.len() {
+                return Err(PathError::Missing);
+            }
+
+            // System records are not iterated, look them up now.
+            if nodes[index].is_none() {
+                let name = mft.get_file_name(index as u64).ok_or(PathError::Missing)?;
+                nodes[index] = Some((name.parent_reference(), name.to_os_string()));
+            }
+
+            chain.push(index);
+            current = nodes[index].as_ref().ok_or(PathError::Missing)?.0;
+        };
+
+        for index in chain.iter().rev() {
+            path.push(&nodes[*index].as_ref().ok_or(PathError::Missing)?.1);
+            paths[*index] = Some(path.clone());
+        }
+
+        paths[number as usize].as_deref().ok_or(PathError::Missing)
+    }
+}
+
+// Like `String::from_utf16_lossy`.
+fn lossy(c: Result<char, DecodeUtf16Error>) -> char {
+    c.unwrap_or(char::REPLACEMENT_CHARACTER)
+}
+
+// Same as pushing `wide_to_os_string`, through `buffer` unless the name is not valid UTF-16.
+fn push_component(path: &mut PathBuf, wide: &[u16], buffer: &mut String) {
+    buffer.clear();
+    for c in char::decode_utf16(wide.iter().copied()) {
+        match c {
+            Ok(c) => buffer.push(c),
+            Err(_) => {
+                path.push(wide_to_os_string(wide));
+                return;
+            }
+        }
+    }
+    path.push(buffer.as_str());
+}
+
+// Reused by `FileInfoBuilder::build_into` between the calls (with the same `Mft`), to resolve
+// the paths without allocating once they are big enough.
+#[derive(Default)]
+pub struct FileInfoBuffers {
+    // The names of the parents, from the file up, each ending at the offset in `ends`.
+    names: Vec<u16>,
+    ends: Vec<usize>,
+    component: String,
+    root: Option<(PathFormat, PathBuf)>,
+}
+
+enum PathError {
+    Missing,
+    // See `FileInfo::stale_parent`.
+    Stale,
 }
 
 pub struct FileInfo {
@@ -63,66 +737,120 @@ pub struct FileInfo {
     pub created: Option<OffsetDateTime>,
     pub accessed: Option<OffsetDateTime>,
     pub modified: Option<OffsetDateTime>,
+    // The raw FILETIME values, the times above are None when they are zero or out of range.
+    pub created_filetime: u64,
+    pub accessed_filetime: u64,
+    pub modified_filetime: u64,
+    pub streams: Vec<(String, u64)>,
+    // A directory in the path was deleted or reused by another file since the name was written
+    // (the sequence number of the parent reference doesn't match), the path is left empty.
+    pub stale_parent: bool,
 }
 
 impl FileInfo {
     pub fn new(mft: &Mft, file: &NtfsFile) -> Self {
-        let mut info = Self::_new(file);
-        info._compute_path(mft, file);
-        info
+        Self::builder().build(mft, file)
     }
 
-    pub fn with_cache<C: for<'a> FileInfoCache<'a>>(
-        mft: &Mft,
-        file: &NtfsFile,
-        cache: &mut C,
-    ) -> Self {
-        let mut info = Self::_new(file);
-        info._compute_path_with_cache(mft, file, cache);
+    pub fn with_cache<C: FileInfoCache>(mft: &Mft, file: &NtfsFile, cache: &mut C) -> Self {
+        Self::builder().build_with_cache(mft, file, cache)
+    }
+
+    pub fn builder() -> FileInfoBuilder {
+        FileInfoBuilder::default()
+    }
+
+    fn _new(file: &NtfsFile, options: &FileInfoOptions) -> Self {
+        let mut info = Self::empty();
+        info._fill(file, options);
         info
     }
 
-    fn _new(file: &NtfsFile) -> Self {
-        let mut accessed = None;
-        let mut created = None;
-        let mut modified = None;
-        let mut size = 0u64;
+    fn empty() -> Self {
+        FileInfo {
+            name: String::new(),
+            path: PathBuf::new(),
+            is_directory: false,
+            size: 0,
+            created: None,
+            accessed: None,
+            modified: None,
+            created_filetime: 0,
+            accessed_filetime: 0,
+            modified_filetime: 0,
+            streams: Vec::new(),
+            stale_parent: false,
+        }
+    }
+
+    // Resets all the fields, keeping the buffers of the name, the path and the streams.
+    fn _fill(&mut self, file: &NtfsFile, options: &FileInfoOptions) {
+        self.name.clear();
+        self.path.as_mut_os_string().clear();
+        self.is_directory = file.is_directory();
+        self.size = 0;
+        self.created = None;
+        self.accessed = None;
+        self.modified = None;
+        self.created_filetime = 0;
+        self.accessed_filetime = 0;
+        self.modified_filetime = 0;
+        self.stale_parent = false;
+        let mut streams = 0;
 
         file.attributes(|att| {
-            if att.header.type_id == NtfsAttributeType::StandardInformation as u32 {
-                let stdinfo = att.as_standard_info();
-
-                accessed = Some(ntfs_to_unix_time(stdinfo.access_time));
-                created = Some(ntfs_to_unix_time(stdinfo.creation_time));
-                modified = Some(ntfs_to_unix_time(stdinfo.modification_time));
+            if let Some(stdinfo) = att.as_standard_info().filter(|_| options.times) {
+                self.accessed = try_ntfs_to_unix_time(stdinfo.access_time());
+                self.created = try_ntfs_to_unix_time(stdinfo.creation_time());
+                self.modified = try_ntfs_to_unix_time(stdinfo.modification_time());
+                self.created_filetime = stdinfo.creation_time();
+                self.accessed_filetime = stdinfo.access_time();
+                self.modified_filetime = stdinfo.modification_time();
             }
 
-            if att.header.type_id == NtfsAttributeType::Data as u32 {
-                if att.header.is_non_resident == 0 {
-                    size = att.header_res.value_length as u64;
+            if att.header.type_id() == NtfsAttributeType::Data as u32 {
+                let att_size = if att.header.is_non_resident() == 0 {
+                    att.header_res.value_length() as u64
                 } else {
-                    size = att.header_nonres.data_size;
+                    att.header_nonres.data_size()
+                };
+
+                // Named data attributes are alternate streams.
+                if att.header.name_length() == 0 {
+                    if options.size {
+                        self.size = att_size;
+                    }
+                } else if options.streams {
+                    match self.streams.get_mut(streams) {
+                        Some((name, size)) => {
+                            name.clear();
+                            att.name_into(name);
+                            *size = att_size;
+                        }
+                        None => self.streams.push((att.name(), att_size)),
+                    }
+                    streams += 1;
                 }
             }
         });
 
-        FileInfo {
-            name: String::new(),
-            path: PathBuf::new(),
-            is_directory: file.is_directory(),
-            size,
-            created,
-            accessed,
-            modified,
+        self.streams.truncate(streams);
+    }
+
+    fn _compute_name(&mut self, mft: &Mft, file: &NtfsFile) {
+        if let Some(name) = file.get_best_file_name(mft) {
+            self.name = name.to_string();
         }
     }
 
-    fn _compute_path(&mut self, mft: &Mft, file: &NtfsFile) {
+    fn _compute_path(&mut self, mft: &Mft, file: &NtfsFile, format: PathFormat) {
         let mut next_parent;
+        let file_name;
 
         if let Some(name) = file.get_best_file_name(mft) {
             self.name = name.to_string();
-            next_parent = name.parent();
+            file_name = name.to_os_string();
+            next_parent = name.parent_reference();
         } else {
             //warn!("No name for file {}", file.number);
             return;
@@ -130,87 +858,352 @@ impl FileInfo {
 
         let mut components = Vec::new();
         loop {
-            if next_parent == ROOT_RECORD {
+            if reference_number(next_parent) == ROOT_RECORD {
                 break;
             }
 
-            let cur_file = mft.get_record(next_parent);
-            if cur_file.is_none() {
+            if !mft.is_reference_current(next_parent) {
+                self.stale_parent = true;
+                return;
+            }
+
+            // Corrupted parent references could loop forever.
+            if components.len() as u64 > mft.max_record {
                 return;
             }
-            let cur_file = cur_file.unwrap();
 
-            if let Some(cur_name_att) = cur_file.get_best_file_name(mft) {
-                let cur_name = cur_name_att.to_string();
-                components.push((cur_file.number(), PathBuf::from(cur_name)));
-                next_parent = cur_name_att.parent();
+            if let Some(cur_name_att) = mft.get_file_name(reference_number(next_parent)) {
+                components.push((next_parent, cur_name_att.to_os_string()));
+                next_parent = cur_name_att.parent_reference();
             } else {
                 return;
             }
         }
 
-        let mut path = mft.volume.path.clone();
+        let mut path = mft.volume.root(format);
         for (_, comp) in components.iter().rev() {
             path.push(comp);
         }
-        path.push(&self.name);
+        path.push(file_name);
 
         self.path = path;
     }
 
-    fn _compute_path_with_cache<C: for<'a> FileInfoCache<'a>>(
+    fn _compute_path_with_cache<C: FileInfoCache>(
         &mut self,
         mft: &Mft,
         file: &NtfsFile,
+        format: PathFormat,
         cache: &mut C,
     ) {
         let mut next_parent;
+        let file_name;
 
         if let Some(name) = file.get_best_file_name(mft) {
             self.name = name.to_string();
-            next_parent = name.parent();
+            file_name = name.to_os_string();
+            next_parent = name.parent_reference();
         } else {
             return;
         }
 
+        // Already resolved (e.g. loaded from a previous scan).
+        if let Some(path) = cache.get(file.reference()) {
+            self.path = path.into_owned();
+            return;
+        }
+
+        let file_parent = next_parent;
         let mut components = Vec::new();
         let mut cached_path = None;
         loop {
-            if next_parent == ROOT_RECORD {
+            if reference_number(next_parent) == ROOT_RECORD {
                 break;
             }
 
             // Cache hit?
             if let Some(cur_path) = cache.get(next_parent) {
-                cached_path = Some(cur_path);
+                cached_path = Some(cur_path.into_owned());
                 break;
             }
 
-            let cur_file = mft.get_record(next_parent);
-            if cur_file.is_none() {
+            if !mft.is_reference_current(next_parent) {
+                self.stale_parent = true;
+                return;
+            }
+
+            // Corrupted parent references could loop forever.
+            if components.len() as u64 > mft.max_record {
                 return;
             }
-            let cur_file = cur_file.unwrap();
 
-            if let Some(cur_name_att) = cur_file.get_best_file_name(mft) {
-                let cur_name = cur_name_att.to_string();
-                components.push((cur_file.number(), PathBuf::from(cur_name)));
-                next_parent = cur_name_att.parent();
+            if let Some(cur_name_att) = mft.get_file_name(reference_number(next_parent)) {
+                let cur_name = cur_name_att.to_os_string();
+                components.push((next_parent, cur_name_att.parent_reference(), cur_name));
+                next_parent = cur_name_att.parent_reference();
             } else {
                 return;
             }
         }
 
-        let mut path = PathBuf::from(cached_path.unwrap_or(&mft.volume.path));
+        let mut path = match cached_path {
+            Some(cached_path) => cached_path,
+            None => mft.volume.root(format),
+        };
 
-        for (number, comp) in components.iter().rev() {
+        for (reference, parent, comp) in components.iter().rev() {
             path.push(comp);
-            cache.insert(*number, path.clone());
+            cache.insert_component(*reference, *parent, comp, &path);
         }
 
-        path.push(&self.name);
-        cache.insert(file.number, path.clone());
+        path.push(&file_name);
+        // Only the directories are looked up again (as parents), the files would only grow the
+        // cache, and the saved `PersistentCache` with it.
+        if file.is_directory() {
+            cache.insert_component(file.reference(), file_parent, &file_name, &path);
+        }
 
         self.path = path;
     }
+
+    // Same as `_compute_path`, in the buffers of `self` and `buffers`.
+    fn _compute_path_into(
+        &mut self,
+        mft: &Mft,
+        file: &NtfsFile,
+        format: PathFormat,
+        buffers: &mut FileInfoBuffers,
+    ) {
+        let Some(name) = file.get_best_file_name_ref(mft) else {
+            return;
+        };
+        self.name
+            .extend(char::decode_utf16(name.name_units()).map(lossy));
+
+        buffers.names.clear();
+        buffers.ends.clear();
+        buffers.names.extend(name.name_units());
+        buffers.ends.push(buffers.names.len());
+        let mut next_parent = name.parent_reference();
+        loop {
+            if reference_number(next_parent) == ROOT_RECORD {
+                break;
+            }
+
+            if !mft.is_reference_current(next_parent) {
+                self.stale_parent = true;
+                return;
+            }
+
+            // Corrupted parent references could loop forever.
+            if buffers.ends.len() as u64 > mft.max_record {
+                return;
+            }
+
+            let Some(parent) = mft.get_file_name(reference_number(next_parent)) else {
+                return;
+            };
+            buffers.names.extend(parent.name_units());
+            buffers.ends.push(buffers.names.len());
+            next_parent = parent.parent_reference();
+        }
+
+        let root = match &buffers.root {
+            Some((root_format, root)) if *root_format == format => root,
+            _ => &buffers.root.insert((format, mft.volume.root(format))).1,
+        };
+        self.path.as_mut_os_string().push(root);
+
+        for (i, end) in buffers.ends.iter().enumerate().rev() {
+            let start = if i == 0 { 0 } else { buffers.ends[i - 1] };
+            push_component(
+                &mut self.path,
+                &buffers.names[start..*end],
+                &mut buffers.component,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+    use std::path::{Path, PathBuf};
+
+    use super::{
+        FileInfo, FileInfoBuffers, FileInfoCache, HashMapCache, LruCache, PersistentCache, VecCache,
+    };
+    use crate::{
+        api::{file_reference, ROOT_RECORD},
+        errors::NtfsReaderError,
+        mft::memory_tests::{make_mft, make_named_record, make_record, make_tree},
+        volume::PathFormat,
+    };
+
+    #[test]
+    fn lru_cache_evicts_least_recently_used() {
+        let mut cache = LruCache::new(NonZeroUsize::new(2).unwrap());
+        cache.insert(1, "a".into());
+        cache.insert(2, "b".into());
+
+        // Touch 1 so that 2 becomes the oldest.
+        assert!(cache.get(1).is_some());
+        cache.insert(3, "c".into());
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(1).as_deref(), Some(Path::new("a")));
+        assert!(cache.get(2).is_none());
+        assert_eq!(cache.get(3).as_deref(), Some(Path::new("c")));
+    }
+
+    #[test]
+    fn cache_ignores_reused_records() {
+        let mut cache = HashMapCache::default();
+        cache.insert(file_reference(42, 1), "old".into());
+
+        assert!(cache.get(file_reference(42, 2)).is_none());
+        assert_eq!(
+            cache.get(file_reference(42, 1)).as_deref(),
+            Some(Path::new("old"))
+        );
+    }
+
+    #[test]
+    fn vec_cache_distinguishes_unknown_records() {
+        let mut cache = VecCache::default();
+        cache.insert(5, PathBuf::new());
+        cache.insert(10, "dir".into());
+
+        assert_eq!(cache.get(5).as_deref(), Some(Path::new("")));
+        assert!(cache.get(7).is_none());
+        assert!(cache.get(11).is_none());
+
+        cache.insert(10, "other".into());
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(10).as_deref(), Some(Path::new("other")));
+    }
+
+    #[test]
+    fn cache_directories_only() {
+        let mft = make_mft(make_tree(33));
+        let mut cache = HashMapCache::default();
+
+        let info =
+            FileInfo::builder().build_with_cache(&mft, &mft.get_record(31).unwrap(), &mut cache);
+        assert_eq!(info.path.file_name(), Some("file".as_ref()));
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get(file_reference(30, 2)).is_some());
+        assert!(cache.get(file_reference(31, 1)).is_none());
+    }
+
+    #[test]
+    fn persistent_cache_round_trip() {
+        let mft = make_mft(make_tree(33));
+
+        // Not valid Unicode, it must not be dropped.
+        #[cfg(windows)]
+        let name = std::os::windows::ffi::OsStringExt::from_wide(&[0x64, 0xD800]);
+        #[cfg(not(windows))]
+        let name = std::os::unix::ffi::OsStringExt::from_vec(vec![0x64, 0xFF]);
+        let path = PathBuf::from("dir").join::<std::ffi::OsString>(name);
+
+        let mut cache = PersistentCache {
+            usn: 42,
+            ..Default::default()
+        };
+        cache.insert(file_reference(30, 2), path.clone());
+        // Reused since.
+        cache.insert(file_reference(31, 3), "old".into());
+
+        let mut saved = Vec::new();
+        cache.save(&mut saved).unwrap();
+        let loaded = PersistentCache::load(&mft, &mut saved.as_slice()).unwrap();
+        assert_eq!(loaded.usn, 42);
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(
+            loaded.get(file_reference(30, 2)).as_deref(),
+            Some(path.as_path())
+        );
+    }
+
+    #[test]
+    fn persistent_cache_truncated() {
+        let mft = make_mft(make_tree(33));
+        let mut cache = PersistentCache::default();
+        cache.insert(file_reference(30, 2), "dir".into());
+
+        let mut saved = Vec::new();
+        cache.save(&mut saved).unwrap();
+        // The length of the path says more than what follows.
+        let len = saved.len() - 3 - 4;
+        saved[len..len + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        match PersistentCache::load(&mft, &mut saved.as_slice()) {
+            Err(NtfsReaderError::IOError(err)) => {
+                assert_eq!(err.kind(), std::io::ErrorKind::InvalidData)
+            }
+            _ => panic!("expected an I/O error"),
+        }
+    }
+
+    #[test]
+    fn parent_cycle() {
+        let mut records = vec![make_record(); 32];
+        records[ROOT_RECORD as usize] = make_named_record(5, file_reference(5, 5), ".");
+        records[30] = make_named_record(1, file_reference(31, 1), "a");
+        records[31] = make_named_record(1, file_reference(30, 1), "b");
+        let mft = make_mft(records);
+        let file = mft.get_record(30).unwrap();
+
+        let info = FileInfo::builder().build(&mft, &file);
+        assert_eq!(info.name, "a");
+        assert_eq!(info.path, PathBuf::new());
+
+        let mut cache = HashMapCache::default();
+        let info = FileInfo::builder().build_with_cache(&mft, &file, &mut cache);
+        assert_eq!(info.path, PathBuf::new());
+        assert_eq!(cache.len(), 0);
+
+        let mut info = FileInfo::builder().build(&mft, &file);
+        FileInfo::builder().build_into(&mft, &file, &mut info, &mut FileInfoBuffers::default());
+        assert_eq!(info.path, PathBuf::new());
+    }
+
+    #[test]
+    fn build_into_reuses_the_info() {
+        let mut records = make_tree(34);
+        records[32] = make_named_record(1, file_reference(30, 1), "stale");
+        records[33] = make_named_record(1, file_reference(31, 1), "n\u{e9}e");
+        let mft = make_mft(records);
+
+        for builder in [
+            FileInfo::builder(),
+            FileInfo::builder().with_path_format(PathFormat::Rootless),
+            FileInfo::builder().with_path(false),
+        ] {
+            let mut info = FileInfo::builder().build(&mft, &mft.get_record(33).unwrap());
+            let mut buffers = FileInfoBuffers::default();
+            let mut count = 0;
+
+            mft.iterate_files(|file| {
+                let expected = builder.build(&mft, file);
+                builder.build_into(&mft, file, &mut info, &mut buffers);
+                assert_eq!(info.name, expected.name);
+                assert_eq!(info.path, expected.path);
+                assert_eq!(info.stale_parent, expected.stale_parent);
+            });
+
+            builder.iterate(&mft, |file, info| {
+                assert_eq!(info.path, builder.build(&mft, file).path);
+                count += 1;
+            });
+            assert_eq!(count, 10);
+        }
+
+        let builder = FileInfo::builder().with_path_format(PathFormat::Rootless);
+        let mut info = FileInfo::builder().build(&mft, &mft.get_record(30).unwrap());
+        let file = mft.get_record(33).unwrap();
+        builder.build_into(&mft, &file, &mut info, &mut FileInfoBuffers::default());
+        assert_eq!(info.path, Path::new("dir").join("file").join("n\u{e9}e"));
+    }
 }
@@ -0,0 +1,364 @@
+// Copyright (c) 2022, Matteo Bernacchia <dev@kikijiki.com>. All rights reserved.
+// This project is dual licensed under the Apache License 2.0 and the MIT license.
+// See the LICENSE files in the project root for details.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::{
+    errors::NtfsReaderResult,
+    volume::{FileSystemType, Volume},
+};
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const MBR_PROTECTIVE: u8 = 0xEE;
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+// Logical chains longer than this are most likely corrupted (or looping).
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+// Larger GPT entries (and tables) are corrupted, the entries are 128 bytes in practice.
+const MAX_GPT_ENTRY_SIZE: u64 = 4096;
+const MAX_GPT_ENTRIES: u64 = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionKind {
+    Mbr {
+        partition_type: u8,
+    },
+    Gpt {
+        // As stored on disk, the first three fields are little endian.
+        type_guid: [u8; 16],
+        name: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionInfo {
+    // In the order of the partition table, the logical partitions follow the primary ones.
+    pub index: usize,
+    // In bytes, from the start of the image.
+    pub offset: u64,
+    pub size: u64,
+    pub kind: PartitionKind,
+    // Detected from the boot sector of the partition.
+    pub file_system: FileSystemType,
+}
+
+impl PartitionInfo {
+    pub fn is_ntfs(&self) -> bool {
+        self.file_system == FileSystemType::Ntfs
+    }
+}
+
+// A whole disk image (e.g. made with dd), to find the volumes in it.
+pub struct Image {
+    pub path: PathBuf,
+}
+
+impl Image {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        // Fail early if it can't be read.
+        File::open(path.as_ref())?;
+        Ok(Image {
+            path: path.as_ref().to_path_buf(),
+        })
+    }
+
+    // Empty if there is no partition table, e.g. for the image of a single volume.
+    pub fn partitions(&self) -> io::Result<Vec<PartitionInfo>> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        read_partitions(&mut reader)
+    }
+
+    pub fn ntfs_partitions(&self) -> io::Result<Vec<PartitionInfo>> {
+        Ok(self
+            .partitions()?
+            .into_iter()
+            .filter(PartitionInfo::is_ntfs)
+            .collect())
+    }
+
+    pub fn open_volume(&self, partition: &PartitionInfo) -> NtfsReaderResult<Volume> {
+        Volume::from_image(&self.path, partition.offset)
+    }
+}
+
+// Reads the MBR, or the GPT when the MBR is only protective.
+pub fn read_partitions<R: Read + Seek>(reader: &mut R) -> io::Result<Vec<PartitionInfo>> {
+    for sector_size in [512, 4096] {
+        if let Some(partitions) = read_gpt(reader, sector_size)? {
+            return Ok(partitions);
+        }
+    }
+
+    let mbr = read_at(reader, 0, 512)?;
+    if mbr[510..512] != MBR_SIGNATURE {
+        return Ok(Vec::new());
+    }
+
+    // Volume boot sectors have the same signature.
+    if !matches!(
+        FileSystemType::detect(&mbr.clone().try_into().unwrap()),
+        FileSystemType::Unknown(_)
+    ) {
+        return Ok(Vec::new());
+    }
+
+    let mut partitions = Vec::new();
+    let mut extended = Vec::new();
+    for entry in mbr_entries(&mbr) {
+        if MBR_EXTENDED.contains(&entry.0) {
+            extended.push(entry.1);
+        } else if entry.0 != MBR_PROTECTIVE {
+            partitions.push(entry);
+        }
+    }
+
+    // The logical partitions are in a chain of extended boot records,
+    // each one has the partition (relative to itself) and the next record (relative to the first).
+    for first in extended {
+        let mut ebr = first;
+        for _ in 0..MAX_LOGICAL_PARTITIONS {
+            let data = read_at(reader, ebr * 512, 512)?;
+            if data[510..512] != MBR_SIGNATURE {
+                break;
+            }
+
+            let mut next = None;
+            for (partition_type, start, sectors) in mbr_entries(&data) {
+                if MBR_EXTENDED.contains(&partition_type) {
+                    next = Some(first + start);
+                } else {
+                    partitions.push((partition_type, ebr + start, sectors));
+                }
+            }
+
+            match next {
+                Some(next) => ebr = next,
+                None => break,
+            }
+        }
+    }
+
+    let mut results = Vec::new();
+    for (index, (partition_type, start, sectors)) in partitions.into_iter().enumerate() {
+        let offset = start * 512;
+        results.push(PartitionInfo {
+            index,
+            offset,
+            size: sectors * 512,
+            kind: PartitionKind::Mbr { partition_type },
+            file_system: detect_at(reader, offset),
+        });
+    }
+
+    Ok(results)
+}
+
+// The used entries of a partition table, as (type, first sector, sectors).
+fn mbr_entries(sector: &[u8]) -> Vec<(u8, u64, u64)> {
+    (0..4)
+        .map(|i| &sector[446 + i * 16..446 + (i + 1) * 16])
+        .filter(|entry| entry[4] != 0)
+        .map(|entry| {
+            let start = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64;
+            let sectors = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as u64;
+            (entry[4], start, sectors)
+        })
+        .collect()
+}
+
+fn read_gpt<R: Read + Seek>(
+    reader: &mut R,
+    sector_size: u64,
+) -> io::Result<Option<Vec<PartitionInfo>>> {
+    let header = match read_at(reader, sector_size, 92) {
+        Ok(header) => header,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    if header[0..8] != *GPT_SIGNATURE {
+        return Ok(None);
+    }
+
+    let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+    let entry_count = u32::from_le_bytes(header[80..84].try_into().unwrap()) as u64;
+    let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap()) as u64;
+    if !(128..=MAX_GPT_ENTRY_SIZE).contains(&entry_size)
+        || !entry_size.is_multiple_of(8)
+        || entry_count > MAX_GPT_ENTRIES
+    {
+        return Err(invalid_gpt());
+    }
+
+    // Both bounded above, at most 4 MiB.
+    let table_size = (entry_count * entry_size) as usize;
+    let table_offset = entries_lba
+        .checked_mul(sector_size)
+        .ok_or_else(invalid_gpt)?;
+    let entries = read_at(reader, table_offset, table_size)?;
+
+    let mut results = Vec::new();
+    for entry in entries.chunks_exact(entry_size as usize) {
+        let type_guid: [u8; 16] = entry[0..16].try_into().unwrap();
+        if type_guid == [0; 16] {
+            continue;
+        }
+
+        let first_lba = u64::from_le_bytes(entry[32..40].try_into().unwrap());
+        let last_lba = u64::from_le_bytes(entry[40..48].try_into().unwrap());
+        let name: Vec<u16> = entry[56..128]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|c| *c != 0)
+            .collect();
+
+        let offset = first_lba.checked_mul(sector_size);
+        let size = last_lba
+            .checked_add(1)
+            .map(|end| end.saturating_sub(first_lba))
+            .and_then(|sectors| sectors.checked_mul(sector_size));
+        let (Some(offset), Some(size)) = (offset, size) else {
+            return Err(invalid_gpt());
+        };
+
+        results.push(PartitionInfo {
+            index: results.len(),
+            offset,
+            size,
+            kind: PartitionKind::Gpt {
+                type_guid,
+                name: String::from_utf16_lossy(&name),
+            },
+            file_system: detect_at(reader, offset),
+        });
+    }
+
+    Ok(Some(results))
+}
+
+fn invalid_gpt() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid GPT partition entries")
+}
+
+fn detect_at<R: Read + Seek>(reader: &mut R, offset: u64) -> FileSystemType {
+    match read_at(reader, offset, 512) {
+        Ok(sector) => FileSystemType::detect(&sector.try_into().unwrap()),
+        Err(_) => FileSystemType::Unknown([0; 8]),
+    }
+}
+
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut data = vec![0; len];
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut data)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{read_partitions, PartitionKind};
+    use crate::volume::FileSystemType;
+
+    fn write_entry(data: &mut [u8], at: usize, partition_type: u8, start: u32, sectors: u32) {
+        data[at + 4] = partition_type;
+        data[at + 8..at + 12].copy_from_slice(&start.to_le_bytes());
+        data[at + 12..at + 16].copy_from_slice(&sectors.to_le_bytes());
+    }
+
+    #[test]
+    fn mbr_partitions() {
+        let mut disk = vec![0u8; 16 * 512];
+        disk[510..512].copy_from_slice(&[0x55, 0xAA]);
+        write_entry(&mut disk, 446, 0x07, 2, 4);
+        write_entry(&mut disk, 462, 0x05, 8, 8);
+        disk[2 * 512 + 3..2 * 512 + 11].copy_from_slice(b"NTFS    ");
+
+        // A single logical partition in the extended one.
+        let ebr = 8 * 512;
+        disk[ebr + 510..ebr + 512].copy_from_slice(&[0x55, 0xAA]);
+        write_entry(&mut disk, ebr + 446, 0x07, 1, 2);
+
+        let partitions = read_partitions(&mut Cursor::new(disk)).unwrap();
+        assert_eq!(partitions.len(), 2);
+
+        assert_eq!(partitions[0].offset, 2 * 512);
+        assert_eq!(partitions[0].size, 4 * 512);
+        assert_eq!(partitions[0].file_system, FileSystemType::Ntfs);
+        assert_eq!(
+            partitions[0].kind,
+            PartitionKind::Mbr {
+                partition_type: 0x07
+            }
+        );
+
+        assert_eq!(partitions[1].offset, 9 * 512);
+        assert!(!partitions[1].is_ntfs());
+    }
+
+    #[test]
+    fn gpt_partitions() {
+        let mut disk = vec![0u8; 64 * 512];
+        disk[512..520].copy_from_slice(b"EFI PART");
+        disk[512 + 72..512 + 80].copy_from_slice(&2u64.to_le_bytes());
+        disk[512 + 80..512 + 84].copy_from_slice(&4u32.to_le_bytes());
+        disk[512 + 84..512 + 88].copy_from_slice(&128u32.to_le_bytes());
+
+        // The second entry, the first one is unused.
+        let entry = 2 * 512 + 128;
+        disk[entry] = 0xA2;
+        disk[entry + 32..entry + 40].copy_from_slice(&34u64.to_le_bytes());
+        disk[entry + 40..entry + 48].copy_from_slice(&63u64.to_le_bytes());
+        disk[entry + 56..entry + 60].copy_from_slice(&[b'D', 0, b'a', 0]);
+        disk[34 * 512 + 3..34 * 512 + 11].copy_from_slice(b"NTFS    ");
+
+        let partitions = read_partitions(&mut Cursor::new(disk)).unwrap();
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].offset, 34 * 512);
+        assert_eq!(partitions[0].size, 30 * 512);
+        assert!(partitions[0].is_ntfs());
+        assert!(matches!(&partitions[0].kind, PartitionKind::Gpt { name, .. } if name == "Da"));
+    }
+
+    #[test]
+    fn corrupt_gpt() {
+        let mut disk = vec![0u8; 4 * 512];
+        disk[512..520].copy_from_slice(b"EFI PART");
+        disk[512 + 72..512 + 80].copy_from_slice(&2u64.to_le_bytes());
+        disk[512 + 80..512 + 84].copy_from_slice(&1u32.to_le_bytes());
+        disk[512 + 84..512 + 88].copy_from_slice(&128u32.to_le_bytes());
+        disk[2 * 512] = 0xA2;
+        disk[2 * 512 + 32..2 * 512 + 40].copy_from_slice(&u64::MAX.to_le_bytes());
+        disk[2 * 512 + 40..2 * 512 + 48].copy_from_slice(&u64::MAX.to_le_bytes());
+
+        let err = read_partitions(&mut Cursor::new(disk.clone())).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        // Not allocated.
+        disk[512 + 80..512 + 84].copy_from_slice(&1024u32.to_le_bytes());
+        disk[512 + 84..512 + 88].copy_from_slice(&0x4000_0000u32.to_le_bytes());
+        let err = read_partitions(&mut Cursor::new(disk.clone())).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        disk[512 + 72..512 + 80].copy_from_slice(&u64::MAX.to_le_bytes());
+        disk[512 + 84..512 + 88].copy_from_slice(&128u32.to_le_bytes());
+        let err = read_partitions(&mut Cursor::new(disk)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn no_partition_table() {
+        let mut disk = vec![0u8; 4 * 512];
+        assert!(read_partitions(&mut Cursor::new(disk.clone()))
+            .unwrap()
+            .is_empty());
+
+        // The image of a single volume.
+        disk[3..11].copy_from_slice(b"NTFS    ");
+        disk[510..512].copy_from_slice(&[0x55, 0xAA]);
+        assert!(read_partitions(&mut Cursor::new(disk)).unwrap().is_empty());
+    }
+}
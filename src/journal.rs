@@ -2,21 +2,35 @@
 // This project is dual licensed under the Apache License 2.0 and the MIT license.
 // See the LICENSE files in the project root for details.
 
-use std::collections::VecDeque;
-use std::ffi::{CString, OsString};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{CString, OsStr, OsString};
+use std::io::{Read, Write};
 use std::mem::size_of;
+use std::ops::BitOr;
 use std::os::raw::c_void;
-use std::os::windows::ffi::OsStringExt;
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use windows::core::PCSTR;
-use windows::Win32::Foundation::{self, ERROR_MORE_DATA};
+use windows::Win32::Foundation::{
+    self, ERROR_INSUFFICIENT_BUFFER, ERROR_IO_PENDING, ERROR_JOURNAL_DELETE_IN_PROGRESS,
+    ERROR_JOURNAL_ENTRY_DELETED, ERROR_JOURNAL_NOT_ACTIVE, ERROR_MORE_DATA,
+};
 use windows::Win32::Storage::FileSystem::{self, FILE_FLAG_BACKUP_SEMANTICS};
 use windows::Win32::System::Ioctl;
 use windows::Win32::System::Threading::INFINITE;
 use windows::Win32::System::IO::{self, GetQueuedCompletionStatus};
 
-use crate::volume::Volume;
+use time::OffsetDateTime;
+
+use crate::api::{ntfs_to_unix_time, reference_number, reference_sequence, EPOCH_DIFFERENCE};
+use crate::channel::Spill;
+use crate::errors::{win32, JournalError, ParseIssue, Win32Error};
+use crate::file_info::FileInfo;
+use crate::mft::Mft;
+use crate::volume::{PathFormat, Volume};
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum FileId {
@@ -24,31 +38,84 @@ pub enum FileId {
     Extended(FileSystem::FILE_ID_128),
 }
 
-#[repr(align(64))]
-#[derive(Debug, Clone, Copy)]
-struct AlignedBuffer<const N: usize>([u8; N]);
+impl FileId {
+    // The 64 bit file reference, for NTFS the extended id holds the same value.
+    pub fn reference(&self) -> u64 {
+        match self {
+            FileId::Normal(id) => *id,
+            FileId::Extended(id) => u64::from_le_bytes(id.Identifier[..8].try_into().unwrap()),
+        }
+    }
+
+    // FILE_ID_128 is not hashable.
+    fn key(&self) -> u128 {
+        match self {
+            FileId::Normal(id) => *id as u128,
+            FileId::Extended(id) => u128::from_le_bytes(id.Identifier),
+        }
+    }
+}
 
-fn get_usn_record_time(timestamp: i64) -> std::time::Duration {
-    std::time::Duration::from_nanos(timestamp as u64 * 100u64)
+// Resolves the paths of the journal records without opening the files,
+// which also works for files that were already deleted.
+pub trait PathResolver {
+    // The path relative to the volume root, without the leading separator.
+    fn resolve(&self, file_id: FileId) -> Option<PathBuf>;
 }
 
-fn get_usn_record_name(file_name_length: u16, file_name: *const u16) -> String {
+// Resolves from a snapshot of the MFT, build the parent index first to make it fast.
+impl PathResolver for Mft {
+    fn resolve(&self, file_id: FileId) -> Option<PathBuf> {
+        let reference = file_id.reference();
+        let number = reference_number(reference);
+        if number >= self.max_record {
+            return None;
+        }
+
+        // The record was reused after the snapshot.
+        let sequence = self.get_record(number)?.header.sequence_value;
+        if reference_sequence(reference) != 0 && reference_sequence(reference) != sequence {
+            return None;
+        }
+
+        self.get_relative_path(number)
+    }
+}
+
+impl<R: PathResolver + ?Sized> PathResolver for std::sync::Arc<R> {
+    fn resolve(&self, file_id: FileId) -> Option<PathBuf> {
+        (**self).resolve(file_id)
+    }
+}
+
+// The records are written in pages, and never cross them.
+const JOURNAL_PAGE_SIZE: i64 = 4096;
+
+// Upper limit for the automatic growth of the read buffer.
+const MAX_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+
+fn get_usn_record_time(filetime: i64) -> OffsetDateTime {
+    ntfs_to_unix_time(filetime.max(0) as u64)
+}
+
+pub(crate) fn get_usn_record_name(file_name_length: u16, file_name: *const u16) -> OsString {
     let size = (file_name_length / 2) as usize;
 
     if size > 0 {
         unsafe {
             let name_u16 = std::slice::from_raw_parts(file_name, size);
-            let name = std::ffi::OsString::from_wide(name_u16)
-                .to_string_lossy()
-                .into_owned();
-            return name;
+            return OsString::from_wide(name_u16);
         }
     }
 
-    return String::new();
+    OsString::new()
 }
 
-fn get_file_path(volume_handle: Foundation::HANDLE, file_id: FileId) -> Option<PathBuf> {
+fn open_file_by_id(
+    volume_handle: Foundation::HANDLE,
+    file_id: FileId,
+    access: u32,
+) -> windows::core::Result<Foundation::HANDLE> {
     let (id, id_type) = match file_id {
         FileId::Normal(id) => (
             FileSystem::FILE_ID_DESCRIPTOR_0 { FileId: id as i64 },
@@ -67,17 +134,23 @@ fn get_file_path(volume_handle: Foundation::HANDLE, file_id: FileId) -> Option<P
     };
 
     unsafe {
-        let file_handle = FileSystem::OpenFileById(
+        FileSystem::OpenFileById(
             volume_handle,
             &file_id_desc,
-            0,
+            access,
             FileSystem::FILE_SHARE_READ
                 | FileSystem::FILE_SHARE_WRITE
                 | FileSystem::FILE_SHARE_DELETE,
             None,
             FILE_FLAG_BACKUP_SEMANTICS,
         )
-        .unwrap_or(Foundation::INVALID_HANDLE_VALUE);
+    }
+}
+
+fn get_file_path(volume_handle: Foundation::HANDLE, file_id: FileId) -> Option<PathBuf> {
+    unsafe {
+        let file_handle =
+            open_file_by_id(volume_handle, file_id, 0).unwrap_or(Foundation::INVALID_HANDLE_VALUE);
 
         if file_handle.is_invalid() {
             return None;
@@ -125,91 +198,741 @@ fn get_file_path(volume_handle: Foundation::HANDLE, file_id: FileId) -> Option<P
     }
 }
 
+// The parent paths resolved with OpenFileById, since many records in a row are usually
+// for the same directories.
+// Cleared when any directory is renamed or deleted, as that changes the paths of all its
+// descendants (and it's rare enough not to be worth tracking them).
+struct ParentCache {
+    capacity: usize,
+    entries: RefCell<HashMap<u128, PathBuf>>,
+}
+
+impl ParentCache {
+    fn new(capacity: usize) -> Self {
+        ParentCache {
+            capacity,
+            entries: RefCell::new(HashMap::new()),
+        }
+    }
+
+    fn get_file_path(&self, volume_handle: Foundation::HANDLE, file_id: FileId) -> Option<PathBuf> {
+        if self.capacity == 0 {
+            return get_file_path(volume_handle, file_id);
+        }
+
+        if let Some(path) = self.entries.borrow().get(&file_id.key()) {
+            return Some(path.clone());
+        }
+
+        let path = get_file_path(volume_handle, file_id)?;
+
+        let mut entries = self.entries.borrow_mut();
+        if entries.len() >= self.capacity {
+            entries.clear();
+        }
+        entries.insert(file_id.key(), path.clone());
+
+        Some(path)
+    }
+
+    fn clear(&self) {
+        self.entries.borrow_mut().clear();
+    }
+
+    fn invalidate(&self, reason: u32, file_attributes: u32) {
+        let changed = reason
+            & (Ioctl::USN_REASON_RENAME_OLD_NAME
+                | Ioctl::USN_REASON_RENAME_NEW_NAME
+                | Ioctl::USN_REASON_FILE_DELETE)
+            != 0;
+
+        if changed && file_attributes & FileSystem::FILE_ATTRIBUTE_DIRECTORY.0 != 0 {
+            self.clear();
+        }
+    }
+}
+
 fn get_usn_record_path(
-    volume_path: &Path,
+    root: &Path,
     volume_handle: Foundation::HANDLE,
-    file_name: String,
+    resolver: Option<&(dyn PathResolver + Send)>,
+    parent_cache: &ParentCache,
+    file_name: &OsStr,
     file_id: FileId,
     parent_id: FileId,
-) -> PathBuf {
+) -> Option<PathBuf> {
+    if let Some(parent_path) = resolver.and_then(|resolver| resolver.resolve(parent_id)) {
+        return Some(root.join(parent_path.join(file_name)));
+    }
+
     // First try to get the full path from the parent.
     // We do this because if the file was moved, computing the path from the file id
     // could return the wrong path.
-    if let Some(parent_path) = get_file_path(volume_handle, parent_id) {
-        return volume_path.join(parent_path.join(&file_name));
+    // The returned paths start with a separator, strip it so that they can be joined to any root.
+    if let Some(parent_path) = parent_cache.get_file_path(volume_handle, parent_id) {
+        let parent_path = parent_path.strip_prefix("\\").unwrap_or(&parent_path);
+        return Some(root.join(parent_path.join(file_name)));
     } else {
         // If we can't get the parent path, try to get the path from the file id.
         // This can happen if the parent was deleted.
         if let Some(path) = get_file_path(volume_handle, file_id) {
-            return volume_path.join(path);
+            let path = path.strip_prefix("\\").unwrap_or(&path);
+            return Some(root.join(path));
+        }
+    }
+
+    None
+}
+
+// The errors converted directly from `windows::core::Error` hold the HRESULT, not the win32 code.
+fn is_win32_error(err: &std::io::Error, code: Foundation::WIN32_ERROR) -> bool {
+    match Win32Error::from_io(err) {
+        Some(err) => err.code == code.0,
+        None => err.raw_os_error() == Some(code.to_hresult().0),
+    }
+}
+
+pub(crate) fn open_volume_handle(
+    path: &Path,
+    access: FileSystem::FILE_ACCESS_RIGHTS,
+    flags: FileSystem::FILE_FLAGS_AND_ATTRIBUTES,
+) -> Result<Foundation::HANDLE, std::io::Error> {
+    // Needs to be null terminated.
+    let path = CString::new(path.to_str().unwrap()).unwrap();
+
+    let handle = unsafe {
+        FileSystem::CreateFileA(
+            PCSTR::from_raw(path.as_bytes_with_nul().as_ptr()),
+            access.0,
+            FileSystem::FILE_SHARE_READ
+                | FileSystem::FILE_SHARE_WRITE
+                | FileSystem::FILE_SHARE_DELETE,
+            None,
+            FileSystem::OPEN_EXISTING,
+            flags,
+            None,
+        )
+        .map_err(win32("CreateFileA"))?
+    };
+
+    Ok(handle)
+}
+
+fn query_journal(
+    volume_handle: Foundation::HANDLE,
+) -> Result<Ioctl::USN_JOURNAL_DATA_V2, std::io::Error> {
+    let mut journal = Ioctl::USN_JOURNAL_DATA_V2::default();
+
+    unsafe {
+        let mut ioctl_bytes_returned = 0;
+        IO::DeviceIoControl(
+            volume_handle,
+            Ioctl::FSCTL_QUERY_USN_JOURNAL,
+            None,
+            0,
+            Some(&mut journal as *mut _ as *mut c_void),
+            size_of::<Ioctl::USN_JOURNAL_DATA_V2>() as u32,
+            Some(&mut ioctl_bytes_returned),
+            None,
+        )
+        .map_err(win32("FSCTL_QUERY_USN_JOURNAL"))?;
+    }
+
+    Ok(journal)
+}
+
+// Checks that the record is complete before it is cast to the structure of its version.
+// Returns the major version.
+fn check_record(data: &[u8]) -> Result<u16, ParseIssue> {
+    let Some(header) = data.get(0..6) else {
+        return Err(ParseIssue::Truncated);
+    };
+    let record_len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+    let major_version = u16::from_le_bytes(header[4..6].try_into().unwrap());
+
+    let name_start = match major_version {
+        2 => std::mem::offset_of!(Ioctl::USN_RECORD_V2, FileName),
+        3 => std::mem::offset_of!(Ioctl::USN_RECORD_V3, FileName),
+        _ => return Err(ParseIssue::UnknownVersion(major_version)),
+    };
+    if record_len != data.len() || record_len < name_start {
+        return Err(ParseIssue::Truncated);
+    }
+
+    let (name_offset, name_length) = unsafe {
+        match major_version {
+            2 => {
+                let rec = &*(data.as_ptr() as *const Ioctl::USN_RECORD_V2);
+                (rec.FileNameOffset, rec.FileNameLength)
+            }
+            _ => {
+                let rec = &*(data.as_ptr() as *const Ioctl::USN_RECORD_V3);
+                (rec.FileNameOffset, rec.FileNameLength)
+            }
+        }
+    };
+    let name_end = usize::max(name_start, name_offset as usize) + name_length as usize;
+    if name_end > record_len {
+        return Err(ParseIssue::Truncated);
+    }
+
+    Ok(major_version)
+}
+
+fn as_bytes(buffer: &[u64]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(buffer.as_ptr() as *const u8, buffer.len() * 8) }
+}
+
+fn as_bytes_mut(buffer: &mut [u64]) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, buffer.len() * 8) }
+}
+
+// What to do after a read completed, see `Journal::end_read`.
+pub(crate) enum ReadEnd {
+    // The number of bytes to parse, zero if there are no new records.
+    Bytes(usize),
+    // The next record didn't fit, read again with a buffer of this size.
+    Retry(usize),
+}
+
+// Waits for a completion on the port of a journal: its key and the result of the read (the bytes
+// written). Fails only if the port does, without dequeuing anything.
+pub(crate) fn dequeue_completion(
+    port: Foundation::HANDLE,
+    operation: &'static str,
+) -> Result<(usize, Result<u32, std::io::Error>), std::io::Error> {
+    let mut bytes_returned = 0;
+    let mut key = 0usize;
+    let mut overlapped = std::ptr::null_mut();
+
+    let result = unsafe {
+        GetQueuedCompletionStatus(
+            port,
+            &mut bytes_returned,
+            &mut key,
+            &mut overlapped,
+            INFINITE,
+        )
+    };
+
+    match result {
+        Ok(()) => Ok((key, Ok(bytes_returned))),
+        Err(err) if overlapped.is_null() => Err(win32("GetQueuedCompletionStatus")(err)),
+        // The result of the read.
+        Err(err) => Ok((key, Err(win32(operation)(err)))),
+    }
+}
+
+// The timeout is in seconds, and zero means infinite.
+fn wait_params(timeout: Option<Duration>) -> (u64, u64) {
+    match timeout {
+        Some(timeout) if timeout.is_zero() => (0, 0),
+        Some(timeout) => {
+            let seconds = timeout.as_secs() + u64::from(timeout.subsec_nanos() > 0);
+            (1, seconds)
         }
+        None => (1, 0),
     }
+}
 
-    //warn!("Could not get path: {}", file_name);
-    PathBuf::from(&file_name)
+const HISTORY_MAGIC: &[u8; 4] = b"NRRH";
+const HISTORY_VERSION: u32 = 1;
+
+fn write_file_id<W: Write>(writer: &mut W, file_id: FileId) -> Result<(), std::io::Error> {
+    let (tag, key) = match file_id {
+        FileId::Normal(id) => (0u8, id as u128),
+        FileId::Extended(id) => (1u8, u128::from_le_bytes(id.Identifier)),
+    };
+    writer.write_all(&[tag])?;
+    writer.write_all(&key.to_le_bytes())
+}
+
+fn write_os_str<W: Write>(writer: &mut W, value: &OsStr) -> Result<(), std::io::Error> {
+    let wide: Vec<u16> = value.encode_wide().collect();
+    writer.write_all(&(wide.len() as u32).to_le_bytes())?;
+    for unit in wide {
+        writer.write_all(&unit.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_array<R: Read, const N: usize>(reader: &mut R) -> Result<[u8; N], std::io::Error> {
+    let mut buf = [0u8; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_file_id<R: Read>(reader: &mut R) -> Result<FileId, std::io::Error> {
+    let [tag] = read_array::<_, 1>(reader)?;
+    let key = read_array::<_, 16>(reader)?;
+    Ok(match tag {
+        0 => FileId::Normal(u128::from_le_bytes(key) as u64),
+        _ => FileId::Extended(FileSystem::FILE_ID_128 { Identifier: key }),
+    })
+}
+
+fn read_os_string<R: Read>(reader: &mut R) -> Result<OsString, std::io::Error> {
+    let len = u32::from_le_bytes(read_array(reader)?) as usize;
+    let mut bytes = vec![0u8; len * 2];
+    reader.read_exact(&mut bytes)?;
+    let wide: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .collect();
+    Ok(OsString::from_wide(&wide))
+}
+
+fn validate_checkpoint(
+    journal: &Ioctl::USN_JOURNAL_DATA_V2,
+    checkpoint: &JournalCheckpoint,
+) -> Result<(), std::io::Error> {
+    if checkpoint.journal_id != journal.UsnJournalID {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "The journal id changed ({:#x} -> {:#x})",
+                checkpoint.journal_id, journal.UsnJournalID
+            ),
+        ));
+    }
+
+    if checkpoint.usn < journal.LowestValidUsn || checkpoint.usn > journal.NextUsn {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "The checkpoint usn {} is out of the journal range ({}..{})",
+                checkpoint.usn, journal.LowestValidUsn, journal.NextUsn
+            ),
+        ));
+    }
+
+    Ok(())
 }
 
 #[derive(Debug, Clone)]
 pub struct UsnRecord {
     pub usn: i64,
-    pub timestamp: std::time::Duration,
+    pub timestamp: OffsetDateTime,
+    // The raw FILETIME, 100ns intervals since 1601-01-01 UTC.
+    pub filetime: i64,
     pub file_id: FileId,
     pub parent_id: FileId,
     pub reason: u32,
+    pub file_attributes: u32,
+    // Ioctl::USN_SOURCE_*, e.g. set by backup or replication tools.
+    pub source_info: u32,
+    pub security_id: u32,
+    pub name: OsString,
+    // Only the file name when `JournalOptions::resolve_paths` is disabled.
     pub path: PathBuf,
+    // For RENAME_NEW_NAME records, the path from the matching RENAME_OLD_NAME record.
+    pub rename_from: Option<PathBuf>,
 }
 
 impl UsnRecord {
-    fn from_v2(journal: &Journal, rec: &Ioctl::USN_RECORD_V2) -> Self {
+    pub fn is_directory(&self) -> bool {
+        self.file_attributes & FileSystem::FILE_ATTRIBUTE_DIRECTORY.0 != 0
+    }
+
+    pub fn categories(&self) -> ChangeCategories {
+        ChangeCategories::from_reason(self.reason)
+    }
+
+    // Without `rename_from`, see `Journal::save_history`.
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), std::io::Error> {
+        writer.write_all(&self.usn.to_le_bytes())?;
+        writer.write_all(&self.filetime.to_le_bytes())?;
+        write_file_id(writer, self.file_id)?;
+        write_file_id(writer, self.parent_id)?;
+        writer.write_all(&self.reason.to_le_bytes())?;
+        writer.write_all(&self.file_attributes.to_le_bytes())?;
+        writer.write_all(&self.source_info.to_le_bytes())?;
+        writer.write_all(&self.security_id.to_le_bytes())?;
+        write_os_str(writer, &self.name)?;
+        write_os_str(writer, self.path.as_os_str())
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> Result<Self, std::io::Error> {
+        let usn = i64::from_le_bytes(read_array(reader)?);
+        let filetime = i64::from_le_bytes(read_array(reader)?);
+        let file_id = read_file_id(reader)?;
+        let parent_id = read_file_id(reader)?;
+        let reason = u32::from_le_bytes(read_array(reader)?);
+        let file_attributes = u32::from_le_bytes(read_array(reader)?);
+        let source_info = u32::from_le_bytes(read_array(reader)?);
+        let security_id = u32::from_le_bytes(read_array(reader)?);
+        let name = read_os_string(reader)?;
+        let path = PathBuf::from(read_os_string(reader)?);
+
+        Ok(UsnRecord {
+            usn,
+            timestamp: get_usn_record_time(filetime),
+            filetime,
+            file_id,
+            parent_id,
+            reason,
+            file_attributes,
+            source_info,
+            security_id,
+            name,
+            path,
+            rename_from: None,
+        })
+    }
+
+    fn from_v2(journal: &Journal, rec: &Ioctl::USN_RECORD_V2) -> Option<Self> {
         let usn = rec.Usn;
-        let timestamp = get_usn_record_time(rec.TimeStamp);
+        let filetime = rec.TimeStamp;
         let file_id = FileId::Normal(rec.FileReferenceNumber);
         let parent_id = FileId::Normal(rec.ParentFileReferenceNumber);
         let reason = rec.Reason;
+        // Before filtering, it has to see all the directory changes.
+        journal.parent_cache.invalidate(reason, rec.FileAttributes);
+
         let name = get_usn_record_name(rec.FileNameLength, rec.FileName.as_ptr());
-        let path = get_usn_record_path(
-            &journal.volume.path,
-            journal.volume_handle,
-            name,
-            file_id,
-            parent_id,
-        );
+        if !journal.filter.matches(&name) {
+            return None;
+        }
+
+        let path = journal.record_path(&name, file_id, parent_id);
 
-        UsnRecord {
+        Some(UsnRecord {
             usn,
-            timestamp,
+            timestamp: get_usn_record_time(filetime),
+            filetime,
             file_id,
             parent_id,
             reason,
+            file_attributes: rec.FileAttributes,
+            source_info: rec.SourceInfo,
+            security_id: rec.SecurityId,
+            name,
             path,
-        }
+            rename_from: None,
+        })
     }
 
-    fn from_v3(journal: &Journal, rec: &Ioctl::USN_RECORD_V3) -> Self {
+    fn from_v3(journal: &Journal, rec: &Ioctl::USN_RECORD_V3) -> Option<Self> {
         let usn = rec.Usn;
-        let timestamp = get_usn_record_time(rec.TimeStamp);
+        let filetime = rec.TimeStamp;
         let file_id = FileId::Extended(rec.FileReferenceNumber);
         let parent_id = FileId::Extended(rec.ParentFileReferenceNumber);
         let reason = rec.Reason;
 
+        // Before filtering, it has to see all the directory changes.
+        journal.parent_cache.invalidate(reason, rec.FileAttributes);
+
         let name = get_usn_record_name(rec.FileNameLength, rec.FileName.as_ptr());
-        let path = get_usn_record_path(
-            &journal.volume.path,
-            journal.volume_handle,
-            name,
-            file_id,
-            parent_id,
-        );
+        if !journal.filter.matches(&name) {
+            return None;
+        }
+
+        let path = journal.record_path(&name, file_id, parent_id);
 
-        UsnRecord {
+        Some(UsnRecord {
             usn,
-            timestamp,
+            timestamp: get_usn_record_time(filetime),
+            filetime,
             file_id,
             parent_id,
             reason,
+            file_attributes: rec.FileAttributes,
+            source_info: rec.SourceInfo,
+            security_id: rec.SecurityId,
+            name,
             path,
+            rename_from: None,
+        })
+    }
+}
+
+impl Spill for UsnRecord {
+    fn spill<W: Write>(&self, writer: &mut W) -> Result<(), std::io::Error> {
+        self.write_to(writer)?;
+        match &self.rename_from {
+            Some(path) => {
+                writer.write_all(&[1])?;
+                write_os_str(writer, path.as_os_str())
+            }
+            None => writer.write_all(&[0]),
+        }
+    }
+
+    fn unspill<R: Read>(reader: &mut R) -> Result<Self, std::io::Error> {
+        let mut record = Self::read_from(reader)?;
+        if read_array::<_, 1>(reader)? == [1] {
+            record.rename_from = Some(PathBuf::from(read_os_string(reader)?));
+        }
+        Ok(record)
+    }
+}
+
+// What was skipped while parsing since the journal was opened, see `Journal::parse_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ParseStats {
+    pub skipped_records: u64,
+    pub skipped_bytes: u64,
+    pub unknown_version: u64,
+    pub truncated: u64,
+    pub last_issue: Option<ParseIssue>,
+}
+
+impl ParseStats {
+    fn add(&mut self, issue: ParseIssue, bytes: usize) {
+        self.skipped_records += 1;
+        self.skipped_bytes += bytes as u64;
+        match issue {
+            ParseIssue::UnknownVersion(_) => self.unknown_version += 1,
+            ParseIssue::Truncated => self.truncated += 1,
+        }
+        self.last_issue = Some(issue);
+    }
+}
+
+// Counted since the journal was opened, see `Journal::counters`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JournalCounters {
+    pub records_read: u64,
+    pub bytes_consumed: u64,
+    // Dropped by the name filter (the reason mask is applied by the system).
+    pub records_filtered: u64,
+    pub path_resolutions: u64,
+    // The records with only the name as the path.
+    pub resolution_failures: u64,
+    // How far behind the end of the journal after the last read, in bytes of USN space.
+    // Only updated when metrics are set, see `Journal::info` otherwise.
+    pub lag: i64,
+}
+
+// Receives the counters after each read, e.g. to alert before a reader falls behind
+// and the journal wraps. See `Journal::set_metrics`.
+pub trait JournalMetrics {
+    fn update(&self, counters: &JournalCounters);
+}
+
+impl<F: Fn(&JournalCounters)> JournalMetrics for F {
+    fn update(&self, counters: &JournalCounters) {
+        self(counters)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JournalInfo {
+    pub journal_id: u64,
+    pub first_usn: i64,
+    pub next_usn: i64,
+    pub lowest_valid_usn: i64,
+    pub max_usn: i64,
+    pub max_size: u64,
+    pub allocation_delta: u64,
+    // How far behind the end of the journal we are, in bytes of USN space.
+    pub lag: i64,
+}
+
+// A point in a specific journal instance, which can be persisted to resume reading later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JournalCheckpoint {
+    pub journal_id: u64,
+    pub usn: i64,
+}
+
+// Glob patterns (`*` and `?`) matched case insensitively against the file names,
+// e.g. `*.docx`. An empty include list includes everything.
+// The records that don't match are skipped before resolving their paths,
+// note that this also affects rename matching when only one of the names matches.
+#[derive(Debug, Clone, Default)]
+pub struct NameFilter {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl NameFilter {
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    pub fn matches(&self, name: &OsStr) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+
+        let name = name.to_string_lossy();
+        let included = self.include.is_empty() || self.include.iter().any(|p| glob_match(p, &name));
+        included && !self.exclude.iter().any(|p| glob_match(p, &name))
+    }
+}
+
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().flat_map(char::to_lowercase).collect();
+    let name: Vec<char> = name.chars().flat_map(char::to_lowercase).collect();
+
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*` and the name position it was tried at.
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // Let the `*` match one more character.
+                Some((star, start)) => {
+                    backtrack = Some((star, start + 1));
+                    p = star + 1;
+                    n = start + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// What a record says happened to the file, from its reason bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChangeCategory {
+    // The data of the file or of its named streams.
+    ContentChanged,
+    // Attributes, times, extended attributes, compression, encryption, reparse points, etc.
+    MetadataChanged,
+    SecurityChanged,
+    // Renamed, moved, or a hard link added or removed.
+    NameChanged,
+    LifecycleCreate,
+    LifecycleDelete,
+}
+
+impl ChangeCategory {
+    pub const ALL: [ChangeCategory; 6] = [
+        ChangeCategory::ContentChanged,
+        ChangeCategory::MetadataChanged,
+        ChangeCategory::SecurityChanged,
+        ChangeCategory::NameChanged,
+        ChangeCategory::LifecycleCreate,
+        ChangeCategory::LifecycleDelete,
+    ];
+
+    // The reasons of the category, e.g. for `JournalOptions::reason_mask`.
+    pub fn reason_mask(self) -> u32 {
+        match self {
+            ChangeCategory::ContentChanged => {
+                Ioctl::USN_REASON_DATA_OVERWRITE
+                    | Ioctl::USN_REASON_DATA_EXTEND
+                    | Ioctl::USN_REASON_DATA_TRUNCATION
+                    | Ioctl::USN_REASON_NAMED_DATA_OVERWRITE
+                    | Ioctl::USN_REASON_NAMED_DATA_EXTEND
+                    | Ioctl::USN_REASON_NAMED_DATA_TRUNCATION
+                    | Ioctl::USN_REASON_STREAM_CHANGE
+            }
+            ChangeCategory::MetadataChanged => {
+                Ioctl::USN_REASON_BASIC_INFO_CHANGE
+                    | Ioctl::USN_REASON_EA_CHANGE
+                    | Ioctl::USN_REASON_COMPRESSION_CHANGE
+                    | Ioctl::USN_REASON_ENCRYPTION_CHANGE
+                    | Ioctl::USN_REASON_INDEXABLE_CHANGE
+                    | Ioctl::USN_REASON_INTEGRITY_CHANGE
+                    | Ioctl::USN_REASON_OBJECT_ID_CHANGE
+                    | Ioctl::USN_REASON_REPARSE_POINT_CHANGE
+                    | Ioctl::USN_REASON_DESIRED_STORAGE_CLASS_CHANGE
+                    | Ioctl::USN_REASON_TRANSACTED_CHANGE
+            }
+            ChangeCategory::SecurityChanged => Ioctl::USN_REASON_SECURITY_CHANGE,
+            ChangeCategory::NameChanged => {
+                Ioctl::USN_REASON_RENAME_OLD_NAME
+                    | Ioctl::USN_REASON_RENAME_NEW_NAME
+                    | Ioctl::USN_REASON_HARD_LINK_CHANGE
+            }
+            ChangeCategory::LifecycleCreate => Ioctl::USN_REASON_FILE_CREATE,
+            ChangeCategory::LifecycleDelete => Ioctl::USN_REASON_FILE_DELETE,
         }
     }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+// A set of categories, e.g. `ChangeCategory::ContentChanged | ChangeCategory::NameChanged`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct ChangeCategories(u8);
+
+impl ChangeCategories {
+    pub const NONE: ChangeCategories = ChangeCategories(0);
+    pub const ALL: ChangeCategories = ChangeCategories((1u8 << ChangeCategory::ALL.len()) - 1);
+
+    // The categories of a record, none for a close record without other reasons.
+    pub fn from_reason(reason: u32) -> Self {
+        ChangeCategory::ALL
+            .into_iter()
+            .filter(|category| reason & category.reason_mask() != 0)
+            .collect()
+    }
+
+    pub fn reason_mask(self) -> u32 {
+        self.iter()
+            .fold(0, |mask, category| mask | category.reason_mask())
+    }
+
+    pub fn contains(self, category: ChangeCategory) -> bool {
+        self.0 & category.bit() != 0
+    }
+
+    pub fn intersects(self, other: ChangeCategories) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub fn insert(&mut self, category: ChangeCategory) {
+        self.0 |= category.bit();
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = ChangeCategory> {
+        ChangeCategory::ALL
+            .into_iter()
+            .filter(move |category| self.contains(*category))
+    }
+}
+
+impl From<ChangeCategory> for ChangeCategories {
+    fn from(category: ChangeCategory) -> Self {
+        ChangeCategories(category.bit())
+    }
+}
+
+impl FromIterator<ChangeCategory> for ChangeCategories {
+    fn from_iter<I: IntoIterator<Item = ChangeCategory>>(iter: I) -> Self {
+        let mut categories = ChangeCategories::NONE;
+        for category in iter {
+            categories.insert(category);
+        }
+        categories
+    }
+}
+
+impl<C: Into<ChangeCategories>> BitOr<C> for ChangeCategory {
+    type Output = ChangeCategories;
+
+    fn bitor(self, other: C) -> ChangeCategories {
+        ChangeCategories::from(self) | other
+    }
+}
+
+impl<C: Into<ChangeCategories>> BitOr<C> for ChangeCategories {
+    type Output = ChangeCategories;
+
+    fn bitor(self, other: C) -> ChangeCategories {
+        ChangeCategories(self.0 | other.into().0)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -217,6 +940,9 @@ pub enum NextUsn {
     First,
     Next,
     Custom(i64),
+    // Fails if the journal was recreated or the records were already purged,
+    // since some changes would be missed.
+    Checkpoint(JournalCheckpoint),
 }
 
 #[derive(Debug, Clone)]
@@ -225,12 +951,50 @@ pub enum HistorySize {
     Limited(usize),
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct JournalSize {
+    pub max_size: u64,
+    pub allocation_delta: u64,
+}
+
+impl Default for JournalSize {
+    fn default() -> Self {
+        // Same defaults used by `fsutil usn createjournal`.
+        JournalSize {
+            max_size: 32 * 1024 * 1024,
+            allocation_delta: 8 * 1024 * 1024,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct JournalOptions {
     pub reason_mask: u32,
     pub next_usn: NextUsn,
     pub max_history_size: HistorySize,
+    // Drop the history records older than this, relative to the newest record.
+    pub max_history_age: Option<Duration>,
     pub version_range: (u16, u16),
+    pub path_format: PathFormat,
+    // Create the journal with the given size if it is not active on the volume.
+    pub create: Option<JournalSize>,
+    // Open the volume with reduced access and use FSCTL_READ_UNPRIVILEGED_USN_JOURNAL,
+    // so that the journal can be read without admin rights (see `Journal::open`).
+    // Paths of files the user can't open will contain only the file name.
+    pub unprivileged: bool,
+    // Resolving the full path of each record is the most expensive part of reading,
+    // disable it to get only the names and resolve the interesting ones with `Journal::resolve_path`.
+    pub resolve_paths: bool,
+    pub filter: NameFilter,
+    // Initial size of the read buffer, it grows when a record doesn't fit.
+    pub buffer_size: usize,
+    // How many parent directory paths to keep, zero to disable.
+    // Only used when the reason mask includes the rename and delete reasons,
+    // which are needed to know when the cached paths change.
+    pub parent_cache_size: usize,
+    // Fail the read with `JournalError::InvalidRecord` instead of skipping the records
+    // that can't be parsed. The other records of that batch are dropped too.
+    pub strict: bool,
 }
 
 impl Default for JournalOptions {
@@ -239,179 +1003,666 @@ impl Default for JournalOptions {
             reason_mask: 0xFFFFFFFF,
             next_usn: NextUsn::Next,
             max_history_size: HistorySize::Unlimited,
+            max_history_age: None,
             version_range: (2, 3),
+            path_format: PathFormat::default(),
+            create: None,
+            unprivileged: false,
+            resolve_paths: true,
+            filter: NameFilter::default(),
+            buffer_size: 4096,
+            parent_cache_size: 1024,
+            strict: false,
         }
     }
 }
 
 pub struct Journal {
-    volume: Volume,
+    volume: Option<Volume>,
+    root: PathBuf,
     volume_handle: Foundation::HANDLE,
     port: Foundation::HANDLE,
+    // Of the current read, see `begin_read`.
+    read_request: Box<Ioctl::READ_USN_JOURNAL_DATA_V1>,
+    overlapped: Box<IO::OVERLAPPED>,
     journal: Ioctl::USN_JOURNAL_DATA_V2,
     next_usn: i64,
     reason_mask: u32, // Ioctl::USN_REASON_FILE_CREATE
-    history: VecDeque<UsnRecord>,
+    // The latest record for each file id, and the order they were added in (for trimming).
+    // The order can contain stale entries for files that were forgotten or added again.
+    history: HashMap<u128, UsnRecord>,
+    history_order: VecDeque<(u128, i64)>,
     max_history_size: usize,
+    max_history_age: Option<Duration>,
     version_range: (u16, u16),
+    unprivileged: bool,
+    resolve_paths: bool,
+    filter: NameFilter,
+    buffer: Vec<u64>,
+    buffer_size: usize,
+    parent_cache: ParentCache,
+    resolver: Option<Box<dyn PathResolver + Send>>,
+    strict: bool,
+    parse_stats: ParseStats,
+    // Cell, since the paths are resolved while parsing.
+    counters: Cell<JournalCounters>,
+    metrics: Option<Box<dyn JournalMetrics + Send>>,
 }
 
+// The handles can be used from any thread.
+unsafe impl Send for Journal {}
+
 impl Journal {
     pub fn new(volume: Volume, options: JournalOptions) -> Result<Journal, std::io::Error> {
-        let volume_handle: Foundation::HANDLE;
+        let path = volume.path.clone();
+        let root = volume.root(options.path_format);
+        Self::open_impl(&path, root, Some(volume), options)
+    }
 
-        unsafe {
-            // Needs to be null terminated.
-            let path = CString::new(volume.path.to_str().unwrap()).unwrap();
-
-            volume_handle = FileSystem::CreateFileA(
-                PCSTR::from_raw(path.as_bytes_with_nul().as_ptr()),
-                (FileSystem::FILE_GENERIC_READ | FileSystem::FILE_GENERIC_WRITE).0,
-                FileSystem::FILE_SHARE_READ
-                    | FileSystem::FILE_SHARE_WRITE
-                    | FileSystem::FILE_SHARE_DELETE,
+    // Opens the journal without a `Volume`, which requires elevation to be created.
+    // Use together with `JournalOptions::unprivileged` when running without admin rights.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        options: JournalOptions,
+    ) -> Result<Journal, std::io::Error> {
+        let path = Volume::normalize_path(path.as_ref());
+        let root = Volume::root_of(&path, options.path_format);
+        Self::open_impl(&path, root, None, options)
+    }
+
+    fn open_impl(
+        path: &Path,
+        root: PathBuf,
+        volume: Option<Volume>,
+        options: JournalOptions,
+    ) -> Result<Journal, std::io::Error> {
+        let access = if options.unprivileged {
+            FileSystem::FILE_READ_ATTRIBUTES
+        } else {
+            FileSystem::FILE_GENERIC_READ | FileSystem::FILE_GENERIC_WRITE
+        };
+
+        let volume_handle = open_volume_handle(path, access, FileSystem::FILE_FLAG_OVERLAPPED)?;
+
+        let journal = match query_journal(volume_handle) {
+            Err(err) if is_win32_error(&err, ERROR_JOURNAL_NOT_ACTIVE) => match options.create {
+                Some(size) => {
+                    Self::create_at(path, size).and_then(|_| query_journal(volume_handle))
+                }
+                None => Err(err),
+            },
+            result => result,
+        };
+
+        let journal = match journal {
+            Ok(journal) => journal,
+            Err(err) => {
+                unsafe {
+                    let _ = Foundation::CloseHandle(volume_handle);
+                }
+                return Err(err);
+            }
+        };
+
+        let next_usn = match options.next_usn {
+            NextUsn::First => 0,
+            NextUsn::Next => journal.NextUsn,
+            NextUsn::Custom(usn) => usn,
+            NextUsn::Checkpoint(checkpoint) => {
+                if let Err(err) = validate_checkpoint(&journal, &checkpoint) {
+                    unsafe {
+                        let _ = Foundation::CloseHandle(volume_handle);
+                    }
+                    return Err(err);
+                }
+                checkpoint.usn
+            }
+        };
+
+        let invalidating_mask = Ioctl::USN_REASON_RENAME_OLD_NAME
+            | Ioctl::USN_REASON_RENAME_NEW_NAME
+            | Ioctl::USN_REASON_FILE_DELETE;
+        let parent_cache_size = if options.reason_mask & invalidating_mask == invalidating_mask {
+            options.parent_cache_size
+        } else {
+            0
+        };
+
+        let max_history_size = match options.max_history_size {
+            HistorySize::Unlimited => 0,
+            HistorySize::Limited(size) => size,
+        };
+
+        let port = match unsafe { IO::CreateIoCompletionPort(volume_handle, None, 0, 1) } {
+            Ok(port) => port,
+            Err(err) => {
+                unsafe {
+                    let _ = Foundation::CloseHandle(volume_handle);
+                }
+                return Err(win32("CreateIoCompletionPort")(err));
+            }
+        };
+
+        Ok(Journal {
+            volume,
+            root,
+            volume_handle,
+            port,
+            read_request: Box::default(),
+            overlapped: Box::default(),
+            journal,
+            next_usn,
+            reason_mask: options.reason_mask,
+            history: HashMap::new(),
+            history_order: VecDeque::new(),
+            max_history_size,
+            max_history_age: options.max_history_age,
+            version_range: options.version_range,
+            unprivileged: options.unprivileged,
+            resolve_paths: options.resolve_paths,
+            filter: options.filter,
+            buffer: Vec::new(),
+            buffer_size: options.buffer_size.max(size_of::<i64>()),
+            parent_cache: ParentCache::new(parent_cache_size),
+            resolver: None,
+            strict: options.strict,
+            parse_stats: ParseStats::default(),
+            counters: Cell::new(JournalCounters::default()),
+            metrics: None,
+        })
+    }
+
+    // Writes the close record for the file now, instead of when the last handle is closed,
+    // so that the changes are visible right away. Returns the usn of the record.
+    pub fn flush_close(&self, file_id: FileId) -> Result<i64, std::io::Error> {
+        let file_handle =
+            open_file_by_id(self.volume_handle, file_id, FileSystem::FILE_GENERIC_READ.0)
+                .map_err(win32("OpenFileById"))?;
+
+        let mut usn = 0i64;
+        let result = unsafe {
+            let mut ioctl_bytes_returned = 0;
+            IO::DeviceIoControl(
+                file_handle,
+                Ioctl::FSCTL_WRITE_USN_CLOSE_RECORD,
                 None,
-                FileSystem::OPEN_EXISTING,
-                FileSystem::FILE_FLAG_OVERLAPPED,
+                0,
+                Some(&mut usn as *mut _ as *mut c_void),
+                size_of::<i64>() as u32,
+                Some(&mut ioctl_bytes_returned),
                 None,
-            )?;
+            )
+        };
+
+        unsafe {
+            let _ = Foundation::CloseHandle(file_handle);
         }
 
-        let mut journal = Ioctl::USN_JOURNAL_DATA_V2::default();
+        result.map_err(win32("FSCTL_WRITE_USN_CLOSE_RECORD"))?;
+        Ok(usn)
+    }
+
+    // Cancels any pending read and closes the handles, the journal can't be read anymore.
+    // Also done on drop, calling it explicitly allows to check for errors.
+    pub fn close(&mut self) -> Result<(), std::io::Error> {
+        if self.volume_handle.is_invalid() {
+            return Ok(());
+        }
 
         unsafe {
+            // Fails if there is nothing to cancel.
+            let _ = IO::CancelIoEx(self.volume_handle, None);
+
+            let port = Foundation::CloseHandle(self.port);
+            let volume = Foundation::CloseHandle(self.volume_handle);
+
+            self.port = Foundation::HANDLE::default();
+            self.volume_handle = Foundation::HANDLE::default();
+
+            port.map_err(win32("CloseHandle"))?;
+            volume.map_err(win32("CloseHandle"))?;
+        }
+
+        Ok(())
+    }
+
+    // Creates the journal, or changes its size if it already exists.
+    pub fn create(volume: &Volume, size: JournalSize) -> Result<(), std::io::Error> {
+        Self::create_at(&volume.path, size)
+    }
+
+    fn create_at(path: &Path, size: JournalSize) -> Result<(), std::io::Error> {
+        let volume_handle = open_volume_handle(
+            path,
+            FileSystem::FILE_GENERIC_READ | FileSystem::FILE_GENERIC_WRITE,
+            FileSystem::FILE_FLAGS_AND_ATTRIBUTES(0),
+        )?;
+
+        let create_data = Ioctl::CREATE_USN_JOURNAL_DATA {
+            MaximumSize: size.max_size,
+            AllocationDelta: size.allocation_delta,
+        };
+
+        let result = unsafe {
             let mut ioctl_bytes_returned = 0;
             IO::DeviceIoControl(
                 volume_handle,
-                Ioctl::FSCTL_QUERY_USN_JOURNAL,
+                Ioctl::FSCTL_CREATE_USN_JOURNAL,
+                Some(&create_data as *const _ as *const c_void),
+                size_of::<Ioctl::CREATE_USN_JOURNAL_DATA>() as u32,
                 None,
                 0,
-                Some(&mut journal as *mut _ as *mut c_void),
-                size_of::<Ioctl::USN_JOURNAL_DATA_V2>() as u32,
                 Some(&mut ioctl_bytes_returned),
                 None,
-            )?;
+            )
+        };
+
+        unsafe {
+            let _ = Foundation::CloseHandle(volume_handle);
         }
 
-        let next_usn = match options.next_usn {
-            NextUsn::First => 0,
-            NextUsn::Next => journal.NextUsn,
-            NextUsn::Custom(usn) => usn,
+        result.map_err(win32("FSCTL_CREATE_USN_JOURNAL"))
+    }
+
+    // Deletes the journal. Deletion happens in the background,
+    // if `wait` is set this blocks until it is complete.
+    pub fn delete(volume: &Volume, wait: bool) -> Result<(), std::io::Error> {
+        let volume_handle = open_volume_handle(
+            &volume.path,
+            FileSystem::FILE_GENERIC_READ | FileSystem::FILE_GENERIC_WRITE,
+            FileSystem::FILE_FLAGS_AND_ATTRIBUTES(0),
+        )?;
+
+        let result = query_journal(volume_handle).and_then(|journal| {
+            let mut flags = Ioctl::USN_DELETE_FLAG_DELETE;
+            if wait {
+                flags |= Ioctl::USN_DELETE_FLAG_NOTIFY;
+            }
+
+            let delete_data = Ioctl::DELETE_USN_JOURNAL_DATA {
+                UsnJournalID: journal.UsnJournalID,
+                DeleteFlags: flags,
+            };
+
+            unsafe {
+                let mut ioctl_bytes_returned = 0;
+                IO::DeviceIoControl(
+                    volume_handle,
+                    Ioctl::FSCTL_DELETE_USN_JOURNAL,
+                    Some(&delete_data as *const _ as *const c_void),
+                    size_of::<Ioctl::DELETE_USN_JOURNAL_DATA>() as u32,
+                    None,
+                    0,
+                    Some(&mut ioctl_bytes_returned),
+                    None,
+                )
+                .map_err(win32("FSCTL_DELETE_USN_JOURNAL"))?;
+            }
+
+            Ok(())
+        });
+
+        unsafe {
+            let _ = Foundation::CloseHandle(volume_handle);
+        }
+
+        result
+    }
+
+    pub fn read(&mut self) -> Result<Vec<UsnRecord>, std::io::Error> {
+        self.read_impl(self.buffer_size, 0, 0)
+    }
+
+    // Iterate the records until the end of the journal, reading more as needed.
+    // Can be called again later to get the new records.
+    pub fn records(&mut self) -> JournalRecords<'_> {
+        JournalRecords {
+            journal: self,
+            pending: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    // Block until new data is added to the journal or the timeout expires (`None` waits forever).
+    // Note that it can still return no records, since they are filtered after waking up.
+    pub fn read_wait(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<UsnRecord>, std::io::Error> {
+        let (bytes_to_wait_for, timeout) = wait_params(timeout);
+        self.read_impl(self.buffer_size, bytes_to_wait_for, timeout)
+    }
+
+    pub fn read_sized<const BUFFER_SIZE: usize>(
+        &mut self,
+    ) -> Result<Vec<UsnRecord>, std::io::Error> {
+        self.read_impl(BUFFER_SIZE, 0, 0)
+    }
+
+    pub fn read_sized_wait<const BUFFER_SIZE: usize>(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<UsnRecord>, std::io::Error> {
+        let (bytes_to_wait_for, timeout) = wait_params(timeout);
+        self.read_impl(BUFFER_SIZE, bytes_to_wait_for, timeout)
+    }
+
+    fn read_operation(&self) -> (u32, &'static str) {
+        if self.unprivileged {
+            (
+                Ioctl::FSCTL_READ_UNPRIVILEGED_USN_JOURNAL,
+                "FSCTL_READ_UNPRIVILEGED_USN_JOURNAL",
+            )
+        } else {
+            (Ioctl::FSCTL_READ_USN_JOURNAL, "FSCTL_READ_USN_JOURNAL")
+        }
+    }
+
+    // Starts an overlapped read from `next_usn` into `self.buffer`. Unless this fails, the
+    // completion is queued on the port also when the read completes right away, and the buffer
+    // must be left alone until it is dequeued (see `dequeue_completion` and `end_read`).
+    pub(crate) fn begin_read(
+        &mut self,
+        buffer_size: usize,
+        bytes_to_wait_for: u64,
+        timeout: u64,
+    ) -> Result<(), std::io::Error> {
+        // Kept as u64 so that the records are aligned.
+        let mut buffer = std::mem::take(&mut self.buffer);
+        buffer.resize(buffer_size.div_ceil(8), 0);
+
+        let result = self.issue_read(
+            as_bytes_mut(&mut buffer),
+            self.next_usn,
+            self.reason_mask,
+            bytes_to_wait_for,
+            timeout,
+        );
+        // The data is not moved.
+        self.buffer = buffer;
+        result
+    }
+
+    // Returns the number of bytes written to the buffer.
+    fn read_journal_data(
+        &mut self,
+        buffer: &mut [u8],
+        start_usn: i64,
+        reason_mask: u32,
+    ) -> Result<u32, std::io::Error> {
+        let (port, operation) = self.completion_port();
+        self.issue_read(buffer, start_usn, reason_mask, 0, 0)?;
+        dequeue_completion(port, operation)?.1
+    }
+
+    fn issue_read(
+        &mut self,
+        buffer: &mut [u8],
+        start_usn: i64,
+        reason_mask: u32,
+        bytes_to_wait_for: u64,
+        timeout: u64,
+    ) -> Result<(), std::io::Error> {
+        let (control_code, operation) = self.read_operation();
+
+        // Both boxed, they must not move while the read is pending.
+        *self.read_request = Ioctl::READ_USN_JOURNAL_DATA_V1 {
+            StartUsn: start_usn,
+            ReasonMask: reason_mask,
+            ReturnOnlyOnClose: 0,
+            Timeout: timeout,
+            BytesToWaitFor: bytes_to_wait_for,
+            UsnJournalID: self.journal.UsnJournalID,
+            MinMajorVersion: u16::max(self.version_range.0, self.journal.MinSupportedMajorVersion),
+            MaxMajorVersion: u16::min(self.version_range.1, self.journal.MaxSupportedMajorVersion),
         };
+        *self.overlapped = IO::OVERLAPPED::default();
 
-        let max_history_size = match options.max_history_size {
-            HistorySize::Unlimited => 0,
-            HistorySize::Limited(size) => size,
+        let result = unsafe {
+            IO::DeviceIoControl(
+                self.volume_handle,
+                control_code,
+                Some(&mut *self.read_request as *mut _ as *mut c_void),
+                size_of::<Ioctl::READ_USN_JOURNAL_DATA_V1>() as u32,
+                Some(buffer.as_mut_ptr() as *mut c_void),
+                buffer.len() as u32,
+                None,
+                Some(&mut *self.overlapped),
+            )
+        };
+
+        match result {
+            // Pending when waiting for new data.
+            Err(err) if err.code() != ERROR_IO_PENDING.to_hresult() => Err(win32(operation)(err)),
+            _ => Ok(()),
+        }
+    }
+
+    // After the read started by `begin_read` completed with `result` (the bytes written).
+    pub(crate) fn end_read(
+        &mut self,
+        result: Result<u32, std::io::Error>,
+    ) -> Result<ReadEnd, std::io::Error> {
+        let bytes_returned = match result {
+            Ok(bytes_returned) => bytes_returned as usize,
+            Err(err)
+                if (is_win32_error(&err, ERROR_INSUFFICIENT_BUFFER)
+                    || is_win32_error(&err, ERROR_MORE_DATA))
+                    && as_bytes(&self.buffer).len() < MAX_BUFFER_SIZE =>
+            {
+                let buffer_size = (as_bytes(&self.buffer).len() * 2).min(MAX_BUFFER_SIZE);
+                self.buffer_size = self.buffer_size.max(buffer_size);
+                return Ok(ReadEnd::Retry(buffer_size));
+            }
+            Err(err) => return Err(self.journal_error(err)),
         };
 
-        let port = unsafe { IO::CreateIoCompletionPort(volume_handle, None, 0, 1)? };
+        if bytes_returned < size_of::<i64>() {
+            return Ok(ReadEnd::Bytes(0));
+        }
+
+        let next_usn = i64::from_le_bytes(as_bytes(&self.buffer)[0..8].try_into().unwrap());
+        if next_usn == 0 || next_usn < self.next_usn {
+            return Ok(ReadEnd::Bytes(0));
+        }
+
+        self.next_usn = next_usn;
+        self.count(|c| c.bytes_consumed += (bytes_returned - size_of::<i64>()) as u64);
+        Ok(ReadEnd::Bytes(bytes_returned))
+    }
+
+    // Cancels the read started by `begin_read`, its completion is still queued.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn cancel_read(&self) {
+        unsafe {
+            // Fails if it already completed.
+            let _ = IO::CancelIoEx(self.volume_handle, Some(&*self.overlapped));
+        }
+    }
 
-        Ok(Journal {
-            volume,
-            volume_handle,
-            port,
-            journal,
-            next_usn,
-            reason_mask: options.reason_mask,
-            history: VecDeque::new(),
-            max_history_size,
-            version_range: options.version_range,
-        })
+    // Grown when a record didn't fit.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn buffer_size(&self) -> usize {
+        self.buffer_size
     }
 
-    pub fn read(&mut self) -> Result<Vec<UsnRecord>, std::io::Error> {
-        self.read_sized::<4096>()
+    pub(crate) fn completion_port(&self) -> (Foundation::HANDLE, &'static str) {
+        (self.port, self.read_operation().1)
     }
 
-    pub fn read_sized<const BUFFER_SIZE: usize>(
+    // Parses the `len` bytes of the last read, from `end_read`.
+    pub(crate) fn take_records(&mut self, len: usize) -> Result<Vec<UsnRecord>, std::io::Error> {
+        let buffer = std::mem::take(&mut self.buffer);
+        let results = self.parse_records(&as_bytes(&buffer)[..len]);
+        self.buffer = buffer;
+
+        self.report_metrics();
+        results
+    }
+
+    fn read_impl(
         &mut self,
+        buffer_size: usize,
+        bytes_to_wait_for: u64,
+        timeout: u64,
     ) -> Result<Vec<UsnRecord>, std::io::Error> {
-        let mut results = Vec::<UsnRecord>::new();
+        let len = self.fill_buffer(buffer_size, bytes_to_wait_for, timeout)?;
+        self.take_records(len)
+    }
 
-        let mut read = Ioctl::READ_USN_JOURNAL_DATA_V1 {
-            StartUsn: self.next_usn,
-            ReasonMask: self.reason_mask,
-            ReturnOnlyOnClose: 0,
-            Timeout: 0,
-            BytesToWaitFor: 0,
-            UsnJournalID: self.journal.UsnJournalID,
-            MinMajorVersion: u16::max(self.version_range.0, self.journal.MinSupportedMajorVersion),
-            MaxMajorVersion: u16::min(self.version_range.1, self.journal.MaxSupportedMajorVersion),
+    fn count(&self, update: impl FnOnce(&mut JournalCounters)) {
+        let mut counters = self.counters.get();
+        update(&mut counters);
+        self.counters.set(counters);
+    }
+
+    fn report_metrics(&self) {
+        let Some(metrics) = &self.metrics else {
+            return;
         };
+        if let Ok(journal) = query_journal(self.volume_handle) {
+            self.count(|c| c.lag = (journal.NextUsn - self.next_usn).max(0));
+        }
+        metrics.update(&self.counters.get());
+    }
+
+    // Reads into `self.buffer`, growing it when it can't hold the next record.
+    // Returns the number of bytes to parse, zero if there are no new records.
+    fn fill_buffer(
+        &mut self,
+        mut buffer_size: usize,
+        bytes_to_wait_for: u64,
+        timeout: u64,
+    ) -> Result<usize, std::io::Error> {
+        let (port, operation) = self.completion_port();
+
+        loop {
+            let result = self
+                .begin_read(buffer_size, bytes_to_wait_for, timeout)
+                .and_then(|_| dequeue_completion(port, operation)?.1);
+
+            match self.end_read(result)? {
+                ReadEnd::Bytes(len) => return Ok(len),
+                ReadEnd::Retry(size) => buffer_size = size,
+            }
+        }
+    }
 
-        let mut buffer = AlignedBuffer::<BUFFER_SIZE>([0u8; BUFFER_SIZE]);
+    // Reads the next batch without converting the records, which are borrowed from the buffer.
+    // The names and paths are not resolved, and the filters and history are not applied
+    // (only the bytes are counted).
+    pub fn read_raw(&mut self) -> Result<RawUsnRecords<'_>, std::io::Error> {
+        let len = self.fill_buffer(self.buffer_size, 0, 0)?;
+        self.report_metrics();
+        Ok(RawUsnRecords {
+            data: &as_bytes(&self.buffer)[..len],
+            offset: size_of::<i64>(),
+        })
+    }
 
-        let mut bytes_returned = 0;
-        let mut overlapped = IO::OVERLAPPED {
-            ..Default::default()
-        };
+    // Turns the errors for lost records into a `JournalError`.
+    fn journal_error(&self, err: std::io::Error) -> std::io::Error {
+        let lost = is_win32_error(&err, ERROR_JOURNAL_ENTRY_DELETED)
+            || is_win32_error(&err, ERROR_JOURNAL_NOT_ACTIVE)
+            || is_win32_error(&err, ERROR_JOURNAL_DELETE_IN_PROGRESS);
+        if !lost {
+            return err;
+        }
 
-        unsafe {
-            IO::DeviceIoControl(
-                self.volume_handle,
-                Ioctl::FSCTL_READ_USN_JOURNAL,
-                Some(&mut read as *mut _ as *mut c_void),
-                size_of::<Ioctl::READ_USN_JOURNAL_DATA_V1>() as u32,
-                Some(&mut buffer as *mut _ as *mut c_void),
-                BUFFER_SIZE as u32,
-                Some(&mut bytes_returned),
-                Some(&mut overlapped),
-            )?;
-
-            // NOTE: Switched to overlapped IO while investigating a bug,
-            // but it's not needed (we just wait immediately anyway).
-
-            // Wait for the operation to complete.
-            let mut key = 0usize;
-            let mut overlapped = 0 as *mut _;
-            GetQueuedCompletionStatus(
-                self.port,
-                &mut bytes_returned,
-                &mut key,
-                &mut overlapped,
-                INFINITE,
-            )?;
-        }
-
-        let next_usn = i64::from_le_bytes(buffer.0[0..8].try_into().unwrap());
-        if next_usn == 0 || next_usn < self.next_usn {
-            return Ok(results);
+        match query_journal(self.volume_handle) {
+            Ok(journal) if journal.UsnJournalID == self.journal.UsnJournalID => {
+                JournalError::EntryDeleted {
+                    lowest_valid_usn: journal.LowestValidUsn,
+                }
+            }
+            Ok(journal) => JournalError::Recreated {
+                journal_id: journal.UsnJournalID,
+                lowest_valid_usn: journal.LowestValidUsn,
+            },
+            Err(_) => JournalError::Recreated {
+                journal_id: 0,
+                lowest_valid_usn: 0,
+            },
+        }
+        .into()
+    }
+
+    // After a `JournalError`, continue from the oldest records still available.
+    // Returns the new position.
+    pub fn resync(&mut self) -> Result<i64, std::io::Error> {
+        let journal = query_journal(self.volume_handle)?;
+
+        if journal.UsnJournalID == self.journal.UsnJournalID {
+            self.next_usn = i64::max(self.next_usn, journal.LowestValidUsn);
         } else {
-            self.next_usn = next_usn;
+            // The old records can't be matched anymore.
+            self.trim_history(None);
+            self.next_usn = journal.LowestValidUsn;
         }
 
+        self.parent_cache.clear();
+        self.journal = journal;
+        Ok(self.next_usn)
+    }
+
+    fn parse_records(&mut self, buffer: &[u8]) -> Result<Vec<UsnRecord>, std::io::Error> {
+        let mut results = Vec::<UsnRecord>::new();
+
         let mut offset = 8; // sizeof(USN)
-        while offset < bytes_returned {
-            let (record_len, record) = unsafe {
-                let record_ptr = std::mem::transmute::<*const u8, *const Ioctl::USN_RECORD_UNION>(
-                    buffer.0[offset as usize..].as_ptr(),
-                );
-
-                let record_len = (*record_ptr).Header.RecordLength;
-                if record_len == 0 {
-                    break;
+        while offset < buffer.len() {
+            let remaining = &buffer[offset..];
+            let record_len = match remaining.get(0..4) {
+                Some(header) => u32::from_le_bytes(header.try_into().unwrap()) as usize,
+                None => remaining.len(),
+            };
+            if record_len == 0 {
+                break;
+            }
+
+            let data = remaining.get(..record_len).unwrap_or(remaining);
+            let record = match check_record(data) {
+                Ok(major_version) => unsafe {
+                    match major_version {
+                        2 => UsnRecord::from_v2(
+                            self,
+                            &*(data.as_ptr() as *const Ioctl::USN_RECORD_V2),
+                        ),
+                        _ => UsnRecord::from_v3(
+                            self,
+                            &*(data.as_ptr() as *const Ioctl::USN_RECORD_V3),
+                        ),
+                    }
+                },
+                Err(issue) => {
+                    // The following records can't be found when the length is wrong.
+                    let skipped = match issue {
+                        ParseIssue::Truncated => remaining.len(),
+                        ParseIssue::UnknownVersion(_) => record_len,
+                    };
+                    self.parse_stats.add(issue, skipped);
+                    if self.strict {
+                        return Err(JournalError::InvalidRecord(issue).into());
+                    }
+                    offset += skipped;
+                    continue;
                 }
+            };
 
-                let record = match (*record_ptr).Header.MajorVersion {
-                    2 => Some(UsnRecord::from_v2(&self, &(*record_ptr).V2)),
-                    3 => Some(UsnRecord::from_v3(&self, &(*record_ptr).V3)),
-                    _ => None,
-                };
+            self.count(|c| c.records_read += 1);
+            if record.is_none() {
+                self.count(|c| c.records_filtered += 1);
+            }
 
-                (record_len, record)
-            };
+            if let Some(mut record) = record {
+                record.rename_from = self.match_rename(&record);
 
-            if let Some(record) = record {
                 if record.reason
                     & (Ioctl::USN_REASON_RENAME_OLD_NAME
                         | Ioctl::USN_REASON_HARD_LINK_CHANGE
                         | Ioctl::USN_REASON_REPARSE_POINT_CHANGE)
                     != 0
                 {
-                    if self.max_history_size > 0 && self.history.len() >= self.max_history_size {
-                        self.history.pop_front();
-                    }
-                    self.history.push_back(record.clone());
+                    self.add_history(record.clone());
                 }
 
                 results.push(record);
@@ -423,32 +1674,335 @@ impl Journal {
         Ok(results)
     }
 
+    // Counts the records skipped because they could not be parsed,
+    // so that gaps in the events can be detected.
+    pub fn parse_stats(&self) -> ParseStats {
+        self.parse_stats
+    }
+
+    pub fn counters(&self) -> JournalCounters {
+        self.counters.get()
+    }
+
+    // Called after each read with the counters. Also queries the journal for the lag,
+    // which is one more system call per read.
+    pub fn set_metrics<M: JournalMetrics + Send + 'static>(&mut self, metrics: M) {
+        self.metrics = Some(Box::new(metrics));
+    }
+
+    pub fn clear_metrics(&mut self) {
+        self.metrics = None;
+    }
+
     pub fn match_rename(&self, record: &UsnRecord) -> Option<PathBuf> {
         if record.reason & Ioctl::USN_REASON_RENAME_NEW_NAME == 0 {
             return None;
         }
 
-        match self
-            .history
-            .iter()
-            .find(|r| r.file_id == record.file_id && r.usn < record.usn)
-        {
-            Some(r) => Some(r.path.clone()),
-            None => None,
+        self.history
+            .get(&record.file_id.key())
+            .filter(|r| r.usn < record.usn)
+            .map(|r| r.path.clone())
+    }
+
+    fn add_history(&mut self, record: UsnRecord) {
+        let key = record.file_id.key();
+
+        if self.max_history_size > 0 {
+            while self.history.len() >= self.max_history_size && !self.history.contains_key(&key) {
+                let Some((old_key, old_usn)) = self.history_order.pop_front() else {
+                    break;
+                };
+                if self.history.get(&old_key).is_some_and(|r| r.usn == old_usn) {
+                    self.history.remove(&old_key);
+                }
+            }
+        }
+
+        if let Some(max_age) = self.max_history_age {
+            while let Some((old_key, old_usn)) = self.history_order.front() {
+                match self.history.get(old_key) {
+                    // Still recent enough, the following ones are newer.
+                    Some(r) if r.usn == *old_usn && r.timestamp + max_age >= record.timestamp => {
+                        break
+                    }
+                    Some(r) if r.usn == *old_usn => {
+                        self.history.remove(old_key);
+                    }
+                    _ => {}
+                }
+                self.history_order.pop_front();
+            }
+        }
+
+        self.history_order.push_back((key, record.usn));
+        self.history.insert(key, record);
+
+        // Drop the stale entries once they are the majority.
+        if self.history_order.len() > 2 * self.history.len() + 64 {
+            let history = &self.history;
+            self.history_order
+                .retain(|(key, usn)| history.get(key).is_some_and(|r| r.usn == *usn));
         }
     }
 
+    pub fn forget_history(&mut self, file_id: FileId) {
+        self.history.remove(&file_id.key());
+    }
+
     pub fn trim_history(&mut self, min_usn: Option<i64>) {
         match min_usn {
-            Some(usn) => self.history.retain(|r| r.usn > usn),
-            None => self.history.clear(),
+            Some(usn) => {
+                self.history.retain(|_, r| r.usn > usn);
+                self.history_order.retain(|(_, r_usn)| *r_usn > usn);
+            }
+            None => {
+                self.history.clear();
+                self.history_order.clear();
+            }
+        }
+    }
+
+    // The records used to match the renames, oldest first.
+    pub fn history(&self) -> impl Iterator<Item = &UsnRecord> + '_ {
+        self.history_order
+            .iter()
+            .filter_map(|(key, usn)| self.history.get(key).filter(|r| r.usn == *usn))
+    }
+
+    // Adds records (e.g. from `history` of a previous instance) to match the renames against,
+    // subject to the same limits as the records read.
+    pub fn import_history<I: IntoIterator<Item = UsnRecord>>(&mut self, records: I) {
+        for record in records {
+            // Already there, or replaced since.
+            let newer = self.history.get(&record.file_id.key());
+            if newer.is_none_or(|r| r.usn < record.usn) {
+                self.add_history(record);
+            }
+        }
+    }
+
+    // Together with a checkpoint, so that a rename whose old name was read before a restart
+    // can still be matched after it, see `load_history`.
+    pub fn save_history<W: Write>(&self, writer: &mut W) -> Result<(), std::io::Error> {
+        writer.write_all(HISTORY_MAGIC)?;
+        writer.write_all(&HISTORY_VERSION.to_le_bytes())?;
+        writer.write_all(&self.journal.UsnJournalID.to_le_bytes())?;
+        let records: Vec<&UsnRecord> = self.history().collect();
+        writer.write_all(&(records.len() as u64).to_le_bytes())?;
+
+        for record in records {
+            record.write_to(writer)?;
+        }
+
+        Ok(())
+    }
+
+    // Fails if the history was saved for another journal instance, as the usns are not
+    // comparable. Returns how many records were loaded.
+    pub fn load_history<R: Read>(&mut self, reader: &mut R) -> Result<usize, std::io::Error> {
+        let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+
+        if &read_array::<_, 4>(reader)? != HISTORY_MAGIC {
+            return Err(invalid("Not a rename history".to_string()));
+        }
+        if u32::from_le_bytes(read_array(reader)?) != HISTORY_VERSION {
+            return Err(invalid("Unsupported rename history version".to_string()));
+        }
+        let journal_id = u64::from_le_bytes(read_array(reader)?);
+        if journal_id != self.journal.UsnJournalID {
+            return Err(invalid(format!(
+                "The journal id changed ({:#x} -> {:#x})",
+                journal_id, self.journal.UsnJournalID
+            )));
+        }
+
+        let count = u64::from_le_bytes(read_array(reader)?);
+        let mut records = Vec::new();
+        for _ in 0..count {
+            records.push(UsnRecord::read_from(reader)?);
+        }
+
+        // Only once the whole history was read, to not load part of it.
+        let loaded = records.len();
+        self.import_history(records);
+        Ok(loaded)
+    }
+
+    pub fn seek_to_usn(&mut self, usn: i64) -> Result<(), std::io::Error> {
+        let journal = query_journal(self.volume_handle)?;
+
+        if usn < journal.FirstUsn || usn > journal.NextUsn {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "The usn {} is out of the journal range ({}..{})",
+                    usn, journal.FirstUsn, journal.NextUsn
+                ),
+            ));
+        }
+
+        // The skipped records could have changed the cached paths.
+        self.parent_cache.clear();
+        self.next_usn = usn;
+        Ok(())
+    }
+
+    // Moves the cursor close to the first record at or after `time`, so that reading
+    // returns the records from then on (and possibly a few older ones, check the timestamps).
+    // Binary searches the journal pages, which are written in chronological order.
+    // Returns the new position.
+    pub fn seek_to_time(&mut self, time: SystemTime) -> Result<i64, std::io::Error> {
+        let since_unix = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let target = EPOCH_DIFFERENCE as i64 + (since_unix.as_nanos() / 100) as i64;
+
+        let journal = query_journal(self.volume_handle)?;
+        if journal.NextUsn <= journal.FirstUsn {
+            self.next_usn = journal.NextUsn;
+            return Ok(self.next_usn);
+        }
+
+        let first_page = journal.FirstUsn / JOURNAL_PAGE_SIZE;
+        let end_page = (journal.NextUsn - 1) / JOURNAL_PAGE_SIZE + 1;
+        let page_usn = |page: i64| i64::max(page * JOURNAL_PAGE_SIZE, journal.FirstUsn);
+
+        // Find the first page starting at or after the target.
+        let mut buffer = vec![0u64; JOURNAL_PAGE_SIZE as usize / 8];
+        let (mut low, mut high) = (first_page, end_page);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.first_record_time(&mut buffer, page_usn(mid))? {
+                Some(timestamp) if timestamp < target => low = mid + 1,
+                _ => high = mid,
+            }
+        }
+
+        self.parent_cache.clear();
+
+        // The previous page can still contain records after the target.
+        self.next_usn = page_usn(i64::max(low - 1, first_page));
+        Ok(self.next_usn)
+    }
+
+    fn first_record_time(
+        &mut self,
+        buffer: &mut [u64],
+        usn: i64,
+    ) -> Result<Option<i64>, std::io::Error> {
+        let bytes = as_bytes_mut(buffer);
+        let bytes_returned = self.read_journal_data(bytes, usn, 0xFFFFFFFF)? as usize;
+        if bytes_returned <= size_of::<i64>() {
+            return Ok(None);
         }
+
+        unsafe {
+            let record = bytes[size_of::<i64>()..].as_ptr() as *const Ioctl::USN_RECORD_UNION;
+            Ok(match (*record).Header.MajorVersion {
+                2 => Some((*record).V2.TimeStamp),
+                3 => Some((*record).V3.TimeStamp),
+                _ => None,
+            })
+        }
+    }
+
+    // Queries the journal again, so the values reflect its current state.
+    pub fn info(&self) -> Result<JournalInfo, std::io::Error> {
+        let journal = query_journal(self.volume_handle)?;
+
+        Ok(JournalInfo {
+            journal_id: journal.UsnJournalID,
+            first_usn: journal.FirstUsn,
+            next_usn: journal.NextUsn,
+            lowest_valid_usn: journal.LowestValidUsn,
+            max_usn: journal.MaxUsn,
+            max_size: journal.MaximumSize,
+            allocation_delta: journal.AllocationDelta,
+            lag: (journal.NextUsn - self.next_usn).max(0),
+        })
+    }
+
+    // The full path of the record, for when `JournalOptions::resolve_paths` is disabled.
+    pub fn resolve_path(&self, record: &UsnRecord) -> PathBuf {
+        self.get_path(&record.name, record.file_id, record.parent_id)
+    }
+
+    fn record_path(&self, name: &OsStr, file_id: FileId, parent_id: FileId) -> PathBuf {
+        if !self.resolve_paths {
+            return PathBuf::from(name);
+        }
+
+        self.get_path(name, file_id, parent_id)
+    }
+
+    // Only the name when the path can't be resolved.
+    fn get_path(&self, name: &OsStr, file_id: FileId, parent_id: FileId) -> PathBuf {
+        let path = get_usn_record_path(
+            &self.root,
+            self.volume_handle,
+            self.resolver.as_deref(),
+            &self.parent_cache,
+            name,
+            file_id,
+            parent_id,
+        );
+
+        self.count(|c| {
+            c.path_resolutions += 1;
+            c.resolution_failures += path.is_none() as u64;
+        });
+        path.unwrap_or_else(|| PathBuf::from(name))
+    }
+
+    // The size, times, etc. of the record file as of when the MFT was read,
+    // call `Mft::reload_record` before to get the current values.
+    pub fn enrich(&self, record: &UsnRecord, mft: &Mft) -> Option<FileInfo> {
+        let reference = record.file_id.reference();
+        let number = reference_number(reference);
+        if number >= mft.max_record {
+            return None;
+        }
+
+        // The record was reused for another file.
+        let file = mft.get_record(number)?;
+        if file.reference() != reference {
+            return None;
+        }
+
+        Some(FileInfo::new(mft, &file))
+    }
+
+    // Tried first for the parent directory, falling back to OpenFileById when it fails.
+    pub fn set_path_resolver<R: PathResolver + Send + 'static>(&mut self, resolver: R) {
+        self.resolver = Some(Box::new(resolver));
+    }
+
+    pub fn clear_path_resolver(&mut self) {
+        self.resolver = None;
     }
 
+    // The usn the next read starts from.
     pub fn get_next_usn(&self) -> i64 {
         self.next_usn
     }
 
+    // Persist this and pass it back with `NextUsn::Checkpoint` to resume from the same point.
+    pub fn checkpoint(&self) -> JournalCheckpoint {
+        JournalCheckpoint {
+            journal_id: self.journal.UsnJournalID,
+            usn: self.next_usn,
+        }
+    }
+
+    // What the paths of the records are relative to, in the `JournalOptions::path_format`.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // Not available when opened with `Journal::open`.
+    pub fn get_volume(&self) -> Option<&Volume> {
+        self.volume.as_ref()
+    }
+
     pub fn get_reason_str(reason: u32) -> String {
         let mut reason_str = String::new();
 
@@ -529,12 +2083,154 @@ impl Journal {
     }
 }
 
+// A record borrowed from the read buffer, see `Journal::read_raw`.
+// The accessors return `None` for the versions without that field.
+#[derive(Clone, Copy)]
+pub struct RawUsnRecord<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> RawUsnRecord<'a> {
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn major_version(&self) -> u16 {
+        u16::from_le_bytes(self.data[4..6].try_into().unwrap())
+    }
+
+    pub fn as_v2(&self) -> Option<&'a Ioctl::USN_RECORD_V2> {
+        (check_record(self.data) == Ok(2))
+            .then(|| unsafe { &*(self.data.as_ptr() as *const Ioctl::USN_RECORD_V2) })
+    }
+
+    pub fn as_v3(&self) -> Option<&'a Ioctl::USN_RECORD_V3> {
+        (check_record(self.data) == Ok(3))
+            .then(|| unsafe { &*(self.data.as_ptr() as *const Ioctl::USN_RECORD_V3) })
+    }
+
+    pub fn usn(&self) -> Option<i64> {
+        self.as_v2()
+            .map(|r| r.Usn)
+            .or_else(|| self.as_v3().map(|r| r.Usn))
+    }
+
+    pub fn timestamp(&self) -> Option<OffsetDateTime> {
+        self.filetime().map(get_usn_record_time)
+    }
+
+    pub fn filetime(&self) -> Option<i64> {
+        self.as_v2()
+            .map(|r| r.TimeStamp)
+            .or_else(|| self.as_v3().map(|r| r.TimeStamp))
+    }
+
+    pub fn reason(&self) -> Option<u32> {
+        self.as_v2()
+            .map(|r| r.Reason)
+            .or_else(|| self.as_v3().map(|r| r.Reason))
+    }
+
+    pub fn file_id(&self) -> Option<FileId> {
+        self.as_v2()
+            .map(|r| FileId::Normal(r.FileReferenceNumber))
+            .or_else(|| {
+                self.as_v3()
+                    .map(|r| FileId::Extended(r.FileReferenceNumber))
+            })
+    }
+
+    pub fn parent_id(&self) -> Option<FileId> {
+        self.as_v2()
+            .map(|r| FileId::Normal(r.ParentFileReferenceNumber))
+            .or_else(|| {
+                self.as_v3()
+                    .map(|r| FileId::Extended(r.ParentFileReferenceNumber))
+            })
+    }
+
+    pub fn name_wide(&self) -> Option<&'a [u16]> {
+        let (offset, length) = self
+            .as_v2()
+            .map(|r| (r.FileNameOffset, r.FileNameLength))
+            .or_else(|| self.as_v3().map(|r| (r.FileNameOffset, r.FileNameLength)))?;
+
+        let start = offset as usize;
+        let name = self.data.get(start..start + length as usize)?;
+        // Viewed in place as UTF-16, which needs the alignment.
+        if name.as_ptr().align_offset(2) != 0 {
+            return None;
+        }
+        Some(unsafe { std::slice::from_raw_parts(name.as_ptr() as *const u16, name.len() / 2) })
+    }
+
+    pub fn name(&self) -> Option<OsString> {
+        self.name_wide().map(OsString::from_wide)
+    }
+}
+
+pub struct RawUsnRecords<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for RawUsnRecords<'a> {
+    type Item = RawUsnRecord<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let header = self.data.get(self.offset..self.offset + 4)?;
+        let record_len = u32::from_le_bytes(header.try_into().unwrap()) as usize;
+        if record_len < 8 || self.offset + record_len > self.data.len() {
+            return None;
+        }
+
+        let data = &self.data[self.offset..self.offset + record_len];
+        self.offset += record_len;
+        Some(RawUsnRecord { data })
+    }
+}
+
+pub struct JournalRecords<'a> {
+    journal: &'a mut Journal,
+    pending: VecDeque<UsnRecord>,
+    done: bool,
+}
+
+impl Iterator for JournalRecords<'_> {
+    type Item = Result<UsnRecord, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.pending.pop_front() {
+                return Some(Ok(record));
+            }
+
+            if self.done {
+                return None;
+            }
+
+            let usn = self.journal.next_usn;
+            match self.journal.read() {
+                Ok(records) => {
+                    // All the records in a batch can be filtered out by the reason mask,
+                    // so only stop when the cursor doesn't move anymore.
+                    if records.is_empty() && self.journal.next_usn == usn {
+                        self.done = true;
+                    }
+                    self.pending.extend(records);
+                }
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
 impl Drop for Journal {
     fn drop(&mut self) {
-        unsafe {
-            let _ = Foundation::CloseHandle(self.volume_handle);
-            let _ = Foundation::CloseHandle(self.port);
-        }
+        let _ = self.close();
     }
 }
 
@@ -601,7 +2297,7 @@ mod test {
 
         // Retry a few times in case there is a lot of unrelated activity.
         for _ in 0..10 {
-            for result in journal.read()? {
+            for result in journal.read_wait(Some(Duration::from_secs(1)))? {
                 found.push(result.path);
             }
 
@@ -642,10 +2338,12 @@ mod test {
 
         // Retry a few times in case there is a lot of unrelated activity.
         for _ in 0..10 {
-            for result in journal.read()? {
+            for result in journal.read_wait(Some(Duration::from_secs(1)))? {
                 if (result.path == path_new)
                     && (result.reason & Ioctl::USN_REASON_RENAME_NEW_NAME != 0)
                 {
+                    assert_eq!(result.rename_from.as_ref(), Some(&path_old));
+
                     if let Some(path) = journal.match_rename(&result) {
                         assert_eq!(path, path_old);
                         return Ok(());
@@ -683,7 +2381,7 @@ mod test {
 
         // Retry a few times in case there is a lot of unrelated activity.
         for _ in 0..10 {
-            for result in journal.read()? {
+            for result in journal.read_wait(Some(Duration::from_secs(1)))? {
                 if result.path == file_path {
                     return Ok(());
                 }
@@ -722,4 +2420,107 @@ mod test {
     fn file_delete_v3() -> NtfsReaderResult<()> {
         test_file_delete(3)
     }
+
+    #[test]
+    fn name_filter() {
+        assert!(glob_match("*.docx", "Report.DOCX"));
+        assert!(glob_match("~$*", "~$report.docx"));
+        assert!(glob_match("a*b?c", "axxbyc"));
+        assert!(!glob_match("*.docx", "report.docx.tmp"));
+        assert!(!glob_match("a?", "a"));
+
+        let filter = NameFilter {
+            include: vec!["*.docx".into()],
+            exclude: vec!["~$*".into()],
+        };
+        assert!(filter.matches(OsStr::new("report.docx")));
+        assert!(!filter.matches(OsStr::new("~$report.docx")));
+        assert!(!filter.matches(OsStr::new("report.txt")));
+        assert!(NameFilter::default().matches(OsStr::new("report.txt")));
+    }
+
+    #[test]
+    fn change_categories() {
+        let reason =
+            Ioctl::USN_REASON_FILE_CREATE | Ioctl::USN_REASON_DATA_EXTEND | Ioctl::USN_REASON_CLOSE;
+        let categories = ChangeCategories::from_reason(reason);
+        assert_eq!(
+            categories,
+            ChangeCategory::ContentChanged | ChangeCategory::LifecycleCreate
+        );
+        assert!(!categories.contains(ChangeCategory::NameChanged));
+        assert!(ChangeCategories::from_reason(Ioctl::USN_REASON_CLOSE).is_empty());
+        assert_eq!(
+            ChangeCategories::from_reason(Ioctl::USN_REASON_HARD_LINK_CHANGE)
+                .iter()
+                .collect::<Vec<_>>(),
+            vec![ChangeCategory::NameChanged]
+        );
+        assert_eq!(
+            ChangeCategories::ALL.iter().count(),
+            ChangeCategory::ALL.len()
+        );
+        assert_eq!(
+            ChangeCategory::SecurityChanged.reason_mask(),
+            Ioctl::USN_REASON_SECURITY_CHANGE
+        );
+    }
+
+    #[test]
+    fn check_truncated_records() {
+        let mut buffer = [0u64; 8];
+        let bytes = as_bytes_mut(&mut buffer);
+        bytes[0..4].copy_from_slice(&64u32.to_le_bytes());
+        bytes[4..6].copy_from_slice(&2u16.to_le_bytes());
+        bytes[56..58].copy_from_slice(&4u16.to_le_bytes());
+        bytes[58..60].copy_from_slice(&60u16.to_le_bytes());
+        assert_eq!(check_record(bytes), Ok(2));
+        assert_eq!(check_record(&bytes[..32]), Err(ParseIssue::Truncated));
+
+        // The name doesn't fit in the record.
+        bytes[56..58].copy_from_slice(&8u16.to_le_bytes());
+        assert_eq!(check_record(bytes), Err(ParseIssue::Truncated));
+
+        bytes[4..6].copy_from_slice(&4u16.to_le_bytes());
+        assert_eq!(check_record(bytes), Err(ParseIssue::UnknownVersion(4)));
+    }
+
+    #[test]
+    fn seek_to_time() -> NtfsReaderResult<()> {
+        let mut journal = make_journal(3, Ioctl::USN_REASON_FILE_CREATE)?;
+
+        let dir = make_test_dir("usn-journal-test-seek", 3)?;
+        let start = SystemTime::now();
+        let path = dir.join("usn-journal-test-seek.txt");
+        File::create(&path)?.write_all(b"test")?;
+
+        // Reading from the beginning of the journal would take much longer.
+        journal.seek_to_time(start)?;
+        assert!(journal.records().any(|r| r.is_ok_and(|r| r.path == path)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn checkpoint() -> NtfsReaderResult<()> {
+        let journal = make_journal(3, 0xFFFFFFFF)?;
+        let checkpoint = journal.checkpoint();
+
+        let make_options = |checkpoint| JournalOptions {
+            next_usn: NextUsn::Checkpoint(checkpoint),
+            ..JournalOptions::default()
+        };
+
+        let volume = Volume::new("\\\\?\\C:")?;
+        let resumed = Journal::new(volume.clone(), make_options(checkpoint))?;
+        assert_eq!(resumed.get_next_usn(), checkpoint.usn);
+
+        let other_journal = JournalCheckpoint {
+            journal_id: checkpoint.journal_id.wrapping_add(1),
+            ..checkpoint
+        };
+        assert!(Journal::new(volume, make_options(other_journal)).is_err());
+
+        Ok(())
+    }
 }
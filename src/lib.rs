@@ -2,12 +2,43 @@
 // This project is dual licensed under the Apache License 2.0 and the MIT license.
 // See the LICENSE files in the project root for details.
 
+// The journal and the live volumes need Windows, the images can be read anywhere.
+#[cfg(all(feature = "windows", not(windows)))]
+compile_error!("the `windows` feature needs Windows, build with `--no-default-features`");
+
 pub mod aligned_reader;
 pub mod api;
+#[cfg(feature = "tokio")]
+pub mod async_journal;
+#[cfg(feature = "tokio")]
+pub mod async_reader;
 pub mod attribute;
+pub mod carve;
+pub mod channel;
+pub mod compact;
+pub mod data_runs;
 pub mod errors;
+#[cfg(feature = "ewf")]
+pub mod ewf;
+pub mod export;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 pub mod file;
 pub mod file_info;
+pub mod image;
+#[cfg(feature = "windows")]
 pub mod journal;
+pub mod live_index;
+pub mod metadata;
 pub mod mft;
+#[cfg(feature = "windows")]
+pub mod multi_journal;
+pub mod parse;
+pub mod security;
+#[cfg(feature = "windows")]
+pub mod usn_data;
+pub mod verify;
 pub mod volume;
+pub mod walk;
+#[cfg(feature = "windows")]
+pub mod watcher;
@@ -0,0 +1,670 @@
+// Copyright (c) 2022, Matteo Bernacchia <dev@kikijiki.com>. All rights reserved.
+// This project is dual licensed under the Apache License 2.0 and the MIT license.
+// See the LICENSE files in the project root for details.
+
+// The name, parent and size of every file, from an MFT scan, kept up to date with the journal
+// records so that the paths can be queried at any time without scanning again.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+#[cfg(feature = "windows")]
+use std::time::Duration;
+
+#[cfg(feature = "windows")]
+use windows::Win32::System::Ioctl;
+
+#[cfg(feature = "windows")]
+use crate::journal::{Journal, JournalCheckpoint, JournalOptions, NextUsn, UsnRecord};
+use crate::{
+    api::{reference_number, reference_sequence, ROOT_RECORD},
+    errors::NtfsReaderResult,
+    file_info::FileInfo,
+    mft::{is_reference_current, relative_path, Mft, ParentLinks},
+    volume::{PathFormat, Volume},
+};
+
+// How many changes `LiveIndex::recently_changed` remembers by default.
+const RECENT_CAPACITY: usize = 1024;
+
+const LIVE_INDEX_MAGIC: &[u8; 4] = b"NRLI";
+const LIVE_INDEX_VERSION: u32 = 1;
+// The names are at most 255 UTF-16 units, each at most 3 bytes in UTF-8.
+const MAX_NAME_BYTES: usize = 255 * 3;
+
+#[cfg(feature = "windows")]
+const SIZE_MASK: u32 = Ioctl::USN_REASON_FILE_CREATE
+    | Ioctl::USN_REASON_DATA_OVERWRITE
+    | Ioctl::USN_REASON_DATA_EXTEND
+    | Ioctl::USN_REASON_DATA_TRUNCATION;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LiveEntry {
+    // With the sequence number.
+    pub reference: u64,
+    pub parent: u64,
+    pub name: String,
+    pub size: u64,
+    pub directory: bool,
+    // Of the last record applied, 0 for the entries from the MFT scan.
+    pub usn: i64,
+}
+
+pub struct LiveIndex {
+    pub volume: Volume,
+    path_format: PathFormat,
+    entries: HashMap<u64, LiveEntry>,
+    // The record numbers by lowercase name.
+    names: HashMap<String, Vec<u64>>,
+    // The record numbers and usn of the changes, the oldest first. Can contain stale entries for
+    // the files changed again or removed.
+    recent: VecDeque<(u64, i64)>,
+    recent_capacity: usize,
+    journal_id: u64,
+    usn: i64,
+    #[cfg(feature = "windows")]
+    journal: Option<Journal>,
+}
+
+impl LiveIndex {
+    // The records in use with a name, without following the changes until a journal is attached.
+    pub fn from_mft(mft: &Mft) -> Self {
+        let builder = FileInfo::builder().with_path(false).with_times(false);
+        let mut index = LiveIndex {
+            volume: mft.volume.clone(),
+            path_format: PathFormat::default(),
+            entries: HashMap::new(),
+            names: HashMap::new(),
+            recent: VecDeque::new(),
+            recent_capacity: RECENT_CAPACITY,
+            journal_id: 0,
+            usn: 0,
+            #[cfg(feature = "windows")]
+            journal: None,
+        };
+
+        for number in mft.used_records(0..mft.max_record) {
+            let Some(file) = mft.get_record(number).filter(|file| file.is_used()) else {
+                continue;
+            };
+            let Some(name) = file.get_best_file_name_ref(mft) else {
+                continue;
+            };
+
+            let name_string = name.to_string();
+            index
+                .names
+                .entry(name_string.to_lowercase())
+                .or_default()
+                .push(number);
+            index.entries.insert(
+                number,
+                LiveEntry {
+                    reference: file.reference(),
+                    parent: name.parent_reference(),
+                    name: name_string,
+                    size: builder.build(mft, &file).size,
+                    directory: file.is_directory(),
+                    usn: 0,
+                },
+            );
+        }
+
+        index
+    }
+
+    // The format of the paths returned by `get_path`.
+    pub fn with_path_format(mut self, format: PathFormat) -> Self {
+        self.path_format = format;
+        self
+    }
+
+    // How many changes `recently_changed` can return.
+    pub fn with_recent_capacity(mut self, capacity: usize) -> Self {
+        self.recent_capacity = capacity;
+        self.trim_recent();
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // The id of the journal and the usn the index is up to date with, both 0 without a journal.
+    pub fn journal_id(&self) -> u64 {
+        self.journal_id
+    }
+
+    pub fn usn(&self) -> i64 {
+        self.usn
+    }
+
+    pub fn get(&self, number: u64) -> Option<&LiveEntry> {
+        self.entries.get(&number)
+    }
+
+    // Same as `Mft::is_reference_current`.
+    pub fn is_reference_current(&self, reference: u64) -> bool {
+        is_reference_current(self, reference)
+    }
+
+    // Same as `Mft::get_relative_path`.
+    pub fn get_relative_path(&self, number: u64) -> Option<PathBuf> {
+        relative_path(self, number)
+    }
+
+    // Rooted in the format set with `with_path_format`.
+    pub fn get_path(&self, number: u64) -> Option<PathBuf> {
+        let relative = self.get_relative_path(number)?;
+        Some(self.volume.root(self.path_format).join(relative))
+    }
+
+    // Same as `Mft::find_path`, comparing the names ignoring the case.
+    pub fn find_path<P: AsRef<Path>>(&self, path: P) -> Option<u64> {
+        let path = self.volume.strip_root(path.as_ref());
+
+        let mut current = ROOT_RECORD;
+        for component in path.components() {
+            let name = match component {
+                Component::Normal(name) => name.to_string_lossy(),
+                Component::RootDir | Component::CurDir => continue,
+                // Another volume, or going up.
+                Component::Prefix(_) | Component::ParentDir => return None,
+            };
+
+            current = *self.find_name(&name).iter().find(|child| {
+                self.get(**child).is_some_and(|entry| {
+                    reference_number(entry.parent) == current
+                        && self.is_reference_current(entry.parent)
+                })
+            })?;
+        }
+        Some(current)
+    }
+
+    // The records with the given name, ignoring the case.
+    pub fn find_name(&self, name: &str) -> &[u64] {
+        self.names
+            .get(&name.to_lowercase())
+            .map_or(&[], |numbers| numbers.as_slice())
+    }
+
+    // The files changed since the index was built, the latest first, each one once.
+    // The removed ones are left out.
+    pub fn recently_changed(&self) -> impl Iterator<Item = (u64, &LiveEntry)> + '_ {
+        self.recent.iter().rev().filter_map(|(number, usn)| {
+            self.get(*number)
+                .filter(|entry| entry.usn == *usn)
+                .map(|entry| (*number, entry))
+        })
+    }
+
+    // Adds the file, or moves and renames it, for the changes that don't come from the attached
+    // journal (see `apply`). The size is kept if it is the same file, see `set_size`.
+    pub fn insert(&mut self, reference: u64, parent: u64, name: &str, directory: bool, usn: i64) {
+        let number = reference_number(reference);
+        let size = match self.entries.get(&number) {
+            Some(entry) if entry.reference == reference => entry.size,
+            _ => 0,
+        };
+
+        if self
+            .entries
+            .get(&number)
+            .is_none_or(|entry| entry.name != name)
+        {
+            self.unlink_name(number);
+            self.names
+                .entry(name.to_lowercase())
+                .or_default()
+                .push(number);
+        }
+
+        self.touch(number, usn);
+        self.entries.insert(
+            number,
+            LiveEntry {
+                reference,
+                parent,
+                name: name.to_string(),
+                size,
+                directory,
+                usn,
+            },
+        );
+    }
+
+    // Only if the record was not reused since.
+    pub fn remove(&mut self, reference: u64, usn: i64) {
+        let number = reference_number(reference);
+        if !self.is_reference_current(reference) {
+            return;
+        }
+
+        self.unlink_name(number);
+        self.entries.remove(&number);
+        self.touch(number, usn);
+    }
+
+    pub fn set_size(&mut self, number: u64, size: u64) {
+        if let Some(entry) = self.entries.get_mut(&number) {
+            entry.size = size;
+        }
+    }
+
+    // With the journal position, to continue from there after loading (see `open`).
+    pub fn save<W: Write>(&self, writer: &mut W) -> NtfsReaderResult<()> {
+        writer.write_all(LIVE_INDEX_MAGIC)?;
+        writer.write_all(&LIVE_INDEX_VERSION.to_le_bytes())?;
+        writer.write_all(&self.volume.serial_number.to_le_bytes())?;
+        writer.write_all(&self.journal_id.to_le_bytes())?;
+        writer.write_all(&self.usn.to_le_bytes())?;
+        writer.write_all(&(self.entries.len() as u64).to_le_bytes())?;
+
+        for (number, entry) in &self.entries {
+            writer.write_all(&number.to_le_bytes())?;
+            writer.write_all(&entry.reference.to_le_bytes())?;
+            writer.write_all(&entry.parent.to_le_bytes())?;
+            writer.write_all(&entry.size.to_le_bytes())?;
+            writer.write_all(&entry.usn.to_le_bytes())?;
+            writer.write_all(&[entry.directory as u8])?;
+            writer.write_all(&(entry.name.len() as u32).to_le_bytes())?;
+            writer.write_all(entry.name.as_bytes())?;
+        }
+
+        writer.write_all(&(self.recent.len() as u64).to_le_bytes())?;
+        for (number, usn) in &self.recent {
+            writer.write_all(&number.to_le_bytes())?;
+            writer.write_all(&usn.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    // Fails if the index was saved for another volume. Without a journal, the changes since it
+    // was saved are not applied.
+    pub fn load<R: Read>(volume: Volume, reader: &mut R) -> NtfsReaderResult<Self> {
+        fn read_array<R: Read, const N: usize>(reader: &mut R) -> io::Result<[u8; N]> {
+            let mut buf = [0u8; N];
+            reader.read_exact(&mut buf)?;
+            Ok(buf)
+        }
+        fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+            Ok(u64::from_le_bytes(read_array(reader)?))
+        }
+
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        if &read_array::<_, 4>(reader)? != LIVE_INDEX_MAGIC {
+            return Err(invalid("not a live index").into());
+        }
+        if u32::from_le_bytes(read_array(reader)?) != LIVE_INDEX_VERSION {
+            return Err(invalid("unsupported live index version").into());
+        }
+        if read_u64(reader)? != volume.serial_number {
+            return Err(invalid("the live index was saved for another volume").into());
+        }
+
+        let journal_id = read_u64(reader)?;
+        let usn = read_u64(reader)? as i64;
+        let mut entries = HashMap::new();
+        let mut names: HashMap<String, Vec<u64>> = HashMap::new();
+
+        // Nothing is allocated from the counts, a wrong one fails at the end of the data.
+        for _ in 0..read_u64(reader)? {
+            let number = read_u64(reader)?;
+            let reference = read_u64(reader)?;
+            let parent = read_u64(reader)?;
+            let size = read_u64(reader)?;
+            let entry_usn = read_u64(reader)? as i64;
+            let directory = read_array::<_, 1>(reader)?[0] != 0;
+            let len = u32::from_le_bytes(read_array(reader)?) as usize;
+            if len > MAX_NAME_BYTES {
+                return Err(invalid("invalid name length").into());
+            }
+
+            let mut name = Vec::with_capacity(len);
+            reader.by_ref().take(len as u64).read_to_end(&mut name)?;
+            if name.len() != len {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            let name = String::from_utf8(name).map_err(|_| invalid("invalid name"))?;
+
+            names.entry(name.to_lowercase()).or_default().push(number);
+            entries.insert(
+                number,
+                LiveEntry {
+                    reference,
+                    parent,
+                    name,
+                    size,
+                    directory,
+                    usn: entry_usn,
+                },
+            );
+        }
+
+        let mut recent = VecDeque::new();
+        for _ in 0..read_u64(reader)? {
+            recent.push_back((read_u64(reader)?, read_u64(reader)? as i64));
+        }
+
+        // Saved in no particular order.
+        for numbers in names.values_mut() {
+            numbers.sort_unstable();
+        }
+
+        Ok(LiveIndex {
+            volume,
+            path_format: PathFormat::default(),
+            entries,
+            names,
+            recent_capacity: RECENT_CAPACITY.max(recent.len()),
+            recent,
+            journal_id,
+            usn,
+            #[cfg(feature = "windows")]
+            journal: None,
+        })
+    }
+
+    pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> NtfsReaderResult<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.save(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn load_from_file<P: AsRef<Path>>(volume: Volume, path: P) -> NtfsReaderResult<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        Self::load(volume, &mut reader)
+    }
+
+    fn unlink_name(&mut self, number: u64) {
+        let Some(entry) = self.entries.get(&number) else {
+            return;
+        };
+        let key = entry.name.to_lowercase();
+        if let Some(numbers) = self.names.get_mut(&key) {
+            numbers.retain(|n| *n != number);
+            if numbers.is_empty() {
+                self.names.remove(&key);
+            }
+        }
+    }
+
+    fn touch(&mut self, number: u64, usn: i64) {
+        self.usn = self.usn.max(usn);
+        if self.recent.back() != Some(&(number, usn)) {
+            self.recent.push_back((number, usn));
+            self.trim_recent();
+        }
+    }
+
+    fn trim_recent(&mut self) {
+        while self.recent.len() > self.recent_capacity {
+            self.recent.pop_front();
+        }
+    }
+}
+
+impl ParentLinks for LiveIndex {
+    fn name_and_parent(&self, number: u64) -> Option<(Cow<'_, OsStr>, u64)> {
+        let entry = self.get(number)?;
+        Some((Cow::Borrowed(OsStr::new(&entry.name)), entry.parent))
+    }
+
+    fn sequence(&self, number: u64) -> Option<u16> {
+        self.get(number)
+            .map(|entry| reference_sequence(entry.reference))
+    }
+
+    fn record_count(&self) -> u64 {
+        self.entries.len() as u64
+    }
+}
+
+#[cfg(feature = "windows")]
+impl LiveIndex {
+    // Opens the journal before reading the MFT, so that the changes made during the scan are
+    // applied by the first update.
+    pub fn new(volume: Volume) -> NtfsReaderResult<Self> {
+        let options = JournalOptions {
+            // The paths come from the index.
+            resolve_paths: false,
+            ..JournalOptions::default()
+        };
+        let journal = Journal::new(volume.clone(), options)?;
+        let mft = Mft::new(volume)?;
+        Ok(Self::from_mft(&mft).with_journal(journal))
+    }
+
+    // Loads the index saved with `save_to_file` and applies only the journal records written
+    // since then. Scans the MFT again when that is not possible: the file can't be loaded, or the
+    // journal was recreated or its records were purged in the meantime.
+    pub fn open<P: AsRef<Path>>(volume: Volume, path: P) -> NtfsReaderResult<Self> {
+        let Ok(saved) = Self::load_from_file(volume.clone(), path) else {
+            return Self::new(volume);
+        };
+
+        let options = JournalOptions {
+            resolve_paths: false,
+            next_usn: NextUsn::Checkpoint(JournalCheckpoint {
+                journal_id: saved.journal_id,
+                usn: saved.usn,
+            }),
+            ..JournalOptions::default()
+        };
+        let Ok(journal) = Journal::new(volume.clone(), options) else {
+            return Self::new(volume);
+        };
+
+        let mut index = saved.with_journal(journal);
+        index.catch_up()?;
+        Ok(index)
+    }
+
+    // The journal should have been opened before the MFT was read, see `new`.
+    pub fn with_journal(mut self, journal: Journal) -> Self {
+        let checkpoint = journal.checkpoint();
+        self.journal_id = checkpoint.journal_id;
+        self.usn = checkpoint.usn;
+        self.journal = Some(journal);
+        self
+    }
+
+    pub fn journal(&self) -> Option<&Journal> {
+        self.journal.as_ref()
+    }
+
+    pub fn journal_mut(&mut self) -> Option<&mut Journal> {
+        self.journal.as_mut()
+    }
+
+    // Applies the new journal records, returns how many.
+    pub fn update(&mut self) -> Result<usize, std::io::Error> {
+        let records = self.attached_journal()?.read()?;
+        Ok(self.apply_all(&records))
+    }
+
+    // Blocks until there are new records or the timeout expires (`None` waits forever).
+    pub fn update_wait(&mut self, timeout: Option<Duration>) -> Result<usize, std::io::Error> {
+        let records = self.attached_journal()?.read_wait(timeout)?;
+        Ok(self.apply_all(&records))
+    }
+
+    // Applies the records up to the end of the journal, returns how many.
+    pub fn catch_up(&mut self) -> Result<usize, std::io::Error> {
+        let mut count = 0;
+        loop {
+            let usn = self.attached_journal()?.get_next_usn();
+            let applied = self.update()?;
+            count += applied;
+            // All the records in a batch can be filtered out by the reason mask.
+            if applied == 0 && self.attached_journal()?.get_next_usn() == usn {
+                return Ok(count);
+            }
+        }
+    }
+
+    // For the records read from another journal, the index doesn't need the paths.
+    pub fn apply(&mut self, record: &UsnRecord) {
+        let reference = record.file_id.reference();
+
+        if record.reason & Ioctl::USN_REASON_FILE_DELETE != 0 {
+            self.remove(reference, record.usn);
+            return;
+        }
+        // The name before the rename, the next record has the new one.
+        if record.reason & Ioctl::USN_REASON_RENAME_OLD_NAME != 0 {
+            return;
+        }
+
+        let name = record.name.to_string_lossy();
+        let parent = record.parent_id.reference();
+        let directory = record.is_directory();
+        self.insert(reference, parent, &name, directory, record.usn);
+
+        // The size is read once the file is closed, from the file system.
+        let closed = record.reason & Ioctl::USN_REASON_CLOSE != 0;
+        if closed && !directory && record.reason & SIZE_MASK != 0 {
+            let number = reference_number(reference);
+            let metadata = self
+                .get_path(number)
+                .and_then(|path| std::fs::symlink_metadata(path).ok());
+            if let Some(metadata) = metadata {
+                self.set_size(number, metadata.len());
+            }
+        }
+    }
+
+    fn apply_all(&mut self, records: &[UsnRecord]) -> usize {
+        for record in records {
+            self.apply(record);
+        }
+        if let Some(journal) = &self.journal {
+            self.usn = self.usn.max(journal.get_next_usn());
+        }
+        records.len()
+    }
+
+    fn attached_journal(&mut self) -> Result<&mut Journal, std::io::Error> {
+        self.journal.as_mut().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "the index has no journal, see `LiveIndex::with_journal`",
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use std::path::PathBuf;
+
+    use super::LiveIndex;
+    use crate::api::file_reference;
+    use crate::errors::NtfsReaderError;
+    use crate::mft::memory_tests::{make_mft, make_named_record, make_tree};
+
+    fn make_index() -> LiveIndex {
+        let mut records = make_tree(33);
+        records[31] = make_named_record(1, file_reference(30, 2), "a.txt");
+        records[32] = make_named_record(1, file_reference(5, 5), "a.txt");
+        LiveIndex::from_mft(&make_mft(records))
+    }
+
+    #[test]
+    fn queries() {
+        let index = make_index();
+
+        assert_eq!(index.len(), 4);
+        assert!(index.get(30).unwrap().directory);
+        assert_eq!(index.find_name("A.TXT"), &[31, 32]);
+        assert_eq!(index.find_path("DIR/A.txt"), Some(31));
+        assert_eq!(index.find_path("a.txt"), Some(32));
+        assert_eq!(index.find_path("dir/missing"), None);
+        assert_eq!(
+            index.get_relative_path(31),
+            Some(PathBuf::from("dir").join("a.txt"))
+        );
+        assert_eq!(index.recently_changed().count(), 0);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut index = make_index();
+        index.insert(
+            file_reference(40, 1),
+            file_reference(30, 2),
+            "new.txt",
+            false,
+            100,
+        );
+        index.set_size(40, 7);
+
+        let mut data = Vec::new();
+        index.save(&mut data).unwrap();
+        let loaded = LiveIndex::load(index.volume.clone(), &mut data.as_slice()).unwrap();
+
+        assert_eq!(loaded.len(), index.len());
+        assert_eq!(loaded.usn(), 100);
+        assert_eq!(loaded.get(40), index.get(40));
+        assert_eq!(loaded.find_name("a.txt"), &[31, 32]);
+        assert_eq!(loaded.find_path("dir/new.txt"), Some(40));
+        assert_eq!(loaded.recently_changed().count(), 1);
+
+        let mut other = index.volume.clone();
+        other.serial_number += 1;
+        assert!(LiveIndex::load(other, &mut data.as_slice()).is_err());
+        assert!(LiveIndex::load(index.volume.clone(), &mut &data[..data.len() - 1]).is_err());
+
+        // The length of the first name.
+        data[81..85].copy_from_slice(&u32::MAX.to_le_bytes());
+        match LiveIndex::load(index.volume.clone(), &mut data.as_slice()) {
+            Err(NtfsReaderError::IOError(err)) => assert_eq!(err.kind(), ErrorKind::InvalidData),
+            _ => panic!("the name length should be rejected"),
+        }
+    }
+
+    #[test]
+    fn changes() {
+        let mut index = make_index().with_recent_capacity(3);
+
+        // Created, then moved into the directory.
+        let created = file_reference(40, 1);
+        index.insert(created, file_reference(5, 5), "new.txt", false, 100);
+        assert_eq!(index.find_path("new.txt"), Some(40));
+        index.insert(created, file_reference(30, 2), "moved.txt", false, 200);
+        assert_eq!(index.find_path("new.txt"), None);
+        assert_eq!(index.find_path("dir/moved.txt"), Some(40));
+        assert!(index.find_name("new.txt").is_empty());
+
+        // The record of the directory was reused, its files are not in the tree anymore.
+        index.remove(file_reference(30, 1), 300);
+        assert!(index.get(30).is_some());
+        index.remove(file_reference(30, 2), 300);
+        assert_eq!(index.get_relative_path(31), None);
+        assert_eq!(index.find_path("dir/moved.txt"), None);
+
+        index.insert(
+            file_reference(32, 1),
+            file_reference(5, 5),
+            "a.txt",
+            false,
+            400,
+        );
+        let recent: Vec<u64> = index.recently_changed().map(|(number, _)| number).collect();
+        assert_eq!(recent, vec![32, 40]);
+        assert_eq!(index.usn(), 400);
+    }
+}
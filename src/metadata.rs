@@ -0,0 +1,307 @@
+// Copyright (c) 2022, Matteo Bernacchia <dev@kikijiki.com>. All rights reserved.
+// This project is dual licensed under the Apache License 2.0 and the MIT license.
+// See the LICENSE files in the project root for details.
+
+// The accessors of `std::fs::Metadata` (and of the Windows `MetadataExt`) over the MFT, so that
+// code written against `std::fs` can switch with few changes.
+
+use std::io;
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    api::{NtfsAttributeType, NtfsFileNameFlags, EPOCH_DIFFERENCE},
+    file::NtfsFile,
+    file_info::FileInfo,
+    mft::Mft,
+};
+
+pub const FILE_ATTRIBUTE_READONLY: u32 = NtfsFileNameFlags::ReadOnly as u32;
+pub const FILE_ATTRIBUTE_DIRECTORY: u32 = 0x0010;
+pub const FILE_ATTRIBUTE_NORMAL: u32 = NtfsFileNameFlags::Normal as u32;
+pub const FILE_ATTRIBUTE_REPARSE_POINT: u32 = NtfsFileNameFlags::ReparsePoint as u32;
+
+// The symbolic links and the junctions (the tags with the name surrogate bit), as in std.
+const REPARSE_TAG_NAME_SURROGATE: u32 = 0x2000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileType {
+    directory: bool,
+    symlink: bool,
+}
+
+impl FileType {
+    pub fn is_dir(&self) -> bool {
+        self.directory && !self.symlink
+    }
+
+    pub fn is_file(&self) -> bool {
+        !self.directory && !self.symlink
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.symlink
+    }
+
+    // A symbolic link (or junction) to a directory.
+    pub fn is_symlink_dir(&self) -> bool {
+        self.directory && self.symlink
+    }
+
+    pub fn is_symlink_file(&self) -> bool {
+        !self.directory && self.symlink
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    readonly: bool,
+}
+
+impl Permissions {
+    pub fn readonly(&self) -> bool {
+        self.readonly
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    len: u64,
+    file_type: FileType,
+    attributes: u32,
+    reparse_tag: u32,
+    // FILETIME, zero when not known.
+    creation_time: u64,
+    last_access_time: u64,
+    last_write_time: u64,
+    change_time: u64,
+    file_index: u64,
+    number_of_links: u32,
+    security_id: Option<u32>,
+}
+
+impl Metadata {
+    // The attributes come from $STANDARD_INFORMATION, or from the $FILE_NAME when missing.
+    pub fn new(mft: &Mft, file: &NtfsFile) -> Self {
+        let mut metadata = Self::from(&FileInfo::builder().with_path(false).build(mft, file));
+        let name = file
+            .get_attribute(NtfsAttributeType::FileName)
+            .and_then(|att| att.as_name());
+
+        let mut attributes = match file
+            .get_attribute(NtfsAttributeType::StandardInformation)
+            .and_then(|att| att.as_standard_info())
+        {
+            Some(stdinfo) => {
+                metadata.change_time = stdinfo.mft_record_modification_time();
+                stdinfo.file_attributes()
+            }
+            None => name.map_or(0, |name| name.header.file_attributes()),
+        };
+        attributes &= !(NtfsFileNameFlags::IsDirectory as u32);
+        if file.is_directory() {
+            attributes |= FILE_ATTRIBUTE_DIRECTORY;
+        }
+        if attributes == 0 {
+            attributes = FILE_ATTRIBUTE_NORMAL;
+        }
+
+        if attributes & FILE_ATTRIBUTE_REPARSE_POINT != 0 {
+            metadata.reparse_tag = name.map_or(0, |name| name.header.reparse_point_tag());
+        }
+        metadata.attributes = attributes;
+        metadata.file_type = FileType {
+            directory: file.is_directory(),
+            symlink: metadata.reparse_tag & REPARSE_TAG_NAME_SURROGATE != 0,
+        };
+        metadata.file_index = file.reference();
+        metadata.number_of_links = file.header.link_count() as u32;
+        metadata.security_id = file.security_id();
+        metadata
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type.is_dir()
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.file_type.is_file()
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.file_type.is_symlink()
+    }
+
+    // The size of the unnamed data stream, 0 for the directories.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn permissions(&self) -> Permissions {
+        Permissions {
+            readonly: self.attributes & FILE_ATTRIBUTE_READONLY != 0,
+        }
+    }
+
+    // Like std, an error when the time is not available.
+    pub fn modified(&self) -> io::Result<SystemTime> {
+        to_system_time(self.last_write_time)
+    }
+
+    pub fn accessed(&self) -> io::Result<SystemTime> {
+        to_system_time(self.last_access_time)
+    }
+
+    pub fn created(&self) -> io::Result<SystemTime> {
+        to_system_time(self.creation_time)
+    }
+
+    // The same as `std::os::windows::fs::MetadataExt`.
+
+    pub fn file_attributes(&self) -> u32 {
+        self.attributes
+    }
+
+    pub fn creation_time(&self) -> u64 {
+        self.creation_time
+    }
+
+    pub fn last_access_time(&self) -> u64 {
+        self.last_access_time
+    }
+
+    pub fn last_write_time(&self) -> u64 {
+        self.last_write_time
+    }
+
+    pub fn file_size(&self) -> u64 {
+        self.len
+    }
+
+    pub fn number_of_links(&self) -> Option<u32> {
+        Some(self.number_of_links).filter(|links| *links > 0)
+    }
+
+    // The file reference (with the sequence number), as in BY_HANDLE_FILE_INFORMATION.
+    pub fn file_index(&self) -> Option<u64> {
+        Some(self.file_index).filter(|index| *index != 0)
+    }
+
+    pub fn change_time(&self) -> Option<u64> {
+        Some(self.change_time).filter(|time| *time != 0)
+    }
+
+    pub fn reparse_tag(&self) -> u32 {
+        self.reparse_tag
+    }
+
+    // See `SecurityDescriptors` for the descriptor.
+    pub fn security_id(&self) -> Option<u32> {
+        self.security_id
+    }
+}
+
+// Without the attributes, the links and the file index, which are not in `FileInfo`.
+impl From<&FileInfo> for Metadata {
+    fn from(info: &FileInfo) -> Self {
+        Self {
+            len: info.size,
+            file_type: FileType {
+                directory: info.is_directory,
+                symlink: false,
+            },
+            attributes: if info.is_directory {
+                FILE_ATTRIBUTE_DIRECTORY
+            } else {
+                FILE_ATTRIBUTE_NORMAL
+            },
+            reparse_tag: 0,
+            creation_time: info.created_filetime,
+            last_access_time: info.accessed_filetime,
+            last_write_time: info.modified_filetime,
+            change_time: 0,
+            file_index: 0,
+            number_of_links: 0,
+            security_id: None,
+        }
+    }
+}
+
+impl NtfsFile<'_> {
+    pub fn metadata(&self, mft: &Mft) -> Metadata {
+        Metadata::new(mft, self)
+    }
+}
+
+fn to_system_time(filetime: u64) -> io::Result<SystemTime> {
+    if filetime == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the time is not set",
+        ));
+    }
+
+    let since = |ticks: u64| Duration::new(ticks / 10_000_000, (ticks % 10_000_000) as u32 * 100);
+    let time = if filetime >= EPOCH_DIFFERENCE {
+        SystemTime::UNIX_EPOCH.checked_add(since(filetime - EPOCH_DIFFERENCE))
+    } else {
+        SystemTime::UNIX_EPOCH.checked_sub(since(EPOCH_DIFFERENCE - filetime))
+    };
+    time.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "the time is out of range"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    use super::{Metadata, FILE_ATTRIBUTE_DIRECTORY, FILE_ATTRIBUTE_NORMAL};
+    use crate::api::{file_reference, EPOCH_DIFFERENCE};
+    use crate::file_info::FileInfo;
+    use crate::mft::memory_tests::{make_mft, make_tree};
+
+    #[test]
+    fn metadata() {
+        let mft = make_mft(make_tree(33));
+
+        let dir = mft.get_record(30).unwrap().metadata(&mft);
+        assert!(dir.is_dir() && !dir.is_file() && !dir.is_symlink());
+        assert_eq!(dir.file_attributes(), FILE_ATTRIBUTE_DIRECTORY);
+        assert_eq!(dir.file_index(), Some(file_reference(30, 2)));
+
+        let file = mft.get_record(31).unwrap().metadata(&mft);
+        assert!(file.file_type().is_file() && file.is_empty());
+        assert_eq!(file.file_attributes(), FILE_ATTRIBUTE_NORMAL);
+        assert!(file.modified().is_err());
+        assert!(!file.permissions().readonly());
+
+        let info = FileInfo {
+            name: "a".to_string(),
+            path: PathBuf::from("a"),
+            is_directory: false,
+            size: 42,
+            created: None,
+            accessed: None,
+            modified: None,
+            created_filetime: 0,
+            accessed_filetime: 0,
+            modified_filetime: EPOCH_DIFFERENCE + 15_000_000,
+            streams: Vec::new(),
+            stale_parent: false,
+        };
+        let metadata = Metadata::from(&info);
+        assert_eq!(metadata.len(), 42);
+        assert_eq!(
+            metadata.modified().unwrap(),
+            SystemTime::UNIX_EPOCH + Duration::from_millis(1500)
+        );
+        assert_eq!(metadata.file_index(), None);
+    }
+}
@@ -3,38 +3,251 @@
 // See the LICENSE files in the project root for details.
 
 use std::{
-    io::{Read, Seek, SeekFrom},
+    borrow::Cow,
+    ffi::OsStr,
+    io::{self, Read, Seek, SeekFrom},
+    ops::Range,
+    path::{Component, Path, PathBuf},
+    slice,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        OnceLock,
+    },
     time::Instant,
 };
 
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::{
-    aligned_reader::open_volume, api::*, attribute::NtfsAttribute, errors::NtfsReaderResult,
-    file::NtfsFile, volume::Volume,
+    api::*,
+    attribute::NtfsAttribute,
+    data_runs::{read_data_runs, read_data_runs_in},
+    errors::{NtfsReaderError, NtfsReaderResult, ParseError},
+    file::NtfsFile,
+    parse::{parse_fixed_record_with_context, ParsedRecord},
+    volume::{Volume, VolumeGeometry},
 };
 
+#[derive(Debug, Clone, Copy)]
+pub struct ParentIndexEntry {
+    pub parent: u64,
+    // Offset of the best $FILE_NAME value in `Mft::data`.
+    pub name_offset: usize,
+}
+
+// What the parent walk needs from the MFT, or from an index built from it, so that all of them
+// resolve the paths the same way.
+pub(crate) trait ParentLinks {
+    // The name of the record and the reference of its parent.
+    fn name_and_parent(&self, number: u64) -> Option<(Cow<'_, OsStr>, u64)>;
+    // The current sequence number of the record.
+    fn sequence(&self, number: u64) -> Option<u16>;
+    // No chain of parents is longer.
+    fn record_count(&self) -> u64;
+}
+
+impl ParentLinks for Mft {
+    fn name_and_parent(&self, number: u64) -> Option<(Cow<'_, OsStr>, u64)> {
+        let name = self.get_file_name(number)?;
+        Some((Cow::Owned(name.to_os_string()), name.parent_reference()))
+    }
+
+    fn sequence(&self, number: u64) -> Option<u16> {
+        if number >= self.max_record {
+            return None;
+        }
+        Some(self.get_record(number)?.header.sequence_value())
+    }
+
+    fn record_count(&self) -> u64 {
+        self.max_record
+    }
+}
+
+pub(crate) fn is_reference_current<T: ParentLinks>(index: &T, reference: u64) -> bool {
+    let sequence = reference_sequence(reference);
+    sequence == 0 || index.sequence(reference_number(reference)) == Some(sequence)
+}
+
+pub(crate) fn relative_path<T: ParentLinks>(index: &T, number: u64) -> Option<PathBuf> {
+    if number == ROOT_RECORD {
+        return Some(PathBuf::new());
+    }
+
+    let mut components = Vec::new();
+    let (mut name, mut parent) = index.name_and_parent(number)?;
+    loop {
+        components.push(name);
+
+        if reference_number(parent) == ROOT_RECORD {
+            break;
+        }
+        // Corrupted parent references could loop forever.
+        if components.len() as u64 > index.record_count() || !is_reference_current(index, parent) {
+            return None;
+        }
+        (name, parent) = index.name_and_parent(reference_number(parent))?;
+    }
+
+    Some(components.iter().rev().collect())
+}
+
+pub type CorruptRecordCallback = Box<dyn FnMut(u64, &NtfsReaderError)>;
+
+// What to do with the records in use that fail the signature or fixup validation while loading.
+#[derive(Default)]
+pub enum CorruptRecordPolicy {
+    // The records are marked as BAAD (like NTFS does) so that `get_record` ignores them.
+    #[default]
+    Skip,
+    // Same as `Skip`, calling the function with each record number and what is wrong with it.
+    Callback(CorruptRecordCallback),
+    // Fail the loading with `NtfsReaderError::CorruptRecord`.
+    Error,
+    // Read the record from $MFTMirr, which has only the first few records (at least 4).
+    // Skips the others.
+    Repair,
+}
+
+// When to read the bitmap of the MFT (which records are in use).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BitmapLoading {
+    #[default]
+    Eager,
+    // On the first `record_exists` call, opening the volume again (so not with `from_reader`).
+    Lazy,
+    // Never, the records in use are told by their header flags, after checking the signature
+    // and the update sequence.
+    Skip,
+}
+
+#[derive(Default)]
+pub struct MftOptions {
+    pub corrupt_records: CorruptRecordPolicy,
+    pub bitmap: BitmapLoading,
+    // Apply the fixup to each record when it is first accessed instead of all of them while
+    // loading, so that the first records are available sooner. Only with
+    // `CorruptRecordPolicy::Skip`, the other policies need to check every record while loading
+    // (and `Mft::corrupt_records` is not counted).
+    pub lazy_fixup: bool,
+    // Don't read the parts of $MFT where the bitmap has no records in use, they are left zeroed
+    // (so the deleted records there can't be recovered). Only with `BitmapLoading::Eager`.
+    pub skip_free_regions: bool,
+}
+
+// How much of $MFT `MftOptions::skip_free_regions` skips at least, smaller gaps are read anyway.
+const FREE_REGION_SIZE: usize = 256 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CorruptRecordSummary {
+    pub skipped: u64,
+    pub repaired: u64,
+}
+
 pub struct Mft {
     pub volume: Volume,
-    pub data: Vec<u8>,
+    data: MftData,
+    // Empty unless loaded with `BitmapLoading::Eager`, see `get_bitmap`.
     pub bitmap: Vec<u8>,
+    bitmap_source: BitmapSource,
     pub max_record: u64,
+    pub parent_index: Vec<Option<ParentIndexEntry>>,
+    // How many records were corrupt when loading, see `MftOptions::corrupt_records`.
+    pub corrupt_records: CorruptRecordSummary,
 }
 
 impl Mft {
     pub fn new(volume: Volume) -> NtfsReaderResult<Self> {
-        let mut reader = open_volume(&volume.path)?;
+        Self::with_options(volume, MftOptions::default())
+    }
+
+    pub fn with_options(volume: Volume, options: MftOptions) -> NtfsReaderResult<Self> {
+        let reader = volume.open_reader()?;
+        Self::load(volume, reader, options)
+    }
+
+    // Reads the MFT from any source, e.g. a disk image opened by the caller,
+    // where `geometry` tells where the MFT is and how big the records are.
+    pub fn from_reader<R>(reader: R, geometry: VolumeGeometry) -> NtfsReaderResult<Self>
+    where
+        R: Read + Seek,
+    {
+        Self::from_reader_with_options(reader, geometry, MftOptions::default())
+    }
+
+    pub fn from_reader_with_options<R>(
+        reader: R,
+        geometry: VolumeGeometry,
+        options: MftOptions,
+    ) -> NtfsReaderResult<Self>
+    where
+        R: Read + Seek,
+    {
+        Self::load(Volume::from_geometry(geometry), reader, options)
+    }
+
+    fn load<R>(volume: Volume, mut reader: R, mut options: MftOptions) -> NtfsReaderResult<Self>
+    where
+        R: Read + Seek,
+    {
+        let sector_size = volume.sector_size as usize;
+        let record_size = volume.file_record_size as usize;
+        let mut mft_record =
+            Self::get_record_fs(&mut reader, record_size, sector_size, volume.mft_position);
+        if mft_record.is_empty() {
+            if let CorruptRecordPolicy::Repair = options.corrupt_records {
+                if let Some(record) = Self::read_mirror_record(&volume, &mut reader, MFT_RECORD) {
+                    mft_record = record;
+                }
+            }
+        }
+        if mft_record.is_empty() {
+            return Err(NtfsReaderError::CorruptRecord {
+                record: MFT_RECORD,
+                attribute_type: None,
+                offset: 0,
+                source: ParseError::BadSignature,
+            });
+        }
+
+        // The bitmap first, to know which parts of $DATA to read.
+        let (bitmap, bitmap_source) = match options.bitmap {
+            BitmapLoading::Eager => {
+                let bitmap = Self::read_data_fs(
+                    &volume,
+                    &mut reader,
+                    &mft_record,
+                    NtfsAttributeType::Bitmap,
+                );
+                (bitmap, BitmapSource::Loaded)
+            }
+            BitmapLoading::Lazy => (
+                Vec::new(),
+                BitmapSource::Lazy {
+                    mft_record: mft_record.clone(),
+                    bitmap: OnceLock::new(),
+                },
+            ),
+            BitmapLoading::Skip => (Vec::new(), BitmapSource::Skipped),
+        };
 
-        let mft_record = Self::get_record_fs(
+        let regions = (options.skip_free_regions && !bitmap.is_empty())
+            .then(|| in_use_regions(&bitmap, record_size, FREE_REGION_SIZE));
+        let mut data = Self::read_data_fs_in(
+            &volume,
             &mut reader,
-            volume.file_record_size as usize,
-            volume.mft_position,
+            &mft_record,
+            NtfsAttributeType::Data,
+            regions.as_deref(),
         );
-
-        let mut data =
-            Self::read_data_fs(&volume, &mut reader, &mft_record, NtfsAttributeType::Data);
-        let bitmap =
-            Self::read_data_fs(&volume, &mut reader, &mft_record, NtfsAttributeType::Bitmap);
+        if data.is_empty() {
+            return Err(NtfsReaderError::CorruptRecord {
+                record: MFT_RECORD,
+                attribute_type: Some(NtfsAttributeType::Data as u32),
+                offset: 0,
+                source: ParseError::MissingAttribute,
+            });
+        }
 
         let max_record = (data.len() / volume.file_record_size as usize) as u64;
 
@@ -49,196 +262,1591 @@ impl Mft {
         // let max_record_mft = (data.len() / volume.file_record_size as usize) as u64;
         // let max_record = u64::min(max_record_bitmap, max_record_mft);
 
+        if options.lazy_fixup && matches!(options.corrupt_records, CorruptRecordPolicy::Skip) {
+            return Ok(Mft {
+                data: MftData::lazy(data, record_size, sector_size),
+                volume,
+                bitmap,
+                bitmap_source,
+                max_record,
+                parent_index: Vec::new(),
+                corrupt_records: CorruptRecordSummary::default(),
+            });
+        }
+
         // Fixup all records so we are non mutable from now on.
+        let mut summary = CorruptRecordSummary::default();
         for number in 0..max_record {
-            let start = number as usize * volume.file_record_size as usize;
-            let end = start + volume.file_record_size as usize;
-            let mut data = &mut data[start..end];
-            Self::fixup_record(&mut data);
+            let start = number as usize * record_size;
+            let end = start + record_size;
+            let record = &mut data[start..end];
+
+            let source = match Self::check_and_fixup(record, sector_size) {
+                Ok(()) => continue,
+                Err(source) => source,
+            };
+
+            // The free records are not corrupt, most of them were never used.
+            let in_use = match bitmap_source {
+                BitmapSource::Loaded => bitmap
+                    .get((number / 8) as usize)
+                    .is_some_and(|bits| bits & (1 << (number % 8)) != 0),
+                _ => has_in_use_flag(record),
+            };
+            if !in_use {
+                continue;
+            }
+
+            let err = NtfsReaderError::CorruptRecord {
+                record: number,
+                attribute_type: None,
+                offset: 0,
+                source,
+            };
+            match &mut options.corrupt_records {
+                CorruptRecordPolicy::Skip => {}
+                CorruptRecordPolicy::Callback(f) => f(number, &err),
+                CorruptRecordPolicy::Error => return Err(err),
+                CorruptRecordPolicy::Repair => {
+                    if let Some(mirror) = Self::read_mirror_record(&volume, &mut reader, number) {
+                        record.copy_from_slice(&mirror);
+                        summary.repaired += 1;
+                        continue;
+                    }
+                }
+            }
+
+            warn!("Skipping corrupt record {}: {}", number, source);
+            record[0..4].copy_from_slice(BAAD_RECORD_SIGNATURE);
+            summary.skipped += 1;
         }
 
         Ok(Mft {
+            data: MftData::fixed(data, record_size),
             volume,
-            data,
             bitmap,
+            bitmap_source,
             max_record,
+            parent_index: Vec::new(),
+            corrupt_records: summary,
+        })
+    }
+
+    // Find the best name and parent of every record once, so that path building
+    // and children queries don't have to parse the attributes again and again.
+    pub fn build_parent_index(&mut self) {
+        let mut index = vec![None; self.max_record as usize];
+
+        for (number, entry) in index.iter_mut().enumerate() {
+            *entry = self.parent_index_entry(number as u64);
+        }
+
+        self.parent_index = index;
+    }
+
+    fn parent_index_entry(&self, number: u64) -> Option<ParentIndexEntry> {
+        if !self.record_exists(number) {
+            return None;
+        }
+
+        let file = self.get_record(number)?;
+        if !file.is_used() {
+            return None;
+        }
+
+        let name = file.get_best_file_name_ref(self)?;
+        let name_offset = name as *const _ as usize - self.data.as_ptr() as usize;
+        Some(ParentIndexEntry {
+            parent: name.parent(),
+            name_offset,
         })
     }
 
+    // Reads a single record again from the volume, to get its current state.
+    // Returns false if the record is not part of the snapshot (e.g. the MFT grew since).
+    pub fn reload_record(&mut self, number: u64) -> NtfsReaderResult<bool> {
+        if number >= self.max_record {
+            return Ok(false);
+        }
+
+        let Some(position) = self.get_record_position(number) else {
+            return Ok(false);
+        };
+
+        let record_size = self.volume.file_record_size as usize;
+        let mut reader = self.volume.open_reader()?;
+        let sector_size = self.volume.sector_size as usize;
+        let data = Self::get_record_fs(&mut reader, record_size, sector_size, position);
+        if data.is_empty() {
+            return Ok(false);
+        }
+
+        self.data.set_record(number as usize, &data);
+
+        let used = self.get_record(number).is_some_and(|file| file.is_used());
+        let bitmap = match &mut self.bitmap_source {
+            BitmapSource::Lazy { bitmap, .. } => bitmap.get_mut().and_then(Option::as_mut),
+            _ => Some(&mut self.bitmap),
+        };
+        if let Some(bits) = bitmap.and_then(|bitmap| bitmap.get_mut((number / 8) as usize)) {
+            if used {
+                *bits |= 1 << (number % 8);
+            } else {
+                *bits &= !(1 << (number % 8));
+            }
+        }
+
+        if self.has_parent_index() {
+            self.parent_index[number as usize] = self.parent_index_entry(number);
+        }
+
+        Ok(true)
+    }
+
+    // Where the record is on the volume, following the data runs of $MFT.
+    fn get_record_position(&self, number: u64) -> Option<u64> {
+        let mft = self.get_record(MFT_RECORD)?;
+        let runs = mft
+            .get_attribute(NtfsAttributeType::Data)?
+            .data_runs()
+            .ok()?;
+
+        let cluster_size = self.volume.cluster_size;
+        let mut offset = number.checked_mul(self.volume.file_record_size)?;
+        for run in runs {
+            let len = run.cluster_count.checked_mul(cluster_size)?;
+            if offset < len {
+                // The records are never in a sparse run.
+                let start = run.lcn?.checked_mul(cluster_size)?;
+                return start.checked_add(offset);
+            }
+            offset -= len;
+        }
+
+        None
+    }
+
+    pub fn has_parent_index(&self) -> bool {
+        !self.parent_index.is_empty()
+    }
+
+    // Uses the parent index if available.
+    pub fn get_file_name(&self, number: u64) -> Option<&NtfsFileName> {
+        if self.has_parent_index() {
+            let entry = self.parent_index.get(number as usize)?.as_ref()?;
+            // The name can be in another record, see the attribute lists.
+            let record_size = self.volume.file_record_size as usize;
+            let record = self.get_record_data((entry.name_offset / record_size) as u64);
+            return NtfsFileName::ref_from_prefix(record.get(entry.name_offset % record_size..)?);
+        }
+
+        if number >= self.max_record {
+            return None;
+        }
+        self.get_record(number)?.get_best_file_name_ref(self)
+    }
+
+    // False if the record was reused since the reference was taken, its sequence number changed.
+    // References with a zero sequence number are not checked.
+    pub fn is_reference_current(&self, reference: u64) -> bool {
+        is_reference_current(self, reference)
+    }
+
+    // Same as `get_file_name`, None if the record was reused (see `is_reference_current`).
+    pub fn get_file_name_by_reference(&self, reference: u64) -> Option<&NtfsFileName> {
+        if !self.is_reference_current(reference) {
+            return None;
+        }
+        self.get_file_name(reference_number(reference))
+    }
+
+    pub fn get_parent(&self, number: u64) -> Option<u64> {
+        if self.has_parent_index() {
+            return Some(self.parent_index.get(number as usize)?.as_ref()?.parent);
+        }
+        self.get_file_name(number).map(|name| name.parent())
+    }
+
+    // The path relative to the volume root, without the leading separator.
+    // None if a directory in the chain was deleted, or reused by another file.
+    pub fn get_relative_path(&self, number: u64) -> Option<PathBuf> {
+        relative_path(self, number)
+    }
+
+    // Without the parent index this has to parse every record.
+    pub fn get_children(&self, number: u64) -> Vec<u64> {
+        if self.has_parent_index() {
+            return self
+                .parent_index
+                .iter()
+                .enumerate()
+                .filter(|(_, entry)| entry.is_some_and(|e| e.parent == number))
+                .map(|(child, _)| child as u64)
+                .filter(|child| *child != number)
+                .collect();
+        }
+
+        let mut children = Vec::new();
+        self.iterate_files(|file| {
+            if file.number != number && self.get_parent(file.number) == Some(number) {
+                children.push(file.number);
+            }
+        });
+        children
+    }
+
+    // The record of a directory (or file) from its path, relative to the root of the volume or
+    // rooted at it in any `PathFormat`. The names are compared ignoring the case, like Windows.
+    // Faster with the parent index, otherwise each component parses every record.
+    pub fn find_path<P: AsRef<Path>>(&self, path: P) -> Option<u64> {
+        let path = self.volume.strip_root(path.as_ref());
+
+        let mut current = ROOT_RECORD;
+        for component in path.components() {
+            let name = match component {
+                Component::Normal(name) => name.to_string_lossy(),
+                Component::RootDir | Component::CurDir => continue,
+                // Another volume, or going up.
+                Component::Prefix(_) | Component::ParentDir => return None,
+            };
+
+            current = self.get_children(current).into_iter().find(|child| {
+                self.get_file_name(*child).is_some_and(|child_name| {
+                    self.is_reference_current(child_name.parent_reference())
+                        && char::decode_utf16(child_name.name_units())
+                            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                            .flat_map(char::to_lowercase)
+                            .eq(name.chars().flat_map(char::to_lowercase))
+                })
+            })?;
+        }
+        Some(current)
+    }
+
+    // Like `iterate_files`, only for the files whose parent chain goes through `root` (a record
+    // number or a path, see `find_path`), without `root` itself. Each parent chain is followed
+    // once, faster with the parent index.
+    pub fn iterate_under<'p, R, F>(&self, root: R, mut f: F) -> NtfsReaderResult<()>
+    where
+        R: Into<SubtreeRoot<'p>>,
+        F: FnMut(&NtfsFile),
+    {
+        let root = match root.into() {
+            SubtreeRoot::Record(number) => number,
+            SubtreeRoot::Path(path) => self.find_path(&path).ok_or_else(|| {
+                let message = format!("{} is not in the tree", path.display());
+                io::Error::new(io::ErrorKind::NotFound, message)
+            })?,
+        };
+        if root >= self.max_record {
+            return Err(NtfsReaderError::RecordOutOfRange { record: root });
+        }
+
+        let mut states = vec![SubtreeState::Unknown; self.max_record as usize];
+        states[ROOT_RECORD as usize] = SubtreeState::Outside;
+        states[root as usize] = SubtreeState::Inside;
+        let mut chain = Vec::new();
+
+        self.iterate_files(|file| {
+            chain.clear();
+            let mut current = file.number;
+
+            let state = loop {
+                match states[current as usize] {
+                    SubtreeState::Unknown => {}
+                    // A loop in the parent references.
+                    SubtreeState::Visiting => break SubtreeState::Outside,
+                    state => break state,
+                }
+                states[current as usize] = SubtreeState::Visiting;
+                chain.push(current);
+
+                // The files left in a deleted (or reused) directory are not part of the tree.
+                let Some(parent) = self
+                    .get_file_name(current)
+                    .map(|name| name.parent_reference())
+                    .filter(|parent| {
+                        reference_number(*parent) < self.max_record
+                            && self.is_reference_current(*parent)
+                    })
+                else {
+                    break SubtreeState::Outside;
+                };
+                current = reference_number(parent);
+            };
+
+            for number in &chain {
+                states[*number as usize] = state;
+            }
+            if state == SubtreeState::Inside && file.number != root {
+                f(file);
+            }
+        });
+
+        Ok(())
+    }
+
+    // Without the bitmap (see `BitmapLoading`) the record is read to tell if it is in use.
     pub fn record_exists(&self, number: u64) -> bool {
+        match self.in_bitmap(number) {
+            Some(exists) => exists,
+            None => {
+                number < self.max_record && self.get_record(number).is_some_and(|f| f.is_used())
+            }
+        }
+    }
+
+    // None when the bitmap was not loaded (or failed to load).
+    pub fn get_bitmap(&self) -> Option<&[u8]> {
+        match &self.bitmap_source {
+            BitmapSource::Loaded => Some(&self.bitmap),
+            BitmapSource::Lazy { mft_record, bitmap } => bitmap
+                .get_or_init(|| self.load_bitmap(mft_record))
+                .as_deref(),
+            BitmapSource::Skipped => None,
+        }
+    }
+
+    // Used as if loaded with `BitmapLoading::Eager`.
+    pub(crate) fn set_bitmap(&mut self, bitmap: Vec<u8>) {
+        self.bitmap = bitmap;
+        self.bitmap_source = BitmapSource::Loaded;
+    }
+
+    fn load_bitmap(&self, mft_record: &[u8]) -> Option<Vec<u8>> {
+        let mut reader = match self.volume.open_reader() {
+            Ok(reader) => reader,
+            Err(err) => {
+                warn!("Failed to open the volume to read the MFT bitmap: {}", err);
+                return None;
+            }
+        };
+        let bitmap = Self::read_data_fs(
+            &self.volume,
+            &mut reader,
+            mft_record,
+            NtfsAttributeType::Bitmap,
+        );
+        (!bitmap.is_empty()).then_some(bitmap)
+    }
+
+    fn in_bitmap(&self, number: u64) -> Option<bool> {
+        let bitmap = self.get_bitmap()?;
         if number > self.max_record {
-            return false;
+            return Some(false);
         }
 
         let bitmap_idx = (number / 8) as usize;
         let bitmap_off = number % 8;
 
-        if bitmap_idx >= self.bitmap.len() {
-            return false;
+        if bitmap_idx >= bitmap.len() {
+            return Some(false);
         }
 
-        let bit = self.bitmap[bitmap_idx];
-        return bit & (1 << bitmap_off) != 0;
+        let bit = bitmap[bitmap_idx];
+        Some(bit & (1 << bitmap_off) != 0)
     }
 
     pub fn iterate_files<F>(&self, mut f: F)
     where
         F: FnMut(&NtfsFile) -> (),
     {
-        for number in FIRST_NORMAL_RECORD..self.max_record {
-            if self.record_exists(number) {
-                if let Some(file) = self.get_record(number) {
-                    if file.is_used() {
-                        f(&file);
-                    }
+        for number in self.used_records(FIRST_NORMAL_RECORD..self.max_record) {
+            if let Some(file) = self.get_record(number) {
+                if file.is_used() {
+                    f(&file);
                 }
             }
         }
     }
 
+    // Like `iterate_files`, but the records that are in use according to the bitmap and can't be
+    // read are passed as errors instead of being skipped, and the callback can stop the iteration.
+    pub fn try_iterate_files<F, E>(&self, mut f: F) -> Result<(), E>
+    where
+        F: FnMut(NtfsReaderResult<&NtfsFile>) -> Result<(), E>,
+    {
+        for number in self.used_records(FIRST_NORMAL_RECORD..self.max_record) {
+            match self.try_get_record(number) {
+                Ok(file) if file.is_used() => f(Ok(&file))?,
+                Ok(_) => {}
+                Err(err) => f(Err(err))?,
+            }
+        }
+        Ok(())
+    }
+
+    // The records in `range` marked as used in the bitmap, in order.
+    // Without the bitmap, all of them.
+    pub fn used_records(&self, range: Range<u64>) -> UsedRecords<'_> {
+        UsedRecords::new(self.get_bitmap(), range)
+    }
+
+    // Same as `get_record`, with the reason why the record is invalid.
+    pub fn try_get_record(&self, number: u64) -> NtfsReaderResult<NtfsFile<'_>> {
+        if number >= self.max_record {
+            return Err(NtfsReaderError::RecordOutOfRange { record: number });
+        }
+
+        let data = self.get_record_data(number);
+        let file = NtfsFile::new(number, data)?;
+        if !NtfsFile::is_valid(data, self.volume.sector_size as usize) {
+            return Err(NtfsReaderError::CorruptRecord {
+                record: number,
+                attribute_type: None,
+                offset: 0,
+                source: ParseError::InvalidFixup,
+            });
+        }
+        Ok(file)
+    }
+
+    // Panics if the record is past the end of the MFT.
     pub fn get_record_data(&self, number: u64) -> &[u8] {
-        let start = number as usize * self.volume.file_record_size as usize;
-        let end = start + self.volume.file_record_size as usize;
-        &self.data[start..end]
+        self.data.record(number as usize, |record| {
+            self.in_bitmap(number)
+                .unwrap_or_else(|| has_in_use_flag(record))
+        })
+    }
+
+    // All the records, fixing up the ones not accessed yet with `MftOptions::lazy_fixup`.
+    pub fn data(&self) -> &[u8] {
+        self.data.all(|number, record| {
+            self.in_bitmap(number as u64)
+                .unwrap_or_else(|| has_in_use_flag(record))
+        })
+    }
+
+    // Checks every length and offset unlike `get_record`, the error tells where the record is corrupt.
+    pub fn parse_record(&self, number: u64) -> NtfsReaderResult<ParsedRecord> {
+        if number >= self.max_record {
+            return Err(NtfsReaderError::RecordOutOfRange { record: number });
+        }
+        parse_fixed_record_with_context(number, self.get_record_data(number))
     }
 
     pub fn get_record(&self, number: u64) -> Option<NtfsFile> {
         let data = self.get_record_data(number);
 
-        if NtfsFile::is_valid(&data) {
-            return Some(NtfsFile::new(number, data));
+        if NtfsFile::is_valid(data, self.volume.sector_size as usize) {
+            // The header fits, `is_valid` checked the length.
+            return Some(NtfsFile::from_header(number, data));
         }
 
         None
     }
 
-    pub fn get_record_fs<R>(fs: &mut R, file_record_size: usize, position: u64) -> Vec<u8>
+    pub fn get_record_fs<R>(
+        fs: &mut R,
+        file_record_size: usize,
+        sector_size: usize,
+        position: u64,
+    ) -> Vec<u8>
     where
         R: Seek + Read,
     {
-        let mut data = vec![0; file_record_size as usize];
+        let mut data = vec![0; file_record_size];
         let _ = fs.seek(SeekFrom::Start(position));
         let _ = fs.read_exact(&mut data);
 
-        if NtfsFile::is_valid(&data) {
-            Self::fixup_record(&mut data);
+        if Self::check_and_fixup(&mut data, sector_size).is_ok() {
             return data;
         } else {
             return Vec::new();
         }
     }
 
+    // $MFTMirr has a copy of the first records, as many as fit in a cluster but at least 4.
+    fn read_mirror_record<R>(volume: &Volume, reader: &mut R, number: u64) -> Option<Vec<u8>>
+    where
+        R: Seek + Read,
+    {
+        let mirror_lcn = volume.boot_sector.mft_lcn_mirror;
+        let mirror_records = u64::max(4, volume.cluster_size / volume.file_record_size.max(1));
+        if mirror_lcn == 0 || number >= mirror_records {
+            return None;
+        }
+
+        let position = mirror_lcn * volume.cluster_size + number * volume.file_record_size;
+        let data = Self::get_record_fs(
+            reader,
+            volume.file_record_size as usize,
+            volume.sector_size as usize,
+            position,
+        );
+        (!data.is_empty()).then_some(data)
+    }
+
+    pub(crate) fn check_and_fixup(data: &mut [u8], sector_size: usize) -> Result<(), ParseError> {
+        if NtfsFile::is_valid(data, sector_size) {
+            Self::fixup_record(data, sector_size)
+        } else if data.get(0..4) == Some(FILE_RECORD_SIGNATURE) {
+            Err(ParseError::InvalidFixup)
+        } else {
+            Err(ParseError::BadSignature)
+        }
+    }
+
     pub fn read_data_fs<R>(
         volume: &Volume,
         reader: &mut R,
         record: &[u8],
         attribute_type: NtfsAttributeType,
     ) -> Vec<u8>
+    where
+        R: Seek + Read,
+    {
+        Self::read_data_fs_in(volume, reader, record, attribute_type, None)
+    }
+
+    // With `ranges`, only those parts of a nonresident attribute are read, see
+    // `read_data_runs_in`.
+    fn read_data_fs_in<R>(
+        volume: &Volume,
+        reader: &mut R,
+        record: &[u8],
+        attribute_type: NtfsAttributeType,
+        ranges: Option<&[Range<usize>]>,
+    ) -> Vec<u8>
     where
         R: Seek + Read,
     {
         let mut data = Vec::<u8>::new();
 
-        let header = unsafe { &*(record.as_ptr() as *const NtfsFileRecordHeader) };
-        let mut att_offset = header.attributes_offset as usize;
+        let Some(header) = NtfsFileRecordHeader::ref_from_prefix(record) else {
+            return data;
+        };
+        let mut att_offset = header.attributes_offset() as usize;
 
         info!("Reading DATA attribute");
 
-        loop {
-            if att_offset >= header.used_size as usize {
+        while att_offset < header.used_size() as usize {
+            let Some(att) = record.get(att_offset..).and_then(NtfsAttribute::new) else {
                 break;
-            }
-
-            let att = NtfsAttribute::new(&record[att_offset..]);
-            if att.header.type_id == NtfsAttributeType::End as u32 {
+            };
+            if att.header.type_id() == NtfsAttributeType::End as u32 || att.header.length() == 0 {
                 break;
             }
 
-            if att.header.type_id == attribute_type as u32 {
-                if att.header.is_non_resident == 0 {
-                    data.copy_from_slice(att.as_resident_data());
+            if att.header.type_id() == attribute_type as u32 {
+                if att.header.is_non_resident() == 0 {
+                    data.extend_from_slice(att.get_resident());
                 } else {
                     let read_start = Instant::now();
 
-                    let mut buffer = Vec::new();
-                    let (size, runs) = att.get_nonresident_data_runs(&volume);
-                    data.reserve_exact(size);
-                    let mut copied = 0usize;
-
-                    for (run_idx, run) in runs.iter().enumerate() {
-                        if copied >= size {
-                            break;
+                    let runs = match att.data_runs() {
+                        Ok(runs) => runs,
+                        Err(err) => {
+                            warn!("Invalid data runs: {}", err);
+                            return Vec::new();
                         }
+                    };
+                    let size = att.header_nonres.data_size();
+                    // Can't be held in memory anyway on 32 bits builds.
+                    if usize::try_from(size).is_err() {
+                        warn!("The attribute data is too big ({} bytes)", size);
+                        return Vec::new();
+                    }
 
-                        let run_start = Instant::now();
-
-                        let buf_size = usize::min(run.len(), size - copied);
-                        buffer.resize(buf_size, 0u8);
-
-                        let _ = reader.seek(SeekFrom::Start(run.start as u64));
-                        let _ = reader.read_exact(&mut buffer);
-
-                        data.append(&mut buffer.clone());
-                        copied += buf_size;
-
-                        info!(
-                            "- Run {}/{} (size: {}, took {:?})",
-                            run_idx + 1,
-                            runs.len(),
-                            buf_size,
-                            Instant::now() - run_start
-                        );
+                    let cluster_size = volume.cluster_size;
+                    let runs_data = match ranges {
+                        Some(ranges) => {
+                            read_data_runs_in(reader, &runs, cluster_size, size, ranges)
+                        }
+                        None => read_data_runs(reader, &runs, cluster_size, size, false),
+                    };
+                    match runs_data {
+                        Ok(runs_data) => data = runs_data,
+                        Err(err) => {
+                            warn!("Failed to read the data runs: {}", err);
+                            return Vec::new();
+                        }
                     }
 
                     info!(
-                        "Loaded DATA of size {} in {:?}",
-                        copied,
+                        "Loaded DATA of size {} ({} runs) in {:?}",
+                        data.len(),
+                        runs.len(),
                         Instant::now() - read_start
                     );
                 }
             }
 
-            att_offset += att.header.length as usize;
+            att_offset += att.header.length() as usize;
         }
 
         data
     }
 
-    fn fixup_record(data: &mut [u8]) {
-        let header = unsafe { &*(data.as_ptr() as *const NtfsFileRecordHeader) };
+    // Same as `read_data_fs` for a record of the MFT, also when the attribute was moved to other
+    // records (and maybe split between them) listed in its $ATTRIBUTE_LIST, like the $SDS of
+    // $Secure on the volumes with many security descriptors.
+    pub fn read_attribute_fs<R>(
+        &self,
+        reader: &mut R,
+        number: u64,
+        attribute_type: NtfsAttributeType,
+    ) -> Vec<u8>
+    where
+        R: Seek + Read,
+    {
+        if number >= self.max_record {
+            return Vec::new();
+        }
+        let record = self.get_record_data(number);
+        let has_list = self.get_record(number).is_some_and(|file| {
+            file.get_attribute(NtfsAttributeType::AttributeList)
+                .is_some()
+        });
+        if !has_list {
+            return Self::read_data_fs(&self.volume, reader, record, attribute_type);
+        }
+
+        // The list itself can be nonresident.
+        let list = Self::read_data_fs(
+            &self.volume,
+            reader,
+            record,
+            NtfsAttributeType::AttributeList,
+        );
+
+        let mut resident = None;
+        let mut runs = Vec::new();
+        let mut size = None;
+        let mut offset = 0;
+        while let Some(entry) = list
+            .get(offset..)
+            .and_then(NtfsAttributeListEntry::ref_from_prefix)
+        {
+            if entry.length() == 0 {
+                break;
+            }
+            offset += entry.length() as usize;
+            if entry.type_id() != attribute_type as u32 {
+                continue;
+            }
+
+            // The extents are listed in order.
+            let Ok(extent) = self.try_get_record(entry.reference()) else {
+                warn!(
+                    "The record {} of the attribute list is missing",
+                    entry.reference()
+                );
+                return Vec::new();
+            };
+            let mut found = false;
+            let mut invalid = false;
+            extent.attributes(|att| {
+                if found || att.header.type_id() != attribute_type as u32 {
+                    return;
+                }
+                if att.header.id() != entry.id() {
+                    return;
+                }
+
+                found = true;
+                if att.header.is_non_resident() == 0 {
+                    resident = Some(att.get_resident().to_vec());
+                    return;
+                }
+                match att.data_runs() {
+                    Ok(extent_runs) => runs.extend(extent_runs),
+                    Err(_) => invalid = true,
+                }
+                if att.header_nonres.lowest_vcn() == 0 {
+                    size = Some(att.header_nonres.data_size());
+                }
+            });
+
+            if let Some(data) = resident {
+                return data;
+            }
+            if !found || invalid {
+                warn!("Invalid attribute in the record {}", entry.reference());
+                return Vec::new();
+            }
+        }
+
+        let Some(size) = size else {
+            return Vec::new();
+        };
+        match read_data_runs(reader, &runs, self.volume.cluster_size, size, false) {
+            Ok(data) => data,
+            Err(err) => {
+                warn!("Failed to read the data runs: {}", err);
+                Vec::new()
+            }
+        }
+    }
+
+    // Puts back the last two bytes of each sector, which were replaced by the update sequence number.
+    // Fails if they don't match the update sequence number (the write of the record was torn),
+    // the sectors are restored anyway.
+    fn fixup_record(data: &mut [u8], sector_size: usize) -> Result<(), ParseError> {
+        let header = NtfsFileRecordHeader::ref_from_prefix(data).ok_or(ParseError::InvalidFixup)?;
 
         // Fixup
-        let usn_start = header.update_sequence_offset as usize;
-        //let usn_end = usn_start + 2;
+        let usn_start = header.update_sequence_offset() as usize;
+        let usn_end = usn_start + 2;
         let usa_start = usn_start + 2;
-        let usa_end = usn_start + header.update_sequence_length as usize * 2;
+        let usa_end = usn_start + header.update_sequence_length() as usize * 2;
 
-        //let mut usn = [0u8; 2];
-        //usn.copy_from_slice(&data[usn_start..usn_end]);
+        let mut usn = [0u8; 2];
+        usn.copy_from_slice(&data[usn_start..usn_end]);
 
-        let mut sector_off = SECTOR_SIZE - 2;
-        for usa_off in (usa_start..usa_end).step_by(2) {
+        let mut result = Ok(());
+        let mut sector_off = sector_size - 2;
+        for (sector, usa_off) in (usa_start..usa_end).step_by(2).enumerate() {
             let mut usa = [0u8; 2];
             usa.clone_from_slice(&data[usa_off..usa_off + 2]);
 
-            //let dst = &data[sector_off..sector_off + 2];
-            //if dst != usn {
-            //    return false;
-            //}
+            let dst = &data[sector_off..sector_off + 2];
+            if dst != usn && result.is_ok() {
+                result = Err(ParseError::FixupMismatch { sector });
+            }
 
             data[sector_off..sector_off + 2].copy_from_slice(&usa);
-            sector_off += SECTOR_SIZE;
+            sector_off += sector_size;
+        }
+
+        result
+    }
+}
+
+// Where `Mft::iterate_under` starts from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubtreeRoot<'a> {
+    Record(u64),
+    Path(Cow<'a, Path>),
+}
+
+impl From<u64> for SubtreeRoot<'_> {
+    fn from(number: u64) -> Self {
+        SubtreeRoot::Record(number)
+    }
+}
+
+impl<'a> From<&'a Path> for SubtreeRoot<'a> {
+    fn from(path: &'a Path) -> Self {
+        SubtreeRoot::Path(Cow::Borrowed(path))
+    }
+}
+
+impl<'a> From<&'a str> for SubtreeRoot<'a> {
+    fn from(path: &'a str) -> Self {
+        SubtreeRoot::Path(Cow::Borrowed(Path::new(path)))
+    }
+}
+
+impl From<PathBuf> for SubtreeRoot<'_> {
+    fn from(path: PathBuf) -> Self {
+        SubtreeRoot::Path(Cow::Owned(path))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SubtreeState {
+    Unknown,
+    Visiting,
+    Inside,
+    Outside,
+}
+
+enum BitmapSource {
+    Loaded,
+    // The $MFT record, to find the bitmap.
+    Lazy {
+        mft_record: Vec<u8>,
+        bitmap: OnceLock<Option<Vec<u8>>>,
+    },
+    Skipped,
+}
+
+// From the header flags, for the records that failed the fixup (without the bitmap).
+fn has_in_use_flag(record: &[u8]) -> bool {
+    record.get(22..24).is_some_and(|flags| {
+        u16::from_le_bytes([flags[0], flags[1]]) & NtfsFileFlags::InUse as u16 != 0
+    })
+}
+
+// The byte ranges of $MFT with records in use, in regions of `region_size` (rounded to whole
+// records), the adjacent ones merged.
+fn in_use_regions(bitmap: &[u8], record_size: usize, region_size: usize) -> Vec<Range<usize>> {
+    let region_records = (region_size / record_size).max(1);
+    let mut regions: Vec<Range<usize>> = Vec::new();
+
+    for first in (0..bitmap.len() * 8).step_by(region_records) {
+        let records = first as u64..(first + region_records) as u64;
+        if UsedRecords::new(Some(bitmap), records).next().is_none() {
+            continue;
+        }
+
+        let start = first * record_size;
+        let end = start + region_records * record_size;
+        match regions.last_mut() {
+            Some(last) if last.end == start => last.end = end,
+            _ => regions.push(start..end),
+        }
+    }
+    regions
+}
+
+// Scans the bitmap 64 records at a time, skipping the free ones.
+pub struct UsedRecords<'a> {
+    bitmap: Option<&'a [u8]>,
+    // The first record of `word`.
+    base: u64,
+    word: u64,
+    end: u64,
+}
+
+impl<'a> UsedRecords<'a> {
+    fn new(bitmap: Option<&'a [u8]>, range: Range<u64>) -> Self {
+        let base = range.start & !63;
+        let word = Self::word(bitmap, base) & (u64::MAX << (range.start - base));
+        Self {
+            bitmap,
+            base,
+            word,
+            end: range.end,
+        }
+    }
+
+    // Past the end of the bitmap the records are free.
+    fn word(bitmap: Option<&[u8]>, base: u64) -> u64 {
+        let Some(bitmap) = bitmap else {
+            return u64::MAX;
+        };
+        let mut bytes = [0u8; 8];
+        if let Some(chunk) = usize::try_from(base / 8).ok().and_then(|i| bitmap.get(i..)) {
+            let len = chunk.len().min(8);
+            bytes[..len].copy_from_slice(&chunk[..len]);
         }
+        u64::from_le_bytes(bytes)
     }
 }
 
+impl Iterator for UsedRecords<'_> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        while self.word == 0 {
+            self.base += 64;
+            if self.base >= self.end {
+                self.base = self.end;
+                return None;
+            }
+            self.word = Self::word(self.bitmap, self.base);
+        }
+
+        let number = self.base + self.word.trailing_zeros() as u64;
+        self.word &= self.word - 1;
+        if number >= self.end {
+            self.word = 0;
+            self.base = self.end;
+            return None;
+        }
+        Some(number)
+    }
+}
+
+// The state of each record with `MftOptions::lazy_fixup`.
+const RAW: u8 = 0;
+const FIXING: u8 = 1;
+const FIXED: u8 = 2;
+
+// The records of the MFT, optionally fixed up on the first access: the bytes of a record are
+// written only once (under its state), before any reference to it is handed out, and the
+// references never cover the other records.
+struct MftData {
+    ptr: *mut u8,
+    len: usize,
+    record_size: usize,
+    sector_size: usize,
+    // Empty when all the records were fixed up while loading.
+    states: Box<[AtomicU8]>,
+}
+
+unsafe impl Send for MftData {}
+unsafe impl Sync for MftData {}
+
+impl MftData {
+    fn fixed(data: Vec<u8>, record_size: usize) -> Self {
+        Self::new(data, record_size, 0, Box::default())
+    }
+
+    fn lazy(data: Vec<u8>, record_size: usize, sector_size: usize) -> Self {
+        let records = data.len() / record_size;
+        let states = (0..records).map(|_| AtomicU8::new(RAW)).collect();
+        Self::new(data, record_size, sector_size, states)
+    }
+
+    fn new(data: Vec<u8>, record_size: usize, sector_size: usize, states: Box<[AtomicU8]>) -> Self {
+        let len = data.len();
+        Self {
+            ptr: Box::into_raw(data.into_boxed_slice()) as *mut u8,
+            len,
+            record_size,
+            sector_size,
+            states,
+        }
+    }
+
+    // `in_use` is only called when the fixup fails, the free records are left as they are.
+    fn record(&self, number: usize, in_use: impl FnOnce(&[u8]) -> bool) -> &[u8] {
+        let start = number * self.record_size;
+        assert!(
+            start + self.record_size <= self.len,
+            "record {} out of range",
+            number
+        );
+
+        if let Some(state) = self.states.get(number) {
+            Self::fix(state, || {
+                // Only this thread can get here for this record, and nobody has a reference to it.
+                let record =
+                    unsafe { slice::from_raw_parts_mut(self.ptr.add(start), self.record_size) };
+                if let Err(err) = Mft::check_and_fixup(record, self.sector_size) {
+                    if in_use(record) {
+                        warn!("Skipping corrupt record {}: {}", number, err);
+                        record[0..4].copy_from_slice(BAAD_RECORD_SIGNATURE);
+                    }
+                }
+            });
+        }
+
+        unsafe { slice::from_raw_parts(self.ptr.add(start), self.record_size) }
+    }
+
+    fn fix(state: &AtomicU8, f: impl FnOnce()) {
+        // Puts the state back if `f` panics, the other threads would spin forever otherwise.
+        struct Unfixed<'a>(&'a AtomicU8);
+
+        impl Drop for Unfixed<'_> {
+            fn drop(&mut self) {
+                self.0.store(RAW, Ordering::Release);
+            }
+        }
+
+        loop {
+            match state.compare_exchange(RAW, FIXING, Ordering::Acquire, Ordering::Acquire) {
+                Ok(_) => {
+                    let unfixed = Unfixed(state);
+                    f();
+                    std::mem::forget(unfixed);
+                    state.store(FIXED, Ordering::Release);
+                    return;
+                }
+                Err(FIXED) => return,
+                // Another thread is fixing it up.
+                Err(_) => std::hint::spin_loop(),
+            }
+        }
+    }
+
+    fn all(&self, in_use: impl Fn(usize, &[u8]) -> bool) -> &[u8] {
+        for number in 0..self.states.len() {
+            self.record(number, |record| in_use(number, record));
+        }
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    // `data` is already fixed up.
+    fn set_record(&mut self, number: usize, data: &[u8]) {
+        let start = number * self.record_size;
+        assert!(
+            start + self.record_size <= self.len,
+            "record {} out of range",
+            number
+        );
+
+        let record = unsafe { slice::from_raw_parts_mut(self.ptr.add(start), self.record_size) };
+        record.copy_from_slice(data);
+        if let Some(state) = self.states.get_mut(number) {
+            *state.get_mut() = FIXED;
+        }
+    }
+
+    fn as_ptr(&self) -> *const u8 {
+        self.ptr
+    }
+}
+
+impl Drop for MftData {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(std::ptr::slice_from_raw_parts_mut(self.ptr, self.len)) });
+    }
+}
+
+// On records built in memory, these run everywhere.
 #[cfg(test)]
+pub(crate) mod memory_tests {
+    use std::{
+        cell::RefCell,
+        io::Cursor,
+        path::PathBuf,
+        rc::Rc,
+        sync::atomic::{AtomicU8, Ordering},
+    };
+
+    use crate::{
+        api::{file_reference, NtfsAttributeType, BAAD_RECORD_SIGNATURE, ROOT_RECORD},
+        errors::{NtfsReaderError, ParseError},
+        file_info::{FileInfo, HashMapCache},
+        mft::{
+            in_use_regions, BitmapLoading, BitmapSource, CorruptRecordPolicy, CorruptRecordSummary,
+            Mft, MftData, MftOptions, SubtreeRoot, FIXED, RAW,
+        },
+        verify::VerifyIssue,
+        volume::{Volume, VolumeGeometry},
+    };
+
+    pub const RECORD_SIZE: usize = 1024;
+
+    // A valid record in use, without attributes.
+    pub fn make_record() -> Vec<u8> {
+        let mut data = vec![0u8; RECORD_SIZE];
+        data[0..4].copy_from_slice(b"FILE");
+        data[4..6].copy_from_slice(&48u16.to_le_bytes());
+        data[6..8].copy_from_slice(&3u16.to_le_bytes());
+        data[20..22].copy_from_slice(&56u16.to_le_bytes());
+        data[22..24].copy_from_slice(&1u16.to_le_bytes());
+        data[24..28].copy_from_slice(&64u32.to_le_bytes());
+        data[56..60].copy_from_slice(&u32::MAX.to_le_bytes());
+        data
+    }
+
+    // With a $FILE_NAME in the Win32 namespace.
+    pub fn make_named_record(sequence: u16, parent: u64, name: &str) -> Vec<u8> {
+        let mut data = make_record();
+        data[16..18].copy_from_slice(&sequence.to_le_bytes());
+
+        let name: Vec<u8> = name.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let value_length = 66 + name.len();
+        let length = (24 + value_length).next_multiple_of(8);
+
+        let att = 56;
+        data[att..att + 4].copy_from_slice(&0x30u32.to_le_bytes());
+        data[att + 4..att + 8].copy_from_slice(&(length as u32).to_le_bytes());
+        data[att + 16..att + 20].copy_from_slice(&(value_length as u32).to_le_bytes());
+        data[att + 20..att + 22].copy_from_slice(&24u16.to_le_bytes());
+
+        let value = att + 24;
+        data[value..value + 8].copy_from_slice(&parent.to_le_bytes());
+        data[value + 64] = (name.len() / 2) as u8;
+        data[value + 65] = 1;
+        data[value + 66..value + 66 + name.len()].copy_from_slice(&name);
+
+        let end = att + length;
+        data[end..end + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        data[24..28].copy_from_slice(&(end as u32 + 8).to_le_bytes());
+        data
+    }
+
+    // In use and a directory.
+    pub fn make_directory(mut record: Vec<u8>) -> Vec<u8> {
+        record[22..24].copy_from_slice(&3u16.to_le_bytes());
+        record
+    }
+
+    // `len` records with the root, the directory "dir" (30, sequence 2) and "file" in it (31).
+    pub fn make_tree(len: usize) -> Vec<Vec<u8>> {
+        let mut records = vec![make_record(); len];
+        records[ROOT_RECORD as usize] =
+            make_directory(make_named_record(5, file_reference(5, 5), "."));
+        records[30] = make_directory(make_named_record(2, file_reference(5, 5), "dir"));
+        records[31] = make_named_record(1, file_reference(30, 2), "file");
+        records
+    }
+
+    // All the records are marked as used in the bitmap.
+    pub fn make_mft(records: Vec<Vec<u8>>) -> Mft {
+        let volume = Volume::from_geometry(VolumeGeometry {
+            sector_size: 512,
+            cluster_size: 4096,
+            volume_size: 1 << 30,
+            file_record_size: RECORD_SIZE as u64,
+            mft_position: 4096,
+        });
+        Mft {
+            volume,
+            max_record: records.len() as u64,
+            bitmap: vec![0xff; records.len().div_ceil(8)],
+            bitmap_source: BitmapSource::Loaded,
+            data: MftData::fixed(records.concat(), RECORD_SIZE),
+            parent_index: Vec::new(),
+            corrupt_records: Default::default(),
+        }
+    }
+
+    // A volume of 4 clusters: the boot sector, 8 records in the next two and $MFTMirr in the last.
+    fn make_image() -> Vec<u8> {
+        let mut image = vec![0u8; 4 * 4096];
+        image[3..11].copy_from_slice(b"NTFS    ");
+        image[11..13].copy_from_slice(&512u16.to_le_bytes());
+        image[13] = 8;
+        image[40..48].copy_from_slice(&32u64.to_le_bytes());
+        image[48..56].copy_from_slice(&1u64.to_le_bytes());
+        image[56..64].copy_from_slice(&3u64.to_le_bytes());
+        image[64] = -10i8 as u8;
+
+        // $MFT, with a nonresident $DATA (the two clusters) and a resident $BITMAP (all in use).
+        let mut mft = make_record();
+        mft[24..28].copy_from_slice(&168u32.to_le_bytes());
+        let att = 56;
+        mft[att..att + 4].copy_from_slice(&0x80u32.to_le_bytes());
+        mft[att + 4..att + 8].copy_from_slice(&72u32.to_le_bytes());
+        mft[att + 8] = 1;
+        mft[att + 32..att + 34].copy_from_slice(&64u16.to_le_bytes());
+        mft[att + 48..att + 56].copy_from_slice(&8192u64.to_le_bytes());
+        mft[att + 64..att + 68].copy_from_slice(&[0x11, 2, 1, 0]);
+        let att = 128;
+        mft[att..att + 4].copy_from_slice(&0xB0u32.to_le_bytes());
+        mft[att + 4..att + 8].copy_from_slice(&32u32.to_le_bytes());
+        mft[att + 16..att + 20].copy_from_slice(&1u32.to_le_bytes());
+        mft[att + 20..att + 22].copy_from_slice(&24u16.to_le_bytes());
+        mft[att + 24] = 0xff;
+        mft[160..164].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        let mut records = vec![mft];
+        records.extend(vec![make_record(); 7]);
+        let records = records.concat();
+        image[4096..4096 + 8192].copy_from_slice(&records);
+        image[3 * 4096..4 * 4096].copy_from_slice(&records[..4096]);
+        image
+    }
+
+    fn load(image: Vec<u8>, corrupt_records: CorruptRecordPolicy) -> Result<Mft, NtfsReaderError> {
+        let volume = Volume::from_boot_sector(&image[..512], "image").unwrap();
+        let options = MftOptions {
+            corrupt_records,
+            ..Default::default()
+        };
+        Mft::load(volume, Cursor::new(image), options)
+    }
+
+    #[test]
+    fn corrupt_records() {
+        let mut image = make_image();
+        // A torn write in record 2 (mirrored) and a garbage record 5.
+        image[4096 + 2 * RECORD_SIZE + 1022] = 7;
+        image[4096 + 5 * RECORD_SIZE] = 0;
+
+        let mft = load(image.clone(), CorruptRecordPolicy::Skip).unwrap();
+        assert_eq!(mft.max_record, 8);
+        assert!(mft.get_record(2).is_none() && mft.get_record(5).is_none());
+        assert!(mft.get_record(4).is_some());
+        assert_eq!(
+            mft.corrupt_records,
+            CorruptRecordSummary {
+                skipped: 2,
+                repaired: 0
+            }
+        );
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let seen_by_callback = seen.clone();
+        let callback = Box::new(move |number, err: &NtfsReaderError| {
+            if let NtfsReaderError::CorruptRecord { source, .. } = err {
+                seen_by_callback.borrow_mut().push((number, *source));
+            }
+        });
+        load(image.clone(), CorruptRecordPolicy::Callback(callback)).unwrap();
+        assert_eq!(
+            *seen.borrow(),
+            vec![
+                (2, ParseError::FixupMismatch { sector: 1 }),
+                (5, ParseError::BadSignature)
+            ]
+        );
+
+        assert!(matches!(
+            load(image.clone(), CorruptRecordPolicy::Error),
+            Err(NtfsReaderError::CorruptRecord { record: 2, .. })
+        ));
+
+        let mft = load(image, CorruptRecordPolicy::Repair).unwrap();
+        assert!(mft.get_record(2).is_some());
+        assert_eq!(
+            mft.corrupt_records,
+            CorruptRecordSummary {
+                skipped: 1,
+                repaired: 1
+            }
+        );
+    }
+
+    #[test]
+    fn used_records() {
+        let mut mft = make_mft(vec![make_record(); 200]);
+        mft.bitmap.fill(0);
+        for number in [0, 3, 63, 64, 130, 199] {
+            mft.bitmap[number / 8] |= 1 << (number % 8);
+        }
+
+        let naive = |range: std::ops::Range<u64>| -> Vec<u64> {
+            range.filter(|number| mft.record_exists(*number)).collect()
+        };
+        for range in [0..200, 2..131, 0..130, 64..65, 65..130, 140..140, 190..400] {
+            let used: Vec<u64> = mft.used_records(range.clone()).collect();
+            assert_eq!(used, naive(range.start..range.end.min(200)), "{:?}", range);
+        }
+    }
+
+    #[test]
+    fn skip_free_regions() {
+        let mut bitmap = vec![0u8; 4];
+        for number in [0, 1, 5, 20, 21] {
+            bitmap[number / 8] |= 1 << (number % 8);
+        }
+        // Regions of 4 records: 0..4, 4..8 and 20..24.
+        assert_eq!(
+            in_use_regions(&bitmap, RECORD_SIZE, 4 * RECORD_SIZE),
+            vec![0..8 * RECORD_SIZE, 20 * RECORD_SIZE..24 * RECORD_SIZE]
+        );
+        assert!(in_use_regions(&[0; 4], RECORD_SIZE, 4 * RECORD_SIZE).is_empty());
+
+        let image = make_image();
+        let volume = Volume::from_boot_sector(&image[..512], "image").unwrap();
+        let options = MftOptions {
+            skip_free_regions: true,
+            ..Default::default()
+        };
+        // The only region covers the whole MFT.
+        let mft = Mft::load(volume, Cursor::new(image.clone()), options).unwrap();
+        let full = load(image, CorruptRecordPolicy::Skip).unwrap();
+        assert_eq!(mft.max_record, 8);
+        assert_eq!(mft.data(), full.data());
+    }
+
+    #[test]
+    fn bitmap_loading() {
+        let mut image = make_image();
+        // Free in the bitmap, but in use according to the record.
+        image[4096 + 128 + 24] = 0x7f;
+        let path =
+            std::env::temp_dir().join(format!("ntfs-reader-bitmap-{}.img", std::process::id()));
+        std::fs::write(&path, &image).unwrap();
+
+        let load = |bitmap| {
+            let options = MftOptions {
+                bitmap,
+                ..Default::default()
+            };
+            Mft::with_options(Volume::from_image(&path, 0).unwrap(), options).unwrap()
+        };
+
+        let eager = load(BitmapLoading::Eager);
+        assert!(!eager.record_exists(7));
+        assert_eq!(eager.used_records(0..8).count(), 7);
+
+        let skipped = load(BitmapLoading::Skip);
+        assert!(skipped.get_bitmap().is_none() && skipped.record_exists(7));
+        assert_eq!(skipped.used_records(0..8).count(), 8);
+
+        let lazy = load(BitmapLoading::Lazy);
+        assert!(lazy.bitmap.is_empty());
+        assert!(!lazy.record_exists(7) && lazy.record_exists(6));
+        assert_eq!(lazy.get_bitmap(), Some(&[0x7f][..]));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn lazy_fixup() {
+        let mut image = make_image();
+        image[4096 + 2 * RECORD_SIZE + 1022] = 7;
+        image[4096 + 5 * RECORD_SIZE] = 0;
+
+        let volume = Volume::from_boot_sector(&image[..512], "image").unwrap();
+        let options = MftOptions {
+            lazy_fixup: true,
+            ..Default::default()
+        };
+        let mft = Mft::load(volume, Cursor::new(image), options).unwrap();
+        assert_eq!(mft.max_record, 8);
+        // Not fixed up yet.
+        assert_eq!(mft.data.states[4].load(Ordering::Relaxed), RAW);
+
+        assert!(mft.get_record(2).is_none() && mft.get_record(5).is_none());
+        assert!(mft.get_record(4).is_some());
+        assert_eq!(&mft.get_record_data(2)[0..4], BAAD_RECORD_SIGNATURE);
+        assert_eq!(mft.data().len(), 8 * RECORD_SIZE);
+        assert!(mft
+            .data
+            .states
+            .iter()
+            .all(|state| state.load(Ordering::Relaxed) == FIXED));
+
+        // A panic while fixing up leaves the record to be fixed up again.
+        let state = AtomicU8::new(RAW);
+        let fixed = std::panic::catch_unwind(|| MftData::fix(&state, || panic!("fixup")));
+        assert!(fixed.is_err());
+        assert_eq!(state.load(Ordering::Relaxed), RAW);
+        MftData::fix(&state, || {});
+        assert_eq!(state.load(Ordering::Relaxed), FIXED);
+    }
+
+    #[test]
+    fn parent_cycle() {
+        let mut records = vec![make_record(); 32];
+        records[ROOT_RECORD as usize] = make_named_record(5, file_reference(5, 5), ".");
+        records[30] = make_named_record(1, file_reference(31, 1), "a");
+        records[31] = make_named_record(1, file_reference(30, 1), "b");
+        let mft = make_mft(records);
+
+        assert_eq!(mft.get_relative_path(30), None);
+        assert_eq!(mft.into_compact().get_relative_path(31), None);
+    }
+
+    #[test]
+    fn stale_parent_references() {
+        let mut records = make_tree(33);
+        // Written when record 30 was an older directory.
+        records[32] = make_named_record(1, file_reference(30, 1), "old");
+        let plain = make_mft(records.clone());
+        let mut indexed = make_mft(records);
+        indexed.build_parent_index();
+
+        for mft in [&plain, &indexed] {
+            assert!(mft.is_reference_current(file_reference(30, 2)));
+            assert!(!mft.is_reference_current(file_reference(30, 1)));
+            assert_eq!(
+                mft.get_relative_path(31),
+                Some(PathBuf::from("dir").join("file"))
+            );
+            assert_eq!(mft.get_relative_path(32), None);
+
+            let file = mft.get_record(32).unwrap();
+            let info = FileInfo::new(mft, &file);
+            assert!(info.stale_parent && info.path.as_os_str().is_empty());
+            assert!(!FileInfo::new(mft, &mft.get_record(31).unwrap()).stale_parent);
+            assert!(FileInfo::with_cache(mft, &file, &mut HashMapCache::default()).stale_parent);
+
+            let infos = FileInfo::builder().build_all(mft);
+            assert_eq!(infos.iter().filter(|info| info.stale_parent).count(), 1);
+        }
+    }
+
+    #[test]
+    fn iterate_under() {
+        let mut records = make_tree(36);
+        records[30] = make_directory(make_named_record(2, file_reference(5, 5), "Users"));
+        records[31] = make_directory(make_named_record(1, file_reference(30, 2), "alice"));
+        records[32] = make_named_record(1, file_reference(31, 1), "notes.txt");
+        records[33] = make_named_record(1, file_reference(5, 5), "top");
+        // Written when record 30 was an older directory.
+        records[34] = make_named_record(1, file_reference(30, 1), "old");
+        records[35] = make_named_record(1, file_reference(30, 2), "bob.txt");
+        let plain = make_mft(records.clone());
+        let mut indexed = make_mft(records);
+        indexed.build_parent_index();
+
+        for mft in [&plain, &indexed] {
+            let under = |root: SubtreeRoot| -> Vec<u64> {
+                let mut files = Vec::new();
+                mft.iterate_under(root, |file| files.push(file.number))
+                    .unwrap();
+                files
+            };
+
+            assert_eq!(under(30.into()), vec![31, 32, 35]);
+            assert_eq!(under("users/ALICE".into()), vec![32]);
+            assert_eq!(under(PathBuf::from("Users").join("alice").into()), vec![32]);
+            assert!(under(33.into()).is_empty());
+            assert_eq!(under(ROOT_RECORD.into()).len(), 5);
+
+            assert_eq!(mft.find_path("Users/alice/notes.txt"), Some(32));
+            assert_eq!(mft.find_path(""), Some(ROOT_RECORD));
+            assert_eq!(mft.find_path("Users/old"), None);
+            assert!(matches!(
+                mft.iterate_under("missing", |_| {}),
+                Err(NtfsReaderError::IOError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn verify() {
+        let mut records = make_tree(33);
+        assert!(make_mft(records.clone()).verify().is_ok());
+
+        records[32] = make_named_record(1, file_reference(30, 1), "old");
+        records[26] = make_named_record(1, file_reference(27, 1), "a");
+        records[27] = make_named_record(1, file_reference(26, 1), "b");
+        records[26][22] = 3;
+        records[27][22] = 3;
+        records[24][22] = 0;
+        records[25][0..4].copy_from_slice(b"BAAD");
+        let mut mft = make_mft(records);
+        mft.bitmap[2] &= !0x80;
+
+        let report = mft.verify();
+        assert_eq!(report.records_checked, 33);
+        assert_eq!(
+            report.issues,
+            vec![
+                VerifyIssue::NotInBitmap { record: 23 },
+                VerifyIssue::NotInUse { record: 24 },
+                VerifyIssue::CorruptRecord { record: 25 },
+                VerifyIssue::ParentLoop { record: 26 },
+                VerifyIssue::InvalidParent {
+                    record: 32,
+                    parent: file_reference(30, 1)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn try_iterate_files() {
+        let mut records = vec![make_record(); 30];
+        records[25][0] = 0;
+
+        let mft = make_mft(records);
+        let mut files = Vec::new();
+        let mut corrupt = Vec::new();
+        mft.try_iterate_files(|file| -> Result<(), ()> {
+            match file {
+                Ok(file) => files.push(file.number()),
+                Err(NtfsReaderError::CorruptRecord { record, .. }) => corrupt.push(record),
+                Err(_) => return Err(()),
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(files, vec![24, 26, 27, 28, 29]);
+        assert_eq!(corrupt, vec![25]);
+
+        // Stopped by the callback.
+        let mut count = 0;
+        let result = mft.try_iterate_files(|_| {
+            count += 1;
+            if count == 2 {
+                return Err("stop");
+            }
+            Ok(())
+        });
+        assert_eq!(result, Err("stop"));
+        assert_eq!(count, 2);
+    }
+
+    // Appends `attribute` to the attributes of `record`, with its length and id.
+    fn push_attribute(record: &mut [u8], mut attribute: Vec<u8>, id: u16) {
+        attribute.resize(attribute.len().next_multiple_of(8), 0);
+        let len = attribute.len();
+        attribute[4..8].copy_from_slice(&(len as u32).to_le_bytes());
+        attribute[14..16].copy_from_slice(&id.to_le_bytes());
+
+        let end = u32::from_le_bytes(record[24..28].try_into().unwrap()) as usize - 8;
+        record[end..end + len].copy_from_slice(&attribute);
+        record[end + len..end + len + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        record[24..28].copy_from_slice(&((end + len + 8) as u32).to_le_bytes());
+    }
+
+    fn resident(type_id: u32, value: &[u8]) -> Vec<u8> {
+        let mut attribute = vec![0u8; 24];
+        attribute[0..4].copy_from_slice(&type_id.to_le_bytes());
+        attribute[16..20].copy_from_slice(&(value.len() as u32).to_le_bytes());
+        attribute[20..22].copy_from_slice(&24u16.to_le_bytes());
+        attribute.extend_from_slice(value);
+        attribute
+    }
+
+    // A single run of one cluster.
+    fn nonresident(lowest_vcn: u64, lcn: u8, size: u64) -> Vec<u8> {
+        let mut attribute = vec![0u8; 64];
+        attribute[0..4].copy_from_slice(&0x80u32.to_le_bytes());
+        attribute[8] = 1;
+        attribute[16..24].copy_from_slice(&lowest_vcn.to_le_bytes());
+        attribute[24..32].copy_from_slice(&lowest_vcn.to_le_bytes());
+        attribute[32..34].copy_from_slice(&64u16.to_le_bytes());
+        attribute[48..56].copy_from_slice(&size.to_le_bytes());
+        attribute.extend_from_slice(&[0x11, 1, lcn, 0]);
+        attribute
+    }
+
+    fn list_entry(type_id: u32, lowest_vcn: u64, number: u64, id: u16) -> Vec<u8> {
+        let mut entry = vec![0u8; 32];
+        entry[0..4].copy_from_slice(&type_id.to_le_bytes());
+        entry[4..6].copy_from_slice(&32u16.to_le_bytes());
+        entry[8..16].copy_from_slice(&lowest_vcn.to_le_bytes());
+        entry[16..24].copy_from_slice(&file_reference(number, 1).to_le_bytes());
+        entry[24..26].copy_from_slice(&id.to_le_bytes());
+        entry
+    }
+
+    #[test]
+    fn attribute_list() {
+        // $DATA split between the records 31 and 32, listed by 30.
+        let mut records = vec![make_record(); 33];
+        let list = [
+            list_entry(0x10, 0, 30, 0),
+            list_entry(0x80, 0, 31, 3),
+            list_entry(0x80, 1, 32, 4),
+        ]
+        .concat();
+        push_attribute(&mut records[30], resident(0x20, &list), 1);
+        push_attribute(&mut records[31], resident(0x80, b"other"), 2);
+        push_attribute(&mut records[31], nonresident(0, 2, 5000), 3);
+        push_attribute(&mut records[32], nonresident(1, 5, 5000), 4);
+        let mft = make_mft(records);
+
+        let mut disk = vec![0u8; 6 * 4096];
+        disk[2 * 4096..3 * 4096].fill(1);
+        disk[5 * 4096..6 * 4096].fill(2);
+        let mut reader = Cursor::new(disk);
+
+        let data = mft.read_attribute_fs(&mut reader, 30, NtfsAttributeType::Data);
+        assert_eq!(data.len(), 5000);
+        assert!(data[..4096].iter().all(|b| *b == 1));
+        assert!(data[4096..].iter().all(|b| *b == 2));
+        // Not in the base record itself.
+        let base = mft.get_record_data(30);
+        assert!(
+            Mft::read_data_fs(&mft.volume, &mut reader, base, NtfsAttributeType::Data).is_empty()
+        );
+
+        // Moved whole to another record.
+        let mut records = vec![make_record(); 32];
+        push_attribute(
+            &mut records[30],
+            resident(0x20, &list_entry(0x80, 0, 31, 7)),
+            1,
+        );
+        push_attribute(&mut records[31], resident(0x80, b"moved"), 7);
+        let mft = make_mft(records);
+        let data = mft.read_attribute_fs(&mut reader, 30, NtfsAttributeType::Data);
+        assert_eq!(data, b"moved");
+
+        // Listed past the end of the MFT.
+        let mut records = vec![make_record(); 31];
+        push_attribute(
+            &mut records[30],
+            resident(0x20, &list_entry(0x80, 0, 1000, 7)),
+            1,
+        );
+        let mft = make_mft(records);
+        assert!(mft
+            .read_attribute_fs(&mut reader, 30, NtfsAttributeType::Data)
+            .is_empty());
+        assert!(mft
+            .read_attribute_fs(&mut reader, 1000, NtfsAttributeType::Data)
+            .is_empty());
+    }
+}
+
+#[cfg(all(test, feature = "windows"))]
 mod tests {
 
     use std::time::Instant;
 
     use crate::{
-        errors::NtfsReaderResult, file::NtfsFile, file_info::FileInfo, mft::Mft, volume::Volume,
+        errors::NtfsReaderResult,
+        file::NtfsFile,
+        file_info::{FileInfo, InternedCache},
+        mft::Mft,
+        volume::Volume,
     };
     use tracing::info;
     use tracing_subscriber::FmtSubscriber;
@@ -288,6 +1896,32 @@ mod tests {
         let no_cache_iteration_duration = Instant::now() - start_time;
         let no_cache_total_duration = no_cache_iteration_duration;
 
+        // Test bulk resolution
+        info!("======== Testing Bulk ========");
+        let start_time = Instant::now();
+        let files = FileInfo::builder().build_all(&mft);
+        info!("Read all {} records", files.len());
+        let bulk_iteration_duration = Instant::now() - start_time;
+
+        let pre_drop_time = Instant::now();
+        drop(files);
+        let bulk_drop_duration = Instant::now() - pre_drop_time;
+        let bulk_total_duration = Instant::now() - start_time;
+
+        // Test with interned cache
+        let mut interned_cache = InternedCache::default();
+        let start_time = Instant::now();
+        test_iteration("Interned Cache", &mft, |mft: &Mft, file: &NtfsFile| {
+            FileInfo::with_cache(mft, file, &mut interned_cache)
+        })?;
+        let interned_iteration_duration = Instant::now() - start_time;
+
+        let pre_drop_time = Instant::now();
+        info!("Dropping cache...");
+        drop(interned_cache);
+        let interned_cache_drop_duration = Instant::now() - pre_drop_time;
+        let interned_cache_total_duration = Instant::now() - start_time;
+
         // Test with HashMap cache
         //let mut hashmap_cache = HashMapCache::default();
         //let start_time = Instant::now();
@@ -327,6 +1961,17 @@ mod tests {
             "{:<13} {:<10.3?} {:<10} {:<10.3?}",
             "No Cache", no_cache_iteration_duration, "0", no_cache_total_duration
         );
+        info!(
+            "{:<13} {:<10.3?} {:<10.3?} {:<10.3?}",
+            "Bulk", bulk_iteration_duration, bulk_drop_duration, bulk_total_duration
+        );
+        info!(
+            "{:<13} {:<10.3?} {:<10.3?} {:<10.3?}",
+            "Interned",
+            interned_iteration_duration,
+            interned_cache_drop_duration,
+            interned_cache_total_duration
+        );
 
         //info!(
         //    "{:<13} {:<10.3?} {:<10.3?} {:<10.3?}",
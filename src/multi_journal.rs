@@ -0,0 +1,114 @@
+// Copyright (c) 2022, Matteo Bernacchia <dev@kikijiki.com>. All rights reserved.
+// This project is dual licensed under the Apache License 2.0 and the MIT license.
+// See the LICENSE files in the project root for details.
+
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+use crate::journal::{Journal, JournalOptions, UsnRecord};
+use crate::volume::Volume;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Reads the journals of several volumes as a single feed ordered by timestamp.
+// The records are returned together with the index of the journal they come from.
+pub struct MultiJournal {
+    journals: Vec<Journal>,
+    // From a journal that failed after the others were read, returned by the next read.
+    error: Option<std::io::Error>,
+}
+
+impl MultiJournal {
+    pub fn new(journals: Vec<Journal>) -> Self {
+        MultiJournal {
+            journals,
+            error: None,
+        }
+    }
+
+    // Opens the journals of all the fixed NTFS volumes. The volumes without an active journal
+    // (or that can't be opened) are skipped, see `journals` for the ones opened.
+    pub fn open_fixed(options: JournalOptions) -> Self {
+        let journals = Volume::fixed_ntfs_paths()
+            .into_iter()
+            .filter_map(|path| match Journal::open(&path, options.clone()) {
+                Ok(journal) => Some(journal),
+                Err(err) => {
+                    warn!("Skipping the journal of {}: {}", path.display(), err);
+                    None
+                }
+            })
+            .collect();
+
+        Self::new(journals)
+    }
+
+    pub fn add(&mut self, journal: Journal) -> usize {
+        self.journals.push(journal);
+        self.journals.len() - 1
+    }
+
+    pub fn journals(&self) -> &[Journal] {
+        &self.journals
+    }
+
+    pub fn journals_mut(&mut self) -> &mut [Journal] {
+        &mut self.journals
+    }
+
+    pub fn into_journals(self) -> Vec<Journal> {
+        self.journals
+    }
+
+    // The other journals have already advanced past their records when one fails, so those are
+    // returned first and the error by the next call.
+    pub fn read(&mut self) -> Result<Vec<(usize, UsnRecord)>, std::io::Error> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+
+        let mut results = Vec::new();
+        for (index, journal) in self.journals.iter_mut().enumerate() {
+            match journal.read() {
+                Ok(records) => results.extend(records.into_iter().map(|record| (index, record))),
+                Err(err) if self.error.is_none() => self.error = Some(err),
+                Err(err) => warn!("Failed to read the journal {}: {}", index, err),
+            }
+        }
+
+        if results.is_empty() {
+            if let Some(err) = self.error.take() {
+                return Err(err);
+            }
+        }
+
+        // Stable, so records with the same timestamp keep the journal order.
+        results.sort_by_key(|(_, record)| record.timestamp);
+        Ok(results)
+    }
+
+    // Each journal waits on its own handle, so this polls all of them
+    // until some records are available or the timeout expires.
+    pub fn read_wait(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<(usize, UsnRecord)>, std::io::Error> {
+        let start = Instant::now();
+
+        loop {
+            let results = self.read()?;
+            if !results.is_empty() {
+                return Ok(results);
+            }
+
+            if let Some(timeout) = timeout {
+                if start.elapsed() >= timeout {
+                    return Ok(results);
+                }
+            }
+
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
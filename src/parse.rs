@@ -0,0 +1,451 @@
+// Copyright (c) 2022, Matteo Bernacchia <dev@kikijiki.com>. All rights reserved.
+// This project is dual licensed under the Apache License 2.0 and the MIT license.
+// See the LICENSE files in the project root for details.
+
+// Owned parsing of records and attributes that checks every length and offset read from the data,
+// so that it can be used on untrusted input (it never panics).
+
+use std::mem::size_of;
+
+use crate::{
+    api::{
+        NtfsAttributeType, NtfsFileFlags, NtfsFileRecordHeader, FILE_RECORD_SIGNATURE,
+        REFERENCE_NUMBER_MASK,
+    },
+    errors::{NtfsReaderError, NtfsReaderResult, ParseError},
+};
+
+const ATTRIBUTE_HEADER_SIZE: usize = 16;
+const RESIDENT_HEADER_SIZE: usize = 24;
+const NONRESIDENT_HEADER_SIZE: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedRecord {
+    pub sequence_value: u16,
+    pub link_count: u16,
+    pub flags: u16,
+    pub base_reference: u64,
+    pub attributes: Vec<ParsedAttribute>,
+}
+
+impl ParsedRecord {
+    pub fn is_used(&self) -> bool {
+        self.flags & NtfsFileFlags::InUse as u16 != 0
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.flags & NtfsFileFlags::IsDirectory as u16 != 0
+    }
+
+    // Zero for base records.
+    pub fn base_record(&self) -> u64 {
+        self.base_reference & REFERENCE_NUMBER_MASK
+    }
+
+    pub fn attribute(&self, attribute_type: NtfsAttributeType) -> Option<&ParsedAttribute> {
+        self.attributes
+            .iter()
+            .find(|att| att.type_id == attribute_type as u32)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedAttribute {
+    pub type_id: u32,
+    pub name: String,
+    pub flags: u16,
+    pub id: u16,
+    pub content: AttributeContent,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttributeContent {
+    Resident(Vec<u8>),
+    NonResident {
+        lowest_vcn: i64,
+        highest_vcn: i64,
+        allocated_size: u64,
+        data_size: u64,
+        initialized_size: u64,
+        runs: Vec<DataRun>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataRun {
+    // None for sparse runs, which have no clusters on disk.
+    pub lcn: Option<u64>,
+    pub cluster_count: u64,
+}
+
+// The data of a whole file record, as read from the MFT (the fixup is applied to a copy).
+pub fn parse_record(data: &[u8]) -> Result<ParsedRecord, ParseError> {
+    parse_located(data, true).map_err(|(_, _, err)| err)
+}
+
+// Same as `parse_record`, for the records that were already fixed up (e.g. `Mft::get_record_data`).
+pub fn parse_fixed_record(data: &[u8]) -> Result<ParsedRecord, ParseError> {
+    parse_located(data, false).map_err(|(_, _, err)| err)
+}
+
+// Same as `parse_record`, the error tells which record and attribute are corrupt.
+pub fn parse_record_with_context(number: u64, data: &[u8]) -> NtfsReaderResult<ParsedRecord> {
+    with_context(number, parse_located(data, true))
+}
+
+pub fn parse_fixed_record_with_context(number: u64, data: &[u8]) -> NtfsReaderResult<ParsedRecord> {
+    with_context(number, parse_located(data, false))
+}
+
+fn with_context(
+    number: u64,
+    result: Result<ParsedRecord, Located>,
+) -> NtfsReaderResult<ParsedRecord> {
+    result.map_err(
+        |(attribute_type, offset, source)| NtfsReaderError::CorruptRecord {
+            record: number,
+            attribute_type,
+            offset,
+            source,
+        },
+    )
+}
+
+// The type of the attribute (None for the record header) and its offset in the record.
+type Located = (Option<u32>, usize, ParseError);
+
+fn parse_located(data: &[u8], apply_fixup: bool) -> Result<ParsedRecord, Located> {
+    let header = |err| (None, 0, err);
+
+    if data.len() < size_of::<NtfsFileRecordHeader>() {
+        return Err(header(ParseError::OutOfBounds {
+            offset: 0,
+            len: size_of::<NtfsFileRecordHeader>(),
+        }));
+    }
+    if data[0..4] != *FILE_RECORD_SIGNATURE {
+        return Err(header(ParseError::BadSignature));
+    }
+
+    let mut data = data.to_vec();
+    if apply_fixup {
+        fixup(&mut data).map_err(header)?;
+    }
+
+    let attributes_offset = read_u16(&data, 20).map_err(header)? as usize;
+    let used_size = read_u32(&data, 24).map_err(header)? as usize;
+    if used_size > data.len() {
+        return Err(header(ParseError::OutOfBounds {
+            offset: 0,
+            len: used_size,
+        }));
+    }
+
+    let mut attributes = Vec::new();
+    let mut offset = attributes_offset;
+    while offset < used_size {
+        let in_attribute = |type_id, err| (type_id, offset, err);
+
+        let type_id =
+            read_u32(&data[..used_size], offset).map_err(|err| in_attribute(None, err))?;
+        if type_id == NtfsAttributeType::End as u32 {
+            break;
+        }
+
+        let length = read_u32(&data[..used_size], offset + 4)
+            .map_err(|err| in_attribute(Some(type_id), err))? as usize;
+        if length < ATTRIBUTE_HEADER_SIZE || length > used_size - offset {
+            return Err(in_attribute(
+                Some(type_id),
+                ParseError::InvalidAttribute { offset: 0 },
+            ));
+        }
+
+        let attribute = parse_attribute(&data[offset..offset + length])
+            .map_err(|err| in_attribute(Some(type_id), err))?;
+        attributes.push(attribute);
+        offset += length;
+    }
+
+    Ok(ParsedRecord {
+        sequence_value: read_u16(&data, 16).map_err(header)?,
+        link_count: read_u16(&data, 18).map_err(header)?,
+        flags: read_u16(&data, 22).map_err(header)?,
+        base_reference: read_u64(&data, 32).map_err(header)?,
+        attributes,
+    })
+}
+
+// The data starting at the attribute header, anything past its length is ignored.
+pub fn parse_attribute(data: &[u8]) -> Result<ParsedAttribute, ParseError> {
+    let length = read_u32(data, 4)? as usize;
+    if length < ATTRIBUTE_HEADER_SIZE || length > data.len() {
+        return Err(ParseError::InvalidAttribute { offset: 0 });
+    }
+    let data = &data[..length];
+
+    let name_length = data[9] as usize;
+    let name_offset = read_u16(data, 10)? as usize;
+    let name: Vec<u16> = slice(data, name_offset, name_length * 2)?
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+
+    let content = if data[8] == 0 {
+        if length < RESIDENT_HEADER_SIZE {
+            return Err(ParseError::InvalidAttribute { offset: 0 });
+        }
+        let value_length = read_u32(data, 16)? as usize;
+        let value_offset = read_u16(data, 20)? as usize;
+        AttributeContent::Resident(slice(data, value_offset, value_length)?.to_vec())
+    } else {
+        if length < NONRESIDENT_HEADER_SIZE {
+            return Err(ParseError::InvalidAttribute { offset: 0 });
+        }
+        let runs_offset = read_u16(data, 32)? as usize;
+        let runs = match data.get(runs_offset..) {
+            Some(runs) => parse_data_runs(runs)?,
+            None => {
+                return Err(ParseError::OutOfBounds {
+                    offset: runs_offset,
+                    len: 1,
+                })
+            }
+        };
+        AttributeContent::NonResident {
+            lowest_vcn: read_u64(data, 16)? as i64,
+            highest_vcn: read_u64(data, 24)? as i64,
+            allocated_size: read_u64(data, 40)?,
+            data_size: read_u64(data, 48)?,
+            initialized_size: read_u64(data, 56)?,
+            runs,
+        }
+    };
+
+    Ok(ParsedAttribute {
+        type_id: read_u32(data, 0)?,
+        name: String::from_utf16_lossy(&name),
+        flags: read_u16(data, 12)?,
+        id: read_u16(data, 14)?,
+        content,
+    })
+}
+
+// The mapping pairs of a nonresident attribute, up to the terminating zero.
+pub fn parse_data_runs(data: &[u8]) -> Result<Vec<DataRun>, ParseError> {
+    let mut runs = Vec::new();
+    let mut cursor = 0usize;
+    let mut prev_lcn = 0i64;
+
+    loop {
+        let header = match data.get(cursor) {
+            Some(0) => break,
+            Some(header) => *header as usize,
+            None => {
+                return Err(ParseError::OutOfBounds {
+                    offset: cursor,
+                    len: 1,
+                })
+            }
+        };
+
+        let count_b = header & 0x0f;
+        let offset_b = header >> 4;
+        if count_b == 0 || count_b > 8 || offset_b > 8 {
+            return Err(ParseError::InvalidDataRun { offset: cursor });
+        }
+        cursor += 1;
+
+        let mut buf = [0u8; 8];
+        buf[..count_b].copy_from_slice(slice(data, cursor, count_b)?);
+        let cluster_count = u64::from_le_bytes(buf);
+        cursor += count_b;
+
+        let lcn = if offset_b == 0 {
+            None
+        } else {
+            // Relative to the previous run, and signed.
+            let mut buf = [0u8; 8];
+            buf[..offset_b].copy_from_slice(slice(data, cursor, offset_b)?);
+            let empty_bits = (8 - offset_b) * 8;
+            let delta = (i64::from_le_bytes(buf) << empty_bits) >> empty_bits;
+            cursor += offset_b;
+
+            match prev_lcn.checked_add(delta) {
+                Some(lcn) if lcn >= 0 => {
+                    prev_lcn = lcn;
+                    Some(lcn as u64)
+                }
+                _ => return Err(ParseError::InvalidDataRun { offset: cursor }),
+            }
+        };
+
+        runs.push(DataRun { lcn, cluster_count });
+    }
+
+    Ok(runs)
+}
+
+// Checks and puts back the last two bytes of each sector, which were replaced by the update sequence number.
+fn fixup(data: &mut [u8]) -> Result<(), ParseError> {
+    let usn_offset = read_u16(data, 4)? as usize;
+    let usa_length = read_u16(data, 6)? as usize;
+    if usa_length < 2 {
+        return Err(ParseError::InvalidFixup);
+    }
+
+    let sectors = usa_length - 1;
+    if !data.len().is_multiple_of(sectors) || data.len() / sectors < 2 {
+        return Err(ParseError::InvalidFixup);
+    }
+    let sector_size = data.len() / sectors;
+
+    let usa = slice(data, usn_offset, usa_length * 2)?.to_vec();
+    for sector in 0..sectors {
+        let end = (sector + 1) * sector_size;
+        if data[end - 2..end] != usa[0..2] {
+            return Err(ParseError::FixupMismatch { sector });
+        }
+        data[end - 2..end].copy_from_slice(&usa[(sector + 1) * 2..(sector + 2) * 2]);
+    }
+
+    Ok(())
+}
+
+fn slice(data: &[u8], offset: usize, len: usize) -> Result<&[u8], ParseError> {
+    offset
+        .checked_add(len)
+        .and_then(|end| data.get(offset..end))
+        .ok_or(ParseError::OutOfBounds { offset, len })
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, ParseError> {
+    Ok(u16::from_le_bytes(
+        slice(data, offset, 2)?.try_into().unwrap(),
+    ))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, ParseError> {
+    Ok(u32::from_le_bytes(
+        slice(data, offset, 4)?.try_into().unwrap(),
+    ))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, ParseError> {
+    Ok(u64::from_le_bytes(
+        slice(data, offset, 8)?.try_into().unwrap(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        parse_attribute, parse_data_runs, parse_record, parse_record_with_context,
+        AttributeContent, DataRun,
+    };
+    use crate::{
+        api::NtfsAttributeType,
+        errors::{NtfsReaderError, ParseError},
+    };
+
+    // A 1024 bytes record with a resident $FILE_NAME-like attribute and a nonresident $DATA.
+    fn make_record() -> Vec<u8> {
+        let mut data = vec![0u8; 1024];
+        data[0..4].copy_from_slice(b"FILE");
+        data[4..6].copy_from_slice(&48u16.to_le_bytes());
+        data[6..8].copy_from_slice(&3u16.to_le_bytes());
+        data[16..18].copy_from_slice(&7u16.to_le_bytes());
+        data[20..22].copy_from_slice(&56u16.to_le_bytes());
+        data[22..24].copy_from_slice(&1u16.to_le_bytes());
+
+        // Resident, with a two characters name and four bytes of value.
+        let att = 56;
+        data[att..att + 4].copy_from_slice(&0x30u32.to_le_bytes());
+        data[att + 4..att + 8].copy_from_slice(&32u32.to_le_bytes());
+        data[att + 9] = 2;
+        data[att + 10..att + 12].copy_from_slice(&24u16.to_le_bytes());
+        data[att + 16..att + 20].copy_from_slice(&4u32.to_le_bytes());
+        data[att + 20..att + 22].copy_from_slice(&28u16.to_le_bytes());
+        data[att + 24..att + 28].copy_from_slice(&[b'a', 0, b'b', 0]);
+        data[att + 28..att + 32].copy_from_slice(&[1, 2, 3, 4]);
+
+        // Nonresident, 16 clusters at lcn 256 then a sparse run of 4 clusters.
+        let att = 88;
+        data[att..att + 4].copy_from_slice(&0x80u32.to_le_bytes());
+        data[att + 4..att + 8].copy_from_slice(&72u32.to_le_bytes());
+        data[att + 8] = 1;
+        data[att + 32..att + 34].copy_from_slice(&64u16.to_le_bytes());
+        data[att + 64..att + 69].copy_from_slice(&[0x21, 16, 0x00, 0x01, 0x01]);
+        data[att + 69] = 4;
+
+        data[160..164].copy_from_slice(&u32::MAX.to_le_bytes());
+        data[24..28].copy_from_slice(&168u32.to_le_bytes());
+
+        // Update sequence number 0x0102 at the end of both sectors.
+        data[48..50].copy_from_slice(&[1, 2]);
+        data[510..512].copy_from_slice(&[1, 2]);
+        data[1022..1024].copy_from_slice(&[1, 2]);
+        data
+    }
+
+    #[test]
+    fn parse_valid_record() {
+        let record = parse_record(&make_record()).unwrap();
+        assert_eq!(record.sequence_value, 7);
+        assert!(record.is_used());
+        assert_eq!(record.attributes.len(), 2);
+
+        let name = record.attribute(NtfsAttributeType::FileName).unwrap();
+        assert_eq!(name.name, "ab");
+        assert_eq!(name.content, AttributeContent::Resident(vec![1, 2, 3, 4]));
+
+        let data = record.attribute(NtfsAttributeType::Data).unwrap();
+        assert!(matches!(
+            &data.content,
+            AttributeContent::NonResident { runs, .. } if runs == &[
+                DataRun { lcn: Some(256), cluster_count: 16 },
+                DataRun { lcn: None, cluster_count: 4 },
+            ]
+        ));
+    }
+
+    #[test]
+    fn parse_invalid_input() {
+        let record = make_record();
+
+        // Every truncation and every single corrupted byte must fail (or not) without panicking.
+        for len in 0..record.len() {
+            let _ = parse_record(&record[..len]);
+            let _ = parse_attribute(&record[56..56 + len.min(record.len() - 56)]);
+        }
+        for at in 0..200 {
+            let mut corrupted = record.clone();
+            corrupted[at] = 0xff;
+            let _ = parse_record(&corrupted);
+        }
+
+        let mut bad_fixup = record.clone();
+        bad_fixup[1022] = 0;
+        assert_eq!(
+            parse_record(&bad_fixup),
+            Err(ParseError::FixupMismatch { sector: 1 })
+        );
+
+        // The second attribute has a run list past its end.
+        let mut bad_runs = record.clone();
+        bad_runs[88 + 32..88 + 34].copy_from_slice(&100u16.to_le_bytes());
+        assert!(matches!(
+            parse_record_with_context(42, &bad_runs),
+            Err(NtfsReaderError::CorruptRecord {
+                record: 42,
+                attribute_type: Some(0x80),
+                offset: 88,
+                source: ParseError::OutOfBounds { offset: 100, .. },
+            })
+        ));
+
+        // Before the start of the volume.
+        assert!(parse_data_runs(&[0x11, 1, 0xff, 0]).is_err());
+        assert!(parse_data_runs(&[0x11, 1]).is_err());
+    }
+}
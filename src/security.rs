@@ -0,0 +1,162 @@
+// Copyright (c) 2022, Matteo Bernacchia <dev@kikijiki.com>. All rights reserved.
+// This project is dual licensed under the Apache License 2.0 and the MIT license.
+// See the LICENSE files in the project root for details.
+
+// The security descriptors of the volume, from the $SDS stream of $Secure. Since NTFS 3.0 the
+// files only hold the id of theirs (see `NtfsFile::security_id`), shared by all the files with
+// the same descriptor.
+
+use std::collections::HashMap;
+use std::io;
+
+use crate::{api::*, errors::NtfsReaderResult, file::NtfsFile, mft::Mft};
+
+// Each block is followed by a mirror copy.
+const SDS_BLOCK_SIZE: usize = 0x40000;
+// Hash, security id, offset of the entry in the stream, length of the entry.
+const SDS_HEADER_SIZE: usize = 20;
+
+#[derive(Debug, Clone, Default)]
+pub struct SecurityDescriptors {
+    // Self-relative SECURITY_DESCRIPTORs.
+    descriptors: HashMap<u32, Vec<u8>>,
+}
+
+impl SecurityDescriptors {
+    // Reads the $SDS stream from the volume of the MFT.
+    pub fn load(mft: &Mft) -> NtfsReaderResult<Self> {
+        if !mft
+            .try_get_record(SECURE_RECORD)
+            .is_ok_and(|file| file.is_used())
+        {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "$Secure is missing").into());
+        }
+
+        // $SDS is the only $DATA of $Secure, often in other records through its attribute list.
+        let mut reader = mft.volume.open_reader()?;
+        let sds = mft.read_attribute_fs(&mut reader, SECURE_RECORD, NtfsAttributeType::Data);
+        Ok(Self::parse(&sds))
+    }
+
+    // The entries that don't fit in the rest of a block start at the next one, the rest of the
+    // block is left empty.
+    pub fn parse(sds: &[u8]) -> Self {
+        let mut descriptors = HashMap::new();
+
+        for block_start in (0..sds.len()).step_by(2 * SDS_BLOCK_SIZE) {
+            let block = &sds[block_start..sds.len().min(block_start + SDS_BLOCK_SIZE)];
+            let mut offset = 0;
+
+            while let Some(header) = block.get(offset..offset + SDS_HEADER_SIZE) {
+                let security_id = u32::from_le_bytes(header[4..8].try_into().unwrap());
+                let entry_offset = u64::from_le_bytes(header[8..16].try_into().unwrap());
+                let length = u32::from_le_bytes(header[16..20].try_into().unwrap()) as usize;
+                if length <= SDS_HEADER_SIZE || entry_offset != (block_start + offset) as u64 {
+                    break;
+                }
+                let Some(descriptor) = block.get(offset + SDS_HEADER_SIZE..offset + length) else {
+                    break;
+                };
+
+                descriptors
+                    .entry(security_id)
+                    .or_insert_with(|| descriptor.to_vec());
+                offset = (offset + length).next_multiple_of(16);
+            }
+        }
+
+        SecurityDescriptors { descriptors }
+    }
+
+    pub fn get(&self, security_id: u32) -> Option<&[u8]> {
+        self.descriptors.get(&security_id).map(Vec::as_slice)
+    }
+
+    pub fn get_for(&self, file: &NtfsFile) -> Option<&[u8]> {
+        self.get(file.security_id()?)
+    }
+
+    pub fn len(&self) -> usize {
+        self.descriptors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.descriptors.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use super::{SecurityDescriptors, SDS_BLOCK_SIZE};
+    use crate::errors::NtfsReaderError;
+    use crate::file::NtfsFile;
+    use crate::mft::memory_tests::{make_mft, make_record};
+
+    fn add_entry(sds: &mut Vec<u8>, offset: usize, security_id: u32, descriptor: &[u8]) -> usize {
+        let length = 20 + descriptor.len();
+        if sds.len() < offset + length {
+            sds.resize(offset + length, 0);
+        }
+        sds[offset + 4..offset + 8].copy_from_slice(&security_id.to_le_bytes());
+        sds[offset + 8..offset + 16].copy_from_slice(&(offset as u64).to_le_bytes());
+        sds[offset + 16..offset + 20].copy_from_slice(&(length as u32).to_le_bytes());
+        sds[offset + 20..offset + length].copy_from_slice(descriptor);
+        (offset + length).next_multiple_of(16)
+    }
+
+    // With a $STANDARD_INFORMATION of `length` bytes.
+    fn make_stdinfo_record(length: usize, security_id: u32) -> Vec<u8> {
+        let mut data = make_record();
+        let att = 56;
+        let att_length = (24 + length).next_multiple_of(8);
+        data[att..att + 4].copy_from_slice(&0x10u32.to_le_bytes());
+        data[att + 4..att + 8].copy_from_slice(&(att_length as u32).to_le_bytes());
+        data[att + 16..att + 20].copy_from_slice(&(length as u32).to_le_bytes());
+        data[att + 20..att + 22].copy_from_slice(&24u16.to_le_bytes());
+        if length >= 56 {
+            data[att + 24 + 52..att + 24 + 56].copy_from_slice(&security_id.to_le_bytes());
+        }
+
+        let end = att + att_length;
+        data[end..end + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        data[24..28].copy_from_slice(&(end as u32 + 8).to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn parse_sds() {
+        let mut sds = Vec::new();
+        let next = add_entry(&mut sds, 0, 0x100, b"owner");
+        add_entry(&mut sds, next, 0x101, b"group and dacl");
+        // The mirror of the first block is skipped.
+        add_entry(&mut sds, SDS_BLOCK_SIZE, 0x103, b"mirror");
+        add_entry(&mut sds, 2 * SDS_BLOCK_SIZE, 0x102, b"next block");
+
+        let descriptors = SecurityDescriptors::parse(&sds);
+        assert_eq!(descriptors.len(), 3);
+        assert_eq!(descriptors.get(0x100), Some(&b"owner"[..]));
+        assert_eq!(descriptors.get(0x101), Some(&b"group and dacl"[..]));
+        assert_eq!(descriptors.get(0x102), Some(&b"next block"[..]));
+        assert!(SecurityDescriptors::parse(&sds[..30]).get(0x101).is_none());
+
+        let record = make_stdinfo_record(72, 0x101);
+        let file = NtfsFile::new(30, &record).unwrap();
+        assert_eq!(file.security_id(), Some(0x101));
+        assert_eq!(descriptors.get_for(&file), Some(&b"group and dacl"[..]));
+
+        let record = make_stdinfo_record(48, 0);
+        assert_eq!(NtfsFile::new(30, &record).unwrap().security_id(), None);
+    }
+
+    #[test]
+    fn missing_secure() {
+        // $Secure is past the end of the MFT.
+        let mft = make_mft(vec![make_record(); 5]);
+        match SecurityDescriptors::load(&mft) {
+            Err(NtfsReaderError::IOError(err)) => assert_eq!(err.kind(), ErrorKind::NotFound),
+            _ => panic!("$Secure should be missing"),
+        }
+    }
+}
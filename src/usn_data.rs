@@ -0,0 +1,185 @@
+// Copyright (c) 2022, Matteo Bernacchia <dev@kikijiki.com>. All rights reserved.
+// This project is dual licensed under the Apache License 2.0 and the MIT license.
+// See the LICENSE files in the project root for details.
+
+use std::ffi::OsString;
+use std::mem::size_of;
+use std::os::raw::c_void;
+
+use windows::Win32::Foundation::{self, ERROR_HANDLE_EOF};
+use windows::Win32::Storage::FileSystem;
+use windows::Win32::System::Ioctl;
+use windows::Win32::System::IO;
+
+use crate::errors::win32;
+use crate::journal::{get_usn_record_name, open_volume_handle, FileId};
+use crate::volume::Volume;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+#[repr(align(64))]
+struct AlignedBuffer([u8; BUFFER_SIZE]);
+
+#[derive(Debug, Clone)]
+pub struct UsnDataEntry {
+    pub file_id: FileId,
+    pub parent_id: FileId,
+    // The last usn of the file, zero if it never changed since the journal was created.
+    pub usn: i64,
+    pub file_attributes: u32,
+    pub name: OsString,
+}
+
+impl UsnDataEntry {
+    pub fn is_directory(&self) -> bool {
+        self.file_attributes & FileSystem::FILE_ATTRIBUTE_DIRECTORY.0 != 0
+    }
+}
+
+// Enumerates the files of the volume through FSCTL_ENUM_USN_DATA,
+// which lets the file system walk the MFT instead of reading and parsing it here.
+pub struct UsnDataEntries {
+    volume_handle: Foundation::HANDLE,
+    enum_data: Ioctl::MFT_ENUM_DATA_V1,
+    buffer: Box<AlignedBuffer>,
+    offset: usize,
+    len: usize,
+    done: bool,
+}
+
+impl UsnDataEntries {
+    pub fn new(volume: &Volume) -> Result<Self, std::io::Error> {
+        let volume_handle = open_volume_handle(
+            &volume.path,
+            FileSystem::FILE_GENERIC_READ | FileSystem::FILE_GENERIC_WRITE,
+            FileSystem::FILE_FLAGS_AND_ATTRIBUTES(0),
+        )?;
+
+        Ok(UsnDataEntries {
+            volume_handle,
+            enum_data: Ioctl::MFT_ENUM_DATA_V1 {
+                StartFileReferenceNumber: 0,
+                LowUsn: 0,
+                HighUsn: i64::MAX,
+                MinMajorVersion: 2,
+                MaxMajorVersion: 3,
+            },
+            buffer: Box::new(AlignedBuffer([0u8; BUFFER_SIZE])),
+            offset: 0,
+            len: 0,
+            done: false,
+        })
+    }
+
+    // Returns false at the end of the enumeration.
+    fn fill(&mut self) -> Result<bool, std::io::Error> {
+        let mut bytes_returned = 0;
+
+        let result = unsafe {
+            IO::DeviceIoControl(
+                self.volume_handle,
+                Ioctl::FSCTL_ENUM_USN_DATA,
+                Some(&self.enum_data as *const _ as *const c_void),
+                size_of::<Ioctl::MFT_ENUM_DATA_V1>() as u32,
+                Some(self.buffer.0.as_mut_ptr() as *mut c_void),
+                BUFFER_SIZE as u32,
+                Some(&mut bytes_returned),
+                None,
+            )
+        };
+
+        if let Err(err) = result {
+            if err.code() == ERROR_HANDLE_EOF.to_hresult() {
+                return Ok(false);
+            }
+            return Err(win32("FSCTL_ENUM_USN_DATA")(err));
+        }
+
+        // The buffer starts with the reference to continue from.
+        let next = u64::from_le_bytes(self.buffer.0[0..8].try_into().unwrap());
+        self.enum_data.StartFileReferenceNumber = next;
+        self.offset = 8; // sizeof(USN)
+        self.len = bytes_returned as usize;
+
+        Ok(self.len > self.offset)
+    }
+
+    fn parse(&self) -> (usize, Option<UsnDataEntry>) {
+        unsafe {
+            let record_ptr =
+                self.buffer.0[self.offset..].as_ptr() as *const Ioctl::USN_RECORD_UNION;
+            let record_len = (*record_ptr).Header.RecordLength as usize;
+
+            let entry = match (*record_ptr).Header.MajorVersion {
+                2 => {
+                    let rec = &(*record_ptr).V2;
+                    Some(UsnDataEntry {
+                        file_id: FileId::Normal(rec.FileReferenceNumber),
+                        parent_id: FileId::Normal(rec.ParentFileReferenceNumber),
+                        usn: rec.Usn,
+                        file_attributes: rec.FileAttributes,
+                        name: get_usn_record_name(rec.FileNameLength, rec.FileName.as_ptr()),
+                    })
+                }
+                3 => {
+                    let rec = &(*record_ptr).V3;
+                    Some(UsnDataEntry {
+                        file_id: FileId::Extended(rec.FileReferenceNumber),
+                        parent_id: FileId::Extended(rec.ParentFileReferenceNumber),
+                        usn: rec.Usn,
+                        file_attributes: rec.FileAttributes,
+                        name: get_usn_record_name(rec.FileNameLength, rec.FileName.as_ptr()),
+                    })
+                }
+                _ => None,
+            };
+
+            (record_len, entry)
+        }
+    }
+}
+
+impl Iterator for UsnDataEntries {
+    type Item = Result<UsnDataEntry, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.done {
+                return None;
+            }
+
+            if self.offset >= self.len {
+                match self.fill() {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        self.done = true;
+                        return None;
+                    }
+                    Err(err) => {
+                        self.done = true;
+                        return Some(Err(err));
+                    }
+                }
+            }
+
+            let (record_len, entry) = self.parse();
+            if record_len == 0 {
+                self.offset = self.len;
+                continue;
+            }
+            self.offset += record_len;
+
+            if let Some(entry) = entry {
+                return Some(Ok(entry));
+            }
+        }
+    }
+}
+
+impl Drop for UsnDataEntries {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = Foundation::CloseHandle(self.volume_handle);
+        }
+    }
+}
@@ -0,0 +1,251 @@
+// Copyright (c) 2022, Matteo Bernacchia <dev@kikijiki.com>. All rights reserved.
+// This project is dual licensed under the Apache License 2.0 and the MIT license.
+// See the LICENSE files in the project root for details.
+
+// A chkdsk-like audit of a loaded MFT, it only reports the problems and never changes anything.
+
+use crate::{
+    api::*,
+    errors::{NtfsReaderError, ParseError},
+    mft::Mft,
+    parse::{AttributeContent, ParsedRecord},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyIssue {
+    // Marked as BAAD, the signature or the update sequence was invalid when loading
+    // (see `MftOptions::corrupt_records`).
+    CorruptRecord {
+        record: u64,
+    },
+    // The record can't be parsed, see `Mft::parse_record`.
+    InvalidRecord {
+        record: u64,
+        attribute_type: Option<u32>,
+        offset: usize,
+        source: ParseError,
+    },
+    // In use according to the bitmap, but the record is not.
+    NotInUse {
+        record: u64,
+    },
+    // The record is in use, but free according to the bitmap.
+    NotInBitmap {
+        record: u64,
+    },
+    // The parent is missing, not a directory or was reused (the sequence number doesn't match).
+    InvalidParent {
+        record: u64,
+        parent: u64,
+    },
+    // Following the parents from the record comes back to it instead of reaching the root.
+    ParentLoop {
+        record: u64,
+    },
+    // The entry of the attribute list points to a record that is not an extension of this one.
+    InvalidAttributeListEntry {
+        record: u64,
+        reference: u64,
+    },
+    // A run of a nonresident attribute goes past the end of the volume.
+    DataRunOutOfBounds {
+        record: u64,
+        attribute_type: u32,
+        lcn: u64,
+        cluster_count: u64,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub records_checked: u64,
+    pub issues: Vec<VerifyIssue>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ParentState {
+    Unknown,
+    Visiting,
+    // Reaches the root, or the problem was already reported.
+    Done,
+}
+
+impl Mft {
+    // Checks every record: the signature and the update sequence, the bitmap, the parents,
+    // the attribute lists and the data runs.
+    // The nonresident attribute lists are not checked, they are not part of the MFT data.
+    pub fn verify(&self) -> VerifyReport {
+        let mut report = VerifyReport::default();
+        let total_clusters = self.volume.volume_size / self.volume.cluster_size.max(1);
+
+        for number in 0..self.max_record {
+            report.records_checked += 1;
+
+            let data = self.get_record_data(number);
+            let in_bitmap = self.record_exists(number);
+
+            if &data[0..4] == BAAD_RECORD_SIGNATURE {
+                report
+                    .issues
+                    .push(VerifyIssue::CorruptRecord { record: number });
+                continue;
+            }
+
+            let record = match self.parse_record(number) {
+                Ok(record) => record,
+                // Most of the free records were never used.
+                Err(NtfsReaderError::CorruptRecord {
+                    source: ParseError::BadSignature,
+                    ..
+                }) if !in_bitmap => continue,
+                Err(NtfsReaderError::CorruptRecord {
+                    attribute_type,
+                    offset,
+                    source,
+                    ..
+                }) => {
+                    report.issues.push(VerifyIssue::InvalidRecord {
+                        record: number,
+                        attribute_type,
+                        offset,
+                        source,
+                    });
+                    continue;
+                }
+                Err(_) => continue,
+            };
+
+            match (in_bitmap, record.is_used()) {
+                (true, false) => report.issues.push(VerifyIssue::NotInUse { record: number }),
+                (false, true) => report
+                    .issues
+                    .push(VerifyIssue::NotInBitmap { record: number }),
+                _ => {}
+            }
+            if !record.is_used() {
+                continue;
+            }
+
+            self.verify_attribute_list(number, &record, &mut report);
+
+            for att in &record.attributes {
+                if let AttributeContent::NonResident { runs, .. } = &att.content {
+                    for run in runs {
+                        let Some(lcn) = run.lcn else {
+                            continue;
+                        };
+                        if lcn.saturating_add(run.cluster_count) > total_clusters {
+                            report.issues.push(VerifyIssue::DataRunOutOfBounds {
+                                record: number,
+                                attribute_type: att.type_id,
+                                lcn,
+                                cluster_count: run.cluster_count,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        self.verify_parents(&mut report);
+        report
+    }
+
+    fn verify_attribute_list(&self, number: u64, record: &ParsedRecord, report: &mut VerifyReport) {
+        let Some(AttributeContent::Resident(list)) = record
+            .attribute(NtfsAttributeType::AttributeList)
+            .map(|att| &att.content)
+        else {
+            return;
+        };
+
+        let mut offset = 0;
+        while let Some(entry) = list.get(offset..offset + 24) {
+            let length = u16::from_le_bytes([entry[4], entry[5]]) as usize;
+            if length == 0 {
+                break;
+            }
+
+            let reference = u64::from_le_bytes(entry[16..24].try_into().unwrap());
+            let extension = reference_number(reference);
+            let valid = extension == number
+                || (self.is_reference_current(reference)
+                    && self.get_record(extension).is_some_and(|file| {
+                        file.is_used() && reference_number(file.header.base_reference()) == number
+                    }));
+            if !valid {
+                report.issues.push(VerifyIssue::InvalidAttributeListEntry {
+                    record: number,
+                    reference,
+                });
+            }
+
+            // The entries are aligned to 8 bytes.
+            offset += length.next_multiple_of(8);
+        }
+    }
+
+    fn verify_parents(&self, report: &mut VerifyReport) {
+        let mut states = vec![ParentState::Unknown; self.max_record as usize];
+        let mut chain = Vec::new();
+
+        for number in 0..self.max_record {
+            chain.clear();
+            let mut current = number;
+
+            loop {
+                match states[current as usize] {
+                    ParentState::Done => break,
+                    ParentState::Visiting => {
+                        report
+                            .issues
+                            .push(VerifyIssue::ParentLoop { record: current });
+                        break;
+                    }
+                    ParentState::Unknown => {}
+                }
+                if current == ROOT_RECORD {
+                    break;
+                }
+
+                states[current as usize] = ParentState::Visiting;
+                chain.push(current);
+
+                // Only the base records in use have a name.
+                let Some(file) = self.get_record(current).filter(|file| file.is_used()) else {
+                    break;
+                };
+                let Some(name) = file.get_best_file_name_ref(self) else {
+                    break;
+                };
+
+                let parent = name.parent_reference();
+                let parent_number = reference_number(parent);
+                let valid = parent_number < self.max_record
+                    && self.is_reference_current(parent)
+                    && self
+                        .get_record(parent_number)
+                        .is_some_and(|parent| parent.is_used() && parent.is_directory());
+                if !valid {
+                    report.issues.push(VerifyIssue::InvalidParent {
+                        record: current,
+                        parent,
+                    });
+                    break;
+                }
+
+                current = parent_number;
+            }
+
+            for record in &chain {
+                states[*record as usize] = ParentState::Done;
+            }
+        }
+    }
+}
@@ -2,79 +2,695 @@
 // This project is dual licensed under the Apache License 2.0 and the MIT license.
 // See the LICENSE files in the project root for details.
 
+use std::io::{Cursor, Read, Seek, SeekFrom};
+#[cfg(feature = "windows")]
+use std::mem::size_of;
+#[cfg(feature = "windows")]
+use std::os::raw::c_void;
 use std::path::{Path, PathBuf};
 
 use binread::BinReaderExt;
 
+#[cfg(feature = "windows")]
+use windows::core::HSTRING;
+#[cfg(feature = "windows")]
 use windows::Win32::{
-    Foundation::HANDLE,
-    Security::{GetTokenInformation, TokenElevation, TOKEN_ELEVATION, TOKEN_QUERY},
+    Foundation::{CloseHandle, GetLastError, ERROR_NOT_ALL_ASSIGNED, HANDLE},
+    Security::{
+        AdjustTokenPrivileges, LookupPrivilegeValueW, SE_BACKUP_NAME, SE_PRIVILEGE_ENABLED,
+        TOKEN_ADJUST_PRIVILEGES, TOKEN_PRIVILEGES, TOKEN_QUERY,
+    },
+    Storage::FileSystem::{
+        GetDriveTypeW, GetLogicalDrives, GetVolumeInformationW, GetVolumeNameForVolumeMountPointW,
+        FILE_FLAGS_AND_ATTRIBUTES, FILE_GENERIC_READ,
+    },
+    System::Ioctl::{FSCTL_GET_NTFS_VOLUME_DATA, NTFS_VOLUME_DATA_BUFFER},
     System::Threading::{GetCurrentProcess, OpenProcessToken},
+    System::IO::DeviceIoControl,
 };
 
 use crate::{
-    aligned_reader::open_volume,
+    aligned_reader::{open_image, open_volume_with_options, ReadSeek, VolumeReadOptions},
     api::*,
-    errors::{NtfsReaderError, NtfsReaderResult},
+    errors::{BootSectorError, NtfsReaderError, NtfsReaderResult},
 };
+#[cfg(feature = "windows")]
+use crate::{errors::win32, journal::open_volume_handle, usn_data::UsnDataEntries};
+
+// From WinBase.h, the windows crate only has it behind an unrelated feature.
+#[cfg(feature = "windows")]
+const DRIVE_FIXED: u32 = 3;
+
+const MAX_CLUSTER_SIZE: u64 = 2 * 1024 * 1024;
+const MAX_FILE_RECORD_SIZE: u64 = 64 * 1024;
+// The largest clusters with the smallest sectors (2 MB with 256 bytes).
+const MAX_SECTORS_PER_CLUSTER_SHIFT: u32 = 13;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PathFormat {
+    // \\?\C:\Windows (the normalized path used to open the volume)
+    #[default]
+    DevicePath,
+    // C:\Windows
+    DriveLetter,
+    // \\?\Volume{GUID}\Windows
+    VolumeGuid,
+    // Windows
+    Rootless,
+}
+
+// Guessed from the boot sector, to tell why a volume can't be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileSystemType {
+    Ntfs,
+    // FAT12 and FAT16
+    Fat,
+    Fat32,
+    ExFat,
+    ReFs,
+    // The OEM ID found in the boot sector.
+    Unknown([u8; 8]),
+}
+
+impl FileSystemType {
+    pub fn detect(boot_sector: &[u8; 512]) -> Self {
+        let oem_id: [u8; 8] = boot_sector[3..11].try_into().unwrap();
+        match &oem_id {
+            b"NTFS    " => FileSystemType::Ntfs,
+            b"EXFAT   " => FileSystemType::ExFat,
+            _ if oem_id.starts_with(b"ReFS") => FileSystemType::ReFs,
+            // The OEM ID of FAT volumes is the name of the tool that formatted them.
+            _ if boot_sector[82..87] == *b"FAT32" => FileSystemType::Fat32,
+            _ if boot_sector[54..57] == *b"FAT" => FileSystemType::Fat,
+            _ => FileSystemType::Unknown(oem_id),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Volume {
     pub path: PathBuf,
     pub boot_sector: BootSector,
+    // 512, or 4096 on 4Kn disks. The update sequence of the records protects each sector.
+    pub sector_size: u64,
     pub cluster_size: u64,
     pub volume_size: u64,
     pub file_record_size: u64,
     pub mft_position: u64,
+    pub guid_path: Option<PathBuf>,
+    // None if the file system could not be queried, then the values above come from the boot sector.
+    pub volume_data: Option<VolumeData>,
+    // From the boot sector, doesn't change with the drive letter (until the volume is formatted).
+    pub serial_number: u64,
+    pub label: Option<String>,
+    // Where the volume starts, when it was opened from an image file.
+    pub image_offset: Option<u64>,
+    // Used by `open_reader` (so by `Mft::new`) for live volumes.
+    pub read_options: VolumeReadOptions,
+}
+
+// What is needed to read the MFT, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VolumeGeometry {
+    pub sector_size: u64,
+    pub cluster_size: u64,
+    pub volume_size: u64,
+    pub file_record_size: u64,
+    pub mft_position: u64,
+}
+
+// As reported by FSCTL_GET_NTFS_VOLUME_DATA, the positions are in clusters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VolumeData {
+    pub serial_number: u64,
+    pub total_clusters: u64,
+    pub free_clusters: u64,
+    pub bytes_per_sector: u32,
+    pub bytes_per_cluster: u32,
+    pub bytes_per_file_record: u32,
+    pub mft_valid_data_length: u64,
+    pub mft_start_lcn: u64,
+    pub mft_mirror_start_lcn: u64,
+    pub mft_zone_start: u64,
+    pub mft_zone_end: u64,
 }
 
 impl Volume {
+    #[cfg(feature = "windows")]
     pub fn new<P: AsRef<Path>>(path: P) -> NtfsReaderResult<Self> {
-        if !Self::is_elevated().unwrap_or(false) {
-            return Err(NtfsReaderError::ElevationError);
+        // Reading the volume without elevation is allowed by the backup privilege, when the user has it.
+        Self::enable_backup_privilege();
+
+        let path = Self::normalize_path(path.as_ref());
+        let mut reader = match open_volume_with_options(&path, VolumeReadOptions::default()) {
+            Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+                return Err(NtfsReaderError::ElevationError);
+            }
+            result => result?,
+        };
+        let (boot_sector, mut geometry) = Self::read_boot_sector(&mut reader)?;
+
+        // Prefer the values of the file system, the boot sector is only the fallback.
+        let volume_data = Self::query_volume_data(&path).ok();
+        if let Some(data) = &volume_data {
+            geometry.sector_size = data.bytes_per_sector as u64;
+            geometry.cluster_size = data.bytes_per_cluster as u64;
+            geometry.file_record_size = data.bytes_per_file_record as u64;
+            geometry.mft_position = data.mft_start_lcn * geometry.cluster_size;
         }
 
-        let mut reader = open_volume(path.as_ref())?;
-        let boot_sector = reader.read_le::<BootSector>()?;
+        let mut volume = Self::from_parts(path, boot_sector, geometry);
+        volume.guid_path = Self::drive_letter_of(&volume.path).and_then(Self::get_guid_path);
+        volume.label = Self::get_label(&volume.path);
+        volume.volume_data = volume_data;
+        Ok(volume)
+    }
+
+    // Opens a raw image of a volume (e.g. made with dd), which starts at `offset` in the file.
+    // Doesn't need elevation, and works without a live volume.
+    pub fn from_image<P: AsRef<Path>>(path: P, offset: u64) -> NtfsReaderResult<Self> {
+        let mut reader = open_image(path.as_ref(), offset)?;
+        let (boot_sector, geometry) = Self::read_boot_sector(&mut reader)?;
+
+        let mut volume = Self::from_parts(path.as_ref().to_path_buf(), boot_sector, geometry);
+        volume.image_offset = Some(offset);
+        Ok(volume)
+    }
+
+    // From a boot sector found elsewhere, e.g. the backup at the end of the volume.
+    // `path` is only used to read the volume later and for the roots, it doesn't have to exist.
+    pub fn from_boot_sector<P: AsRef<Path>>(bytes: &[u8], path: P) -> NtfsReaderResult<Self> {
+        let (boot_sector, geometry) = Self::read_boot_sector(&mut Cursor::new(bytes))?;
+        Ok(Self::from_parts(
+            path.as_ref().to_path_buf(),
+            boot_sector,
+            geometry,
+        ))
+    }
+
+    // For a volume that is read from another source, see `Mft::from_reader`.
+    // There is no path, so the records can't be reloaded.
+    pub fn from_geometry(geometry: VolumeGeometry) -> Self {
+        let boot_sector = BootSector {
+            crap_0: [0; 11],
+            sector_size: geometry.sector_size as u16,
+            sectors_per_cluster: encode_sectors_per_cluster(
+                geometry.cluster_size / geometry.sector_size.max(1),
+            ),
+            crap_1: [0; 26],
+            total_sectors: geometry.volume_size / geometry.sector_size.max(1),
+            mft_lcn: geometry.mft_position / geometry.cluster_size.max(1),
+            mft_lcn_mirror: 0,
+            file_record_size_info: -(geometry.file_record_size.trailing_zeros() as i8),
+            crap_2: [0; 3],
+            index_record_size_info: 0,
+            crap_3: [0; 3],
+            serial_number: 0,
+            crap_4: [0; 432],
+        };
+        Self::from_parts(PathBuf::new(), boot_sector, geometry)
+    }
+
+    fn from_parts(path: PathBuf, boot_sector: BootSector, geometry: VolumeGeometry) -> Self {
+        Volume {
+            path,
+            serial_number: boot_sector.serial_number,
+            boot_sector,
+            sector_size: geometry.sector_size,
+            cluster_size: geometry.cluster_size,
+            volume_size: geometry.volume_size,
+            file_record_size: geometry.file_record_size,
+            mft_position: geometry.mft_position,
+            guid_path: None,
+            volume_data: None,
+            label: None,
+            image_offset: None,
+            read_options: VolumeReadOptions::default(),
+        }
+    }
+
+    fn read_boot_sector<R: Read>(reader: &mut R) -> NtfsReaderResult<(BootSector, VolumeGeometry)> {
+        let mut sector = [0u8; 512];
+        reader.read_exact(&mut sector)?;
+
+        let detected = FileSystemType::detect(&sector);
+        if detected != FileSystemType::Ntfs {
+            return Err(NtfsReaderError::NotNtfs { detected });
+        }
+        let boot_sector = Cursor::new(&sector[..]).read_le::<BootSector>()?;
+
+        let sector_size = boot_sector.sector_size;
+        let sector_size = match sector_size {
+            0 => SECTOR_SIZE as u64,
+            size if size.is_power_of_two() && (256..=4096).contains(&size) => size as u64,
+            size => return Err(BootSectorError::SectorSize(size).into()),
+        };
+
+        let sectors_per_cluster = decode_sectors_per_cluster(boot_sector.sectors_per_cluster)
+            .filter(|sectors| sectors.is_power_of_two())
+            .filter(|sectors| sectors * sector_size <= MAX_CLUSTER_SIZE);
+        let Some(sectors_per_cluster) = sectors_per_cluster else {
+            return Err(BootSectorError::SectorsPerCluster(boot_sector.sectors_per_cluster).into());
+        };
+        let cluster_size = sectors_per_cluster * sector_size;
 
-        let cluster_size = boot_sector.sectors_per_cluster as u64 * boot_sector.sector_size as u64;
-        let volume_size = boot_sector.total_sectors as u64 * boot_sector.sector_size as u64;
         let file_record_size = {
-            if boot_sector.file_record_size_info > 0 {
-                boot_sector.file_record_size_info as u64
+            let info = boot_sector.file_record_size_info;
+            if info > 0 {
+                // In clusters.
+                info as u64 * cluster_size
             } else {
-                1u64 << (-boot_sector.file_record_size_info) as u64
+                1u64.checked_shl(info.unsigned_abs() as u32).unwrap_or(0)
             }
         };
-        let mft_position = boot_sector.mft_lcn * cluster_size;
+        // The update sequence array needs whole sectors.
+        if file_record_size < sector_size
+            || file_record_size > MAX_FILE_RECORD_SIZE
+            || !file_record_size.is_power_of_two()
+        {
+            return Err(BootSectorError::FileRecordSize(boot_sector.file_record_size_info).into());
+        }
 
-        Ok(Volume {
-            path: path.as_ref().into(),
-            boot_sector,
+        let total_clusters = boot_sector.total_sectors / sectors_per_cluster;
+        if boot_sector.mft_lcn >= total_clusters {
+            return Err(BootSectorError::MftOutOfBounds {
+                mft_lcn: boot_sector.mft_lcn,
+                total_clusters,
+            }
+            .into());
+        }
+
+        let geometry = VolumeGeometry {
+            sector_size,
             cluster_size,
-            volume_size,
+            volume_size: boot_sector.total_sectors.saturating_mul(sector_size),
             file_record_size,
-            mft_position,
+            mft_position: boot_sector.mft_lcn.saturating_mul(cluster_size),
+        };
+        Ok((boot_sector, geometry))
+    }
+
+    // Reads the boot sector at the start of `reader`, e.g. an `OffsetReader` over a disk image.
+    pub fn read_geometry<R: Read + Seek>(reader: &mut R) -> NtfsReaderResult<VolumeGeometry> {
+        reader.seek(SeekFrom::Start(0))?;
+        Ok(Self::read_boot_sector(reader)?.1)
+    }
+
+    pub fn geometry(&self) -> VolumeGeometry {
+        VolumeGeometry {
+            sector_size: self.sector_size,
+            cluster_size: self.cluster_size,
+            volume_size: self.volume_size,
+            file_record_size: self.file_record_size,
+            mft_position: self.mft_position,
+        }
+    }
+
+    // Reads the volume, or the image it was opened from.
+    pub fn open_reader(&self) -> std::io::Result<Box<dyn ReadSeek>> {
+        Ok(match self.image_offset {
+            Some(offset) => Box::new(open_image(&self.path, offset)?),
+            None => {
+                let mut reader = open_volume_with_options(&self.path, self.read_options.clone())?;
+                reader.set_len(self.volume_size);
+                Box::new(reader)
+            }
         })
     }
 
-    fn is_elevated() -> windows::core::Result<bool> {
+    // Queries the geometry of the volume with FSCTL_GET_NTFS_VOLUME_DATA.
+    #[cfg(feature = "windows")]
+    pub fn query_volume_data(path: &Path) -> Result<VolumeData, std::io::Error> {
+        let handle = open_volume_handle(path, FILE_GENERIC_READ, FILE_FLAGS_AND_ATTRIBUTES(0))?;
+
+        let mut data = NTFS_VOLUME_DATA_BUFFER::default();
+        let result = unsafe {
+            let mut bytes_returned = 0;
+            DeviceIoControl(
+                handle,
+                FSCTL_GET_NTFS_VOLUME_DATA,
+                None,
+                0,
+                Some(&mut data as *mut _ as *mut c_void),
+                size_of::<NTFS_VOLUME_DATA_BUFFER>() as u32,
+                Some(&mut bytes_returned),
+                None,
+            )
+        };
+
+        unsafe {
+            let _ = CloseHandle(handle);
+        }
+        result.map_err(win32("FSCTL_GET_NTFS_VOLUME_DATA"))?;
+
+        Ok(VolumeData {
+            serial_number: data.VolumeSerialNumber as u64,
+            total_clusters: data.TotalClusters as u64,
+            free_clusters: data.FreeClusters as u64,
+            bytes_per_sector: data.BytesPerSector,
+            bytes_per_cluster: data.BytesPerCluster,
+            bytes_per_file_record: data.BytesPerFileRecordSegment,
+            mft_valid_data_length: data.MftValidDataLength as u64,
+            mft_start_lcn: data.MftStartLcn as u64,
+            mft_mirror_start_lcn: data.Mft2StartLcn as u64,
+            mft_zone_start: data.MftZoneStart as u64,
+            mft_zone_end: data.MftZoneEnd as u64,
+        })
+    }
+
+    // Turns "C", "C:", "C:\", "\\.\C:" and "\\?\C:" into the same device path (\\?\C:),
+    // which is what `Volume::path` holds. Other volume paths only lose the trailing separator,
+    // which would open the root directory instead of the volume.
+    pub fn normalize_path(path: &Path) -> PathBuf {
+        let Some(text) = path.to_str() else {
+            return path.to_path_buf();
+        };
+
+        let (prefix, rest) = match text.get(..4) {
+            Some(prefix @ ("\\\\.\\" | "\\\\?\\")) => (prefix, &text[4..]),
+            _ => ("", text),
+        };
+        let rest = rest.trim_end_matches(['\\', '/']);
+
+        let mut chars = rest.chars();
+        match (chars.next(), chars.next(), chars.next()) {
+            (Some(letter), None | Some(':'), None) if letter.is_ascii_alphabetic() => {
+                PathBuf::from(format!("\\\\?\\{}:", letter.to_ascii_uppercase()))
+            }
+            _ if rest.is_empty() => path.to_path_buf(),
+            _ => PathBuf::from(format!("{}{}", prefix, rest)),
+        }
+    }
+
+    // The paths (e.g. \\?\C:) of all the fixed drives formatted as NTFS.
+    #[cfg(feature = "windows")]
+    pub fn fixed_ntfs_paths() -> Vec<PathBuf> {
+        let drives = unsafe { GetLogicalDrives() };
+
+        (0..26u8)
+            .filter(|i| drives & (1 << i) != 0)
+            .map(|i| (b'A' + i) as char)
+            .filter(|letter| Self::is_fixed_ntfs(*letter))
+            .map(|letter| PathBuf::from(format!("\\\\?\\{}:", letter)))
+            .collect()
+    }
+
+    // A faster alternative to reading the MFT when only the names and parents are needed.
+    #[cfg(feature = "windows")]
+    pub fn enum_usn_data(&self) -> Result<UsnDataEntries, std::io::Error> {
+        UsnDataEntries::new(self)
+    }
+
+    // The low half of the serial number, as shown by `vol` and GetVolumeInformation.
+    pub fn short_serial_number(&self) -> u32 {
+        self.serial_number as u32
+    }
+
+    pub fn drive_letter(&self) -> Option<char> {
+        Self::drive_letter_of(&self.path)
+    }
+
+    // The path that all the paths on this volume are relative to.
+    // Falls back to the device path if the requested format is not available.
+    pub fn root(&self, format: PathFormat) -> PathBuf {
+        Self::root_impl(&self.path, self.guid_path.as_deref(), format)
+    }
+
+    // The path relative to the root of the volume, when it starts with the root in any
+    // `PathFormat` (other than `Rootless`), otherwise the path as it is.
+    pub fn strip_root<'p>(&self, path: &'p Path) -> &'p Path {
+        for format in [
+            PathFormat::DevicePath,
+            PathFormat::DriveLetter,
+            PathFormat::VolumeGuid,
+        ] {
+            if let Ok(relative) = path.strip_prefix(self.root(format)) {
+                return relative;
+            }
+        }
+        path
+    }
+
+    // Same as `root`, for a volume path that was not opened (e.g. without elevation).
+    pub fn root_of(path: &Path, format: PathFormat) -> PathBuf {
+        let guid_path = match format {
+            PathFormat::VolumeGuid => Self::drive_letter_of(path).and_then(Self::get_guid_path),
+            _ => None,
+        };
+        Self::root_impl(path, guid_path.as_deref(), format)
+    }
+
+    fn root_impl(path: &Path, guid_path: Option<&Path>, format: PathFormat) -> PathBuf {
+        match format {
+            PathFormat::DevicePath => path.to_path_buf(),
+            PathFormat::DriveLetter => match Self::drive_letter_of(path) {
+                Some(letter) => PathBuf::from(format!("{}:\\", letter)),
+                None => path.to_path_buf(),
+            },
+            PathFormat::VolumeGuid => guid_path.unwrap_or(path).to_path_buf(),
+            PathFormat::Rootless => PathBuf::new(),
+        }
+    }
+
+    fn drive_letter_of(path: &Path) -> Option<char> {
+        let path = path.to_str()?;
+        let path = path
+            .strip_prefix("\\\\.\\")
+            .or_else(|| path.strip_prefix("\\\\?\\"))
+            .unwrap_or(path);
+
+        let mut chars = path.chars();
+        match (chars.next(), chars.next()) {
+            (Some(letter), Some(':')) if letter.is_ascii_alphabetic() => {
+                Some(letter.to_ascii_uppercase())
+            }
+            _ => None,
+        }
+    }
+
+    #[cfg(feature = "windows")]
+    fn is_fixed_ntfs(letter: char) -> bool {
+        let root = HSTRING::from(format!("{}:\\", letter));
+        let mut fs_name = [0u16; 16];
+
+        unsafe {
+            if GetDriveTypeW(&root) != DRIVE_FIXED {
+                return false;
+            }
+
+            if GetVolumeInformationW(&root, None, None, None, None, Some(&mut fs_name)).is_err() {
+                return false;
+            }
+        }
+
+        let len = fs_name
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(fs_name.len());
+        String::from_utf16_lossy(&fs_name[..len]) == "NTFS"
+    }
+
+    #[cfg(feature = "windows")]
+    fn get_label(path: &Path) -> Option<String> {
+        // The root directory of the volume, with the trailing separator.
+        let root = HSTRING::from(format!("{}\\", path.to_str()?));
+        let mut label = [0u16; 261]; // MAX_PATH + 1
+
+        unsafe { GetVolumeInformationW(&root, Some(&mut label), None, None, None, None).ok()? };
+
+        let len = label.iter().position(|c| *c == 0).unwrap_or(label.len());
+        Some(String::from_utf16_lossy(&label[..len]))
+    }
+
+    #[cfg(feature = "windows")]
+    fn get_guid_path(letter: char) -> Option<PathBuf> {
+        let mount_point = HSTRING::from(format!("{}:\\", letter));
+        let mut buffer = [0u16; 64];
+
+        unsafe { GetVolumeNameForVolumeMountPointW(&mount_point, &mut buffer).ok()? };
+
+        let len = buffer.iter().position(|c| *c == 0).unwrap_or(buffer.len());
+        Some(PathBuf::from(String::from_utf16_lossy(&buffer[..len])))
+    }
+
+    #[cfg(not(feature = "windows"))]
+    fn get_guid_path(_letter: char) -> Option<PathBuf> {
+        None
+    }
+
+    // Returns false if the privilege is not held by the process token.
+    #[cfg(feature = "windows")]
+    pub fn enable_backup_privilege() -> bool {
         unsafe {
             let mut handle: HANDLE = HANDLE::default();
-            OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut handle)?;
+            if OpenProcessToken(
+                GetCurrentProcess(),
+                TOKEN_ADJUST_PRIVILEGES | TOKEN_QUERY,
+                &mut handle,
+            )
+            .is_err()
+            {
+                return false;
+            }
 
-            let mut elevation = TOKEN_ELEVATION::default();
-            let mut returned_length = 0;
+            let mut privileges = TOKEN_PRIVILEGES {
+                PrivilegeCount: 1,
+                ..Default::default()
+            };
+            privileges.Privileges[0].Attributes = SE_PRIVILEGE_ENABLED;
 
-            GetTokenInformation(
-                handle,
-                TokenElevation,
-                Some(&mut elevation as *mut _ as *mut _),
-                std::mem::size_of::<TOKEN_ELEVATION>() as u32,
-                &mut returned_length,
-            )?;
+            let enabled =
+                LookupPrivilegeValueW(None, SE_BACKUP_NAME, &mut privileges.Privileges[0].Luid)
+                    .and_then(|_| {
+                        AdjustTokenPrivileges(handle, false, Some(&privileges), 0, None, None)
+                    })
+                    // Also succeeds when the privilege is not held.
+                    .is_ok_and(|_| GetLastError() != ERROR_NOT_ALL_ASSIGNED);
+
+            let _ = CloseHandle(handle);
+            enabled
+        }
+    }
+}
+
+// Clusters bigger than 128 sectors (e.g. 2 MB) are stored as a negative power of two.
+// None if the shift is too large for a valid cluster size.
+pub fn decode_sectors_per_cluster(value: u8) -> Option<u64> {
+    if value > 0x80 {
+        let shift = (value as i8).unsigned_abs() as u32;
+        (shift <= MAX_SECTORS_PER_CLUSTER_SHIFT).then(|| 1u64 << shift)
+    } else {
+        Some(value as u64)
+    }
+}
+
+pub fn encode_sectors_per_cluster(sectors: u64) -> u8 {
+    if sectors > 0x80 {
+        (-(sectors.trailing_zeros() as i8)) as u8
+    } else {
+        sectors as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{decode_sectors_per_cluster, FileSystemType, Volume, VolumeGeometry};
+    use crate::errors::{BootSectorError, NtfsReaderError};
 
-            Ok(elevation.TokenIsElevated != 0)
+    #[test]
+    fn normalize_path() {
+        for path in ["C", "c:", "C:\\", "\\\\.\\C:", "\\\\?\\C:", "\\\\?\\C:\\"] {
+            assert_eq!(
+                Volume::normalize_path(Path::new(path)),
+                PathBuf::from("\\\\?\\C:")
+            );
         }
+
+        let guid = "\\\\?\\Volume{00000000-0000-0000-0000-000000000000}";
+        assert_eq!(
+            Volume::normalize_path(Path::new(&format!("{}\\", guid))),
+            PathBuf::from(guid)
+        );
+    }
+
+    #[test]
+    fn from_boot_sector() {
+        let mut sector = [0u8; 512];
+        sector[3..11].copy_from_slice(b"NTFS    ");
+        sector[11..13].copy_from_slice(&512u16.to_le_bytes());
+        sector[13] = 8;
+        sector[40..48].copy_from_slice(&1000u64.to_le_bytes());
+        sector[48..56].copy_from_slice(&4u64.to_le_bytes());
+        sector[64] = -10i8 as u8;
+        sector[72..80].copy_from_slice(&0x1234u64.to_le_bytes());
+
+        let volume = Volume::from_boot_sector(&sector, "backup").unwrap();
+        assert_eq!(volume.cluster_size, 4096);
+        assert_eq!(volume.volume_size, 512 * 1000);
+        assert_eq!(volume.file_record_size, 1024);
+        assert_eq!(volume.mft_position, 4 * 4096);
+        assert_eq!(volume.serial_number, 0x1234);
+
+        assert!(Volume::from_boot_sector(&sector[..100], "backup").is_err());
+
+        let invalid = |at: usize, value: u8| {
+            let mut sector = sector;
+            sector[at] = value;
+            match Volume::from_boot_sector(&sector, "backup") {
+                Err(NtfsReaderError::InvalidBootSector(err)) => err,
+                _ => panic!("the boot sector should be invalid"),
+            }
+        };
+        assert_eq!(invalid(12, 3), BootSectorError::SectorSize(0x300));
+        assert_eq!(invalid(13, 3), BootSectorError::SectorsPerCluster(3));
+        assert_eq!(invalid(13, 0), BootSectorError::SectorsPerCluster(0));
+        assert_eq!(invalid(13, 0x81), BootSectorError::SectorsPerCluster(0x81));
+        assert_eq!(invalid(13, 0xC0), BootSectorError::SectorsPerCluster(0xC0));
+        assert_eq!(invalid(13, 0xF0), BootSectorError::SectorsPerCluster(0xF0));
+        assert_eq!(
+            invalid(64, -20i8 as u8),
+            BootSectorError::FileRecordSize(-20)
+        );
+        assert_eq!(
+            invalid(48, 200),
+            BootSectorError::MftOutOfBounds {
+                mft_lcn: 200,
+                total_clusters: 125
+            }
+        );
+    }
+
+    #[test]
+    fn huge_clusters() {
+        // 2 MB clusters on a 256 TB volume.
+        let mut sector = [0u8; 512];
+        sector[3..11].copy_from_slice(b"NTFS    ");
+        sector[11..13].copy_from_slice(&512u16.to_le_bytes());
+        sector[13] = -12i8 as u8;
+        sector[40..48].copy_from_slice(&(1u64 << 39).to_le_bytes());
+        sector[48..56].copy_from_slice(&(1u64 << 26).to_le_bytes());
+        sector[64] = -10i8 as u8;
+
+        let volume = Volume::from_boot_sector(&sector, "huge").unwrap();
+        assert_eq!(volume.cluster_size, 2 * 1024 * 1024);
+        assert_eq!(volume.volume_size, 1u64 << 48);
+        assert_eq!(volume.mft_position, 1u64 << 47);
+        assert_eq!(volume.file_record_size, 1024);
+
+        assert_eq!(decode_sectors_per_cluster(0x80), Some(128));
+        assert_eq!(decode_sectors_per_cluster(0xF8), Some(256));
+        assert_eq!(decode_sectors_per_cluster(0x81), None);
+
+        // And back.
+        let volume = Volume::from_geometry(VolumeGeometry {
+            sector_size: 4096,
+            cluster_size: 2 * 1024 * 1024,
+            volume_size: 1u64 << 48,
+            file_record_size: 4096,
+            mft_position: 1u64 << 47,
+        });
+        assert_eq!(volume.boot_sector.sectors_per_cluster, -9i8 as u8);
+        assert_eq!({ volume.boot_sector.mft_lcn }, 1u64 << 26);
+    }
+
+    #[test]
+    fn detect_file_system() {
+        let mut sector = [0u8; 512];
+        sector[3..11].copy_from_slice(b"NTFS    ");
+        assert_eq!(FileSystemType::detect(&sector), FileSystemType::Ntfs);
+
+        sector[3..11].copy_from_slice(b"MSDOS5.0");
+        assert_eq!(
+            FileSystemType::detect(&sector),
+            FileSystemType::Unknown(*b"MSDOS5.0")
+        );
+
+        sector[82..90].copy_from_slice(b"FAT32   ");
+        assert_eq!(FileSystemType::detect(&sector), FileSystemType::Fat32);
+
+        sector[3..11].copy_from_slice(b"EXFAT   ");
+        assert_eq!(FileSystemType::detect(&sector), FileSystemType::ExFat);
     }
 }
@@ -0,0 +1,349 @@
+// Copyright (c) 2022, Matteo Bernacchia <dev@kikijiki.com>. All rights reserved.
+// This project is dual licensed under the Apache License 2.0 and the MIT license.
+// See the LICENSE files in the project root for details.
+
+// A directory walk over the MFT, with the API of the `walkdir` crate. The tree is built once
+// from the parent references, no directory is opened.
+
+use std::cmp::Ordering;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    api::ROOT_RECORD,
+    errors::NtfsReaderResult,
+    metadata::{FileType, Metadata},
+    mft::Mft,
+    volume::PathFormat,
+};
+
+type Sorter<'a> = Box<dyn FnMut(&DirEntry, &DirEntry) -> Ordering + 'a>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    path: PathBuf,
+    file_name: OsString,
+    depth: usize,
+    number: u64,
+    metadata: Metadata,
+}
+
+impl DirEntry {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn into_path(self) -> PathBuf {
+        self.path
+    }
+
+    pub fn file_name(&self) -> &OsStr {
+        &self.file_name
+    }
+
+    // The root of the walk is at depth 0.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.metadata.file_type()
+    }
+
+    // Never fails, the `Result` is only there to match `walkdir`.
+    pub fn metadata(&self) -> io::Result<Metadata> {
+        Ok(self.metadata.clone())
+    }
+
+    pub fn path_is_symlink(&self) -> bool {
+        self.metadata.is_symlink()
+    }
+
+    pub fn record_number(&self) -> u64 {
+        self.number
+    }
+}
+
+pub struct WalkMft<'a> {
+    mft: &'a Mft,
+    root: u64,
+    min_depth: usize,
+    max_depth: usize,
+    path_format: PathFormat,
+    sorter: Option<Sorter<'a>>,
+}
+
+impl<'a> WalkMft<'a> {
+    // From the root of the volume.
+    pub fn new(mft: &'a Mft) -> Self {
+        Self::from_record(mft, ROOT_RECORD)
+    }
+
+    // From the directory (or file) with the given record number.
+    pub fn from_record(mft: &'a Mft, number: u64) -> Self {
+        Self {
+            mft,
+            root: number,
+            min_depth: 0,
+            max_depth: usize::MAX,
+            path_format: PathFormat::default(),
+            sorter: None,
+        }
+    }
+
+    // As in `walkdir`, the depths are swapped when they cross.
+    pub fn min_depth(mut self, depth: usize) -> Self {
+        self.min_depth = depth;
+        if self.min_depth > self.max_depth {
+            self.min_depth = self.max_depth;
+        }
+        self
+    }
+
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        if self.max_depth < self.min_depth {
+            self.max_depth = self.min_depth;
+        }
+        self
+    }
+
+    pub fn path_format(mut self, format: PathFormat) -> Self {
+        self.path_format = format;
+        self
+    }
+
+    // The entries of each directory are sorted before being returned, otherwise they come in
+    // record order.
+    pub fn sort_by<F>(mut self, cmp: F) -> Self
+    where
+        F: FnMut(&DirEntry, &DirEntry) -> Ordering + 'a,
+    {
+        self.sorter = Some(Box::new(cmp));
+        self
+    }
+
+    pub fn sort_by_file_name(self) -> Self {
+        self.sort_by(|a, b| a.file_name().cmp(b.file_name()))
+    }
+
+    pub fn sort_by_key<K, F>(self, mut key: F) -> Self
+    where
+        K: Ord,
+        F: FnMut(&DirEntry) -> K + 'a,
+    {
+        self.sort_by(move |a, b| key(a).cmp(&key(b)))
+    }
+}
+
+impl<'a> IntoIterator for WalkMft<'a> {
+    type Item = NtfsReaderResult<DirEntry>;
+    type IntoIter = IntoIter<'a>;
+
+    fn into_iter(self) -> IntoIter<'a> {
+        IntoIter {
+            children: Vec::new(),
+            stack: Vec::new(),
+            started: false,
+            options: self,
+        }
+    }
+}
+
+// Depth first, each directory before its contents.
+pub struct IntoIter<'a> {
+    options: WalkMft<'a>,
+    // The records in use by parent record number, built on the first call to `next`.
+    children: Vec<Vec<u64>>,
+    stack: Vec<std::vec::IntoIter<NtfsReaderResult<DirEntry>>>,
+    started: bool,
+}
+
+impl IntoIter<'_> {
+    fn build_children(&mut self) {
+        let mft = self.options.mft;
+        let mut children = vec![Vec::new(); mft.max_record as usize];
+
+        mft.iterate_files(|file| {
+            let Some(name) = file.get_best_file_name_ref(mft) else {
+                return;
+            };
+            // The files left in a deleted (or reused) directory are not part of the tree.
+            let parent = name.parent() as usize;
+            if parent != file.number as usize
+                && parent < children.len()
+                && mft.is_reference_current(name.parent_reference())
+            {
+                children[parent].push(file.number);
+            }
+        });
+
+        self.children = children;
+    }
+
+    fn root(&self) -> NtfsReaderResult<DirEntry> {
+        let mft = self.options.mft;
+        let root = mft.volume.root(self.options.path_format);
+        let path = match mft.get_relative_path(self.options.root) {
+            Some(relative) => root.join(relative),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("record {} is not in the tree", self.options.root),
+                )
+                .into())
+            }
+        };
+        let file_name = match path.file_name() {
+            Some(name) => name.to_os_string(),
+            None => path.clone().into_os_string(),
+        };
+        self.entry(self.options.root, path, file_name, 0)
+    }
+
+    fn entry(
+        &self,
+        number: u64,
+        path: PathBuf,
+        file_name: OsString,
+        depth: usize,
+    ) -> NtfsReaderResult<DirEntry> {
+        let mft = self.options.mft;
+        let file = mft.try_get_record(number)?;
+        if !file.is_used() {
+            let message = format!("record {} is not in use", number);
+            return Err(io::Error::new(io::ErrorKind::NotFound, message).into());
+        }
+
+        Ok(DirEntry {
+            path,
+            file_name,
+            depth,
+            number,
+            metadata: file.metadata(mft),
+        })
+    }
+
+    fn read_dir(&mut self, parent: &DirEntry) -> Vec<NtfsReaderResult<DirEntry>> {
+        let mft = self.options.mft;
+        let children = self
+            .children
+            .get(parent.number as usize)
+            .map_or(&[][..], |children| children.as_slice());
+
+        let mut entries: Vec<_> = children
+            .iter()
+            .map(|child| {
+                let name = mft
+                    .get_file_name(*child)
+                    .map(|name| name.to_os_string())
+                    .unwrap_or_default();
+                self.entry(*child, parent.path.join(&name), name, parent.depth + 1)
+            })
+            .collect();
+
+        // The errors first, they can't be compared.
+        if let Some(sorter) = self.options.sorter.as_mut() {
+            entries.sort_by(|a, b| match (a, b) {
+                (Ok(a), Ok(b)) => sorter(a, b),
+                (Err(_), Ok(_)) => Ordering::Less,
+                (Ok(_), Err(_)) => Ordering::Greater,
+                (Err(_), Err(_)) => Ordering::Equal,
+            });
+        }
+        entries
+    }
+
+    // Queues the contents of the directory if it is not too deep, and tells if the entry itself
+    // is deep enough to be returned.
+    fn visit(&mut self, entry: &DirEntry) -> bool {
+        if entry.metadata.is_dir() && entry.depth < self.options.max_depth {
+            let entries = self.read_dir(entry);
+            self.stack.push(entries.into_iter());
+        }
+        entry.depth >= self.options.min_depth
+    }
+}
+
+impl Iterator for IntoIter<'_> {
+    type Item = NtfsReaderResult<DirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.started {
+            self.started = true;
+            self.build_children();
+
+            let root = match self.root() {
+                Ok(root) => root,
+                Err(err) => return Some(Err(err)),
+            };
+            if self.visit(&root) {
+                return Some(Ok(root));
+            }
+        }
+
+        loop {
+            let entry = match self.stack.last_mut()?.next() {
+                Some(Ok(entry)) => entry,
+                Some(Err(err)) => return Some(Err(err)),
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+
+            if self.visit(&entry) {
+                return Some(Ok(entry));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::WalkMft;
+    use crate::api::file_reference;
+    use crate::mft::memory_tests::{make_mft, make_named_record, make_tree};
+    use crate::volume::PathFormat;
+
+    #[test]
+    fn walk() {
+        let mut records = make_tree(34);
+        records[31] = make_named_record(1, file_reference(30, 2), "b");
+        records[32] = make_named_record(1, file_reference(30, 2), "a");
+        records[33] = make_named_record(1, file_reference(5, 5), "top");
+        let mft = make_mft(records);
+
+        let walk = |walk: WalkMft| -> Vec<(PathBuf, usize)> {
+            walk.path_format(PathFormat::Rootless)
+                .into_iter()
+                .map(|entry| entry.unwrap())
+                .map(|entry| (entry.path().to_path_buf(), entry.depth()))
+                .collect()
+        };
+
+        assert_eq!(
+            walk(WalkMft::new(&mft).sort_by_file_name()),
+            [
+                (PathBuf::new(), 0),
+                (PathBuf::from("dir"), 1),
+                (PathBuf::from("dir").join("a"), 2),
+                (PathBuf::from("dir").join("b"), 2),
+                (PathBuf::from("top"), 1),
+            ]
+        );
+        assert_eq!(
+            walk(
+                WalkMft::new(&mft)
+                    .min_depth(1)
+                    .max_depth(1)
+                    .sort_by_file_name()
+            ),
+            [(PathBuf::from("dir"), 1), (PathBuf::from("top"), 1)]
+        );
+        assert_eq!(walk(WalkMft::from_record(&mft, 30).min_depth(1)).len(), 2);
+    }
+}
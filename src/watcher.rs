@@ -0,0 +1,1102 @@
+// Copyright (c) 2022, Matteo Bernacchia <dev@kikijiki.com>. All rights reserved.
+// This project is dual licensed under the Apache License 2.0 and the MIT license.
+// See the LICENSE files in the project root for details.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use windows::Win32::System::Ioctl;
+
+use crate::api::{reference_number, ROOT_RECORD};
+use crate::errors::{JournalError, NtfsReaderError, NtfsReaderResult};
+use crate::file::NtfsFile;
+use crate::journal::{ChangeCategories, Journal, JournalOptions, UsnRecord};
+use crate::mft::Mft;
+use crate::volume::Volume;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Created(PathBuf),
+    Modified(PathBuf),
+    Removed(PathBuf),
+    Renamed {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    // Records were lost (the journal wrapped or was recreated) and the changes in between are
+    // not reported, compare with the files found by the rescan instead. See `Watcher::with_resync`.
+    Resync {
+        // Empty when the whole volume was rescanned.
+        directories: Vec<PathBuf>,
+        // The files and directories in them as of the rescan.
+        files: Vec<PathBuf>,
+    },
+}
+
+// What the watcher rescans when records were lost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResyncMode {
+    // The directories of (at most this many of) the latest records, or the whole volume when
+    // there were none.
+    Targeted(usize),
+    Full,
+}
+
+// A read of the journal of the watcher.
+enum JournalRead {
+    Records(Vec<UsnRecord>),
+    // The records were lost, the MFT was read again and the journal continues from its point.
+    Resynced(Box<Mft>, ResyncMode),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EventKind {
+    Created,
+    Modified,
+    Removed,
+    Renamed,
+}
+
+const MODIFIED_MASK: u32 = Ioctl::USN_REASON_DATA_OVERWRITE
+    | Ioctl::USN_REASON_DATA_EXTEND
+    | Ioctl::USN_REASON_DATA_TRUNCATION
+    | Ioctl::USN_REASON_NAMED_DATA_OVERWRITE
+    | Ioctl::USN_REASON_NAMED_DATA_EXTEND
+    | Ioctl::USN_REASON_NAMED_DATA_TRUNCATION
+    | Ioctl::USN_REASON_BASIC_INFO_CHANGE
+    | Ioctl::USN_REASON_EA_CHANGE
+    | Ioctl::USN_REASON_SECURITY_CHANGE
+    | Ioctl::USN_REASON_COMPRESSION_CHANGE
+    | Ioctl::USN_REASON_ENCRYPTION_CHANGE
+    | Ioctl::USN_REASON_STREAM_CHANGE;
+
+// The close record carries all the reasons accumulated while the file was open.
+fn classify(reason: u32) -> Option<EventKind> {
+    if reason & Ioctl::USN_REASON_CLOSE == 0 {
+        return None;
+    }
+
+    let created = reason & Ioctl::USN_REASON_FILE_CREATE != 0;
+    let removed = reason & Ioctl::USN_REASON_FILE_DELETE != 0;
+
+    match (created, removed) {
+        // Temporary file.
+        (true, true) => None,
+        (false, true) => Some(EventKind::Removed),
+        (true, false) => Some(EventKind::Created),
+        _ if reason & Ioctl::USN_REASON_RENAME_NEW_NAME != 0 => Some(EventKind::Renamed),
+        _ if reason & MODIFIED_MASK != 0 => Some(EventKind::Modified),
+        _ => None,
+    }
+}
+
+// Holds the created/modified events for a while, merging the ones for the same file.
+struct Coalescer {
+    window: Duration,
+    // Event, first seen, last seen.
+    pending: HashMap<PathBuf, (Event, Instant, Instant)>,
+}
+
+impl Coalescer {
+    fn new(window: Duration) -> Self {
+        Coalescer {
+            window,
+            pending: HashMap::new(),
+        }
+    }
+
+    fn push(&mut self, event: Event, now: Instant, events: &mut Vec<Event>) {
+        match event {
+            Event::Created(path) | Event::Modified(path) if self.pending.contains_key(&path) => {
+                // Keep the first event, so created + modified stays created.
+                self.pending.get_mut(&path).unwrap().2 = now;
+            }
+            Event::Created(ref path) | Event::Modified(ref path) => {
+                self.pending.insert(path.clone(), (event, now, now));
+            }
+            Event::Removed(ref path) | Event::Renamed { from: ref path, .. } => {
+                // Emit what happened before.
+                if let Some((pending, _, _)) = self.pending.remove(path) {
+                    events.push(pending);
+                }
+                events.push(event);
+            }
+            Event::Resync { .. } => {
+                self.flush(now, true, events);
+                events.push(event);
+            }
+        }
+    }
+
+    // Emits the events that have been quiet for the whole window, or all of them.
+    fn flush(&mut self, now: Instant, all: bool, events: &mut Vec<Event>) {
+        let mut ready: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, (_, _, last_seen))| all || now.duration_since(*last_seen) >= self.window)
+            .map(|(path, (_, first_seen, _))| (*first_seen, path.clone()))
+            .collect();
+        ready.sort();
+
+        for (_, path) in ready {
+            events.push(self.pending.remove(&path).unwrap().0);
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.pending
+            .values()
+            .map(|(_, _, last_seen)| *last_seen + self.window)
+            .min()
+    }
+}
+
+// The events of one file in a batch, merged: e.g. created and then modified is only created,
+// created and then removed is nothing, and removed and then created again is modified.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEvents {
+    // The latest path of the file.
+    pub path: PathBuf,
+    pub events: Vec<Event>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BatchOptions {
+    // A file is emitted once it had no new events for this long.
+    pub debounce: Duration,
+    // Files per batch, zero for no limit. When this many files are pending they are all emitted
+    // without waiting, so that a busy volume doesn't hold them forever.
+    pub max_batch_size: usize,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        BatchOptions {
+            debounce: Duration::from_millis(500),
+            max_batch_size: 1000,
+        }
+    }
+}
+
+fn merge_event(events: &mut Vec<Event>, event: Event) {
+    match event {
+        Event::Modified(_)
+            if matches!(events.last(), Some(Event::Created(_) | Event::Modified(_))) => {}
+        // Replaced, e.g. by an editor saving to a temporary file.
+        Event::Created(path) if matches!(events.last(), Some(Event::Removed(_))) => {
+            *events.last_mut().unwrap() = Event::Modified(path);
+        }
+        Event::Removed(path) => {
+            events.retain(|event| !matches!(event, Event::Modified(_)));
+            match events.pop() {
+                // It didn't exist before the batch.
+                Some(Event::Created(_)) => {}
+                Some(Event::Renamed { from, .. }) => events.push(Event::Removed(from)),
+                Some(last) => {
+                    events.push(last);
+                    events.push(Event::Removed(path));
+                }
+                None => events.push(Event::Removed(path)),
+            }
+        }
+        event => events.push(event),
+    }
+}
+
+// The events of a file that was renamed, moved to its new path.
+fn rename_events(mut events: Vec<Event>, from: PathBuf, to: &Path) -> Vec<Event> {
+    let moved = |event: Event| match event {
+        Event::Created(_) => Event::Created(to.to_path_buf()),
+        Event::Modified(_) => Event::Modified(to.to_path_buf()),
+        event => event,
+    };
+
+    match events.first() {
+        Some(Event::Created(_)) => events.into_iter().map(moved).collect(),
+        Some(Event::Renamed { from: original, .. }) => {
+            let original = original.clone();
+            let rest = events.drain(1..).map(moved);
+            // Renamed back.
+            if original == to {
+                rest.collect()
+            } else {
+                let renamed = Event::Renamed {
+                    from: original,
+                    to: to.to_path_buf(),
+                };
+                std::iter::once(renamed).chain(rest).collect()
+            }
+        }
+        _ => {
+            events.push(Event::Renamed {
+                from,
+                to: to.to_path_buf(),
+            });
+            events
+        }
+    }
+}
+
+// Holds the events of each file until it has been quiet for the debounce interval.
+struct Batcher {
+    options: BatchOptions,
+    // Events, first seen, last seen.
+    pending: HashMap<PathBuf, (Vec<Event>, Instant, Instant)>,
+}
+
+impl Batcher {
+    fn new(options: BatchOptions) -> Self {
+        Batcher {
+            options,
+            pending: HashMap::new(),
+        }
+    }
+
+    // Not for `Event::Resync`, see `BatchedWatcher::process`.
+    fn push(&mut self, event: Event, now: Instant) {
+        let path = match &event {
+            Event::Created(path) | Event::Modified(path) | Event::Removed(path) => path.clone(),
+            Event::Renamed { to, .. } => to.clone(),
+            Event::Resync { .. } => return,
+        };
+
+        let (events, first_seen) = match event {
+            Event::Renamed { from, to } => {
+                let (events, first_seen) = self
+                    .pending
+                    .remove(&from)
+                    .map_or((Vec::new(), now), |(events, first_seen, _)| {
+                        (events, first_seen)
+                    });
+                (rename_events(events, from, &to), first_seen)
+            }
+            event => (vec![event], now),
+        };
+
+        let entry = self
+            .pending
+            .entry(path.clone())
+            .or_insert_with(|| (Vec::new(), first_seen, now));
+        entry.1 = entry.1.min(first_seen);
+        entry.2 = now;
+        for event in events {
+            merge_event(&mut entry.0, event);
+        }
+
+        if entry.0.is_empty() {
+            self.pending.remove(&path);
+        }
+    }
+
+    // The files quiet for the whole interval (or all of them) in the order they were first seen,
+    // in batches of at most `max_batch_size`.
+    fn flush(&mut self, now: Instant, all: bool) -> Vec<Vec<FileEvents>> {
+        let max_batch_size = match self.options.max_batch_size {
+            0 => usize::MAX,
+            size => size,
+        };
+        let all = all || self.pending.len() >= max_batch_size;
+
+        let mut ready: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, (_, _, last_seen))| {
+                all || now.duration_since(*last_seen) >= self.options.debounce
+            })
+            .map(|(path, (_, first_seen, _))| (*first_seen, path.clone()))
+            .collect();
+        ready.sort();
+
+        let mut files: Vec<FileEvents> = ready
+            .into_iter()
+            .map(|(_, path)| {
+                let events = self.pending.remove(&path).unwrap().0;
+                FileEvents { path, events }
+            })
+            .collect();
+
+        let mut batches = Vec::new();
+        while !files.is_empty() {
+            let rest = files.split_off(files.len().min(max_batch_size));
+            batches.push(std::mem::replace(&mut files, rest));
+        }
+        batches
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.pending
+            .values()
+            .map(|(_, _, last_seen)| *last_seen + self.options.debounce)
+            .min()
+    }
+}
+
+// Turns the journal records into higher level events, one per file each time it is closed.
+pub struct Watcher {
+    journal: Journal,
+    coalescer: Option<Coalescer>,
+    categories: ChangeCategories,
+    resync: Option<ResyncMode>,
+    // The parents of the latest records, oldest first, for `ResyncMode::Targeted`.
+    recent_directories: VecDeque<u64>,
+    seen_directories: HashSet<u64>,
+}
+
+impl Watcher {
+    pub fn new(volume: Volume, mut options: JournalOptions) -> Result<Self, std::io::Error> {
+        // Needed to emit the events and to match renames.
+        options.reason_mask |= Ioctl::USN_REASON_CLOSE | Ioctl::USN_REASON_RENAME_OLD_NAME;
+        Ok(Self::from_journal(Journal::new(volume, options)?))
+    }
+
+    pub fn from_journal(journal: Journal) -> Self {
+        Watcher {
+            journal,
+            coalescer: None,
+            categories: ChangeCategories::ALL,
+            resync: None,
+            recent_directories: VecDeque::new(),
+            seen_directories: HashSet::new(),
+        }
+    }
+
+    // Editors can close a file many times while saving it, with a window the created/modified
+    // events for the same file are merged until there are no new ones for the whole window.
+    // The held events are emitted by the following reads, or by `flush`.
+    pub fn with_coalesce_window(mut self, window: Duration) -> Self {
+        self.coalescer = Some(Coalescer::new(window));
+        self
+    }
+
+    // Only emit the events for the changes in these categories, e.g. to ignore the attribute and
+    // time changes. A created file with some data is both `LifecycleCreate` and `ContentChanged`.
+    pub fn with_categories<C: Into<ChangeCategories>>(mut self, categories: C) -> Self {
+        self.categories = categories.into();
+        self
+    }
+
+    // Instead of returning the `JournalError` when records were lost, read the MFT again,
+    // continue from its point and emit an `Event::Resync` with the files found. Needs the volume
+    // of the journal (not available with `Journal::open`).
+    pub fn with_resync(mut self, mode: ResyncMode) -> Self {
+        self.resync = Some(mode);
+        self
+    }
+
+    pub fn read(&mut self) -> Result<Vec<Event>, std::io::Error> {
+        let read = self.journal.read();
+        match self.after_read(read)? {
+            JournalRead::Records(records) => Ok(self.process(records)),
+            JournalRead::Resynced(mft, mode) => Ok(self.resync_events(&mft, mode)),
+        }
+    }
+
+    pub fn read_wait(&mut self, timeout: Option<Duration>) -> Result<Vec<Event>, std::io::Error> {
+        let read = self.journal.read_wait(self.wait_timeout(timeout));
+        match self.after_read(read)? {
+            JournalRead::Records(records) => Ok(self.process(records)),
+            JournalRead::Resynced(mft, mode) => Ok(self.resync_events(&mft, mode)),
+        }
+    }
+
+    // Resyncs when the records were lost, for all the reads of the journal.
+    fn after_read(
+        &mut self,
+        read: Result<Vec<UsnRecord>, io::Error>,
+    ) -> Result<JournalRead, io::Error> {
+        match read {
+            Ok(records) => Ok(JournalRead::Records(records)),
+            Err(err) => {
+                let (mft, mode) = self.resync(err)?;
+                Ok(JournalRead::Resynced(Box::new(mft), mode))
+            }
+        }
+    }
+
+    // Don't wait past the point where the held events should be emitted.
+    fn wait_timeout(&self, timeout: Option<Duration>) -> Option<Duration> {
+        let deadline = self.coalescer.as_ref().and_then(Coalescer::next_deadline);
+        match deadline {
+            Some(deadline) => {
+                let until = deadline.saturating_duration_since(Instant::now());
+                Some(timeout.map_or(until, |timeout| timeout.min(until)))
+            }
+            None => timeout,
+        }
+    }
+
+    // Emits all the events held by the coalescing window.
+    pub fn flush(&mut self) -> Vec<Event> {
+        let mut events = Vec::new();
+        if let Some(coalescer) = &mut self.coalescer {
+            coalescer.flush(Instant::now(), true, &mut events);
+        }
+        events
+    }
+
+    pub fn journal(&self) -> &Journal {
+        &self.journal
+    }
+
+    pub fn journal_mut(&mut self) -> &mut Journal {
+        &mut self.journal
+    }
+
+    pub fn into_journal(self) -> Journal {
+        self.journal
+    }
+
+    fn process(&mut self, records: Vec<UsnRecord>) -> Vec<Event> {
+        let mut events = Vec::new();
+
+        for record in records {
+            self.note_directory(reference_number(record.parent_id.reference()));
+            if let Some(event) = self.record_event(record) {
+                self.push_event(event, &mut events);
+            }
+        }
+
+        self.flush_ready(&mut events);
+        events
+    }
+
+    fn record_event(&mut self, record: UsnRecord) -> Option<Event> {
+        let categories = record.categories();
+        let event = match classify(record.reason)? {
+            EventKind::Created => Event::Created(record.path),
+            EventKind::Modified => Event::Modified(record.path),
+            EventKind::Removed => Event::Removed(record.path),
+            EventKind::Renamed => {
+                self.journal.forget_history(record.file_id);
+                match record.rename_from {
+                    Some(from) => Event::Renamed {
+                        from,
+                        to: record.path,
+                    },
+                    // Renamed before we started watching.
+                    None => Event::Created(record.path),
+                }
+            }
+        };
+
+        self.categories.intersects(categories).then_some(event)
+    }
+
+    fn push_event(&mut self, event: Event, events: &mut Vec<Event>) {
+        match &mut self.coalescer {
+            Some(coalescer) => coalescer.push(event, Instant::now(), events),
+            None => events.push(event),
+        }
+    }
+
+    fn flush_ready(&mut self, events: &mut Vec<Event>) {
+        if let Some(coalescer) = &mut self.coalescer {
+            coalescer.flush(Instant::now(), false, events);
+        }
+    }
+
+    fn note_directory(&mut self, number: u64) {
+        let Some(ResyncMode::Targeted(max)) = self.resync else {
+            return;
+        };
+        if max == 0 || !self.seen_directories.insert(number) {
+            return;
+        }
+
+        self.recent_directories.push_back(number);
+        if self.recent_directories.len() > max {
+            let oldest = self.recent_directories.pop_front().unwrap();
+            self.seen_directories.remove(&oldest);
+        }
+    }
+
+    fn resync(&mut self, err: io::Error) -> Result<(Mft, ResyncMode), io::Error> {
+        let lost = matches!(
+            JournalError::from_io(&err),
+            Some(JournalError::EntryDeleted { .. } | JournalError::Recreated { .. })
+        );
+        let Some(mode) = self.resync.filter(|_| lost) else {
+            return Err(err);
+        };
+        let Some(volume) = self.journal.get_volume().cloned() else {
+            return Err(err);
+        };
+
+        // The records written until the MFT is read are reflected in it.
+        self.journal.resync()?;
+        let next_usn = self.journal.info()?.next_usn;
+        let mft = Mft::new(volume).map_err(io_error)?;
+        self.journal.seek_to_usn(next_usn)?;
+        Ok((mft, mode))
+    }
+
+    fn resync_events(&mut self, mft: &Mft, mode: ResyncMode) -> Vec<Event> {
+        let targeted: HashSet<u64> = match mode {
+            ResyncMode::Targeted(_) => self
+                .recent_directories
+                .drain(..)
+                .filter(|number| {
+                    mft.get_record(*number)
+                        .is_some_and(|file| file.is_used() && file.is_directory())
+                })
+                .collect(),
+            ResyncMode::Full => HashSet::new(),
+        };
+        self.seen_directories.clear();
+
+        let root = self.journal.root();
+        let directories = targeted
+            .iter()
+            .filter_map(|number| mft.get_relative_path(*number))
+            .map(|path| root.join(path))
+            .collect();
+        let mut files = Vec::new();
+        mft.iterate_files(|file| {
+            let inside = targeted.is_empty()
+                || mft
+                    .get_parent(file.number)
+                    .is_some_and(|parent| targeted.contains(&parent));
+            if inside {
+                if let Some(path) = mft.get_relative_path(file.number) {
+                    files.push(root.join(path));
+                }
+            }
+        });
+
+        // After the events from before the records were lost.
+        let mut events = self.flush();
+        events.push(Event::Resync { directories, files });
+        events
+    }
+}
+
+fn io_error(err: NtfsReaderError) -> io::Error {
+    match err {
+        NtfsReaderError::IOError(err) => err,
+        err => io::Error::other(err.to_string()),
+    }
+}
+
+// Delivers the events of the watcher grouped by file, once each file stops changing, for the
+// consumers that would otherwise react to every save of an editor.
+pub struct BatchedWatcher {
+    watcher: Watcher,
+    batcher: Batcher,
+}
+
+impl BatchedWatcher {
+    pub fn new(watcher: Watcher, options: BatchOptions) -> Self {
+        BatchedWatcher {
+            watcher,
+            batcher: Batcher::new(options),
+        }
+    }
+
+    // The batches that are ready, usually none or one.
+    pub fn read(&mut self) -> Result<Vec<Vec<FileEvents>>, std::io::Error> {
+        let events = self.watcher.read()?;
+        Ok(self.process(events))
+    }
+
+    pub fn read_wait(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<Vec<FileEvents>>, std::io::Error> {
+        // Don't wait past the point where the held files should be emitted.
+        let timeout = match self.batcher.next_deadline() {
+            Some(deadline) => {
+                let until = deadline.saturating_duration_since(Instant::now());
+                Some(timeout.map_or(until, |timeout| timeout.min(until)))
+            }
+            None => timeout,
+        };
+
+        let events = self.watcher.read_wait(timeout)?;
+        Ok(self.process(events))
+    }
+
+    // Emits all the held files, including the events held by the watcher.
+    pub fn flush(&mut self) -> Vec<Vec<FileEvents>> {
+        let now = Instant::now();
+        for event in self.watcher.flush() {
+            self.batcher.push(event, now);
+        }
+        self.batcher.flush(now, true)
+    }
+
+    pub fn watcher(&self) -> &Watcher {
+        &self.watcher
+    }
+
+    pub fn watcher_mut(&mut self) -> &mut Watcher {
+        &mut self.watcher
+    }
+
+    pub fn into_watcher(self) -> Watcher {
+        self.watcher
+    }
+
+    // An `Event::Resync` is delivered alone in its batch (with the volume root as the path),
+    // after all the files held before it.
+    fn process(&mut self, events: Vec<Event>) -> Vec<Vec<FileEvents>> {
+        let now = Instant::now();
+        let mut batches = Vec::new();
+
+        for event in events {
+            if let Event::Resync { .. } = event {
+                batches.extend(self.batcher.flush(now, true));
+                batches.push(vec![FileEvents {
+                    path: self.watcher.journal.root().to_path_buf(),
+                    events: vec![event],
+                }]);
+            } else {
+                self.batcher.push(event, now);
+            }
+        }
+
+        batches.extend(self.batcher.flush(now, false));
+        batches
+    }
+}
+
+// Watches the files of a single directory, or of all the directories under it. The journal
+// records are filtered by their parent before resolving any path, so on a busy volume it costs
+// little more than reading the journal.
+pub struct DirectoryWatcher {
+    watcher: Watcher,
+    root: u64,
+    recursive: bool,
+    // The watched directories and their parents, by record number.
+    directories: HashMap<u64, u64>,
+    // The paths before the renames inside, until the close record of the file.
+    old_paths: HashMap<u64, PathBuf>,
+    listing: Vec<PathBuf>,
+}
+
+impl DirectoryWatcher {
+    // Opens the journal, then lists the directory from the MFT.
+    pub fn new<P: AsRef<Path>>(
+        volume: Volume,
+        path: P,
+        recursive: bool,
+        mut options: JournalOptions,
+    ) -> NtfsReaderResult<Self> {
+        // Only the records inside are resolved.
+        options.resolve_paths = false;
+        // Needed to follow the directories created, removed and moved.
+        options.reason_mask |= Ioctl::USN_REASON_FILE_CREATE
+            | Ioctl::USN_REASON_FILE_DELETE
+            | Ioctl::USN_REASON_RENAME_NEW_NAME;
+        let watcher = Watcher::new(volume.clone(), options)?;
+
+        let mut mft = Mft::new(volume)?;
+        mft.build_parent_index();
+        Self::from_mft(watcher, &mft, path, recursive)
+    }
+
+    // The journal of the watcher should have been opened before the MFT was read, and better
+    // without `JournalOptions::resolve_paths` since the paths of the other records are not needed.
+    pub fn from_mft<P: AsRef<Path>>(
+        watcher: Watcher,
+        mft: &Mft,
+        path: P,
+        recursive: bool,
+    ) -> NtfsReaderResult<Self> {
+        let path = path.as_ref();
+        let root = mft.find_path(path).ok_or_else(|| {
+            let message = format!("{} is not in the tree", path.display());
+            io::Error::new(io::ErrorKind::NotFound, message)
+        })?;
+        if !mft.get_record(root).is_some_and(|file| file.is_directory()) {
+            let message = format!("{} is not a directory", path.display());
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message).into());
+        }
+
+        let mut watcher = DirectoryWatcher {
+            watcher,
+            root,
+            recursive,
+            directories: HashMap::new(),
+            old_paths: HashMap::new(),
+            listing: Vec::new(),
+        };
+        watcher.scan(mft)?;
+        Ok(watcher)
+    }
+
+    // Lists the directory and the directories to follow from the MFT.
+    fn scan(&mut self, mft: &Mft) -> NtfsReaderResult<()> {
+        let root = self.root;
+        let recursive = self.recursive;
+        self.directories.clear();
+        self.directories
+            .insert(root, mft.get_parent(root).unwrap_or(ROOT_RECORD));
+        self.listing.clear();
+
+        let volume_root = self.watcher.journal().root().to_path_buf();
+        let directories = &mut self.directories;
+        let listing = &mut self.listing;
+        let mut add = |file: &NtfsFile| {
+            if let Some(relative) = mft.get_relative_path(file.number) {
+                listing.push(volume_root.join(relative));
+            }
+            if recursive && file.is_directory() {
+                directories.insert(file.number, mft.get_parent(file.number).unwrap_or(root));
+            }
+        };
+
+        if recursive {
+            mft.iterate_under(root, &mut add)?;
+        } else {
+            for child in mft.get_children(root) {
+                if let Some(file) = mft.get_record(child).filter(|file| file.is_used()) {
+                    add(&file);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The files and directories inside when the watch started.
+    pub fn listing(&self) -> &[PathBuf] {
+        &self.listing
+    }
+
+    // With `Watcher::with_resync`, the directory is listed again when records were lost.
+    pub fn read(&mut self) -> Result<Vec<Event>, std::io::Error> {
+        let read = self.watcher.journal.read();
+        match self.watcher.after_read(read)? {
+            JournalRead::Records(records) => Ok(self.process(records)),
+            JournalRead::Resynced(mft, _) => self.resync(*mft),
+        }
+    }
+
+    pub fn read_wait(&mut self, timeout: Option<Duration>) -> Result<Vec<Event>, std::io::Error> {
+        let timeout = self.watcher.wait_timeout(timeout);
+        let read = self.watcher.journal.read_wait(timeout);
+        match self.watcher.after_read(read)? {
+            JournalRead::Records(records) => Ok(self.process(records)),
+            JournalRead::Resynced(mft, _) => self.resync(*mft),
+        }
+    }
+
+    pub fn flush(&mut self) -> Vec<Event> {
+        self.watcher.flush()
+    }
+
+    pub fn watcher(&self) -> &Watcher {
+        &self.watcher
+    }
+
+    pub fn watcher_mut(&mut self) -> &mut Watcher {
+        &mut self.watcher
+    }
+
+    pub fn into_watcher(self) -> Watcher {
+        self.watcher
+    }
+
+    fn process(&mut self, records: Vec<UsnRecord>) -> Vec<Event> {
+        let mut events = Vec::new();
+
+        for mut record in records {
+            let number = reference_number(record.file_id.reference());
+            let parent = reference_number(record.parent_id.reference());
+            let inside = self.directories.contains_key(&parent);
+            let new_name = record.reason & Ioctl::USN_REASON_RENAME_NEW_NAME != 0;
+
+            // The name before a rename, the new one can be in another directory.
+            if record.reason & Ioctl::USN_REASON_RENAME_OLD_NAME != 0 && !new_name {
+                if inside {
+                    let path = self.watcher.journal.resolve_path(&record);
+                    self.old_paths.insert(number, path);
+                }
+                continue;
+            }
+
+            if record.is_directory() {
+                self.track_directory(&record, number, parent, inside);
+            }
+
+            if record.reason & Ioctl::USN_REASON_CLOSE == 0 {
+                continue;
+            }
+            let old_path = new_name.then(|| self.old_paths.remove(&number)).flatten();
+
+            if inside {
+                record.path = self.watcher.journal.resolve_path(&record);
+                record.rename_from = old_path;
+                if let Some(event) = self.watcher.record_event(record) {
+                    self.watcher.push_event(event, &mut events);
+                }
+            } else if let Some(old_path) = old_path {
+                // Moved out.
+                if self.watcher.categories.intersects(record.categories()) {
+                    self.watcher
+                        .push_event(Event::Removed(old_path), &mut events);
+                }
+            }
+        }
+
+        self.watcher.flush_ready(&mut events);
+        events
+    }
+
+    // Only the watched directory is rescanned, whatever the mode.
+    fn resync(&mut self, mut mft: Mft) -> Result<Vec<Event>, io::Error> {
+        mft.build_parent_index();
+        self.old_paths.clear();
+
+        // Removed in the meantime, nothing is left to watch.
+        if mft
+            .get_record(self.root)
+            .is_some_and(|file| file.is_used() && file.is_directory())
+        {
+            self.scan(&mft).map_err(io_error)?;
+        } else {
+            self.directories.clear();
+            self.listing.clear();
+        }
+
+        let root = self.watcher.journal.root();
+        let directories = mft
+            .get_relative_path(self.root)
+            .map(|path| root.join(path))
+            .into_iter()
+            .collect();
+
+        let mut events = self.watcher.flush();
+        events.push(Event::Resync {
+            directories,
+            files: self.listing.clone(),
+        });
+        Ok(events)
+    }
+
+    fn track_directory(&mut self, record: &UsnRecord, number: u64, parent: u64, inside: bool) {
+        let reason = record.reason;
+        let new_name = reason & Ioctl::USN_REASON_RENAME_NEW_NAME != 0;
+        let watched = self.directories.contains_key(&number);
+
+        if reason & Ioctl::USN_REASON_FILE_DELETE != 0
+            || (watched && new_name && !inside && number != self.root)
+        {
+            // Removed, or moved out.
+            self.remove_subtree(number);
+        } else if watched && inside {
+            // Moved inside.
+            self.directories.insert(number, parent);
+        } else if !watched
+            && inside
+            && self.recursive
+            && reason & (Ioctl::USN_REASON_FILE_CREATE | Ioctl::USN_REASON_RENAME_NEW_NAME) != 0
+        {
+            self.directories.insert(number, parent);
+            if new_name {
+                self.add_subtree(number);
+            }
+        }
+    }
+
+    fn remove_subtree(&mut self, number: u64) {
+        if self.directories.remove(&number).is_none() {
+            return;
+        }
+
+        let mut removed = vec![number];
+        while let Some(parent) = removed.pop() {
+            let children: Vec<u64> = self
+                .directories
+                .iter()
+                .filter(|(_, p)| **p == parent)
+                .map(|(child, _)| *child)
+                .collect();
+            for child in children {
+                self.directories.remove(&child);
+                removed.push(child);
+            }
+        }
+    }
+
+    // The directories inside one that was moved in are not in the journal, they are enumerated
+    // from the file system (not available for the journals opened with `Journal::open`).
+    fn add_subtree(&mut self, number: u64) {
+        let Some(volume) = self.watcher.journal.get_volume() else {
+            return;
+        };
+        let Ok(entries) = volume.enum_usn_data() else {
+            return;
+        };
+
+        let mut children: HashMap<u64, Vec<u64>> = HashMap::new();
+        for entry in entries.filter_map(Result::ok) {
+            if entry.is_directory() {
+                let parent = reference_number(entry.parent_id.reference());
+                let child = reference_number(entry.file_id.reference());
+                children.entry(parent).or_default().push(child);
+            }
+        }
+
+        let mut added = vec![number];
+        while let Some(parent) = added.pop() {
+            for child in children.remove(&parent).unwrap_or_default() {
+                self.directories.insert(child, parent);
+                added.push(child);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use windows::Win32::System::Ioctl;
+
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+
+    use super::{classify, BatchOptions, Batcher, Coalescer, Event, EventKind, FileEvents};
+
+    #[test]
+    fn classify_close_records() {
+        let close = Ioctl::USN_REASON_CLOSE;
+
+        assert_eq!(classify(Ioctl::USN_REASON_FILE_CREATE), None);
+        assert_eq!(
+            classify(close | Ioctl::USN_REASON_FILE_CREATE | Ioctl::USN_REASON_DATA_EXTEND),
+            Some(EventKind::Created)
+        );
+        assert_eq!(
+            classify(close | Ioctl::USN_REASON_FILE_CREATE | Ioctl::USN_REASON_FILE_DELETE),
+            None
+        );
+        assert_eq!(
+            classify(close | Ioctl::USN_REASON_FILE_DELETE),
+            Some(EventKind::Removed)
+        );
+        assert_eq!(
+            classify(close | Ioctl::USN_REASON_RENAME_NEW_NAME),
+            Some(EventKind::Renamed)
+        );
+        assert_eq!(
+            classify(close | Ioctl::USN_REASON_DATA_OVERWRITE),
+            Some(EventKind::Modified)
+        );
+    }
+
+    #[test]
+    fn coalesce_modified() {
+        let window = Duration::from_secs(1);
+        let mut coalescer = Coalescer::new(window);
+        let mut events = Vec::new();
+
+        let a = PathBuf::from("a");
+        let b = PathBuf::from("b");
+        let start = Instant::now();
+
+        coalescer.push(Event::Created(a.clone()), start, &mut events);
+        coalescer.push(Event::Modified(b.clone()), start, &mut events);
+        for i in 1..10 {
+            let now = start + Duration::from_millis(i * 100);
+            coalescer.push(Event::Modified(a.clone()), now, &mut events);
+        }
+
+        // `b` has been quiet for the whole window, `a` is still being modified.
+        coalescer.flush(start + window, false, &mut events);
+        assert_eq!(events, vec![Event::Modified(b.clone())]);
+
+        events.clear();
+        coalescer.push(Event::Removed(a.clone()), start + window, &mut events);
+        assert_eq!(events, vec![Event::Created(a.clone()), Event::Removed(a)]);
+        assert!(coalescer.next_deadline().is_none());
+
+        // The held events are emitted before the resync.
+        events.clear();
+        let resync = Event::Resync {
+            directories: Vec::new(),
+            files: vec![b.clone()],
+        };
+        coalescer.push(Event::Modified(b.clone()), start + window, &mut events);
+        coalescer.push(resync.clone(), start + window, &mut events);
+        assert_eq!(events, vec![Event::Modified(b), resync]);
+    }
+
+    #[test]
+    fn batch_events() {
+        let options = BatchOptions {
+            debounce: Duration::from_secs(1),
+            max_batch_size: 3,
+        };
+        let mut batcher = Batcher::new(options);
+        let path = |name: &str| PathBuf::from(name);
+        let start = Instant::now();
+
+        // Saved with a temporary file: created, written, and renamed over the original.
+        batcher.push(Event::Created(path("a.tmp")), start);
+        batcher.push(Event::Modified(path("a.tmp")), start);
+        batcher.push(Event::Removed(path("a")), start);
+        batcher.push(
+            Event::Renamed {
+                from: path("a.tmp"),
+                to: path("a"),
+            },
+            start,
+        );
+        // Created and removed within the batch.
+        batcher.push(Event::Created(path("b")), start);
+        batcher.push(Event::Removed(path("b")), start);
+        // Renamed twice, and modified.
+        let later = start + Duration::from_millis(500);
+        batcher.push(
+            Event::Renamed {
+                from: path("c"),
+                to: path("d"),
+            },
+            start,
+        );
+        batcher.push(Event::Modified(path("d")), later);
+        batcher.push(
+            Event::Renamed {
+                from: path("d"),
+                to: path("e"),
+            },
+            later,
+        );
+
+        assert!(batcher.flush(start, false).is_empty());
+        assert_eq!(
+            batcher.flush(start + Duration::from_secs(1), false),
+            vec![vec![FileEvents {
+                path: path("a"),
+                events: vec![Event::Modified(path("a"))],
+            }]]
+        );
+        assert_eq!(
+            batcher.next_deadline(),
+            Some(later + Duration::from_secs(1))
+        );
+        assert_eq!(
+            batcher.flush(later, true),
+            vec![vec![FileEvents {
+                path: path("e"),
+                events: vec![
+                    Event::Renamed {
+                        from: path("c"),
+                        to: path("e"),
+                    },
+                    Event::Modified(path("e")),
+                ],
+            }]]
+        );
+
+        // Too many files to wait.
+        for name in ["f", "g", "h", "i"] {
+            batcher.push(Event::Modified(path(name)), start);
+        }
+        let batches = batcher.flush(start, false);
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].len(), 3);
+        assert_eq!(batches[1].len(), 1);
+    }
+}